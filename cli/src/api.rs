@@ -1,29 +1,63 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use reqwest::{multipart::Form, StatusCode};
 use serde_json::{self, json};
 
 use anyhow::{anyhow, Result};
-use shared::{deployable::deploy::Deploy, err, ok, Secret, UserAuthBody, UserSafe};
+use shared::{
+    deployable::{deploy::Deploy, preflight::PreflightIssue, smart::get_env_refs},
+    err, ok,
+    proxy::{ProxySettings, ProxySettingsUpdate},
+    tls::DEFAULT_TLS_PORT,
+    ApiTokenInfo, AuditEntry, AuthTokens, Secret, SecretImportReport, SecretValue, SecretVersion,
+    SsoDevice, SsoLogin, SsoTokenBody, UserAuthBody, UserSafe,
+};
 use url::Url;
 
+use crate::{
+    data::RemoteAuth,
+    tls::{client_builder, Trust},
+};
+
+// a poll of a sso login, see API::poll_sso_login
+pub enum SsoPoll {
+    Pending,
+    SlowDown,
+    Done(SsoLogin),
+}
+
 pub struct API {
     pub main_url: Url,
     pub req_client: reqwest::Client,
+    trust: Trust,
 }
 
 impl API {
-    pub fn new(url: &str) -> Result<Self> {
-        //println!("Connected to {}", url);
+    pub fn new(url: &str, trust: &Trust) -> Result<Self> {
+        let main_url = Url::parse(url)?;
         Ok(API {
-            main_url: Url::parse(url)?,
-            req_client: reqwest::Client::builder()
-                .danger_accept_invalid_certs(true) // Отключаем проверку сертификатов
-                .build()
-                .unwrap(),
+            req_client: client_builder(&main_url, trust)?.build()?,
+            main_url,
+            trust: trust.clone(),
         })
     }
 
+    pub fn for_user(user: &RemoteAuth) -> Result<Self> {
+        // logins of older versions used the plain http route through traefik, which the
+        // manager doesn't serve with TLS on
+        let url = Url::parse(&user.remote_url)?;
+        if url.scheme() == "http" && !user.insecure {
+            err!(anyhow!(
+                "The login of context {} uses plain http, the manager serves the api over \
+                https on port {} now. Run `lev logout` and log in again with `lev login -a {}`",
+                user.context,
+                DEFAULT_TLS_PORT,
+                url.host_str().unwrap_or_default()
+            ))
+        }
+        Self::new(&user.remote_url, &user.trust())
+    }
+
     pub async fn upload_config(
         &self,
         config: String,
@@ -66,11 +100,9 @@ impl API {
 
         let body = serde_json::to_string(&deploys)?;
         //println!("{}", body);
-        let client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(true) // Отключаем проверку сертификатов
+        let client = client_builder(&self.main_url, &self.trust)?
             .connect_timeout(Duration::from_secs(3))
-            .build()
-            .unwrap();
+            .build()?;
         let res = client
             .post(upload_url)
             .body(body)
@@ -98,10 +130,16 @@ impl API {
     ) -> Result<Vec<Deploy>> {
         let mut upload_url = self.main_url.clone();
         upload_url.set_path("/plan");
+        // only the envs the config reads leave this machine
+        let envs: HashMap<String, String> = get_env_refs(&config)?
+            .into_iter()
+            .filter_map(|name| std::env::var(&name).ok().map(|value| (name, value)))
+            .collect();
         let body = json!({
             "config": config,
             "filter": filter,
-            "to_build": to_build
+            "to_build": to_build,
+            "envs": envs
         })
         .to_string();
         let res = self
@@ -149,9 +187,10 @@ impl API {
         }
     }
 
-    pub async fn upload_image(&self, form: Form, token: String) -> Result<()> {
+    pub async fn upload_image(&self, form: Form, project: &str, token: String) -> Result<()> {
         let mut upload_url = self.main_url.clone();
         upload_url.set_path("/upload_image");
+        upload_url.query_pairs_mut().append_pair("project", project);
 
         let res = self
             .req_client
@@ -206,7 +245,12 @@ impl API {
         }
     }
 
-    pub async fn register_super_user(&self, username: &str, password: &str) -> Result<String> {
+    pub async fn register_super_user(
+        &self,
+        username: &str,
+        password: &str,
+        bootstrap_token: &str,
+    ) -> Result<AuthTokens> {
         let mut super_user_url = self.main_url.clone();
         super_user_url.set_path("/register/super");
         let res = self
@@ -221,17 +265,19 @@ impl API {
             )
             .header("Content-Type", "application/json")
             .header("X-LEVERANS-PASS", "true")
+            .header("X-Bootstrap-Token", bootstrap_token)
             .send()
             .await?;
         if res.status().is_success() {
-            Ok(res.text().await?)
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
         } else {
             let error_text = res.text().await?;
             Err(anyhow!("Failed to register super user: {}", error_text))
         }
     }
 
-    pub async fn login_user(&self, username: &str, password: &str) -> Result<String> {
+    pub async fn login_user(&self, username: &str, password: &str) -> Result<AuthTokens> {
         let mut super_user_url = self.main_url.clone();
         super_user_url.set_path("/login/super");
         let res = self
@@ -249,12 +295,92 @@ impl API {
             .send()
             .await?;
         if res.status().is_success() {
-            Ok(res.text().await?)
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
         } else {
             let error_text = res.text().await?;
             Err(anyhow!("Failed to login super user: {}", error_text))
         }
     }
+
+    pub async fn start_sso_login(&self) -> Result<SsoDevice> {
+        let mut sso_url = self.main_url.clone();
+        sso_url.set_path("/sso/device");
+        let res = self
+            .req_client
+            .post(sso_url)
+            .header("X-LEVERANS-PASS", "true")
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to start sso login: {}", error_text))
+        }
+    }
+
+    pub async fn poll_sso_login(&self, device_code: &str) -> Result<SsoPoll> {
+        let mut sso_url = self.main_url.clone();
+        sso_url.set_path("/sso/token");
+        let res = self
+            .req_client
+            .post(sso_url)
+            .body(serde_json::to_string(&SsoTokenBody {
+                device_code: device_code.to_string(),
+            })?)
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .send()
+            .await?;
+        let status = res.status();
+        let text = res.text().await?;
+        match status {
+            StatusCode::ACCEPTED if text == "slow_down" => Ok(SsoPoll::SlowDown),
+            StatusCode::ACCEPTED => Ok(SsoPoll::Pending),
+            status if status.is_success() => Ok(SsoPoll::Done(serde_json::from_str(&text)?)),
+            _ => Err(anyhow!("Failed to login with sso: {}", text)),
+        }
+    }
+
+    pub async fn refresh_session(&self, refresh_token: &str) -> Result<AuthTokens> {
+        let mut refresh_url = self.main_url.clone();
+        refresh_url.set_path("/auth/refresh");
+        let res = self
+            .req_client
+            .post(refresh_url)
+            .body(json!({ "refresh_token": refresh_token }).to_string())
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to refresh session: {}", error_text))
+        }
+    }
+
+    pub async fn logout(&self, token: &str) -> Result<()> {
+        let mut logout_url = self.main_url.clone();
+        logout_url.set_path("/logout");
+        let res = self
+            .req_client
+            .post(logout_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to log out: {}", error_text))
+        }
+    }
     pub async fn list_user(&self, token: &str) -> Result<Vec<UserSafe>> {
         let mut super_user_url = self.main_url.clone();
         super_user_url.set_path("/users");
@@ -309,7 +435,181 @@ impl API {
         }
     }
 
-    pub async fn add_secret(&self, key: &str, value: &str, token: &str) -> Result<()> {
+    pub async fn delete_user(&self, username: &str, token: &str) -> Result<String> {
+        let mut users_url = self.main_url.clone();
+        users_url.set_path("/users");
+        users_url
+            .query_pairs_mut()
+            .append_pair("username", username);
+        let res = self
+            .req_client
+            .delete(users_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to delete user: {}", error_text))
+        }
+    }
+
+    pub async fn set_user_role(&self, username: &str, role: &str, token: &str) -> Result<String> {
+        let mut users_url = self.main_url.clone();
+        users_url.set_path("/users/role");
+        let res = self
+            .req_client
+            .put(users_url)
+            .body(
+                json!({
+                    "username": username,
+                    "role": role
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to change role: {}", error_text))
+        }
+    }
+
+    pub async fn set_user_password(
+        &self,
+        username: &str,
+        password: &str,
+        token: &str,
+    ) -> Result<String> {
+        let mut users_url = self.main_url.clone();
+        users_url.set_path("/users/password");
+        let res = self
+            .req_client
+            .put(users_url)
+            .body(
+                json!({
+                    "username": username,
+                    "password": password
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to change password: {}", error_text))
+        }
+    }
+
+    pub async fn set_user_disabled(
+        &self,
+        username: &str,
+        disabled: bool,
+        token: &str,
+    ) -> Result<String> {
+        let mut users_url = self.main_url.clone();
+        users_url.set_path(if disabled {
+            "/users/disable"
+        } else {
+            "/users/enable"
+        });
+        let res = self
+            .req_client
+            .post(users_url)
+            .body(json!({ "username": username }).to_string())
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to update user: {}", error_text))
+        }
+    }
+
+    pub async fn grant_project(
+        &self,
+        username: &str,
+        project: &str,
+        role: &str,
+        token: &str,
+    ) -> Result<String> {
+        let mut grant_url = self.main_url.clone();
+        grant_url.set_path("/users/grant");
+        let res = self
+            .req_client
+            .put(grant_url)
+            .body(
+                json!({
+                    "username": username,
+                    "project": project,
+                    "role": role
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to grant project: {}", error_text))
+        }
+    }
+
+    pub async fn revoke_project_grant(
+        &self,
+        username: &str,
+        project: &str,
+        token: &str,
+    ) -> Result<String> {
+        let mut grant_url = self.main_url.clone();
+        grant_url.set_path("/users/grant");
+        grant_url
+            .query_pairs_mut()
+            .append_pair("username", username)
+            .append_pair("project", project);
+        let res = self
+            .req_client
+            .delete(grant_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to revoke grant: {}", error_text))
+        }
+    }
+
+    pub async fn add_secret(
+        &self,
+        key: &str,
+        value: &str,
+        project: Option<&str>,
+        note: Option<&str>,
+        token: &str,
+    ) -> Result<()> {
         let mut super_user_url = self.main_url.clone();
         super_user_url.set_path("/secret");
         let res = self
@@ -318,7 +618,9 @@ impl API {
             .body(
                 json!({
                     "key": key,
-                    "value": value
+                    "value": value,
+                    "project": project,
+                    "note": note
                 })
                 .to_string(),
             )
@@ -335,7 +637,14 @@ impl API {
         }
     }
 
-    pub async fn update_secret(&self, key: &str, value: &str, token: &str) -> Result<()> {
+    pub async fn update_secret(
+        &self,
+        key: &str,
+        value: &str,
+        project: Option<&str>,
+        note: Option<&str>,
+        token: &str,
+    ) -> Result<i64> {
         let mut super_user_url = self.main_url.clone();
         super_user_url.set_path("/secret");
         let res = self
@@ -344,7 +653,9 @@ impl API {
             .body(
                 json!({
                     "key": key,
-                    "value": value
+                    "value": value,
+                    "project": project,
+                    "note": note
                 })
                 .to_string(),
             )
@@ -354,14 +665,14 @@ impl API {
             .send()
             .await?;
         if res.status().is_success() {
-            Ok(())
+            Ok(res.text().await?.trim().parse()?)
         } else {
             let error_text = res.text().await?;
             Err(anyhow!("Failed to update secret: {}", error_text))
         }
     }
 
-    pub async fn delete_secret(&self, key: &str, token: &str) -> Result<()> {
+    pub async fn delete_secret(&self, key: &str, project: Option<&str>, token: &str) -> Result<()> {
         let mut super_user_url = self.main_url.clone();
         super_user_url.set_path("/secret");
         let res = self
@@ -370,6 +681,7 @@ impl API {
             .body(
                 json!({
                     "key": key,
+                    "project": project
                 })
                 .to_string(),
             )
@@ -386,7 +698,12 @@ impl API {
         }
     }
 
-    pub async fn show_secret(&self, key: &str, token: &str) -> Result<String> {
+    pub async fn show_secret(
+        &self,
+        key: &str,
+        project: Option<&str>,
+        token: &str,
+    ) -> Result<String> {
         let mut super_user_url = self.main_url.clone();
         super_user_url.set_path("/secret/show");
         let res = self
@@ -395,6 +712,7 @@ impl API {
             .body(
                 json!({
                     "key": key,
+                    "project": project
                 })
                 .to_string(),
             )
@@ -411,12 +729,161 @@ impl API {
         }
     }
 
-    pub async fn list_secret(&self, token: &str) -> Result<Vec<Secret>> {
+    pub async fn rotate_master_key(&self, token: &str) -> Result<()> {
+        let mut rotate_url = self.main_url.clone();
+        rotate_url.set_path("/secret/rotate-master-key");
+        let res = self
+            .req_client
+            .post(rotate_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to rotate master key: {}", error_text))
+        }
+    }
+
+    pub async fn secret_history(
+        &self,
+        key: &str,
+        project: Option<&str>,
+        token: &str,
+    ) -> Result<Vec<SecretVersion>> {
+        let mut history_url = self.main_url.clone();
+        history_url.set_path("/secret/history");
+        let res = self
+            .req_client
+            .get(history_url)
+            .body(
+                json!({
+                    "key": key,
+                    "project": project
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to get secret history: {}", error_text))
+        }
+    }
+
+    pub async fn revert_secret(
+        &self,
+        key: &str,
+        project: Option<&str>,
+        version: i64,
+        token: &str,
+    ) -> Result<i64> {
+        let mut revert_url = self.main_url.clone();
+        revert_url.set_path("/secret/revert");
+        let res = self
+            .req_client
+            .post(revert_url)
+            .body(
+                json!({
+                    "key": key,
+                    "project": project,
+                    "version": version
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?.trim().parse()?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to revert secret: {}", error_text))
+        }
+    }
+
+    pub async fn import_secrets(
+        &self,
+        secrets: &[(String, String)],
+        project: Option<&str>,
+        overwrite: bool,
+        note: Option<&str>,
+        token: &str,
+    ) -> Result<SecretImportReport> {
+        let mut import_url = self.main_url.clone();
+        import_url.set_path("/secret/import");
+        let secrets: Vec<_> = secrets
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+        let res = self
+            .req_client
+            .post(import_url)
+            .body(
+                json!({
+                    "secrets": secrets,
+                    "project": project,
+                    "overwrite": overwrite,
+                    "note": note
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to import secrets: {}", error_text))
+        }
+    }
+
+    pub async fn export_secrets(
+        &self,
+        project: Option<&str>,
+        token: &str,
+    ) -> Result<Vec<SecretValue>> {
+        let mut export_url = self.main_url.clone();
+        export_url.set_path("/secret/export");
+        let res = self
+            .req_client
+            .get(export_url)
+            .body(json!({ "project": project }).to_string())
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to export secrets: {}", error_text))
+        }
+    }
+
+    pub async fn list_secret(&self, project: Option<&str>, token: &str) -> Result<Vec<Secret>> {
         let mut super_user_url = self.main_url.clone();
         super_user_url.set_path("/secret");
         let res = self
             .req_client
             .get(super_user_url)
+            .query(&[("project", project)])
             .header("X-LEVERANS-PASS", "true")
             .header("Authorization", token)
             .send()
@@ -430,4 +897,249 @@ impl API {
             Err(anyhow!("Failed to list secret: {}", error_text))
         }
     }
+
+    pub async fn get_proxy(&self, token: &str) -> Result<ProxySettings> {
+        let mut proxy_url = self.main_url.clone();
+        proxy_url.set_path("/proxy");
+        let res = self
+            .req_client
+            .get(proxy_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to get proxy settings: {}", error_text))
+        }
+    }
+
+    pub async fn update_proxy(
+        &self,
+        update: &ProxySettingsUpdate,
+        token: &str,
+    ) -> Result<ProxySettings> {
+        let mut proxy_url = self.main_url.clone();
+        proxy_url.set_path("/proxy");
+        // the server waits until the new traefik tasks are healthy
+        let res = self
+            .req_client
+            .put(proxy_url)
+            .body(serde_json::to_string(update)?)
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .timeout(Duration::from_secs(300))
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to update proxy: {}", error_text))
+        }
+    }
+
+    pub async fn maintenance_on(
+        &self,
+        project: &str,
+        page: Option<String>,
+        token: &str,
+    ) -> Result<()> {
+        let mut maintenance_url = self.main_url.clone();
+        maintenance_url.set_path("/maintenance");
+        let res = self
+            .req_client
+            .post(maintenance_url)
+            .body(
+                json!({
+                    "project": project,
+                    "page": page
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to enable maintenance: {}", error_text))
+        }
+    }
+
+    pub async fn maintenance_off(&self, project: &str, token: &str) -> Result<()> {
+        let mut maintenance_url = self.main_url.clone();
+        maintenance_url.set_path("/maintenance");
+        let res = self
+            .req_client
+            .delete(maintenance_url)
+            .body(
+                json!({
+                    "project": project,
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to disable maintenance: {}", error_text))
+        }
+    }
+
+    pub async fn create_token(
+        &self,
+        name: &str,
+        role: &str,
+        project: Option<&str>,
+        expires_in: Option<u64>,
+        token: &str,
+    ) -> Result<String> {
+        let mut tokens_url = self.main_url.clone();
+        tokens_url.set_path("/tokens");
+        let res = self
+            .req_client
+            .post(tokens_url)
+            .body(
+                json!({
+                    "name": name,
+                    "role": role,
+                    "project": project,
+                    "expires_in": expires_in
+                })
+                .to_string(),
+            )
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to create token: {}", error_text))
+        }
+    }
+
+    pub async fn list_tokens(&self, token: &str) -> Result<Vec<ApiTokenInfo>> {
+        let mut tokens_url = self.main_url.clone();
+        tokens_url.set_path("/tokens");
+        let res = self
+            .req_client
+            .get(tokens_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to list tokens: {}", error_text))
+        }
+    }
+
+    pub async fn revoke_token(&self, name: &str, token: &str) -> Result<()> {
+        let mut tokens_url = self.main_url.clone();
+        tokens_url.set_path("/tokens");
+        tokens_url.query_pairs_mut().append_pair("name", name);
+        let res = self
+            .req_client
+            .delete(tokens_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to revoke token: {}", error_text))
+        }
+    }
+
+    pub async fn list_audit(
+        &self,
+        query: &[(&str, String)],
+        token: &str,
+    ) -> Result<Vec<AuditEntry>> {
+        let mut audit_url = self.main_url.clone();
+        audit_url.set_path("/audit");
+        for (key, value) in query {
+            audit_url.query_pairs_mut().append_pair(key, value);
+        }
+        let res = self
+            .req_client
+            .get(audit_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to list audit log: {}", error_text))
+        }
+    }
+
+    pub async fn rotate_jwt_key(&self, grace: Option<u64>, token: &str) -> Result<String> {
+        let mut rotate_url = self.main_url.clone();
+        rotate_url.set_path("/jwt-key/rotate");
+        if let Some(grace) = grace {
+            rotate_url
+                .query_pairs_mut()
+                .append_pair("grace", &grace.to_string());
+        }
+        let res = self
+            .req_client
+            .post(rotate_url)
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            Ok(res.text().await?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to rotate jwt key: {}", error_text))
+        }
+    }
+
+    pub async fn preflight(&self, deploys: &[Deploy], token: &str) -> Result<Vec<PreflightIssue>> {
+        let mut preflight_url = self.main_url.clone();
+        preflight_url.set_path("/preflight");
+        let res = self
+            .req_client
+            .post(preflight_url)
+            .body(serde_json::to_string(deploys)?)
+            .header("Content-Type", "application/json")
+            .header("X-LEVERANS-PASS", "true")
+            .header("Authorization", token)
+            .send()
+            .await?;
+        if res.status().is_success() {
+            let text = res.text().await?;
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            let error_text = res.text().await?;
+            Err(anyhow!("Failed to run pre-flight checks: {}", error_text))
+        }
+    }
 }
@@ -1,8 +1,11 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(name =  "leverans", version = option_env!("LEV_VERSION").unwrap_or(env!("CARGO_PKG_VERSION")), about = "leverans cli client")]
 pub struct Lev {
+    #[arg(long, global = true, help = "the context to use instead of the current one, see `lev context ls`", default_value = None)]
+    pub server: Option<String>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -31,6 +34,9 @@ pub enum Commands {
 
         #[arg(short = 't', long, default_value = None)]
         timeout: Option<u64>,
+
+        #[arg(long, value_enum, default_value_t = DnsCheck::Warn)]
+        dns_check: DnsCheck,
     },
     Rollback {
         #[arg(short = 'f', long, default_value = "deploy.yaml")]
@@ -61,6 +67,19 @@ pub enum Commands {
 
         #[arg(short = 's', long, default_value_t = false)]
         skip_confirm: bool,
+
+        #[arg(short = 't', long, help = "the bootstrap token from the server log", default_value = None)]
+        bootstrap_token: Option<String>,
+
+        #[arg(short = 'f', long, help = "the certificate fingerprint from the server log", default_value = None)]
+        fingerprint: Option<String>,
+
+        #[arg(
+            long,
+            help = "don't check the server certificate and allow plain http",
+            default_value_t = false
+        )]
+        insecure: bool,
     },
     Login {
         #[arg(short = 'a', long, help = "the address of your server, eg 312.89.06.172 or mydomain.com", default_value = None)]
@@ -74,6 +93,24 @@ pub enum Commands {
 
         #[arg(short = 's', long, default_value_t = false)]
         skip_confirm: bool,
+
+        #[arg(short = 'f', long, help = "the certificate fingerprint from the server log", default_value = None)]
+        fingerprint: Option<String>,
+
+        #[arg(
+            long,
+            help = "don't check the server certificate and allow plain http",
+            default_value_t = false
+        )]
+        insecure: bool,
+
+        #[arg(
+            long,
+            help = "login with the single sign-on of your company, in a browser on any machine",
+            default_value_t = false,
+            conflicts_with_all = ["username", "password"]
+        )]
+        sso: bool,
     },
     User {
         #[command(subcommand)]
@@ -81,10 +118,43 @@ pub enum Commands {
     },
     Logout,
     Whoami,
+    Context {
+        #[command(subcommand)]
+        command: ContextCommands,
+    },
     Secret {
         #[command(subcommand)]
         command: SecretCommands,
     },
+    Proxy {
+        #[command(subcommand)]
+        command: ProxyCommands,
+    },
+    Maintenance {
+        #[command(subcommand)]
+        command: MaintenanceCommands,
+    },
+    Token {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommands,
+    },
+    Audit {
+        #[arg(short = 'p', long, default_value = None)]
+        project: Option<String>,
+
+        #[arg(short = 'u', long, default_value = None)]
+        user: Option<String>,
+
+        #[arg(short = 's', long, help = "a duration like 24h or a date like 2024-05-01", default_value = None)]
+        since: Option<String>,
+
+        #[arg(short = 'l', long, default_value = None)]
+        limit: Option<i64>,
+    },
     Plan {
         #[arg(short = 'f', long, default_value = "deploy.yaml")]
         file: String,
@@ -102,12 +172,23 @@ pub enum Commands {
 
         #[arg(short = 'u', long, default_value_t = false)]
         unfold: bool,
+
+        #[arg(long, value_enum, default_value_t = DnsCheck::Warn)]
+        dns_check: DnsCheck,
     },
     New {
         name: Option<String>,
     },
 }
 
+// what to do when domains of the plan don't point to the server
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum DnsCheck {
+    Warn,
+    Block,
+    Off,
+}
+
 #[derive(Subcommand, Clone)]
 pub enum UserCommands {
     Ls,
@@ -124,28 +205,251 @@ pub enum UserCommands {
         #[arg(short = 's', long, default_value_t = false)]
         skip_confirm: bool,
     },
+    Delete {
+        username: String,
+
+        #[arg(short = 's', long, default_value_t = false)]
+        skip_confirm: bool,
+    },
+    SetRole {
+        username: String,
+
+        #[arg(help = "super_user, full_access, update_only or read_only")]
+        role: String,
+    },
+    Passwd {
+        #[arg(help = "user to change the password of, yourself by default")]
+        username: Option<String>,
+
+        #[arg(short = 'p', long, help = "new password", default_value = None)]
+        password: Option<String>,
+    },
+    Disable {
+        username: String,
+    },
+    Enable {
+        username: String,
+    },
+    Grant {
+        username: String,
+
+        project: String,
+
+        #[arg(
+            help = "full_access, update_only or read_only, used instead of the global role on the project"
+        )]
+        role: String,
+    },
+    Revoke {
+        username: String,
+
+        project: String,
+    },
 }
 
 #[derive(Subcommand, Clone)]
 pub enum SecretCommands {
-    Ls,
+    Ls {
+        #[arg(short = 'p', long, help = "only global secrets and the ones of this project", default_value = None)]
+        project: Option<String>,
+    },
     Add {
         #[arg(short = 'k', long, default_value = None)]
         key: Option<String>,
         #[arg(short = 'v', long, default_value = None)]
         value: Option<String>,
+        #[arg(short = 'p', long, help = "scope the secret to a project", default_value = None)]
+        project: Option<String>,
+        #[arg(short = 'n', long, help = "note kept in the secret history", default_value = None)]
+        note: Option<String>,
     },
     Update {
         #[arg(short = 'k', long, default_value = None)]
         key: Option<String>,
         #[arg(short = 'v', long, default_value = None)]
         value: Option<String>,
+        #[arg(short = 'p', long, default_value = None)]
+        project: Option<String>,
+        #[arg(short = 'n', long, help = "note kept in the secret history", default_value = None)]
+        note: Option<String>,
     },
     Delete {
         key: Option<String>,
+        #[arg(short = 'p', long, default_value = None)]
+        project: Option<String>,
     },
     Show {
         key: Option<String>,
+        #[arg(short = 'p', long, default_value = None)]
+        project: Option<String>,
+    },
+    History {
+        key: String,
+        #[arg(short = 'p', long, default_value = None)]
+        project: Option<String>,
+    },
+    Revert {
+        key: String,
+        #[arg(long, help = "version to bring back, see `lev secret history`")]
+        version: i64,
+        #[arg(short = 'p', long, default_value = None)]
+        project: Option<String>,
+    },
+    Import {
+        #[arg(help = ".env file to import")]
+        file: String,
+        #[arg(long, help = "prepended to every key of the file", default_value = None)]
+        prefix: Option<String>,
+        #[arg(
+            long,
+            help = "update secrets that already exist",
+            default_value_t = false
+        )]
+        overwrite: bool,
+        #[arg(short = 'p', long, default_value = None)]
+        project: Option<String>,
+        #[arg(short = 'n', long, help = "note kept in the secret history", default_value = None)]
+        note: Option<String>,
+    },
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Env)]
+        format: ExportFormat,
+        #[arg(short = 'p', long, help = "export secrets of this project instead of the global ones", default_value = None)]
+        project: Option<String>,
+        #[arg(short = 'o', long, help = "write to a file instead of stdout", default_value = None)]
+        output: Option<String>,
+        #[arg(short = 's', long, default_value_t = false)]
+        skip_confirm: bool,
+    },
+    RotateMasterKey,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum ExportFormat {
+    Env,
+    Json,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum ProxyCommands {
+    Show,
+    Set {
+        #[arg(long, help = "email for let's encrypt certificates", default_value = None)]
+        acme_email: Option<String>,
+
+        #[arg(
+            short = 'e',
+            long = "entrypoint",
+            help = "entrypoint as name=port, can be repeated"
+        )]
+        entrypoints: Vec<String>,
+
+        #[arg(long, help = "domain to expose the traefik dashboard on", default_value = None)]
+        dashboard_domain: Option<String>,
+
+        #[arg(long, default_value = None)]
+        dashboard_user: Option<String>,
+
+        #[arg(long, default_value = None)]
+        dashboard_password: Option<String>,
+
+        #[arg(long, default_value_t = false)]
+        disable_dashboard: bool,
+
+        #[arg(long, default_value = None)]
+        access_logs: Option<bool>,
+    },
+    Upgrade {
+        #[arg(help = "traefik version, eg v2.11")]
+        version: String,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum MaintenanceCommands {
+    On {
+        project: String,
+
+        #[arg(short = 'p', long, help = "html file to show instead of the project", default_value = None)]
+        page: Option<String>,
+
+        #[arg(short = 'f', long, default_value = "deploy.yaml")]
+        file: String,
+
+        #[arg(short = 'c', long, default_value = "./")]
+        context: String,
+    },
+    Off {
+        project: String,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum TokenCommands {
+    Create {
+        #[arg(short = 'n', long, help = "name to list and revoke the token by")]
+        name: String,
+
+        #[arg(
+            short = 'r',
+            long,
+            help = "super_user, full_access, update_only or read_only"
+        )]
+        role: String,
+
+        #[arg(short = 'p', long, help = "limit the token to a project", default_value = None)]
+        project: Option<String>,
+
+        #[arg(short = 'e', long, help = "lifetime like 90d, 12h or 30m, no expiry by default", default_value = None)]
+        expires: Option<String>,
+    },
+    Ls,
+    Revoke {
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum ContextCommands {
+    Add {
+        name: String,
+
+        #[arg(short = 'a', long, help = "the address of your server, eg 312.89.06.172 or mydomain.com", default_value = None)]
+        address: Option<String>,
+
+        #[arg(short = 'u', long, help = "your username", default_value = None)]
+        username: Option<String>,
+
+        #[arg(short = 'p', long, help = "your password", default_value = None)]
+        password: Option<String>,
+
+        #[arg(short = 's', long, default_value_t = false)]
+        skip_confirm: bool,
+
+        #[arg(short = 'f', long, help = "the certificate fingerprint from the server log", default_value = None)]
+        fingerprint: Option<String>,
+
+        #[arg(
+            long,
+            help = "don't check the server certificate and allow plain http",
+            default_value_t = false
+        )]
+        insecure: bool,
+    },
+    Use {
+        name: String,
+    },
+    Ls,
+    Rm {
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Clone)]
+pub enum AdminCommands {
+    RotateJwtKey {
+        #[arg(short = 'g', long, help = "how long tokens of the current key stay valid, like 1h or 30m", default_value = None)]
+        grace: Option<String>,
     },
 }
 
@@ -1,39 +1,170 @@
-use std::fs;
+use std::{fs, sync::OnceLock};
 
 use anyhow::{anyhow, Result};
-use shared::{create_file_with_dirs, err, get_home_path, ok};
+use shared::{create_file_with_dirs, err, get_home_path, ok, AuthTokens};
 use sqlx::{query, query_as, sqlite::SqlitePool, Executor};
 
+use crate::{
+    api::API,
+    tls::{server_url, Trust},
+    utils::get_unix_seconds,
+};
+
 const DATABASE_URI_FOR_FILE: &str = ".config/leverans/leverans.db";
+// access tokens expiring sooner are refreshed before they are used
+const REFRESH_MARGIN: u64 = 60;
+// the context of logins without --server
+pub const DEFAULT_CONTEXT: &str = "default";
+const REMOTE_AUTH_COLUMNS: &str = "id, context, remote_url, remote_token, username,
+    refresh_token, expires_at, cert_fingerprint, insecure, active";
+
+// the context picked with --server for this run, otherwise the current one is used
+static SELECTED_CONTEXT: OnceLock<String> = OnceLock::new();
+
+pub fn select_context(name: &str) {
+    let _ = SELECTED_CONTEXT.set(name.to_string());
+}
+
+pub fn selected_context() -> Option<&'static str> {
+    SELECTED_CONTEXT.get().map(|c| c.as_str())
+}
 
 pub struct UserData {
     pub pool: SqlitePool,
 }
 
 impl UserData {
+    // LEV_TOKEN and LEV_SERVER, eg in CI, are used without the local login database.
+    // LEV_FINGERPRINT pins a self-signed certificate, LEV_INSECURE=true skips the checks
+    pub async fn current_user() -> Result<RemoteAuth> {
+        if let (Ok(token), Ok(server)) = (std::env::var("LEV_TOKEN"), std::env::var("LEV_SERVER")) {
+            ok!(RemoteAuth {
+                id: 0,
+                context: selected_context().unwrap_or("LEV_SERVER").to_string(),
+                remote_url: server_url(&server)?.to_string(),
+                remote_token: token,
+                username: "LEV_TOKEN".to_string(),
+                refresh_token: None,
+                expires_at: None,
+                cert_fingerprint: std::env::var("LEV_FINGERPRINT").ok(),
+                insecure: std::env::var("LEV_INSECURE").is_ok_and(|v| v == "true" || v == "1"),
+                active: true,
+            })
+        }
+        let db = Self::load_db(false).await?;
+        let user = db.load_current_user().await?;
+        match (&user.refresh_token, user.expires_at) {
+            (Some(refresh_token), Some(expires_at))
+                if expires_at <= (get_unix_seconds() + REFRESH_MARGIN) as i64 =>
+            {
+                let tokens = API::for_user(&user)?
+                    .refresh_session(refresh_token)
+                    .await
+                    .map_err(|e| anyhow!("{}, use `lev login` again", e))?;
+                db.update_tokens(user.id, &tokens).await?;
+                db.load_current_user().await
+            }
+            _ => ok!(user),
+        }
+    }
+
     pub async fn load_current_user(&self) -> Result<RemoteAuth> {
-        let remote_auths = query_as::<_, RemoteAuth>(
-            "select id, remote_url, remote_token, username from remote_auth",
-        )
-        .fetch_all(&self.pool)
-        .await?;
-        match remote_auths.len() {
-            1 => ok!(remote_auths[0].clone()),
-            0 => err!(anyhow!("There is no remote auth set in the database")),
-            _ => err!(anyhow!("There are more that 1 row, this should not happen")),
+        if let Some(name) = selected_context() {
+            return self.load_context(name).await?.ok_or(anyhow!(
+                "There is no context named {}, see `lev context ls`",
+                name
+            ));
         }
+        query_as::<_, RemoteAuth>(&format!(
+            "select {} from remote_auth where active = 1",
+            REMOTE_AUTH_COLUMNS
+        ))
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(anyhow!(
+            "There is no current context, use `lev login` or `lev context use <name>`"
+        ))
     }
 
-    pub async fn save_user(&self, token: String, url: String, username: String) -> Result<()> {
-        query("insert into remote_auth (remote_url, remote_token, username) values ( ?, ?, ? )")
-            .bind(url)
-            .bind(token)
-            .bind(username)
+    pub async fn load_context(&self, name: &str) -> Result<Option<RemoteAuth>> {
+        ok!(query_as::<_, RemoteAuth>(&format!(
+            "select {} from remote_auth where context = ?",
+            REMOTE_AUTH_COLUMNS
+        ))
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    pub async fn list_contexts(&self) -> Result<Vec<RemoteAuth>> {
+        ok!(query_as::<_, RemoteAuth>(&format!(
+            "select {} from remote_auth order by context",
+            REMOTE_AUTH_COLUMNS
+        ))
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    pub async fn use_context(&self, name: &str) -> Result<()> {
+        if self.load_context(name).await?.is_none() {
+            err!(anyhow!(
+                "There is no context named {}, see `lev context ls`",
+                name
+            ))
+        }
+        query("update remote_auth set active = (context = ?)")
+            .bind(name)
             .execute(&self.pool)
             .await?;
         ok!(())
     }
 
+    pub async fn save_user(
+        &self,
+        tokens: &AuthTokens,
+        context: &str,
+        url: String,
+        username: String,
+        trust: &Trust,
+    ) -> Result<()> {
+        // a new login becomes the current context
+        let mut tx = self.pool.begin().await?;
+        query("update remote_auth set active = 0")
+            .execute(&mut *tx)
+            .await?;
+        query(
+            "insert into remote_auth (context, remote_url, remote_token, username,
+            refresh_token, expires_at, cert_fingerprint, insecure, active)
+            values ( ?, ?, ?, ?, ?, ?, ?, ?, 1 )",
+        )
+        .bind(context)
+        .bind(url)
+        .bind(&tokens.access_token)
+        .bind(username)
+        .bind(&tokens.refresh_token)
+        .bind((get_unix_seconds() + tokens.expires_in) as i64)
+        .bind(&trust.fingerprint)
+        .bind(trust.insecure)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        ok!(())
+    }
+
+    pub async fn update_tokens(&self, id: i64, tokens: &AuthTokens) -> Result<()> {
+        query(
+            "update remote_auth set remote_token = ?, refresh_token = ?, expires_at = ?
+            where id = ?",
+        )
+        .bind(&tokens.access_token)
+        .bind(&tokens.refresh_token)
+        .bind((get_unix_seconds() + tokens.expires_in) as i64)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        ok!(())
+    }
+
     pub async fn delete_user(&self, id: i64) -> Result<()> {
         query("delete from remote_auth where id = ?")
             .bind(id)
@@ -65,9 +196,49 @@ impl UserData {
             db_path.to_str().unwrap().to_string()
         };
         let pool = SqlitePool::connect(&url).await?;
-        pool.execute(MIGRATION).await?;
+        Self::migrate(&pool).await?;
         ok!(Self { pool })
     }
+
+    async fn migrate(pool: &SqlitePool) -> Result<()> {
+        pool.execute(MIGRATION).await?;
+        // logins saved before refresh tokens, before TLS was checked, those are checked
+        // like new ones and plain http ones have to log in again, and before contexts, the
+        // only login becomes the current default context
+        let columns: Vec<(String,)> = query_as("select name from pragma_table_info('remote_auth')")
+            .fetch_all(pool)
+            .await?;
+        for (column, definition) in [
+            ("refresh_token", "refresh_token text"),
+            ("expires_at", "expires_at integer"),
+            ("cert_fingerprint", "cert_fingerprint text"),
+            ("insecure", "insecure integer not null default 0"),
+            ("context", "context text not null default 'default'"),
+            ("active", "active integer not null default 1"),
+        ] {
+            if !columns.iter().any(|c| c.0 == column) {
+                pool.execute(format!("alter table remote_auth add column {}", definition).as_str())
+                    .await?;
+            }
+        }
+        // every login used to add a row, the newest one of a context keeps its name and
+        // becomes the only current one
+        pool.execute(
+            "update remote_auth set context = context || '-' || id
+            where id not in (select max(id) from remote_auth group by context)",
+        )
+        .await?;
+        pool.execute(
+            "update remote_auth set active = 0
+            where id != (select max(id) from remote_auth where active = 1)",
+        )
+        .await?;
+        pool.execute(
+            "create unique index if not exists remote_auth_context on remote_auth (context)",
+        )
+        .await?;
+        ok!(())
+    }
 }
 
 const MIGRATION: &str = r#"
@@ -82,18 +253,46 @@ const MIGRATION: &str = r#"
 #[derive(sqlx::FromRow, Clone)]
 pub struct RemoteAuth {
     pub id: i64,
+    // the name the login is picked by with --server and `lev context use`
+    pub context: String,
     pub remote_url: String,
     pub remote_token: String,
     pub username: String,
+    pub refresh_token: Option<String>,
+    // unix seconds when remote_token expires
+    pub expires_at: Option<i64>,
+    // pinned sha256 of a self-signed server certificate
+    pub cert_fingerprint: Option<String>,
+    pub insecure: bool,
+    // the current context, used when there is no --server
+    pub active: bool,
+}
+
+impl RemoteAuth {
+    pub fn trust(&self) -> Trust {
+        Trust {
+            fingerprint: self.cert_fingerprint.clone(),
+            insecure: self.insecure,
+        }
+    }
 }
 
 #[tokio::test]
 async fn test_load_db() {
     let ud = UserData::load_db(true).await.expect("Failed to load db");
     ud.save_user(
-        "sometoken".to_string(),
+        &AuthTokens {
+            access_token: "sometoken".to_string(),
+            refresh_token: "somerefresh".to_string(),
+            expires_in: 900,
+        },
+        DEFAULT_CONTEXT,
         "someurl".to_string(),
         "username".to_string(),
+        &Trust {
+            fingerprint: Some("AB:CD".to_string()),
+            insecure: false,
+        },
     )
     .await
     .expect("Failed to save user");
@@ -101,4 +300,64 @@ async fn test_load_db() {
     let current_user = ud.load_current_user().await.expect("Failed to load user");
     assert_eq!(current_user.remote_url, "someurl");
     assert_eq!(current_user.remote_token, "sometoken");
+    assert_eq!(current_user.refresh_token, Some("somerefresh".to_string()));
+    assert_eq!(current_user.cert_fingerprint, Some("AB:CD".to_string()));
+    assert!(!current_user.insecure);
+
+    let tokens = AuthTokens {
+        access_token: "stagingtoken".to_string(),
+        refresh_token: "stagingrefresh".to_string(),
+        expires_in: 900,
+    };
+    ud.save_user(
+        &tokens,
+        "staging",
+        "stagingurl".to_string(),
+        "username".to_string(),
+        &Trust::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(ud.load_current_user().await.unwrap().context, "staging");
+    assert!(ud
+        .save_user(
+            &tokens,
+            "staging",
+            "otherurl".to_string(),
+            "username".to_string(),
+            &Trust::default(),
+        )
+        .await
+        .is_err());
+    ud.use_context(DEFAULT_CONTEXT).await.unwrap();
+    assert_eq!(ud.load_current_user().await.unwrap().remote_url, "someurl");
+    assert!(ud.use_context("nothing").await.is_err());
+    let contexts = ud.list_contexts().await.unwrap();
+    assert_eq!(contexts.len(), 2);
+    assert!(contexts[0].active && !contexts[1].active);
+}
+
+#[tokio::test]
+async fn duplicate_logins() {
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    pool.execute(MIGRATION).await.unwrap();
+    for url in ["oldurl", "newurl"] {
+        query("insert into remote_auth (remote_url, remote_token, username) values (?, ?, ?)")
+            .bind(url)
+            .bind("token")
+            .bind("username")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    UserData::migrate(&pool).await.unwrap();
+    UserData::migrate(&pool).await.unwrap();
+    let ud = UserData { pool };
+    let current_user = ud.load_current_user().await.unwrap();
+    assert_eq!(current_user.context, DEFAULT_CONTEXT);
+    assert_eq!(current_user.remote_url, "newurl");
+    let contexts = ud.list_contexts().await.unwrap();
+    assert_eq!(contexts.len(), 2);
+    assert_eq!(contexts[1].context, "default-1");
+    assert!(!contexts[1].active);
 }
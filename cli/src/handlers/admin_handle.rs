@@ -0,0 +1,14 @@
+use anyhow::Result;
+use shared::ok;
+
+use crate::{api::API, data::UserData, handlers::token_handle::parse_duration};
+
+pub async fn rotate_jwt_key(grace: Option<String>) -> Result<()> {
+    let grace = grace.as_deref().map(parse_duration).transpose()?;
+    let user = UserData::current_user().await?;
+    let kid = API::for_user(&user)?
+        .rotate_jwt_key(grace, &user.remote_token)
+        .await?;
+    println!("✔︎ JWT key rotated, new tokens are signed with key {}", kid);
+    ok!(())
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use shared::ok;
+
+use crate::{api::API, data::UserData, handlers::token_handle::parse_duration};
+
+pub async fn list_audit(
+    project: Option<String>,
+    username: Option<String>,
+    since: Option<String>,
+    limit: Option<i64>,
+) -> Result<()> {
+    let mut query = vec![];
+    if let Some(project) = project {
+        query.push(("project", project));
+    }
+    if let Some(username) = username {
+        query.push(("user", username));
+    }
+    // durations go as seconds back from now, anything else is sent as a date
+    if let Some(since) = since {
+        match parse_duration(&since) {
+            Ok(seconds) => query.push(("within", seconds.to_string())),
+            Err(_) => query.push(("since", since)),
+        }
+    }
+    if let Some(limit) = limit {
+        query.push(("limit", limit.to_string()));
+    }
+    let user = UserData::current_user().await?;
+    let entries = API::for_user(&user)?
+        .list_audit(&query, &user.remote_token)
+        .await?;
+    println!("Found {} entries: \n", entries.len());
+    for entry in entries {
+        println!(
+            "{}  |  {}  |  {} {}  |  Project: {}  |  {} {}  |  {}ms  |  IP: {}{}  |  Request: {}",
+            entry.created_at,
+            entry.username.as_deref().unwrap_or("(anonymous)"),
+            entry.method,
+            entry.path,
+            entry.project.as_deref().unwrap_or("-"),
+            entry.status,
+            entry.outcome,
+            entry.duration_ms,
+            entry.client_ip.as_deref().unwrap_or("-"),
+            entry
+                .forwarded_for
+                .as_deref()
+                .map(|f| format!(" (forwarded for {})", f))
+                .unwrap_or_default(),
+            entry.request_id
+        );
+    }
+    ok!(())
+}
@@ -1,49 +1,88 @@
 use std::{
     io::{stdin, stdout, Write},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use url::Url;
 
-fn parse_url(input: &str) -> Result<Url> {
-    // Если схема не указана, добавляем "http://"
-    let input_with_scheme = if input.starts_with("http://") || input.starts_with("https://") {
-        input.to_string()
-    } else {
-        format!("http://{}", input)
-    };
-
-    // Пробуем разобрать URL
-    Url::parse(&input_with_scheme).map_err(|e| e.into())
-}
-
 use anyhow::{anyhow, Result};
-use shared::{console::ask, err, ok};
+use shared::{console::ask, err, ok, tls::same_fingerprint};
 
-use crate::{api::API, data::UserData};
+use crate::{
+    api::{SsoPoll, API},
+    data::{RemoteAuth, UserData},
+    tls::{fetch_fingerprint, must_be_https, server_url, Trust},
+};
+
+// checks the server certificate before any password is sent, a self-signed one is
+// pinned once the user compared its fingerprint with the one in the server log
+async fn trust_server(url: &Url, trust: Trust, skip_confirm: bool) -> Result<Trust> {
+    if trust.insecure {
+        println!("⚠️  --insecure is set, the server certificate isn't checked\n");
+        ok!(trust)
+    }
+    must_be_https(url)?;
+    let fingerprint = fetch_fingerprint(url).await?;
+    if let Some(expected) = &trust.fingerprint {
+        if !same_fingerprint(expected, &fingerprint) {
+            err!(anyhow!(
+                "The fingerprint of the server certificate is {}, not {}",
+                fingerprint,
+                expected
+            ))
+        }
+        ok!(trust)
+    }
+    if API::new(url.as_str(), &Trust::default())?
+        .health_check()
+        .await
+        .is_ok()
+    {
+        ok!(Trust::default())
+    }
+    println!(
+        "The certificate of {} isn't signed by a known authority, its sha256 fingerprint is:\n{}\nCompare it with the fingerprint in the server log.\n",
+        url, fingerprint
+    );
+    if !skip_confirm && ask("Trust this server? (y/n): ")? != "y" {
+        err!(anyhow!("💨 Aborted, no changes were made"));
+    }
+    ok!(Trust {
+        fingerprint: Some(fingerprint),
+        insecure: false,
+    })
+}
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_auth(
+    context: String,
     init_address: Option<String>,
     init_username: Option<String>,
     init_password: Option<String>,
+    init_bootstrap_token: Option<String>,
+    trust: Trust,
     is_login: bool,
     skip_confirm: bool,
 ) -> Result<()> {
     let url = if init_address.is_some() {
-        parse_url(init_address.unwrap().as_str())?
+        server_url(init_address.unwrap().as_str())?
     } else {
         let address = ask("Server Address: ")?;
-        parse_url(address.as_str())?
+        server_url(address.as_str())?
     };
     let db = UserData::load_db(false).await?;
-    if let Ok(user) = db.load_current_user().await {
+    if let Some(user) = db.load_context(&context).await? {
         err!(anyhow!(
-            "You are already logged for domain: {}",
-            user.remote_url
+            "You are already logged for domain: {} as context {}, use `lev context add <name>` for another server",
+            user.remote_url,
+            context
         ))
     }
+    let trust = trust_server(&url, trust, skip_confirm).await?;
     let api = Arc::new(
-        API::new(&url.to_string()).map_err(|e| anyhow!("⚠️  Error on parsing address: {}", e))?,
+        API::new(url.as_str(), &trust)
+            .map_err(|e| anyhow!("⚠️  Error on parsing address: {}", e))?,
     );
 
     if is_login {
@@ -98,6 +137,12 @@ pub async fn handle_auth(
     } else {
         ask("Password: ")?
     };
+    // only needed to register the first super user
+    let bootstrap_token = match (init_bootstrap_token, is_login) {
+        (_, true) => String::new(),
+        (Some(token), false) => token,
+        (None, false) => ask("Bootstrap token (printed in the server log): ")?,
+    };
     if !skip_confirm {
         let confirm = ask(&format!(
             "Address: {} | Username: {} | Password: {} | Confirm (y/n): ",
@@ -111,50 +156,140 @@ pub async fn handle_auth(
         }
     }
     if is_login {
-        let token = api
+        let tokens = api
             .login_user(&username, &password)
             .await
             .map_err(|e| anyhow!("Error on login user: {}", e))?;
 
-        if token.is_empty() {
+        if tokens.access_token.is_empty() {
             err!(anyhow!("Error on loginning user: Empty token"));
         }
 
-        db.save_user(token, url.to_string(), username).await?;
+        db.save_user(&tokens, &context, url.to_string(), username, &trust)
+            .await?;
 
         println!("\n✔︎ User logged in uccessfully, you are in system.\n Use `lev new` or `lev init` in root of your project to get started.");
+        println!(" Saved as context {}, it is the current one now.", context);
     } else {
-        let token = api
-            .register_super_user(&username, &password)
+        let tokens = api
+            .register_super_user(&username, &password, bootstrap_token.trim())
             .await
             .map_err(|e| anyhow!("Error on registering super user: {}", e))?;
 
-        if token.is_empty() {
+        if tokens.access_token.is_empty() {
             err!(anyhow!("Error on registering super user: Empty token"));
         }
 
-        db.save_user(token, url.to_string(), username).await?;
+        db.save_user(&tokens, &context, url.to_string(), username, &trust)
+            .await?;
 
         println!("\n✔︎ Super user created successfully, you are in system.\n Use `lev new` or `lev init` in root of your project to get started.");
+        println!(" Saved as context {}, it is the current one now.", context);
     }
     ok!(())
 }
 
+// device login, the user approves it in a browser on any machine and the cli polls
+// the server until then
+pub async fn handle_sso_login(
+    context: String,
+    init_address: Option<String>,
+    trust: Trust,
+    skip_confirm: bool,
+) -> Result<()> {
+    let url = match init_address {
+        Some(address) => server_url(&address)?,
+        None => server_url(&ask("Server Address: ")?)?,
+    };
+    let db = UserData::load_db(false).await?;
+    if let Some(user) = db.load_context(&context).await? {
+        err!(anyhow!(
+            "You are already logged for domain: {} as context {}, use `lev context add <name>` for another server",
+            user.remote_url,
+            context
+        ))
+    }
+    let trust = trust_server(&url, trust, skip_confirm).await?;
+    let api = API::new(url.as_str(), &trust)
+        .map_err(|e| anyhow!("⚠️  Error on parsing address: {}", e))?;
+    let device = api.start_sso_login().await?;
+    println!(
+        "👋 To login to {}, open\n\n  {}\n\nand enter the code {}\n",
+        url, device.verification_uri, device.user_code
+    );
+    if let Some(complete) = &device.verification_uri_complete {
+        println!("or open {} to skip entering the code\n", complete);
+    }
+    println!("Waiting for the login to be approved..");
+    let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = device.interval.max(1);
+    let login = loop {
+        if Instant::now() > deadline {
+            err!(anyhow!("The code expired, run `lev login --sso` again"))
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        match api.poll_sso_login(&device.device_code).await? {
+            SsoPoll::Pending => {}
+            // the provider asks to poll 5 seconds slower from now on
+            SsoPoll::SlowDown => interval += 5,
+            SsoPoll::Done(login) => break login,
+        }
+    };
+    db.save_user(
+        &login.tokens,
+        &context,
+        url.to_string(),
+        login.username.clone(),
+        &trust,
+    )
+    .await?;
+    println!(
+        "\n✔︎ Logged in as {}, you are in system.\n Use `lev new` or `lev init` in root of your project to get started.",
+        login.username
+    );
+    println!(" Saved as context {}, it is the current one now.", context);
+    ok!(())
+}
+
 pub async fn handle_logout() -> Result<()> {
     let db = UserData::load_db(false).await?;
     let user = db.load_current_user().await?;
+    logout_context(&db, &user).await?;
+    println!(
+        "✔︎ User logged out successfully from context {}",
+        user.context
+    );
+    if user.active && !db.list_contexts().await?.is_empty() {
+        println!("Pick another context with `lev context use <name>`");
+    }
+    Ok(())
+}
+
+pub async fn logout_context(db: &UserData, user: &RemoteAuth) -> Result<()> {
+    // logins saved before sessions have nothing to revoke on the server. The access token
+    // may be expired, so a fresh one is revoked together with the session
+    if let Some(refresh_token) = &user.refresh_token {
+        let result = match API::for_user(user) {
+            Ok(api) => match api.refresh_session(refresh_token).await {
+                Ok(tokens) => api.logout(&tokens.access_token).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            println!("⚠️  Could not revoke the session on the server: {}", e);
+        }
+    }
 
     db.delete_user(user.id).await?;
-    println!("✔︎ User logged out successfully");
     Ok(())
 }
 
 pub async fn whoami() -> Result<()> {
-    let db = UserData::load_db(false).await?;
-    let user = db.load_current_user().await?;
+    let user = UserData::current_user().await?;
     println!(
-        "✔︎ Current user: IP: {}  |  Username: {}",
-        user.remote_url, user.username
+        "✔︎ Current user: IP: {}  |  Username: {}  |  Context: {}",
+        user.remote_url, user.username, user.context
     );
     Ok(())
 }
@@ -167,7 +302,7 @@ pub async fn create_user(
     role_id: Option<String>,
     skip_confirm: bool,
 ) -> Result<()> {
-    let user = UserData::load_db(false).await?.load_current_user().await?;
+    let user = UserData::current_user().await?;
     let username = if init_username.is_some() {
         init_username.unwrap()
     } else {
@@ -199,7 +334,7 @@ pub async fn create_user(
             err!(anyhow!("💨 Aborted, no changes were made"));
         }
     }
-    let res = API::new(&user.remote_url)?
+    let res = API::for_user(&user)?
         .create_new_user(&username, &password, &role, user.remote_token.as_str())
         .await?;
     println!("{}", res);
@@ -207,10 +342,81 @@ pub async fn create_user(
 }
 
 pub async fn list_user() -> Result<()> {
-    let user = UserData::load_db(false).await?.load_current_user().await?;
-    let users = API::new(&user.remote_url)?
+    let user = UserData::current_user().await?;
+    let users = API::for_user(&user)?
         .list_user(user.remote_token.as_str())
         .await?;
     println!("{}\n", serde_json::to_string_pretty(&users)?);
     Ok(())
 }
+
+pub async fn delete_user(username: String, skip_confirm: bool) -> Result<()> {
+    let user = UserData::current_user().await?;
+    if !skip_confirm {
+        let confirm = ask(&format!("Delete user {}? (y/n): ", username))?;
+        if confirm != "y" {
+            err!(anyhow!("💨 Aborted, no changes were made"));
+        }
+    }
+    let res = API::for_user(&user)?
+        .delete_user(&username, &user.remote_token)
+        .await?;
+    println!("✔︎ {}", res);
+    Ok(())
+}
+
+pub async fn set_user_role(username: String, role: String) -> Result<()> {
+    let user = UserData::current_user().await?;
+    let res = API::for_user(&user)?
+        .set_user_role(&username, &role, &user.remote_token)
+        .await?;
+    println!("✔︎ {}", res);
+    Ok(())
+}
+
+pub async fn set_user_password(username: Option<String>, password: Option<String>) -> Result<()> {
+    let user = UserData::current_user().await?;
+    let username = username.unwrap_or(user.username.clone());
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let password = ask("New password: ")?;
+            if ask("Repeat the password: ")? != password {
+                err!(anyhow!("Passwords don't match, no changes were made"));
+            }
+            password
+        }
+    };
+    let res = API::for_user(&user)?
+        .set_user_password(&username, &password, &user.remote_token)
+        .await?;
+    println!("✔︎ {}", res);
+    Ok(())
+}
+
+pub async fn set_user_disabled(username: String, disabled: bool) -> Result<()> {
+    let user = UserData::current_user().await?;
+    let res = API::for_user(&user)?
+        .set_user_disabled(&username, disabled, &user.remote_token)
+        .await?;
+    println!("✔︎ {}", res);
+    Ok(())
+}
+
+pub async fn grant_project(username: String, project: String, role: String) -> Result<()> {
+    let user = UserData::current_user().await?;
+    let res = API::for_user(&user)?
+        .grant_project(&username, &project, &role, &user.remote_token)
+        .await?;
+    println!("✔︎ {}", res);
+    Ok(())
+}
+
+pub async fn revoke_project_grant(username: String, project: String) -> Result<()> {
+    let user = UserData::current_user().await?;
+    let res = API::for_user(&user)?
+        .revoke_project_grant(&username, &project, &user.remote_token)
+        .await?;
+    println!("✔︎ {}", res);
+    Ok(())
+}
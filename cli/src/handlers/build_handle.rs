@@ -152,6 +152,7 @@ pub async fn new_build_images(
 pub async fn upload_images(
     docker: DockerService,
     images: Vec<String>,
+    project: &str,
     token: String,
 ) -> Result<()> {
     for task in images {
@@ -166,13 +167,9 @@ pub async fn upload_images(
 
         let form = multipart::Form::new().part("file", part);
 
-        let remote_url = UserData::load_db(false)
-            .await?
-            .load_current_user()
-            .await?
-            .remote_url;
-        API::new(&remote_url)?
-            .upload_image(form, token.clone())
+        let user = UserData::current_user().await?;
+        API::for_user(&user)?
+            .upload_image(form, project, token.clone())
             .await?;
         loader.finish_with_message(format!("uploaded: {}", task));
     }
@@ -0,0 +1,67 @@
+use anyhow::{anyhow, Result};
+use shared::{err, ok};
+
+use crate::{data::UserData, tls::Trust};
+
+use super::auth_handle::{handle_auth, logout_context};
+
+pub async fn add_context(
+    name: String,
+    address: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    trust: Trust,
+    skip_confirm: bool,
+) -> Result<()> {
+    handle_auth(
+        name,
+        address,
+        username,
+        password,
+        None,
+        trust,
+        true,
+        skip_confirm,
+    )
+    .await
+}
+
+pub async fn use_context(name: String) -> Result<()> {
+    let db = UserData::load_db(false).await?;
+    db.use_context(&name).await?;
+    println!("✔︎ Switched to context {}", name);
+    ok!(())
+}
+
+pub async fn list_contexts() -> Result<()> {
+    let db = UserData::load_db(false).await?;
+    let contexts = db.list_contexts().await?;
+    println!("Found {} contexts: \n", contexts.len());
+    for context in contexts {
+        println!(
+            "{} {}  |  Server: {}  |  Username: {}",
+            if context.active { "*" } else { " " },
+            context.context,
+            context.remote_url,
+            context.username
+        );
+    }
+    ok!(())
+}
+
+// logs out of the server of the context and forgets it
+pub async fn remove_context(name: String) -> Result<()> {
+    let db = UserData::load_db(false).await?;
+    let Some(context) = db.load_context(&name).await? else {
+        err!(anyhow!(
+            "There is no context named {}, see `lev context ls`",
+            name
+        ))
+    };
+    logout_context(&db, &context).await?;
+    println!("✔︎ Context {} removed", name);
+    if context.active && !db.list_contexts().await?.is_empty() {
+        println!("Pick another context with `lev context use <name>`");
+    }
+    ok!(())
+}
@@ -10,11 +10,14 @@ use shared::{console::new_loader, docker::DockerService, err, ok};
 
 use crate::{
     api::API,
+    commands::DnsCheck,
+    data::UserData,
     handlers::build_handle::{new_build_images, upload_images},
 };
 
 use super::plan_handle::handle_plan;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn new_handle_deploy(
     file_name: String,
     context: String,
@@ -25,8 +28,9 @@ pub async fn new_handle_deploy(
     unfold: bool,
     rollback: bool,
     timeout: Option<u64>,
+    dns_check: DnsCheck,
 ) -> Result<()> {
-    let (user, deploys) = handle_plan(
+    let (_, deploys) = handle_plan(
         filter,
         only,
         file_name,
@@ -34,6 +38,7 @@ pub async fn new_handle_deploy(
         to_build,
         unfold,
         rollback,
+        dns_check,
     )
     .await?;
     if !skip_confirm {
@@ -57,7 +62,13 @@ pub async fn new_handle_deploy(
         loader
     } else {
         let built_app_names = new_build_images(deploys.clone(), abs_path, docker.clone()).await?;
-        upload_images(docker, built_app_names, user.remote_token.clone()).await?;
+        // the access token of the plan may expire while images are built
+        let user = UserData::current_user().await?;
+        let project = deploys
+            .first()
+            .map(|d| d.deployable.project_name.clone())
+            .unwrap_or_default();
+        upload_images(docker, built_app_names, &project, user.remote_token.clone()).await?;
 
         let loader = new_loader("deploying".to_string());
         loader
@@ -65,7 +76,8 @@ pub async fn new_handle_deploy(
     defer! {
         loader.finish()
     }
-    let api = API::new(&user.remote_url)?;
+    let user = UserData::current_user().await?;
+    let api = API::for_user(&user)?;
     let mut finished = false;
     let mut times = 0;
     let mut errorname = String::new();
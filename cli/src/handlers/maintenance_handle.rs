@@ -0,0 +1,62 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use shared::{config::MainConfig, ok};
+
+use crate::{api::API, data::UserData, utils::open_file_as_string};
+
+pub async fn maintenance_on(
+    project: String,
+    page: Option<String>,
+    file_name: String,
+    context: String,
+) -> Result<()> {
+    let page = get_page(&project, page, &file_name, &context)?;
+    let user = UserData::current_user().await?;
+    API::for_user(&user)?
+        .maintenance_on(&project, page, &user.remote_token)
+        .await?;
+    println!("✔︎ {} is in maintenance mode", project);
+    ok!(())
+}
+
+pub async fn maintenance_off(project: String) -> Result<()> {
+    let user = UserData::current_user().await?;
+    API::for_user(&user)?
+        .maintenance_off(&project, &user.remote_token)
+        .await?;
+    println!("✔︎ {} is back online", project);
+    ok!(())
+}
+
+// the page from the flag, then the one from the config of this project, otherwise the
+// server uses its default page
+fn get_page(
+    project: &str,
+    page: Option<String>,
+    file_name: &str,
+    context: &str,
+) -> Result<Option<String>> {
+    if let Some(page) = page {
+        ok!(Some(open_file_as_string(&page)?))
+    }
+    let context = Path::new(context);
+    let Ok(raw_config) = open_file_as_string(
+        context
+            .join(file_name)
+            .to_str()
+            .ok_or(anyhow!("failed to convert path to string"))?,
+    ) else {
+        ok!(None)
+    };
+    let config = MainConfig::from_str(&raw_config).map_err(|_| anyhow!("invalid yaml"))?;
+    match config.maintenance_page {
+        Some(page) if config.project == project => ok!(Some(open_file_as_string(
+            context
+                .join(page)
+                .to_str()
+                .ok_or(anyhow!("failed to convert path to string"))?,
+        )?)),
+        _ => ok!(None),
+    }
+}
@@ -1,9 +1,15 @@
+pub mod admin_handle;
+pub mod audit_handle;
 pub mod auth_handle;
 pub mod build_handle;
+pub mod context_handle;
 pub mod deploy_handle;
+pub mod maintenance_handle;
 pub mod new_handler;
 pub mod plan_handle;
+pub mod proxy_handle;
 pub mod secret_handle;
+pub mod token_handle;
 
 use std::str::FromStr;
 
@@ -20,9 +26,6 @@ pub async fn handle_local(with_build: bool) -> Result<()> {
     change_local_domain(&mut yaml_config);
 
     //if with_build {
-    //    if is_traefik_running(&docker).await? {
-    //        create_traefik().await?;
-    //    }
     //    build_apps(&yaml_config, &docker).await?;
     //    println!("build");
     //} else {
@@ -32,18 +35,6 @@ pub async fn handle_local(with_build: bool) -> Result<()> {
     ok!(())
 }
 
-pub async fn is_traefik_running(docker: &DockerService) -> Result<bool> {
-    let services = docker.list_services().await?;
-
-    ok!(services
-        .iter()
-        .any(|s| s.spec.clone().unwrap().name.unwrap() == "traefik-service".to_string()))
-}
-
-pub async fn create_traefik() -> Result<()> {
-    ok!(())
-}
-
 pub fn change_local_domain(cfg: &mut MainConfig) {
     if let Some(app) = &mut cfg.apps {
         for (_, app_config) in app {
@@ -1,20 +1,35 @@
-use std::{fs, os, path::Path, process::exit};
+use std::{fs, os, path::Path, process::exit, str::FromStr};
 
 use anyhow::{anyhow, Result};
 use shared::{
+    config::MainConfig,
     deployable::{
         deploy::{Deploy, DeployAction, DeployTask},
+        preflight::PreflightIssue,
         rollback,
     },
-    ok,
+    err, ok,
 };
 
 use crate::{
     api::API,
+    commands::DnsCheck,
     data::{RemoteAuth, UserData},
     utils::open_file_as_string,
 };
 
+fn warn_unexpected_context(config: &MainConfig, user: &RemoteAuth, file_name: &str) {
+    if let Some(server) = &config.server {
+        if *server != user.context {
+            println!(
+                "⚠️  {} is meant for the context {}, but the context {} ({}) is used. Pass --server {} or run `lev context use {}`\n",
+                file_name, server, user.context, user.remote_url, server, server
+            );
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_plan(
     single_filter: Option<String>,
     only: Option<Vec<String>>,
@@ -23,16 +38,20 @@ pub async fn handle_plan(
     to_build: Option<Vec<String>>,
     unfold: bool,
     rollback: bool,
+    dns_check: DnsCheck,
 ) -> Result<(RemoteAuth, Vec<Deploy>)> {
     // prepare config
     let abs_path = fs::canonicalize(Path::new(&context))?;
     let config_path = abs_path.join(&file_name);
-    let user = UserData::load_db(false).await?.load_current_user().await?;
+    let user = UserData::current_user().await?;
     let raw_config = open_file_as_string(
         config_path
             .to_str()
             .ok_or(anyhow!("failed to convert path to string"))?,
     )?;
+    if let Ok(config) = MainConfig::from_str(&raw_config) {
+        warn_unexpected_context(&config, &user, &file_name);
+    }
     let final_filter = if single_filter.is_some() && only.is_some() {
         let mut ffilter = only.clone().unwrap();
         ffilter.push(single_filter.unwrap());
@@ -47,11 +66,11 @@ pub async fn handle_plan(
 
     // get plan
     let deploys = if rollback {
-        API::new(&user.remote_url)?
+        API::for_user(&user)?
             .get_rollback_plans(raw_config, user.remote_token.clone())
             .await?
     } else {
-        API::new(&user.remote_url)?
+        API::for_user(&user)?
             .get_plans(
                 raw_config,
                 user.remote_token.clone(),
@@ -114,14 +133,24 @@ pub async fn handle_plan(
     if !create_tasks.is_empty() {
         println!("  Create - {}:", create_tasks.len());
         for task in create_tasks {
-            println!("    - {}", task.deployable.short_name);
+            match task.color {
+                Some(color) => {
+                    println!("    - {} ({})", task.deployable.short_name, color.as_str())
+                }
+                None => println!("    - {}", task.deployable.short_name),
+            }
         }
     }
 
     if !update_tasks.is_empty() {
         println!("  Update - {}:", update_tasks.len());
         for task in update_tasks {
-            println!("    - {}", task.deployable.short_name);
+            match task.color {
+                Some(color) => {
+                    println!("    - {} ({})", task.deployable.short_name, color.as_str())
+                }
+                None => println!("    - {}", task.deployable.short_name),
+            }
         }
     }
 
@@ -131,5 +160,38 @@ pub async fn handle_plan(
             println!("    - {}", task.deployable.short_name);
         }
     }
+
+    let issues = API::for_user(&user)?
+        .preflight(&deploys, &user.remote_token)
+        .await?;
+    check_preflight(&issues, dns_check)?;
     ok!((user, deploys))
 }
+
+// duplicate routes always stop the deploy, dns problems only with --dns-check block
+fn check_preflight(issues: &[PreflightIssue], dns_check: DnsCheck) -> Result<()> {
+    let mut blocking = 0;
+    let mut printed_header = false;
+    for issue in issues {
+        if issue.is_dns() && dns_check == DnsCheck::Off {
+            continue;
+        }
+        if !printed_header {
+            println!("\nPre-flight checks:");
+            printed_header = true;
+        }
+        if !issue.is_dns() || dns_check == DnsCheck::Block {
+            blocking += 1;
+            println!("  ✘ {}", issue.message);
+        } else {
+            println!("  ! {}", issue.message);
+        }
+    }
+    if blocking > 0 {
+        err!(anyhow!(
+            "{} pre-flight check(s) failed, nothing was changed",
+            blocking
+        ))
+    }
+    ok!(())
+}
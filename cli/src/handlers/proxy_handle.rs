@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use shared::{
+    err, ok,
+    proxy::{ProxySettings, ProxySettingsUpdate},
+};
+
+use crate::{api::API, data::UserData};
+
+pub async fn show_proxy() -> Result<()> {
+    let user = UserData::current_user().await?;
+    let settings = API::for_user(&user)?.get_proxy(&user.remote_token).await?;
+    print_settings(&settings);
+    ok!(())
+}
+
+pub async fn set_proxy(
+    acme_email: Option<String>,
+    entrypoints: Vec<String>,
+    dashboard_domain: Option<String>,
+    dashboard_user: Option<String>,
+    dashboard_password: Option<String>,
+    disable_dashboard: bool,
+    access_logs: Option<bool>,
+) -> Result<()> {
+    let entrypoints = if entrypoints.is_empty() {
+        None
+    } else {
+        Some(parse_entrypoints(&entrypoints)?)
+    };
+    let update = ProxySettingsUpdate {
+        acme_email,
+        entrypoints,
+        dashboard_domain,
+        dashboard_user,
+        dashboard_password,
+        disable_dashboard,
+        access_logs,
+        version: None,
+    };
+    update_proxy(update).await
+}
+
+pub async fn upgrade_proxy(version: String) -> Result<()> {
+    let update = ProxySettingsUpdate {
+        version: Some(version),
+        ..Default::default()
+    };
+    update_proxy(update).await
+}
+
+async fn update_proxy(update: ProxySettingsUpdate) -> Result<()> {
+    let user = UserData::current_user().await?;
+    println!("Updating proxy, waiting for traefik to become healthy...");
+    let settings = API::for_user(&user)?
+        .update_proxy(&update, &user.remote_token)
+        .await?;
+    println!("✔︎ Proxy updated successfully\n");
+    print_settings(&settings);
+    ok!(())
+}
+
+fn parse_entrypoints(entrypoints: &[String]) -> Result<BTreeMap<String, u16>> {
+    let mut result = BTreeMap::new();
+    for entrypoint in entrypoints {
+        let Some((name, port)) = entrypoint.split_once('=') else {
+            err!(anyhow!(
+                "entrypoint should look like name=port, got {}",
+                entrypoint
+            ))
+        };
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| anyhow!("invalid port of entrypoint {}: {}", name, port))?;
+        result.insert(name.to_string(), port);
+    }
+    ok!(result)
+}
+
+fn print_settings(settings: &ProxySettings) {
+    println!("Traefik version: {}", settings.version);
+    println!(
+        "ACME email: {}",
+        settings.acme_email.as_deref().unwrap_or("-")
+    );
+    for (name, port) in &settings.entrypoints {
+        println!("Entrypoint: {} :{}", name, port);
+    }
+    match &settings.dashboard_domain {
+        Some(domain) => println!(
+            "Dashboard: https://{} (user: {})",
+            domain,
+            settings
+                .dashboard_auth
+                .as_deref()
+                .and_then(|auth| auth.split(':').next())
+                .unwrap_or("-")
+        ),
+        None => println!("Dashboard: disabled"),
+    }
+    println!(
+        "Access logs: {}",
+        if settings.access_logs { "on" } else { "off" }
+    );
+}
+
+#[test]
+fn parse_entrypoints_test() {
+    let parsed = parse_entrypoints(&["web=80".to_string(), "metrics=8082".to_string()]).unwrap();
+    assert_eq!(parsed.get("metrics"), Some(&8082));
+    assert!(parse_entrypoints(&["web:80".to_string()]).is_err());
+    assert!(parse_entrypoints(&["web=http".to_string()]).is_err());
+}
@@ -1,9 +1,16 @@
+use std::{fs, io::Write};
+
 use anyhow::{anyhow, Result};
-use shared::{err, ok};
+use shared::{console::ask, err, ok, SecretValue};
 
-use crate::{api::API, data::UserData};
+use crate::{api::API, commands::ExportFormat, data::UserData, utils::open_file_as_string};
 
-pub async fn add_secrets(key: Option<String>, value: Option<String>) -> Result<()> {
+pub async fn add_secrets(
+    key: Option<String>,
+    value: Option<String>,
+    project: Option<String>,
+    note: Option<String>,
+) -> Result<()> {
     let secret_key = match key {
         Some(key) => key,
         None => err!(anyhow!("secret key is required")),
@@ -13,32 +20,51 @@ pub async fn add_secrets(key: Option<String>, value: Option<String>) -> Result<(
         None => err!(anyhow!("secret value is required")),
     };
 
-    let user = UserData::load_db(false).await?.load_current_user().await?;
-    API::new(&user.remote_url)?
-        .add_secret(&secret_key, &secret_value, &user.remote_token)
+    let user = UserData::current_user().await?;
+    API::for_user(&user)?
+        .add_secret(
+            &secret_key,
+            &secret_value,
+            project.as_deref(),
+            note.as_deref(),
+            &user.remote_token,
+        )
         .await?;
 
     println!("✔︎ Secret added successfully");
     ok!(())
 }
 
-pub async fn list_secrets() -> Result<()> {
-    let user = UserData::load_db(false).await?.load_current_user().await?;
+pub async fn list_secrets(project: Option<String>) -> Result<()> {
+    let user = UserData::current_user().await?;
 
-    let secrets = API::new(&user.remote_url)?
-        .list_secret(&user.remote_token)
-        .await?;
+    let secrets = API::for_user(&user)?
+        .list_secret(project.as_deref(), &user.remote_token)
+        .await?
+        .into_iter()
+        .filter(|s| s.project.is_none() || s.project == project)
+        .collect::<Vec<_>>();
 
     println!("Found {} secrets: \n", secrets.len());
 
     for secret in secrets {
-        println!("Key: {}  |  Created at: {}", secret.key, secret.created_at);
+        println!(
+            "Key: {}  |  Project: {}  |  Created at: {}",
+            secret.key,
+            secret.project.as_deref().unwrap_or("(global)"),
+            secret.created_at
+        );
     }
 
     ok!(())
 }
 
-pub async fn update_secrets(key: Option<String>, value: Option<String>) -> Result<()> {
+pub async fn update_secrets(
+    key: Option<String>,
+    value: Option<String>,
+    project: Option<String>,
+    note: Option<String>,
+) -> Result<()> {
     let secret_key = match key {
         Some(key) => key,
         None => err!(anyhow!("secret key is required")),
@@ -48,40 +74,308 @@ pub async fn update_secrets(key: Option<String>, value: Option<String>) -> Resul
         None => err!(anyhow!("secret value is required")),
     };
 
-    let user = UserData::load_db(false).await?.load_current_user().await?;
-    API::new(&user.remote_url)?
-        .update_secret(&secret_key, &secret_value, &user.remote_token)
+    let user = UserData::current_user().await?;
+    let version = API::for_user(&user)?
+        .update_secret(
+            &secret_key,
+            &secret_value,
+            project.as_deref(),
+            note.as_deref(),
+            &user.remote_token,
+        )
         .await?;
 
-    println!("✔︎ Secret updated successfully");
+    println!("✔︎ Secret updated successfully, version {}", version);
     ok!(())
 }
 
-pub async fn delete_secrets(key: Option<String>) -> Result<()> {
+pub async fn delete_secrets(key: Option<String>, project: Option<String>) -> Result<()> {
     let secret_key = match key {
         Some(key) => key,
         None => err!(anyhow!("secret key is required")),
     };
 
-    let user = UserData::load_db(false).await?.load_current_user().await?;
-    API::new(&user.remote_url)?
-        .delete_secret(&secret_key, &user.remote_token)
+    let user = UserData::current_user().await?;
+    API::for_user(&user)?
+        .delete_secret(&secret_key, project.as_deref(), &user.remote_token)
         .await?;
 
     println!("✔︎ Secret deleted successfully");
     ok!(())
 }
 
-pub async fn show_secret(key: Option<String>) -> Result<()> {
+pub async fn show_secret(key: Option<String>, project: Option<String>) -> Result<()> {
     let secret_key = match key {
         Some(key) => key,
         None => err!(anyhow!("secret key is required")),
     };
-    let user = UserData::load_db(false).await?.load_current_user().await?;
-    let secret_value = API::new(&user.remote_url)?
-        .show_secret(&secret_key, &user.remote_token)
+    let user = UserData::current_user().await?;
+    let secret_value = API::for_user(&user)?
+        .show_secret(&secret_key, project.as_deref(), &user.remote_token)
         .await?;
 
     println!("✔︎  {} : {} ", secret_key, secret_value);
     ok!(())
 }
+
+pub async fn secret_history(key: String, project: Option<String>) -> Result<()> {
+    let user = UserData::current_user().await?;
+    let history = API::for_user(&user)?
+        .secret_history(&key, project.as_deref(), &user.remote_token)
+        .await?;
+
+    println!("History of {}: \n", key);
+    for version in history {
+        println!(
+            "Version: {}  |  Author: {}  |  Created at: {}  |  Note: {}",
+            version.version,
+            version.author,
+            version.created_at,
+            version.note.unwrap_or_default()
+        );
+    }
+    ok!(())
+}
+
+pub async fn revert_secret(key: String, version: i64, project: Option<String>) -> Result<()> {
+    let user = UserData::current_user().await?;
+    let new_version = API::for_user(&user)?
+        .revert_secret(&key, project.as_deref(), version, &user.remote_token)
+        .await?;
+
+    println!(
+        "✔︎ Secret reverted to version {}, saved as version {}",
+        version, new_version
+    );
+    ok!(())
+}
+
+pub async fn import_secrets(
+    file: String,
+    prefix: Option<String>,
+    overwrite: bool,
+    project: Option<String>,
+    note: Option<String>,
+) -> Result<()> {
+    let secrets: Vec<(String, String)> = parse_env_file(&open_file_as_string(&file)?)?
+        .into_iter()
+        .map(|(key, value)| {
+            (
+                format!("{}{}", prefix.clone().unwrap_or_default(), key),
+                value,
+            )
+        })
+        .collect();
+    if secrets.is_empty() {
+        err!(anyhow!("no secrets found in {}", file))
+    }
+
+    let user = UserData::current_user().await?;
+    let report = API::for_user(&user)?
+        .import_secrets(
+            &secrets,
+            project.as_deref(),
+            overwrite,
+            note.as_deref(),
+            &user.remote_token,
+        )
+        .await?;
+
+    for (title, keys) in [
+        ("Created", report.created),
+        ("Updated", report.updated),
+        ("Skipped", report.skipped),
+    ] {
+        if !keys.is_empty() {
+            println!("{} ({}): {}", title, keys.len(), keys.join(", "));
+        }
+    }
+    println!("✔︎ Secrets imported successfully");
+    ok!(())
+}
+
+pub async fn export_secrets(
+    format: ExportFormat,
+    project: Option<String>,
+    output: Option<String>,
+    skip_confirm: bool,
+) -> Result<()> {
+    if !skip_confirm {
+        let confirm = ask(&format!(
+            "Secret values of {} will be written in plain text. Confirm (y/n): ",
+            project.as_deref().unwrap_or("the global scope")
+        ))?;
+        if confirm != "y" {
+            err!(anyhow!("💨 Aborted, nothing was exported"));
+        }
+    }
+
+    let user = UserData::current_user().await?;
+    let secrets = API::for_user(&user)?
+        .export_secrets(project.as_deref(), &user.remote_token)
+        .await?;
+    let content = match format {
+        ExportFormat::Env => format_env_file(&secrets),
+        ExportFormat::Json => serde_json::to_string_pretty(&secrets)?,
+    };
+    match output {
+        Some(output) => {
+            write_private_file(&output, &content)?;
+            println!("✔︎ {} secrets exported to {}", secrets.len(), output);
+        }
+        None => println!("{}", content),
+    }
+    ok!(())
+}
+
+// the file holds plain secret values, only the owner can read it
+fn write_private_file(path: &str, content: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // the mode only applies to new files
+        if fs::metadata(path).is_ok() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(content.as_bytes())?;
+    ok!(())
+}
+
+// KEY=value lines, `export` prefixes, comments and quoted values. A key set twice keeps
+// the last value, like in shells
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut secrets: Vec<(String, String)> = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or(anyhow!("line {}: expected KEY=value", i + 1))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            err!(anyhow!("line {}: invalid key '{}'", i + 1, key))
+        }
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('"') {
+            let end = quoted
+                .rfind('"')
+                .ok_or(anyhow!("line {}: missing closing quote", i + 1))?;
+            unescape(&quoted[..end])
+        } else if let Some(quoted) = value.strip_prefix('\'') {
+            let end = quoted
+                .rfind('\'')
+                .ok_or(anyhow!("line {}: missing closing quote", i + 1))?;
+            quoted[..end].to_string()
+        } else {
+            value
+                .split(" #")
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+        secrets.retain(|(k, _)| k != key);
+        secrets.push((key.to_string(), value));
+    }
+    ok!(secrets)
+}
+
+// escapes of double quoted values
+fn unescape(value: &str) -> String {
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+pub fn format_env_file(secrets: &[SecretValue]) -> String {
+    secrets
+        .iter()
+        .map(|s| {
+            let value = s
+                .value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"\n", s.key, value)
+        })
+        .collect()
+}
+
+pub async fn rotate_master_key() -> Result<()> {
+    let user = UserData::current_user().await?;
+    API::for_user(&user)?
+        .rotate_master_key(&user.remote_token)
+        .await?;
+
+    println!("✔︎ Master key rotated, all secrets are re-encrypted");
+    ok!(())
+}
+
+#[test]
+fn env_file_test() {
+    let content = "# database\nexport DB_USER=admin\nDB_PASS=\"p@ss #1\\nline\"\nTOKEN='a b' \nURL=http://x # comment\nDB_USER=root\n";
+    let secrets = parse_env_file(content).unwrap();
+    assert_eq!(
+        secrets,
+        vec![
+            ("DB_PASS".to_string(), "p@ss #1\nline".to_string()),
+            ("TOKEN".to_string(), "a b".to_string()),
+            ("URL".to_string(), "http://x".to_string()),
+            ("DB_USER".to_string(), "root".to_string()),
+        ]
+    );
+    let values: Vec<SecretValue> = secrets
+        .iter()
+        .map(|(key, value)| SecretValue {
+            key: key.clone(),
+            value: value.clone(),
+            project: None,
+            version: 1,
+            digest: String::new(),
+        })
+        .chain([SecretValue {
+            key: "PATH".to_string(),
+            value: "C:\\new\"dir\"".to_string(),
+            project: None,
+            version: 1,
+            digest: String::new(),
+        }])
+        .collect();
+    let parsed = parse_env_file(&format_env_file(&values)).unwrap();
+    assert_eq!(parsed[..4], secrets[..]);
+    assert_eq!(parsed[4].1, "C:\\new\"dir\"");
+    assert!(parse_env_file("NO_VALUE").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn private_file_test() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = std::env::temp_dir().join(format!("lev-export-{}", std::process::id()));
+    let path = path.to_string_lossy().to_string();
+    fs::write(&path, "old").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    write_private_file(&path, "KEY=value").unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "KEY=value");
+    fs::remove_file(&path).unwrap();
+}
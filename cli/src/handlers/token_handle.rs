@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Result};
+use shared::{err, ok};
+
+use crate::{api::API, data::UserData};
+
+pub async fn create_token(
+    name: String,
+    role: String,
+    project: Option<String>,
+    expires: Option<String>,
+) -> Result<()> {
+    let expires_in = expires.as_deref().map(parse_duration).transpose()?;
+    let user = UserData::current_user().await?;
+    let token = API::for_user(&user)?
+        .create_token(
+            &name,
+            &role,
+            project.as_deref(),
+            expires_in,
+            &user.remote_token,
+        )
+        .await?;
+    println!("✔︎ Token {} created, it won't be shown again:\n", name);
+    println!("{}\n", token);
+    println!("Use it with LEV_TOKEN and LEV_SERVER={}", user.remote_url);
+    ok!(())
+}
+
+pub async fn list_tokens() -> Result<()> {
+    let user = UserData::current_user().await?;
+    let tokens = API::for_user(&user)?
+        .list_tokens(&user.remote_token)
+        .await?;
+    println!("Found {} tokens: \n", tokens.len());
+    for token in tokens {
+        println!(
+            "Name: {}  |  Role: {}  |  Project: {}  |  Created by: {}  |  Expires at: {}",
+            token.name,
+            token.role,
+            token.project.as_deref().unwrap_or("(all)"),
+            token.created_by,
+            token.expires_at.as_deref().unwrap_or("never")
+        );
+    }
+    ok!(())
+}
+
+pub async fn revoke_token(name: String) -> Result<()> {
+    let user = UserData::current_user().await?;
+    API::for_user(&user)?
+        .revoke_token(&name, &user.remote_token)
+        .await?;
+    println!("✔︎ Token {} revoked", name);
+    ok!(())
+}
+
+// 90d, 12h, 30m or 45s in seconds
+pub fn parse_duration(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let split = value.char_indices().last().map(|(i, _)| i).unwrap_or(0);
+    let (number, unit) = value.split_at(split);
+    let seconds = match unit {
+        "d" => 24 * 60 * 60,
+        "h" => 60 * 60,
+        "m" => 60,
+        "s" => 1,
+        _ => err!(anyhow!(
+            "Invalid duration {}, use eg 90d, 12h or 30m",
+            value
+        )),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Invalid duration {}, use eg 90d, 12h or 30m", value))?;
+    if number == 0 {
+        err!(anyhow!("Duration must be more than 0"))
+    }
+    number
+        .checked_mul(seconds)
+        .ok_or(anyhow!("Duration {} is too long", value))
+}
+
+#[test]
+fn parse_duration_test() {
+    assert_eq!(parse_duration("90d").unwrap(), 90 * 24 * 60 * 60);
+    assert_eq!(parse_duration("12h").unwrap(), 12 * 60 * 60);
+    assert_eq!(parse_duration("30m").unwrap(), 30 * 60);
+    assert!(parse_duration("90").is_err());
+    assert!(parse_duration("d").is_err());
+    assert!(parse_duration("0d").is_err());
+    assert!(parse_duration("99999999999999999d").is_err());
+}
@@ -2,6 +2,7 @@ use std::process::exit;
 
 use clap::Parser;
 use commands::Lev;
+use data::select_context;
 use routes::handle_routes;
 
 pub mod api;
@@ -9,11 +10,15 @@ pub mod commands;
 pub mod data;
 pub mod handlers;
 pub mod routes;
+pub mod tls;
 pub mod utils;
 
 #[tokio::main]
 async fn main() {
     let cli = Lev::parse();
+    if let Some(server) = &cli.server {
+        select_context(server);
+    }
     match handle_routes(cli).await {
         Ok(_) => exit(0),
         Err(e) => {
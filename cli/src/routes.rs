@@ -2,17 +2,40 @@ use anyhow::Result;
 use shared::ok;
 
 use crate::{
-    commands::{Commands, Lev, UserCommands},
+    commands::{
+        AdminCommands, Commands, ContextCommands, DnsCheck, Lev, MaintenanceCommands,
+        ProxyCommands, TokenCommands, UserCommands,
+    },
+    data::{selected_context, DEFAULT_CONTEXT},
     handlers::{
-        auth_handle::{create_user, handle_auth, handle_logout, list_user, whoami},
+        admin_handle::rotate_jwt_key,
+        audit_handle::list_audit,
+        auth_handle::{
+            create_user, delete_user, grant_project, handle_auth, handle_logout, handle_sso_login,
+            list_user, revoke_project_grant, set_user_disabled, set_user_password, set_user_role,
+            whoami,
+        },
+        context_handle::{add_context, list_contexts, remove_context, use_context},
         deploy_handle::new_handle_deploy,
         handle_local,
+        maintenance_handle::{maintenance_off, maintenance_on},
         new_handler::handle_new,
         plan_handle::handle_plan,
-        secret_handle::{add_secrets, delete_secrets, list_secrets, show_secret, update_secrets},
+        proxy_handle::{set_proxy, show_proxy, upgrade_proxy},
+        secret_handle::{
+            add_secrets, delete_secrets, export_secrets, import_secrets, list_secrets,
+            revert_secret, rotate_master_key, secret_history, show_secret, update_secrets,
+        },
+        token_handle::{create_token, list_tokens, revoke_token},
     },
+    tls::Trust,
 };
 
+// `lev auth` and `lev login` save to the context picked with --server
+fn login_context() -> String {
+    selected_context().unwrap_or(DEFAULT_CONTEXT).to_string()
+}
+
 pub async fn handle_routes(cli: Lev) -> Result<()> {
     match cli.command {
         Commands::Deploy {
@@ -24,6 +47,7 @@ pub async fn handle_routes(cli: Lev) -> Result<()> {
             skip_confirm,
             unfold,
             timeout,
+            dns_check,
         } => {
             new_handle_deploy(
                 file,
@@ -35,6 +59,7 @@ pub async fn handle_routes(cli: Lev) -> Result<()> {
                 unfold,
                 false,
                 timeout,
+                dns_check,
             )
             .await
         }
@@ -43,13 +68,74 @@ pub async fn handle_routes(cli: Lev) -> Result<()> {
             password,
             username,
             skip_confirm,
-        } => handle_auth(address, username, password, false, skip_confirm).await,
+            bootstrap_token,
+            fingerprint,
+            insecure,
+        } => {
+            let trust = Trust {
+                fingerprint,
+                insecure,
+            };
+            handle_auth(
+                login_context(),
+                address,
+                username,
+                password,
+                bootstrap_token,
+                trust,
+                false,
+                skip_confirm,
+            )
+            .await
+        }
         Commands::Login {
             address,
             password,
             username,
             skip_confirm,
-        } => handle_auth(address, username, password, true, skip_confirm).await,
+            fingerprint,
+            insecure,
+            sso,
+        } => {
+            let trust = Trust {
+                fingerprint,
+                insecure,
+            };
+            if sso {
+                return handle_sso_login(login_context(), address, trust, skip_confirm).await;
+            }
+            handle_auth(
+                login_context(),
+                address,
+                username,
+                password,
+                None,
+                trust,
+                true,
+                skip_confirm,
+            )
+            .await
+        }
+        Commands::Context { command } => match command {
+            ContextCommands::Add {
+                name,
+                address,
+                username,
+                password,
+                skip_confirm,
+                fingerprint,
+                insecure,
+            } => {
+                let trust = Trust {
+                    fingerprint,
+                    insecure,
+                };
+                add_context(name, address, username, password, trust, skip_confirm).await
+            }
+            ContextCommands::Use { name } => use_context(name).await,
+            ContextCommands::Ls => list_contexts().await,
+            ContextCommands::Rm { name } => remove_context(name).await,
+        },
         Commands::Logout => handle_logout().await,
         Commands::Whoami => whoami().await,
         Commands::Version => {
@@ -58,14 +144,100 @@ pub async fn handle_routes(cli: Lev) -> Result<()> {
             ok!(())
         }
         Commands::Secret { command } => match command {
-            crate::commands::SecretCommands::Ls => list_secrets().await,
-            crate::commands::SecretCommands::Add { key, value } => add_secrets(key, value).await,
-            crate::commands::SecretCommands::Update { key, value } => {
-                update_secrets(key, value).await
+            crate::commands::SecretCommands::Ls { project } => list_secrets(project).await,
+            crate::commands::SecretCommands::Add {
+                key,
+                value,
+                project,
+                note,
+            } => add_secrets(key, value, project, note).await,
+            crate::commands::SecretCommands::Update {
+                key,
+                value,
+                project,
+                note,
+            } => update_secrets(key, value, project, note).await,
+            crate::commands::SecretCommands::Delete { key, project } => {
+                delete_secrets(key, project).await
+            }
+            crate::commands::SecretCommands::Show { key, project } => {
+                show_secret(key, project).await
+            }
+            crate::commands::SecretCommands::History { key, project } => {
+                secret_history(key, project).await
+            }
+            crate::commands::SecretCommands::Revert {
+                key,
+                version,
+                project,
+            } => revert_secret(key, version, project).await,
+            crate::commands::SecretCommands::Import {
+                file,
+                prefix,
+                overwrite,
+                project,
+                note,
+            } => import_secrets(file, prefix, overwrite, project, note).await,
+            crate::commands::SecretCommands::Export {
+                format,
+                project,
+                output,
+                skip_confirm,
+            } => export_secrets(format, project, output, skip_confirm).await,
+            crate::commands::SecretCommands::RotateMasterKey => rotate_master_key().await,
+        },
+        Commands::Proxy { command } => match command {
+            ProxyCommands::Show => show_proxy().await,
+            ProxyCommands::Set {
+                acme_email,
+                entrypoints,
+                dashboard_domain,
+                dashboard_user,
+                dashboard_password,
+                disable_dashboard,
+                access_logs,
+            } => {
+                set_proxy(
+                    acme_email,
+                    entrypoints,
+                    dashboard_domain,
+                    dashboard_user,
+                    dashboard_password,
+                    disable_dashboard,
+                    access_logs,
+                )
+                .await
             }
-            crate::commands::SecretCommands::Delete { key } => delete_secrets(key).await,
-            crate::commands::SecretCommands::Show { key } => show_secret(key).await,
+            ProxyCommands::Upgrade { version } => upgrade_proxy(version).await,
+        },
+        Commands::Maintenance { command } => match command {
+            MaintenanceCommands::On {
+                project,
+                page,
+                file,
+                context,
+            } => maintenance_on(project, page, file, context).await,
+            MaintenanceCommands::Off { project } => maintenance_off(project).await,
+        },
+        Commands::Token { command } => match command {
+            TokenCommands::Create {
+                name,
+                role,
+                project,
+                expires,
+            } => create_token(name, role, project, expires).await,
+            TokenCommands::Ls => list_tokens().await,
+            TokenCommands::Revoke { name } => revoke_token(name).await,
+        },
+        Commands::Admin { command } => match command {
+            AdminCommands::RotateJwtKey { grace } => rotate_jwt_key(grace).await,
         },
+        Commands::Audit {
+            project,
+            user,
+            since,
+            limit,
+        } => list_audit(project, user, since, limit).await,
         Commands::Plan {
             file,
             context,
@@ -73,8 +245,19 @@ pub async fn handle_routes(cli: Lev) -> Result<()> {
             single_filter,
             only,
             unfold,
+            dns_check,
         } => {
-            handle_plan(single_filter, only, file, context, build, unfold, false).await?;
+            handle_plan(
+                single_filter,
+                only,
+                file,
+                context,
+                build,
+                unfold,
+                false,
+                dns_check,
+            )
+            .await?;
             ok!(())
         }
         Commands::New { name } => handle_new(name),
@@ -86,6 +269,24 @@ pub async fn handle_routes(cli: Lev) -> Result<()> {
                 role,
                 skip_confirm,
             } => create_user(username, password, role, skip_confirm).await,
+            UserCommands::Delete {
+                username,
+                skip_confirm,
+            } => delete_user(username, skip_confirm).await,
+            UserCommands::SetRole { username, role } => set_user_role(username, role).await,
+            UserCommands::Passwd { username, password } => {
+                set_user_password(username, password).await
+            }
+            UserCommands::Disable { username } => set_user_disabled(username, true).await,
+            UserCommands::Enable { username } => set_user_disabled(username, false).await,
+            UserCommands::Grant {
+                username,
+                project,
+                role,
+            } => grant_project(username, project, role).await,
+            UserCommands::Revoke { username, project } => {
+                revoke_project_grant(username, project).await
+            }
         },
         Commands::Rollback {
             file,
@@ -104,6 +305,7 @@ pub async fn handle_routes(cli: Lev) -> Result<()> {
                 unfold,
                 true,
                 timeout,
+                DnsCheck::Warn,
             )
             .await
         }
@@ -0,0 +1,195 @@
+use std::sync::{Arc, Mutex, Once};
+
+use anyhow::{anyhow, Result};
+use reqwest::ClientBuilder;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{
+        ring::default_provider, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
+use shared::{
+    err, ok,
+    tls::{fingerprint, same_fingerprint, DEFAULT_TLS_PORT},
+};
+use url::Url;
+
+// how the certificate of a server is checked, by default against the known cas
+#[derive(Debug, Clone, Default)]
+pub struct Trust {
+    // sha256 of a self-signed certificate, only this certificate is accepted
+    pub fingerprint: Option<String>,
+    // no checks at all and plain http allowed, only with --insecure
+    pub insecure: bool,
+}
+
+// an address without a scheme goes to the TLS port of the manager
+pub fn server_url(input: &str) -> Result<Url> {
+    if input.starts_with("http://") || input.starts_with("https://") {
+        ok!(Url::parse(input)?)
+    }
+    let mut url = Url::parse(&format!("https://{}", input))?;
+    if url.port().is_none() {
+        url.set_port(Some(DEFAULT_TLS_PORT))
+            .map_err(|_| anyhow!("Invalid server address {}", input))?;
+    }
+    ok!(url)
+}
+
+// accepts only the certificate with the pinned fingerprint, without a pin it accepts
+// any certificate and remembers the one it saw
+#[derive(Debug)]
+struct PinnedVerifier {
+    pin: Option<String>,
+    seen: Arc<Mutex<Option<String>>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let seen = fingerprint(end_entity);
+        *self.seen.lock().unwrap() = Some(seen.clone());
+        match &self.pin {
+            Some(pin) if !same_fingerprint(pin, &seen) => Err(rustls::Error::General(format!(
+                "the server certificate changed, its fingerprint is {} instead of {}",
+                seen, pin
+            ))),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn pinned_config(pin: Option<String>, seen: Arc<Mutex<Option<String>>>) -> Result<ClientConfig> {
+    let provider = Arc::new(default_provider());
+    ok!(ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+            pin,
+            seen,
+            provider,
+        }))
+        .with_no_client_auth())
+}
+
+pub fn must_be_https(url: &Url) -> Result<()> {
+    if url.scheme() != "https" {
+        err!(anyhow!(
+            "{} is plain http, passwords and tokens would be sent unencrypted. Use https or \
+            log in with --insecure",
+            url
+        ))
+    }
+    ok!(())
+}
+
+pub fn client_builder(url: &Url, trust: &Trust) -> Result<ClientBuilder> {
+    let builder = reqwest::Client::builder();
+    if trust.insecure {
+        static WARNED: Once = Once::new();
+        WARNED.call_once(|| {
+            eprintln!(
+                "⚠️  insecure mode, the certificate of {} isn't checked, log in again \
+                without --insecure to turn it off",
+                url
+            )
+        });
+        ok!(builder.danger_accept_invalid_certs(true))
+    }
+    must_be_https(url)?;
+    match &trust.fingerprint {
+        Some(pin) => {
+            ok!(builder.use_preconfigured_tls(pinned_config(Some(pin.clone()), Arc::default())?))
+        }
+        None => ok!(builder),
+    }
+}
+
+// the fingerprint of the certificate a server shows, trusted or not
+pub async fn fetch_fingerprint(url: &Url) -> Result<String> {
+    let seen = Arc::new(Mutex::new(None));
+    let client = reqwest::Client::builder()
+        .use_preconfigured_tls(pinned_config(None, seen.clone())?)
+        .build()?;
+    let mut health_url = url.clone();
+    health_url.set_path("/healthz");
+    let result = client
+        .get(health_url)
+        .header("X-LEVERANS-PASS", "true")
+        .send()
+        .await;
+    let seen = seen.lock().unwrap().clone();
+    seen.ok_or_else(|| match result {
+        Err(e) => anyhow!("Failed to connect to {}: {}", url, e),
+        Ok(_) => anyhow!("{} didn't show a certificate", url),
+    })
+}
+
+#[test]
+fn tls_test() {
+    assert_eq!(
+        server_url("10.0.0.1").unwrap().as_str(),
+        "https://10.0.0.1:8443/"
+    );
+    assert_eq!(
+        server_url("lev.example.com:9000").unwrap().as_str(),
+        "https://lev.example.com:9000/"
+    );
+    assert_eq!(
+        server_url("https://lev.example.com").unwrap().as_str(),
+        "https://lev.example.com/"
+    );
+    let url = server_url("http://10.0.0.1").unwrap();
+    assert!(client_builder(&url, &Trust::default()).is_err());
+    assert!(client_builder(
+        &url,
+        &Trust {
+            fingerprint: None,
+            insecure: true
+        }
+    )
+    .is_ok());
+}
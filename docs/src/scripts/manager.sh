@@ -83,33 +83,14 @@ if [ -z "$(docker network ls --filter name=^lev$ --filter driver=overlay -q)" ];
     docker network create --driver overlay lev
 fi
 
-docker service create \
-    --name traefik-service \
-    --network lev \
-    --publish 80:80 \
-    --publish 443:443 \
-    --publish 8080:8080 \
-    --mount type=bind,source=/var/run/docker.sock,target=/var/run/docker.sock \
-    --mount type=volume,source=letsencrypt,target=/letsencrypt \
-    --label "traefik.enable=false" \
-    traefik:v2.10 \
-    --api.insecure=true --api.dashboard=true --entryPoints.web.address=":80" \
-    --entryPoints.websecure.address=":443" \
-    --providers.docker.swarmMode=true \
-    --certificatesresolvers.myresolver.acme.tlschallenge=true \
-    --certificatesresolvers.myresolver.acme.email=$EMAIL \
-    --certificatesresolvers.myresolver.acme.storage=/letsencrypt/acme.json
-
 docker service create \
     --name lev-service \
     --network lev \
     -e "DBPATH=/data/main.db" \
     -e "IMAGES_DIR=/images" \
-    --label "traefik.enable=true" \
-    --label "traefik.http.routers.lev-service.rule=Headers(\`X-LEVERANS-PASS\`, \`true\`)" \
-    --label "traefik.http.routers.lev-service.service=lev-service" \
-    --label "traefik.http.services.lev-service.loadbalancer.server.port=8081" \
-    --label "traefik.http.routers.lev-service.entrypoints=web" \
+    -e "ACME_EMAIL=$EMAIL" \
+    --constraint "node.role == manager" \
+    --publish published=8443,target=8443,mode=host \
     --mount type=bind,source=/var/run/docker.sock,target=/var/run/docker.sock \
     --mount type=volume,source=levstore,target=/data/ \
     --mount type=volume,source=levimage,target=/images/ \
@@ -1,7 +1,12 @@
 use std::error::Error;
 
-use rand::{distributions::Alphanumeric, Rng};
-use server::{auth_handler::change_jwt_secret, start_server, ServerData};
+use repo::crypto::init_master_key;
+use server::{
+    audit_handler::prune_audit_log,
+    deploy_handler::reconcile_deploys,
+    proxy_handler::{reconcile_manager, reconcile_proxy},
+    start_server, ServerData,
+};
 
 pub mod cron;
 pub mod on_start;
@@ -12,21 +17,30 @@ pub mod server;
 async fn main() -> Result<(), Box<dyn Error>> {
     onstart();
     let sr = ServerData::new(8081).await;
+    let proxy_sr = sr.clone();
+    tokio::spawn(async move {
+        if let Err(e) = reconcile_proxy(&proxy_sr).await {
+            println!("failed to reconcile proxy: {}", e);
+        }
+        if let Err(e) = reconcile_manager(&proxy_sr).await {
+            println!("failed to update the manager service: {}", e);
+        }
+    });
+    let deploy_sr = sr.clone();
+    tokio::spawn(async move {
+        if let Err(e) = reconcile_deploys(&deploy_sr).await {
+            println!("failed to reconcile deploys: {}", e);
+        }
+    });
+    tokio::spawn(prune_audit_log(sr.repo.pool.clone()));
     start_server(sr).await?;
     Ok(())
 }
 
 fn onstart() {
     println!("Starting server...");
-    println!("Listening on 0.0.0.0:8081");
+    println!("Listening on port 8081");
 
-    println!("trying to get JWT_KEY env var");
-    let key = std::env::var("JWT_KEY").unwrap_or(generate_random_string(32));
-    change_jwt_secret(key.as_str());
-}
-fn generate_random_string(length: usize) -> String {
-    let mut rng = rand::thread_rng();
-    (0..length)
-        .map(|_| rng.sample(Alphanumeric) as char)
-        .collect()
+    let dbpath = std::env::var("DBPATH").unwrap();
+    init_master_key(&dbpath).unwrap();
 }
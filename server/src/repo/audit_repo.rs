@@ -0,0 +1,214 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use shared::{ok, AuditEntry};
+use sqlx::{prelude::FromRow, query, query_as, Executor, QueryBuilder, Sqlite, SqlitePool};
+
+pub const AUDIT_MIGRATION: &str = r#"
+    create table if not exists audit_log (
+        id text primary key,
+        created_at text not null,
+        username text,
+        role text,
+        method text not null,
+        path text not null,
+        project text,
+        status integer not null,
+        outcome text not null,
+        duration_ms integer not null,
+        client_ip text,
+        forwarded_for text
+    );
+    create index if not exists audit_log_created_at on audit_log (created_at);
+    "#;
+
+// AUDIT_RETENTION_DAYS, 0 keeps the log forever
+pub const DEFAULT_RETENTION_DAYS: i64 = 90;
+
+#[derive(Debug, Clone, FromRow)]
+pub struct AuditLog {
+    // the request id, returned to the client in X-Request-Id
+    pub id: String,
+    pub created_at: String,
+    pub username: Option<String>,
+    pub role: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub project: Option<String>,
+    pub status: i64,
+    pub outcome: String,
+    pub duration_ms: i64,
+    pub client_ip: Option<String>,
+    pub forwarded_for: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub project: Option<String>,
+    pub username: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub limit: i64,
+}
+
+pub fn outcome(status: u16) -> &'static str {
+    match status {
+        401 | 403 => "denied",
+        400.. => "error",
+        _ => "ok",
+    }
+}
+
+pub fn retention_days() -> i64 {
+    std::env::var("AUDIT_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS)
+}
+
+impl AuditLog {
+    pub async fn migrate(conn: &SqlitePool) -> Result<()> {
+        conn.execute(AUDIT_MIGRATION).await?;
+        // databases created before forwarded headers were kept apart
+        let columns: Vec<(String,)> = query_as("select name from pragma_table_info('audit_log')")
+            .fetch_all(conn)
+            .await?;
+        if !columns.iter().any(|c| c.0 == "forwarded_for") {
+            conn.execute("alter table audit_log add column forwarded_for text")
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn insert_db(&self, conn: &SqlitePool) -> Result<()> {
+        query(
+            "insert into audit_log (id, created_at, username, role, method, path, project,
+            status, outcome, duration_ms, client_ip, forwarded_for)
+            values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&self.id)
+        .bind(&self.created_at)
+        .bind(&self.username)
+        .bind(&self.role)
+        .bind(&self.method)
+        .bind(&self.path)
+        .bind(&self.project)
+        .bind(self.status)
+        .bind(&self.outcome)
+        .bind(self.duration_ms)
+        .bind(&self.client_ip)
+        .bind(&self.forwarded_for)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    // newest first
+    pub async fn list_db(filter: &AuditFilter, conn: &SqlitePool) -> Result<Vec<Self>> {
+        let mut builder: QueryBuilder<Sqlite> =
+            QueryBuilder::new("select * from audit_log where 1 = 1");
+        if let Some(project) = &filter.project {
+            builder.push(" and project = ").push_bind(project);
+        }
+        if let Some(username) = &filter.username {
+            builder.push(" and username = ").push_bind(username);
+        }
+        if let Some(since) = &filter.since {
+            builder
+                .push(" and created_at >= ")
+                .push_bind(since.to_rfc3339());
+        }
+        builder
+            .push(" order by created_at desc limit ")
+            .push_bind(filter.limit);
+        let rows = builder.build_query_as::<Self>().fetch_all(conn).await?;
+        ok!(rows)
+    }
+
+    pub async fn prune_db(days: i64, conn: &SqlitePool) -> Result<u64> {
+        if days <= 0 {
+            ok!(0)
+        }
+        let result = query("delete from audit_log where created_at < ?")
+            .bind((Utc::now() - Duration::days(days)).to_rfc3339())
+            .execute(conn)
+            .await?;
+        ok!(result.rows_affected())
+    }
+
+    pub fn to_entry(&self) -> AuditEntry {
+        AuditEntry {
+            request_id: self.id.clone(),
+            created_at: self.created_at.clone(),
+            username: self.username.clone(),
+            role: self.role.clone(),
+            method: self.method.clone(),
+            path: self.path.clone(),
+            project: self.project.clone(),
+            status: self.status as u16,
+            outcome: self.outcome.clone(),
+            duration_ms: self.duration_ms as u64,
+            client_ip: self.client_ip.clone(),
+            forwarded_for: self.forwarded_for.clone(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn audit_repo() {
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    AuditLog::migrate(&pool).await.unwrap();
+    let entry = |id: &str, username: &str, project: Option<&str>, days_ago: i64| AuditLog {
+        id: id.to_string(),
+        created_at: (Utc::now() - Duration::days(days_ago)).to_rfc3339(),
+        username: Some(username.to_string()),
+        role: Some("full_access".to_string()),
+        method: "POST".to_string(),
+        path: "/new-deploy".to_string(),
+        project: project.map(|p| p.to_string()),
+        status: 200,
+        outcome: outcome(200).to_string(),
+        duration_ms: 12,
+        client_ip: Some("10.0.0.1".to_string()),
+        forwarded_for: None,
+    };
+    entry("1", "alice", Some("web"), 0)
+        .insert_db(&pool)
+        .await
+        .unwrap();
+    entry("2", "bob", Some("api"), 2)
+        .insert_db(&pool)
+        .await
+        .unwrap();
+    entry("3", "alice", None, 200)
+        .insert_db(&pool)
+        .await
+        .unwrap();
+
+    let filter = |project: Option<&str>, username: Option<&str>, days: Option<i64>| AuditFilter {
+        project: project.map(|p| p.to_string()),
+        username: username.map(|u| u.to_string()),
+        since: days.map(|d| Utc::now() - Duration::days(d)),
+        limit: 100,
+    };
+    let all = AuditLog::list_db(&filter(None, None, None), &pool)
+        .await
+        .unwrap();
+    assert_eq!(
+        all.iter().map(|a| a.id.as_str()).collect::<Vec<_>>(),
+        ["1", "2", "3"]
+    );
+    let alice = AuditLog::list_db(&filter(None, Some("alice"), Some(7)), &pool)
+        .await
+        .unwrap();
+    assert_eq!(alice.len(), 1);
+    assert_eq!(
+        AuditLog::list_db(&filter(Some("api"), None, None), &pool)
+            .await
+            .unwrap()[0]
+            .id,
+        "2"
+    );
+
+    assert_eq!(AuditLog::prune_db(90, &pool).await.unwrap(), 1);
+    assert_eq!(outcome(403), "denied");
+    assert_eq!(outcome(500), "error");
+}
@@ -0,0 +1,129 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::Result;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use shared::ok;
+use sqlx::SqlitePool;
+
+use crate::repo::{crypto::write_private_file, user_repo::User};
+
+// the one-time token to register the first super user, None once there is one
+static BOOTSTRAP_TOKEN: LazyLock<Mutex<Option<BootstrapToken>>> =
+    LazyLock::new(|| Mutex::new(None));
+
+#[derive(Debug, Clone)]
+pub struct BootstrapToken {
+    value: String,
+    file: Option<PathBuf>,
+}
+
+fn token_file(dbpath: &str) -> PathBuf {
+    Path::new(dbpath)
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join("bootstrap.token")
+}
+
+// LEV_BOOTSTRAP_TOKEN, otherwise bootstrap.token next to the database, generated
+// on the first start while there is no super user
+pub async fn init_bootstrap_token(dbpath: &str, conn: &SqlitePool) -> Result<()> {
+    let file = token_file(dbpath);
+    if User::super_user_exists(conn).await? {
+        if file.exists() {
+            fs::remove_file(&file)?;
+        }
+        *BOOTSTRAP_TOKEN.lock().unwrap() = None;
+        return Ok(());
+    }
+    let token = match std::env::var("LEV_BOOTSTRAP_TOKEN") {
+        Ok(value) => BootstrapToken { value, file: None },
+        Err(_) => {
+            if !file.exists() {
+                write_private_file(&file, generate_token().as_bytes())?;
+            }
+            BootstrapToken {
+                value: fs::read_to_string(&file)?.trim().to_string(),
+                file: Some(file),
+            }
+        }
+    };
+    println!("no super user yet, register one with `lev auth` and the bootstrap token:");
+    println!("{}", token.value);
+    if let Some(file) = &token.file {
+        println!("it is saved in {} until it is used", file.display());
+    }
+    *BOOTSTRAP_TOKEN.lock().unwrap() = Some(token);
+    ok!(())
+}
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+// takes the token when it matches, so of two parallel registrations only one gets it.
+// Compares digests so the time taken doesn't depend on how much of the token matches
+pub fn take_bootstrap_token(token: &str) -> Option<BootstrapToken> {
+    let mut current = BOOTSTRAP_TOKEN.lock().unwrap();
+    let expected = current.as_ref()?;
+    if Sha256::digest(token.as_bytes()) != Sha256::digest(expected.value.as_bytes()) {
+        return None;
+    }
+    current.take()
+}
+
+// puts a taken token back when the super user couldn't be registered with it
+pub fn return_bootstrap_token(token: BootstrapToken) {
+    *BOOTSTRAP_TOKEN.lock().unwrap() = Some(token);
+}
+
+// the token works once, its file is removed after the super user is registered
+pub fn consume_bootstrap_token(token: BootstrapToken) {
+    if let Some(file) = token.file {
+        if let Err(e) = fs::remove_file(&file) {
+            println!("failed to remove {}: {}", file.display(), e);
+        }
+    }
+}
+
+#[tokio::test]
+async fn bootstrap_repo() {
+    let _caches = crate::repo::test_caches().await;
+    let dir = std::env::temp_dir().join(format!("lev-bootstrap-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let dbpath = dir.join("db.sqlite").to_string_lossy().to_string();
+    let repo = crate::repo::Repo::new("", true).await.unwrap();
+
+    init_bootstrap_token(&dbpath, &repo.pool).await.unwrap();
+    let file = token_file(&dbpath);
+    let token = fs::read_to_string(&file).unwrap();
+    assert_eq!(token.len(), 32);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(
+            fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+    assert!(take_bootstrap_token("wrong").is_none());
+    let taken = take_bootstrap_token(&token).unwrap();
+    assert!(take_bootstrap_token(&token).is_none());
+    return_bootstrap_token(taken);
+
+    // a restart keeps the same token
+    init_bootstrap_token(&dbpath, &repo.pool).await.unwrap();
+    let taken = take_bootstrap_token(&token).unwrap();
+    consume_bootstrap_token(taken);
+    assert!(take_bootstrap_token(&token).is_none());
+    assert!(!file.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
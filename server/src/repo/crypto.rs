@@ -0,0 +1,210 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use shared::{err, ok};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const KEY_INFO: &[u8] = b"leverans secrets v1";
+const DIGEST_INFO: &[u8] = b"leverans secret digest v1";
+const NONCE_LEN: usize = 24;
+
+pub struct MasterKey {
+    // derived key, the master key itself is never used for encryption
+    key: [u8; 32],
+    // None when the key comes from LEV_MASTER_KEY, such a key can't be rotated by the server
+    file: Option<PathBuf>,
+}
+
+pub static MASTER_KEY: LazyLock<Mutex<Option<MasterKey>>> = LazyLock::new(|| Mutex::new(None));
+
+// writers hold it from encrypting a value until it is stored and a rotation holds it
+// alone, so nothing is stored encrypted with a key that was just replaced
+static KEY_IN_USE: LazyLock<RwLock<()>> = LazyLock::new(|| RwLock::new(()));
+
+pub async fn key_in_use() -> RwLockReadGuard<'static, ()> {
+    KEY_IN_USE.read().await
+}
+
+pub async fn key_rotation() -> RwLockWriteGuard<'static, ()> {
+    KEY_IN_USE.write().await
+}
+
+// the key is process wide and tests run in parallel, tests that use it hold this
+#[cfg(test)]
+pub async fn test_master_key() -> tokio::sync::MutexGuard<'static, ()> {
+    static TEST_KEY: LazyLock<tokio::sync::Mutex<()>> =
+        LazyLock::new(|| tokio::sync::Mutex::new(()));
+    let guard = TEST_KEY.lock().await;
+    change_master_key(b"test", None).unwrap();
+    guard
+}
+
+pub fn change_master_key(master: &[u8], file: Option<PathBuf>) -> Result<()> {
+    *MASTER_KEY.lock().unwrap() = Some(MasterKey {
+        key: derive_key(master)?,
+        file,
+    });
+    Ok(())
+}
+
+// LEV_MASTER_KEY, otherwise MASTER_KEY_FILE or master.key next to the database,
+// generated on the first start
+pub fn init_master_key(dbpath: &str) -> Result<()> {
+    if let Ok(master) = std::env::var("LEV_MASTER_KEY") {
+        println!("using master key from LEV_MASTER_KEY");
+        return change_master_key(master.as_bytes(), None);
+    }
+    let file = match std::env::var("MASTER_KEY_FILE") {
+        Ok(file) => PathBuf::from(file),
+        Err(_) => Path::new(dbpath)
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("master.key"),
+    };
+    if file.with_extension("key.new").exists() {
+        println!(
+            "warning: {} exists, a master key rotation was interrupted. If secrets fail to \
+            decrypt, replace the key file with it",
+            file.with_extension("key.new").display()
+        );
+    }
+    if !file.exists() {
+        println!("generating master key: {}", file.display());
+        write_key_file(&file, &generate_master_key())?;
+    }
+    let master = STANDARD.decode(fs::read_to_string(&file)?.trim())?;
+    change_master_key(&master, Some(file))
+}
+
+pub fn generate_master_key() -> Vec<u8> {
+    let mut master = vec![0u8; 32];
+    rand::thread_rng().fill_bytes(&mut master);
+    master
+}
+
+pub fn get_master_key_file() -> Result<Option<PathBuf>> {
+    let key = MASTER_KEY.lock().unwrap();
+    let key = key.as_ref().ok_or(anyhow!("master key is not set"))?;
+    ok!(key.file.clone())
+}
+
+// the file is replaced at once, so a crash leaves either the old or the new key
+pub fn write_key_file(file: &Path, master: &[u8]) -> Result<()> {
+    write_private_file(file, STANDARD.encode(master).as_bytes())
+}
+
+// the file is created with mode 0600 under a temporary name and renamed when it is
+// complete, so it is never readable by others or half written
+pub fn write_private_file(file: &Path, content: &[u8]) -> Result<()> {
+    let tmp = file.with_extension("tmp");
+    if tmp.exists() {
+        fs::remove_file(&tmp)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&tmp)?.write_all(content)?;
+    fs::rename(&tmp, file)?;
+    Ok(())
+}
+
+pub fn derive_key(master: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, master)
+        .expand(KEY_INFO, &mut key)
+        .map_err(|_| anyhow!("failed to derive key"))?;
+    Ok(key)
+}
+
+// whether the value decrypts with the current master key, a plaintext value may look like
+// an encrypted one. Only used to mark rows written before they were marked
+pub fn is_encrypted(value: &str) -> Result<bool> {
+    let key = MASTER_KEY.lock().unwrap();
+    let key = key.as_ref().ok_or(anyhow!("master key is not set"))?;
+    ok!(value.starts_with(ENCRYPTED_PREFIX) && decrypt_with(&key.key, value).is_ok())
+}
+
+pub fn encrypt(value: &str) -> Result<String> {
+    let key = MASTER_KEY.lock().unwrap();
+    let key = key.as_ref().ok_or(anyhow!("master key is not set"))?;
+    encrypt_with(&key.key, value)
+}
+
+pub fn decrypt(value: &str) -> Result<String> {
+    let key = MASTER_KEY.lock().unwrap();
+    let key = key.as_ref().ok_or(anyhow!("master key is not set"))?;
+    decrypt_with(&key.key, value)
+}
+
+// keyed, so a digest from a plan can't be matched against guessed values. It changes with
+// the master key, the next deploy after a rotation updates services that use secrets
+pub fn digest(value: &str) -> Result<String> {
+    let key = MASTER_KEY.lock().unwrap();
+    let key = key.as_ref().ok_or(anyhow!("master key is not set"))?;
+    let mut digest_key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, &key.key)
+        .expand(DIGEST_INFO, &mut digest_key)
+        .map_err(|_| anyhow!("failed to derive key"))?;
+    let hash = Sha256::new()
+        .chain_update(digest_key)
+        .chain_update(value.as_bytes())
+        .finalize();
+    Ok(format!("{:x}", hash)[..12].to_string())
+}
+
+pub fn encrypt_with(key: &[u8; 32], value: &str) -> Result<String> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut data = nonce.to_vec();
+    data.extend(
+        cipher
+            .encrypt(&nonce, value.as_bytes())
+            .map_err(|_| anyhow!("failed to encrypt secret"))?,
+    );
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(data)))
+}
+
+pub fn decrypt_with(key: &[u8; 32], value: &str) -> Result<String> {
+    let encoded = value
+        .strip_prefix(ENCRYPTED_PREFIX)
+        .ok_or(anyhow!("secret is not encrypted"))?;
+    let data = STANDARD.decode(encoded)?;
+    if data.len() < NONCE_LEN {
+        err!(anyhow!("encrypted secret is too short"))
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let plain = XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("failed to decrypt secret, wrong master key?"))?;
+    Ok(String::from_utf8(plain)?)
+}
+
+#[test]
+fn crypto_test() {
+    let key = derive_key(b"master").unwrap();
+    let encrypted = encrypt_with(&key, "password").unwrap();
+    assert_ne!(encrypted, encrypt_with(&key, "password").unwrap());
+    assert_eq!(decrypt_with(&key, &encrypted).unwrap(), "password");
+    assert!(decrypt_with(&key, "plain").is_err());
+    assert!(decrypt_with(&key, "enc:v1:plain").is_err());
+    let other = derive_key(b"other").unwrap();
+    assert!(decrypt_with(&other, &encrypted).is_err());
+}
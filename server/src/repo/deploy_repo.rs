@@ -46,6 +46,14 @@ impl DeployData {
             .await?;
         Ok(())
     }
+    pub async fn count_db(project_name: &str, conn: &SqlitePool) -> Result<i64> {
+        let (count,): (i64,) = query_as("select count(*) from deploys where project_name = ?")
+            .bind(project_name)
+            .fetch_one(conn)
+            .await?;
+        Ok(count)
+    }
+
     pub async fn get_last_deploys(conn: &SqlitePool, order: u8) -> Result<Vec<Self>> {
         let rows = query_as::<_, Self>(
             r#"SELECT id, project_name, deploys, created_at 
@@ -0,0 +1,148 @@
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use shared::{err, ok};
+use sqlx::{prelude::FromRow, query, query_as, Executor, SqlitePool};
+use uuid::Uuid;
+
+use super::user_repo::RoleType;
+
+pub const GRANT_MIGRATION: &str = r#"
+    create table if not exists project_grants (
+        id text primary key,
+        username text not null,
+        project text not null,
+        role text not null,
+        created_by text not null,
+        created_at text not null,
+        unique (username, project)
+    );
+    "#;
+
+// roles a grant can give, super users manage the whole server
+pub const GRANT_ROLES: [&str; 3] = ["full_access", "update_only", "read_only"];
+
+// the role of a user on one project, it replaces the global role of the user there
+#[derive(Debug, Clone, FromRow)]
+pub struct Grant {
+    pub id: String,
+    pub username: String,
+    pub project: String,
+    pub role: String,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+// checked on every request on a project, reloaded when grants change
+pub static GRANTS: LazyLock<Mutex<Vec<Grant>>> = LazyLock::new(|| Mutex::new(vec![]));
+
+pub fn grant_role(username: &str, project: &str) -> Option<RoleType> {
+    GRANTS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|g| g.username == username && g.project == project)
+        .map(|g| RoleType::from_string(&g.role))
+}
+
+impl Grant {
+    pub async fn migrate(conn: &SqlitePool) -> Result<()> {
+        conn.execute(GRANT_MIGRATION).await?;
+        Self::load_db(conn).await
+    }
+
+    pub fn new(username: String, project: String, role: String, created_by: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            username,
+            project,
+            role,
+            created_by,
+            created_at: Utc::now().to_rfc3339(),
+        }
+    }
+
+    // a new grant for the same user and project replaces the old one
+    pub async fn upsert_db(&self, conn: &SqlitePool) -> Result<()> {
+        if !GRANT_ROLES.contains(&self.role.as_str()) {
+            err!(anyhow!(
+                "Unknown role {}, use {}",
+                self.role,
+                GRANT_ROLES.join(", ")
+            ))
+        }
+        query(
+            "insert into project_grants (id, username, project, role, created_by, created_at)
+            values (?, ?, ?, ?, ?, ?)
+            on conflict (username, project) do update set role = excluded.role,
+            created_by = excluded.created_by, created_at = excluded.created_at",
+        )
+        .bind(&self.id)
+        .bind(&self.username)
+        .bind(&self.project)
+        .bind(&self.role)
+        .bind(&self.created_by)
+        .bind(&self.created_at)
+        .execute(conn)
+        .await?;
+        Self::load_db(conn).await
+    }
+
+    pub async fn revoke_db(username: &str, project: &str, conn: &SqlitePool) -> Result<()> {
+        let result = query("delete from project_grants where username = ? and project = ?")
+            .bind(username)
+            .bind(project)
+            .execute(conn)
+            .await?;
+        if result.rows_affected() == 0 {
+            err!(anyhow!("{} has no grant on {}", username, project))
+        }
+        Self::load_db(conn).await
+    }
+
+    pub async fn delete_user_db(username: &str, conn: &SqlitePool) -> Result<()> {
+        query("delete from project_grants where username = ?")
+            .bind(username)
+            .execute(conn)
+            .await?;
+        Self::load_db(conn).await
+    }
+
+    pub async fn list_db(conn: &SqlitePool) -> Result<Vec<Self>> {
+        let rows = query_as::<_, Self>("select * from project_grants order by username, project")
+            .fetch_all(conn)
+            .await?;
+        ok!(rows)
+    }
+
+    pub async fn load_db(conn: &SqlitePool) -> Result<()> {
+        *GRANTS.lock().unwrap() = Self::list_db(conn).await?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn grant_repo() {
+    let _caches = crate::repo::test_caches().await;
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    Grant::migrate(&pool).await.unwrap();
+    let grant = |role: &str| {
+        Grant::new(
+            "contractor".to_string(),
+            "web".to_string(),
+            role.to_string(),
+            "admin".to_string(),
+        )
+    };
+    grant("read_only").upsert_db(&pool).await.unwrap();
+    grant("update_only").upsert_db(&pool).await.unwrap();
+    assert!(grant("super_user").upsert_db(&pool).await.is_err());
+    assert_eq!(Grant::list_db(&pool).await.unwrap().len(), 1);
+    assert_eq!(grant_role("contractor", "web"), Some(RoleType::UpdateOnly));
+    assert_eq!(grant_role("contractor", "api"), None);
+
+    Grant::revoke_db("contractor", "web", &pool).await.unwrap();
+    assert_eq!(grant_role("contractor", "web"), None);
+    assert!(Grant::revoke_db("contractor", "web", &pool).await.is_err());
+}
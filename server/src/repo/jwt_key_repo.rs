@@ -0,0 +1,200 @@
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use shared::{err, ok};
+use sqlx::{prelude::FromRow, query, query_as, Executor, SqlitePool};
+use uuid::Uuid;
+
+use super::{
+    crypto::{decrypt, encrypt, key_in_use},
+    expires_after,
+};
+
+// kid of the key from JWT_KEY
+pub const ENV_KID: &str = "env";
+// how long tokens signed by the previous key stay valid after a rotation
+pub const DEFAULT_GRACE: u64 = 60 * 60;
+
+pub const JWT_KEY_MIGRATION: &str = r#"
+    create table if not exists jwt_keys (
+        id text primary key,
+        value text not null,
+        created_at text not null,
+        expires_at text
+    );
+    "#;
+
+// a signing key of access tokens, the kid of the token header is its id. Only the key
+// without expires_at signs, the others verify until they expire
+#[derive(Debug, Clone, FromRow)]
+pub struct JwtKey {
+    pub id: String,
+    // encrypted with the master key in the database
+    pub value: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+}
+
+pub static JWT_KEYS: LazyLock<Mutex<Vec<JwtKey>>> = LazyLock::new(|| Mutex::new(vec![]));
+
+pub fn signing_key() -> Result<JwtKey> {
+    JWT_KEYS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|k| k.expires_at.is_none())
+        .cloned()
+        .ok_or(anyhow!("there is no jwt signing key"))
+}
+
+pub fn verifying_key(kid: &str) -> Result<JwtKey> {
+    let key = JWT_KEYS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|k| k.id == kid)
+        .cloned()
+        .ok_or(anyhow!("unknown jwt key {}", kid))?;
+    if let Some(expires_at) = &key.expires_at {
+        if DateTime::parse_from_rfc3339(expires_at)? < Utc::now() {
+            err!(anyhow!("jwt key {} has expired", kid))
+        }
+    }
+    ok!(key)
+}
+
+// JWT_KEY, otherwise the keys of the database, the first one is generated on the first start
+pub async fn init_jwt_keys(conn: &SqlitePool) -> Result<()> {
+    if let Ok(value) = std::env::var("JWT_KEY") {
+        println!("using jwt key from JWT_KEY");
+        *JWT_KEYS.lock().unwrap() = vec![JwtKey {
+            id: ENV_KID.to_string(),
+            value,
+            created_at: Utc::now().to_rfc3339(),
+            expires_at: None,
+        }];
+        ok!(())
+    }
+    JwtKey::load_db(conn).await?;
+    if signing_key().is_err() {
+        println!("generating jwt signing key");
+        JwtKey::generate().insert_db(conn).await?;
+        JwtKey::load_db(conn).await?;
+    }
+    ok!(())
+}
+
+impl JwtKey {
+    pub async fn migrate(conn: &SqlitePool) -> Result<()> {
+        conn.execute(JWT_KEY_MIGRATION).await?;
+        Ok(())
+    }
+
+    pub fn generate() -> Self {
+        let value: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(48)
+            .map(char::from)
+            .collect();
+        Self {
+            id: Uuid::new_v4().simple().to_string()[..12].to_string(),
+            value,
+            created_at: Utc::now().to_rfc3339(),
+            expires_at: None,
+        }
+    }
+
+    pub async fn insert_db(&self, conn: &SqlitePool) -> Result<()> {
+        let _key = key_in_use().await;
+        query("insert into jwt_keys (id, value, created_at, expires_at) values (?, ?, ?, ?)")
+            .bind(&self.id)
+            .bind(encrypt(&self.value)?)
+            .bind(&self.created_at)
+            .bind(&self.expires_at)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    // keys that can still verify tokens, with decrypted values
+    pub async fn list_db(conn: &SqlitePool) -> Result<Vec<Self>> {
+        let _key = key_in_use().await;
+        let rows = query_as::<_, Self>(
+            "select * from jwt_keys where expires_at is null or expires_at >= ?
+            order by created_at desc",
+        )
+        .bind(Utc::now().to_rfc3339())
+        .fetch_all(conn)
+        .await?;
+        rows.into_iter()
+            .map(|k| {
+                ok!(Self {
+                    value: decrypt(&k.value)?,
+                    ..k
+                })
+            })
+            .collect()
+    }
+
+    pub async fn load_db(conn: &SqlitePool) -> Result<()> {
+        *JWT_KEYS.lock().unwrap() = Self::list_db(conn).await?;
+        Ok(())
+    }
+
+    // a new key signs from now on, tokens of the current one stay valid for grace seconds
+    pub async fn rotate_db(grace: u64, conn: &SqlitePool) -> Result<String> {
+        if JWT_KEYS.lock().unwrap().iter().any(|k| k.id == ENV_KID) {
+            err!(anyhow!(
+                "jwt key is set with JWT_KEY, change it there and restart the manager"
+            ))
+        }
+        let now = Utc::now();
+        let retire_at = expires_after(grace)?;
+        let key = Self::generate();
+        let _key = key_in_use().await;
+        let mut tx = conn.begin().await?;
+        query("delete from jwt_keys where expires_at < ?")
+            .bind(now.to_rfc3339())
+            .execute(&mut *tx)
+            .await?;
+        query("update jwt_keys set expires_at = ? where expires_at is null")
+            .bind(retire_at.to_rfc3339())
+            .execute(&mut *tx)
+            .await?;
+        query("insert into jwt_keys (id, value, created_at, expires_at) values (?, ?, ?, null)")
+            .bind(&key.id)
+            .bind(encrypt(&key.value)?)
+            .bind(&key.created_at)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        // loading takes the key again
+        drop(_key);
+        Self::load_db(conn).await?;
+        ok!(key.id)
+    }
+}
+
+#[tokio::test]
+async fn jwt_key_repo() {
+    let _caches = crate::repo::test_caches().await;
+    let _key = crate::repo::crypto::test_master_key().await;
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    JwtKey::migrate(&pool).await.unwrap();
+    JwtKey::generate().insert_db(&pool).await.unwrap();
+    JwtKey::load_db(&pool).await.unwrap();
+    let old = signing_key().unwrap();
+
+    let kid = JwtKey::rotate_db(60, &pool).await.unwrap();
+    assert_eq!(signing_key().unwrap().id, kid);
+    assert_eq!(verifying_key(&old.id).unwrap().value, old.value);
+
+    assert!(JwtKey::rotate_db(u64::MAX, &pool).await.is_err());
+    assert_eq!(signing_key().unwrap().id, kid);
+
+    JwtKey::rotate_db(0, &pool).await.unwrap();
+    assert!(verifying_key(&kid).is_err());
+    assert!(verifying_key(&old.id).is_ok());
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use shared::ok;
+use sqlx::{prelude::FromRow, query, Executor, SqlitePool};
+use uuid::Uuid;
+
+pub const LOCKOUT_MIGRATION: &str = r#"
+    create table if not exists login_lockouts (
+        id text primary key,
+        target text not null,
+        failures integer not null,
+        locked_at text not null,
+        locked_until text not null
+    );
+    "#;
+
+// LOGIN_MAX_FAILURES, failures in a row before a target is locked out
+pub const DEFAULT_MAX_FAILURES: u32 = 5;
+// LOGIN_LOCKOUT_SECONDS
+pub const DEFAULT_LOCKOUT_SECONDS: u64 = 15 * 60;
+// failures older than this are forgotten
+const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+// failed logins by target, a target is "ip:<addr>" or "user:<name>"
+static LOGIN_ATTEMPTS: LazyLock<Mutex<HashMap<String, Attempts>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug)]
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Instant,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+pub fn max_failures() -> u32 {
+    env_or("LOGIN_MAX_FAILURES", DEFAULT_MAX_FAILURES).max(1)
+}
+
+pub fn lockout_seconds() -> u64 {
+    env_or("LOGIN_LOCKOUT_SECONDS", DEFAULT_LOCKOUT_SECONDS)
+}
+
+// seconds until every target may try again
+fn blocked_at(
+    attempts: &HashMap<String, Attempts>,
+    targets: &[String],
+    now: Instant,
+) -> Option<u64> {
+    targets
+        .iter()
+        .filter_map(|t| attempts.get(t))
+        .filter(|a| a.blocked_until > now)
+        .map(|a| (a.blocked_until - now).as_secs().max(1))
+        .max()
+}
+
+// backs off 1s, 2s, 4s.. after each failure and locks out after max failures,
+// returns the targets that got locked out with their failure count
+fn failed_at(
+    attempts: &mut HashMap<String, Attempts>,
+    targets: &[String],
+    now: Instant,
+    max: u32,
+    lockout: u64,
+) -> Vec<(String, u32)> {
+    let mut locked = vec![];
+    for target in targets {
+        let entry = attempts.entry(target.clone()).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            blocked_until: now,
+        });
+        if now.duration_since(entry.last_failure) > FAILURE_WINDOW {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+        let wait = if entry.failures >= max {
+            locked.push((target.clone(), entry.failures));
+            lockout
+        } else {
+            (1u64 << (entry.failures - 1).min(16)).min(lockout)
+        };
+        entry.blocked_until = now + Duration::from_secs(wait);
+    }
+    locked
+}
+
+// counts the attempt as failed before the password is checked, so parallel guesses are
+// blocked by the ones before them. Err has the seconds left when the targets are blocked,
+// Ok the targets this attempt locks out if it fails
+pub fn login_attempted(targets: &[String]) -> Result<Vec<(String, u32)>, u64> {
+    let mut attempts = LOGIN_ATTEMPTS.lock().unwrap();
+    let now = Instant::now();
+    if let Some(seconds) = blocked_at(&attempts, targets, now) {
+        return Err(seconds);
+    }
+    attempts.retain(|_, a| a.last_failure.elapsed() <= FAILURE_WINDOW);
+    ok!(failed_at(
+        &mut attempts,
+        targets,
+        now,
+        max_failures(),
+        lockout_seconds(),
+    ))
+}
+
+pub fn login_succeeded(targets: &[String]) {
+    let mut attempts = LOGIN_ATTEMPTS.lock().unwrap();
+    for target in targets {
+        attempts.remove(target);
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct LoginLockout {
+    pub id: String,
+    pub target: String,
+    pub failures: i64,
+    pub locked_at: String,
+    pub locked_until: String,
+}
+
+impl LoginLockout {
+    pub async fn migrate(conn: &SqlitePool) -> Result<()> {
+        conn.execute(LOCKOUT_MIGRATION).await?;
+        Ok(())
+    }
+
+    pub fn new(target: &str, failures: u32, seconds: u64) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            target: target.to_string(),
+            failures: failures as i64,
+            locked_at: now.to_rfc3339(),
+            locked_until: (now + chrono::Duration::seconds(seconds as i64)).to_rfc3339(),
+        }
+    }
+
+    pub async fn insert_db(&self, conn: &SqlitePool) -> Result<()> {
+        query(
+            "insert into login_lockouts (id, target, failures, locked_at, locked_until)
+            values (?, ?, ?, ?, ?)",
+        )
+        .bind(&self.id)
+        .bind(&self.target)
+        .bind(self.failures)
+        .bind(&self.locked_at)
+        .bind(&self.locked_until)
+        .execute(conn)
+        .await?;
+        ok!(())
+    }
+}
+
+#[tokio::test]
+async fn lockout_repo() {
+    let _caches = crate::repo::test_caches().await;
+    let mut attempts = HashMap::new();
+    let targets = vec!["ip:10.0.0.1".to_string(), "user:admin".to_string()];
+    let now = Instant::now();
+    assert_eq!(blocked_at(&attempts, &targets, now), None);
+
+    assert!(failed_at(&mut attempts, &targets, now, 3, 600).is_empty());
+    assert_eq!(blocked_at(&attempts, &targets, now), Some(1));
+    assert!(failed_at(&mut attempts, &targets, now, 3, 600).is_empty());
+    assert_eq!(blocked_at(&attempts, &targets, now), Some(2));
+    assert_eq!(
+        blocked_at(&attempts, &targets, now + Duration::from_secs(3)),
+        None
+    );
+    let locked = failed_at(&mut attempts, &targets[1..], now, 3, 600);
+    assert_eq!(locked, vec![("user:admin".to_string(), 3)]);
+    assert_eq!(blocked_at(&attempts, &targets[..1], now), Some(2));
+    assert_eq!(blocked_at(&attempts, &targets, now), Some(600));
+
+    // old failures are forgotten
+    let later = now + FAILURE_WINDOW + Duration::from_secs(1);
+    assert!(failed_at(&mut attempts, &targets, later, 3, 600).is_empty());
+    assert_eq!(blocked_at(&attempts, &targets, later), Some(1));
+
+    // a second attempt right after the first is blocked before its password is checked
+    let targets = vec!["ip:10.0.0.2".to_string()];
+    assert!(login_attempted(&targets).is_ok());
+    assert_eq!(login_attempted(&targets), Err(1));
+    login_succeeded(&targets);
+    assert!(login_attempted(&targets).is_ok());
+
+    let repo = crate::repo::Repo::new("", true).await.unwrap();
+    LoginLockout::new("user:admin", 3, 600)
+        .insert_db(&repo.pool)
+        .await
+        .unwrap();
+    let lockouts = sqlx::query_as::<_, LoginLockout>("select * from login_lockouts")
+        .fetch_all(&repo.pool)
+        .await
+        .unwrap();
+    assert_eq!(lockouts.len(), 1);
+    assert_eq!(lockouts[0].target, "user:admin");
+}
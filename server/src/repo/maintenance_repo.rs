@@ -0,0 +1,60 @@
+use anyhow::Result;
+use chrono::Utc;
+use sqlx::{prelude::FromRow, query, query_as, Executor, SqlitePool};
+
+pub const MAINTENANCE_MIGRATION: &str = r#"
+    create table if not exists maintenance (
+        project_name text primary key,
+        page text not null,
+        created_at text not null
+    );
+    "#;
+
+// a row means the project is in maintenance mode
+#[derive(Debug, Clone, FromRow)]
+pub struct MaintenanceData {
+    pub project_name: String,
+    pub page: String,
+    pub created_at: String,
+}
+
+impl MaintenanceData {
+    pub async fn migrate(conn: &SqlitePool) -> Result<()> {
+        conn.execute(MAINTENANCE_MIGRATION).await?;
+        Ok(())
+    }
+
+    pub fn new(project_name: String, page: String) -> Self {
+        Self {
+            project_name,
+            page,
+            created_at: Utc::now().to_rfc3339(),
+        }
+    }
+
+    pub async fn insert_db(&self, conn: &SqlitePool) -> Result<()> {
+        query("insert or replace into maintenance values (?, ?, ?)")
+            .bind(&self.project_name)
+            .bind(&self.page)
+            .bind(&self.created_at)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_db(project_name: &str, conn: &SqlitePool) -> Result<Option<Self>> {
+        let row = query_as::<_, Self>("select * from maintenance where project_name = ?")
+            .bind(project_name)
+            .fetch_optional(conn)
+            .await?;
+        Ok(row)
+    }
+
+    pub async fn delete_db(project_name: &str, conn: &SqlitePool) -> Result<()> {
+        query("delete from maintenance where project_name = ?")
+            .bind(project_name)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+}
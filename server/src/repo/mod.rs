@@ -1,14 +1,36 @@
+pub mod audit_repo;
+pub mod bootstrap_repo;
 pub mod config_repo;
+pub mod crypto;
 pub mod deploy_repo;
+pub mod grant_repo;
+pub mod jwt_key_repo;
+pub mod lockout_repo;
+pub mod maintenance_repo;
+pub mod oidc_repo;
+pub mod proxy_repo;
+pub mod secret_provider;
 pub mod secret_repo;
+pub mod session_repo;
+pub mod tls_repo;
+pub mod token_repo;
 pub mod user_repo;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use audit_repo::AuditLog;
+use chrono::{DateTime, Datelike, TimeDelta, Utc};
 use config_repo::ConfigData;
 use deploy_repo::DeployData;
+use grant_repo::Grant;
+use jwt_key_repo::JwtKey;
+use lockout_repo::LoginLockout;
+use maintenance_repo::MaintenanceData;
+use proxy_repo::ProxyData;
 use secret_repo::SecretData;
+use session_repo::Session;
 use shared::{create_file_if_not_exist, ok, Secret};
 use sqlx::{query, sqlite::SqlitePool, Executor};
+use token_repo::ApiToken;
 use user_repo::User;
 
 #[derive(Clone, Debug)]
@@ -26,6 +48,38 @@ impl Repo {
         SecretData::migrate(&pool).await?;
         ConfigData::migrate(&pool).await?;
         DeployData::migrate(&pool).await?;
+        ProxyData::migrate(&pool).await?;
+        MaintenanceData::migrate(&pool).await?;
+        ApiToken::migrate(&pool).await?;
+        Session::migrate(&pool).await?;
+        JwtKey::migrate(&pool).await?;
+        Grant::migrate(&pool).await?;
+        AuditLog::migrate(&pool).await?;
+        LoginLockout::migrate(&pool).await?;
+        let encrypted = SecretData::encrypt_plaintext_db(&pool).await?;
+        if encrypted > 0 {
+            println!("encrypted {} plaintext secrets", encrypted);
+        }
         ok!(Self { pool })
     }
 }
+
+// now plus secs, a duration chrono can't add is an error instead of a panic. The date
+// stays before the year 10000, so its rfc3339 string still compares right in sql
+pub fn expires_after(secs: u64) -> Result<DateTime<Utc>> {
+    i64::try_from(secs)
+        .ok()
+        .and_then(TimeDelta::try_seconds)
+        .and_then(|delta| Utc::now().checked_add_signed(delta))
+        .filter(|at| at.year() < 10000)
+        .ok_or(anyhow!("{} seconds is too long", secs))
+}
+
+// the token, session, jwt key and grant caches are process wide and every migrate reloads
+// them, tests that create these tables hold this
+#[cfg(test)]
+pub async fn test_caches() -> tokio::sync::MutexGuard<'static, ()> {
+    static TEST_CACHES: std::sync::LazyLock<tokio::sync::Mutex<()>> =
+        std::sync::LazyLock::new(|| tokio::sync::Mutex::new(()));
+    TEST_CACHES.lock().await
+}
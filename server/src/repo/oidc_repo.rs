@@ -0,0 +1,471 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, Result};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use shared::{err, ok, SsoDevice};
+
+use crate::repo::{
+    session_repo::REFRESH_TOKEN_TTL,
+    user_repo::{RoleType, ROLE_NAMES},
+};
+
+// OIDC_SCOPES
+pub const DEFAULT_SCOPES: &str = "openid profile email";
+// OIDC_GROUPS_CLAIM
+pub const DEFAULT_GROUPS_CLAIM: &str = "groups";
+// OIDC_SESSION_TTL, seconds until an sso login has to be approved by the provider again,
+// so removed groups and disabled accounts lose access
+pub const DEFAULT_SESSION_TTL: i64 = 8 * 60 * 60;
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: String,
+    pub groups_claim: String,
+    // group and the role its members get, the highest role of all groups wins
+    pub group_roles: Vec<(String, RoleType)>,
+    pub session_ttl: i64,
+}
+
+// the parts of the provider metadata the device flow needs
+#[derive(Debug, Clone, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    pub device_authorization_endpoint: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct DeviceResponse {
+    device_code: String,
+    user_code: String,
+    // some providers still use the name from the drafts
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+// the sso account from a verified id token
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsoIdentity {
+    pub subject: String,
+    pub username: String,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DevicePoll {
+    Pending,
+    SlowDown,
+    Done(SsoIdentity),
+}
+
+// OIDC_ISSUER turns sso on, together with OIDC_CLIENT_ID, OIDC_CLIENT_SECRET and
+// OIDC_GROUP_ROLES like "lev-admins=super_user,developers=full_access"
+pub fn init_oidc() -> Result<Option<OidcConfig>> {
+    let Ok(issuer) = std::env::var("OIDC_ISSUER") else {
+        return Ok(None);
+    };
+    let client_id = std::env::var("OIDC_CLIENT_ID")
+        .map_err(|_| anyhow!("OIDC_ISSUER is set but OIDC_CLIENT_ID is not"))?;
+    let group_roles = parse_group_roles(&std::env::var("OIDC_GROUP_ROLES").unwrap_or_default())?;
+    if group_roles.is_empty() {
+        println!("OIDC_GROUP_ROLES is empty, nobody can log in with sso");
+    }
+    let session_ttl = match std::env::var("OIDC_SESSION_TTL") {
+        Ok(ttl) => ttl
+            .parse::<i64>()
+            .ok()
+            .filter(|ttl| (1..=REFRESH_TOKEN_TTL).contains(ttl))
+            .ok_or(anyhow!(
+                "OIDC_SESSION_TTL must be between 1 and {} seconds",
+                REFRESH_TOKEN_TTL
+            ))?,
+        Err(_) => DEFAULT_SESSION_TTL,
+    };
+    println!("sso login with {}", issuer);
+    ok!(Some(OidcConfig {
+        issuer: issuer.trim_end_matches('/').to_string(),
+        client_id,
+        client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+        scopes: std::env::var("OIDC_SCOPES").unwrap_or(DEFAULT_SCOPES.to_string()),
+        groups_claim: std::env::var("OIDC_GROUPS_CLAIM")
+            .unwrap_or(DEFAULT_GROUPS_CLAIM.to_string()),
+        group_roles,
+        session_ttl,
+    }))
+}
+
+pub fn parse_group_roles(value: &str) -> Result<Vec<(String, RoleType)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (group, role) = entry.split_once('=').ok_or(anyhow!(
+                "invalid entry {} in OIDC_GROUP_ROLES, expected group=role",
+                entry
+            ))?;
+            let role = role.trim();
+            if !ROLE_NAMES.contains(&role) {
+                err!(anyhow!(
+                    "unknown role {} in OIDC_GROUP_ROLES, use one of {}",
+                    role,
+                    ROLE_NAMES.join(", ")
+                ))
+            }
+            ok!((group.trim().to_string(), RoleType::from_string(role)))
+        })
+        .collect()
+}
+
+fn rank(role: &RoleType) -> u8 {
+    match role {
+        RoleType::SuperUser => 3,
+        RoleType::FullAccess => 2,
+        RoleType::UpdateOnly => 1,
+        RoleType::ReadOnly => 0,
+    }
+}
+
+// None when no group of the account has a role here
+pub fn role_for_groups(group_roles: &[(String, RoleType)], groups: &[String]) -> Option<RoleType> {
+    group_roles
+        .iter()
+        .filter(|(group, _)| groups.contains(group))
+        .map(|(_, role)| role.clone())
+        .max_by_key(rank)
+}
+
+fn client() -> Result<reqwest::Client> {
+    ok!(reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?)
+}
+
+async fn read_json<T: DeserializeOwned>(res: reqwest::Response) -> Result<T> {
+    let url = res.url().clone();
+    let status = res.status();
+    let text = res.text().await?;
+    if !status.is_success() {
+        err!(anyhow!("{} answered {}: {}", url, status, text))
+    }
+    ok!(serde_json::from_str(&text).map_err(|e| anyhow!(
+        "unexpected answer from {}: {}",
+        url,
+        e
+    ))?)
+}
+
+impl OidcConfig {
+    pub async fn discover(&self) -> Result<Discovery> {
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let discovery: Discovery = read_json(client()?.get(&url).send().await?).await?;
+        if discovery.issuer.trim_end_matches('/') != self.issuer {
+            err!(anyhow!(
+                "{} is for the issuer {}, not {}",
+                url,
+                discovery.issuer,
+                self.issuer
+            ))
+        }
+        ok!(discovery)
+    }
+
+    fn credentials(&self) -> Vec<(&str, &str)> {
+        let mut form = vec![("client_id", self.client_id.as_str())];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+        form
+    }
+
+    pub async fn start_device_flow(&self) -> Result<SsoDevice> {
+        let discovery = self.discover().await?;
+        let endpoint = discovery.device_authorization_endpoint.ok_or(anyhow!(
+            "{} doesn't support the device authorization flow",
+            self.issuer
+        ))?;
+        let mut form = self.credentials();
+        form.push(("scope", self.scopes.as_str()));
+        let res = client()?.post(endpoint).form(&form).send().await?;
+        let device: DeviceResponse = read_json(res).await?;
+        ok!(SsoDevice {
+            device_code: device.device_code,
+            user_code: device.user_code,
+            verification_uri: device.verification_uri,
+            verification_uri_complete: device.verification_uri_complete,
+            expires_in: device.expires_in,
+            interval: device.interval,
+        })
+    }
+
+    pub async fn poll_device_flow(&self, device_code: &str) -> Result<DevicePoll> {
+        let discovery = self.discover().await?;
+        let mut form = self.credentials();
+        form.push(("grant_type", DEVICE_CODE_GRANT));
+        form.push(("device_code", device_code));
+        let res = client()?
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        let status = res.status();
+        let body: Value = serde_json::from_str(&res.text().await?).unwrap_or_default();
+        if !status.is_success() {
+            match body["error"].as_str() {
+                Some("authorization_pending") => ok!(DevicePoll::Pending),
+                Some("slow_down") => ok!(DevicePoll::SlowDown),
+                Some(error) => err!(anyhow!(
+                    "{}",
+                    body["error_description"].as_str().unwrap_or(error)
+                )),
+                None => err!(anyhow!("the sso provider answered {}", status)),
+            }
+        }
+        let id_token = body["id_token"]
+            .as_str()
+            .ok_or(anyhow!("the sso provider sent no id token"))?;
+        let jwks: JwkSet = read_json(client()?.get(&discovery.jwks_uri).send().await?).await?;
+        ok!(DevicePoll::Done(self.verify_id_token(id_token, &jwks)?))
+    }
+
+    // checks the signature against the keys of the provider, the issuer, the audience
+    // and the expiry
+    pub fn verify_id_token(&self, id_token: &str, jwks: &JwkSet) -> Result<SsoIdentity> {
+        let header = decode_header(id_token)?;
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            err!(anyhow!(
+                "id tokens signed with a shared secret aren't accepted"
+            ))
+        }
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }
+        .ok_or(anyhow!("the id token is signed with an unknown key"))?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_issuer(&[self.issuer.clone(), format!("{}/", self.issuer)]);
+        let claims =
+            decode::<HashMap<String, Value>>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?
+                .claims;
+        let subject = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or(anyhow!("the id token has no subject"))?
+            .to_string();
+        let username = ["preferred_username", "email"]
+            .iter()
+            .find_map(|claim| claims.get(*claim).and_then(Value::as_str))
+            .unwrap_or(&subject)
+            .to_string();
+        let groups = match claims.get(&self.groups_claim) {
+            Some(Value::Array(groups)) => groups
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => vec![],
+        };
+        ok!(SsoIdentity {
+            subject,
+            username,
+            groups,
+        })
+    }
+}
+
+#[test]
+fn group_roles() {
+    let group_roles = parse_group_roles("admins=super_user, devs = full_access,").unwrap();
+    assert_eq!(group_roles.len(), 2);
+    assert!(parse_group_roles("admins").is_err());
+    assert!(parse_group_roles("admins=root").is_err());
+    let groups = vec!["devs".to_string(), "admins".to_string()];
+    assert_eq!(
+        role_for_groups(&group_roles, &groups),
+        Some(RoleType::SuperUser)
+    );
+    assert_eq!(
+        role_for_groups(&group_roles, &groups[..1]),
+        Some(RoleType::FullAccess)
+    );
+    assert_eq!(role_for_groups(&group_roles, &["sales".to_string()]), None);
+}
+
+// a local provider that approves the login on the second poll
+#[tokio::test]
+async fn oidc_repo() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let key_pair = rcgen::KeyPair::generate().unwrap();
+    let point = key_pair.public_key_raw();
+    let jwks = json!({"keys": [{
+        "kty": "EC",
+        "crv": "P-256",
+        "kid": "mock",
+        "alg": "ES256",
+        "use": "sig",
+        "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+        "y": URL_SAFE_NO_PAD.encode(&point[33..65]),
+    }]});
+    let mut header = Header::new(Algorithm::ES256);
+    header.kid = Some("mock".to_string());
+    let id_token = encode(
+        &header,
+        &json!({
+            "iss": issuer,
+            "aud": "lev",
+            "sub": "user-1",
+            "exp": jsonwebtoken::get_current_timestamp() + 300,
+            "preferred_username": "alice",
+            "groups": ["devs"],
+        }),
+        &EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).unwrap(),
+    )
+    .unwrap();
+
+    let (signed, jwks_json) = (id_token.clone(), jwks.clone());
+    let polls = Arc::new(AtomicUsize::new(0));
+    let provider = {
+        let issuer = issuer.clone();
+        HttpServer::new(move || {
+            let (issuer, jwks, id_token, polls) = (
+                issuer.clone(),
+                jwks.clone(),
+                id_token.clone(),
+                polls.clone(),
+            );
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to({
+                        let issuer = issuer.clone();
+                        move || {
+                            let issuer = issuer.clone();
+                            async move {
+                                HttpResponse::Ok().json(json!({
+                                    "issuer": issuer,
+                                    "token_endpoint": format!("{}/token", issuer),
+                                    "jwks_uri": format!("{}/jwks", issuer),
+                                    "device_authorization_endpoint": format!("{}/device", issuer),
+                                }))
+                            }
+                        }
+                    }),
+                )
+                .route(
+                    "/device",
+                    web::post().to(move || {
+                        let issuer = issuer.clone();
+                        async move {
+                            HttpResponse::Ok().json(json!({
+                                "device_code": "device-1",
+                                "user_code": "ABCD-EFGH",
+                                "verification_uri": format!("{}/activate", issuer),
+                                "expires_in": 600,
+                            }))
+                        }
+                    }),
+                )
+                .route(
+                    "/token",
+                    web::post().to(move || {
+                        let first = polls.fetch_add(1, Ordering::SeqCst) == 0;
+                        let id_token = id_token.clone();
+                        async move {
+                            if first {
+                                HttpResponse::BadRequest()
+                                    .json(json!({"error": "authorization_pending"}))
+                            } else {
+                                HttpResponse::Ok().json(json!({
+                                    "access_token": "access",
+                                    "token_type": "Bearer",
+                                    "id_token": id_token,
+                                }))
+                            }
+                        }
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to(move || {
+                        let jwks = jwks.clone();
+                        async move { HttpResponse::Ok().json(jwks) }
+                    }),
+                )
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run()
+    };
+    tokio::spawn(provider);
+
+    let config = OidcConfig {
+        issuer: issuer.clone(),
+        client_id: "lev".to_string(),
+        client_secret: Some("secret".to_string()),
+        scopes: DEFAULT_SCOPES.to_string(),
+        groups_claim: DEFAULT_GROUPS_CLAIM.to_string(),
+        group_roles: parse_group_roles("devs=full_access").unwrap(),
+        session_ttl: DEFAULT_SESSION_TTL,
+    };
+    let device = config.start_device_flow().await.unwrap();
+    assert_eq!(device.user_code, "ABCD-EFGH");
+    assert_eq!(device.interval, 5);
+    assert_eq!(
+        config.poll_device_flow(&device.device_code).await.unwrap(),
+        DevicePoll::Pending
+    );
+    let DevicePoll::Done(identity) = config.poll_device_flow(&device.device_code).await.unwrap()
+    else {
+        panic!("the login should be approved");
+    };
+    assert_eq!(identity.subject, "user-1");
+    assert_eq!(identity.username, "alice");
+    assert_eq!(
+        role_for_groups(&config.group_roles, &identity.groups),
+        Some(RoleType::FullAccess)
+    );
+
+    // the token is only good for its client and with an intact signature
+    let jwks: JwkSet = serde_json::from_value(jwks_json).unwrap();
+    assert!(config.verify_id_token(&signed, &jwks).is_ok());
+    let other = OidcConfig {
+        client_id: "other".to_string(),
+        ..config.clone()
+    };
+    assert!(other.verify_id_token(&signed, &jwks).is_err());
+    let mut tampered = signed.clone();
+    tampered.insert(signed.rfind('.').unwrap() + 1, 'A');
+    assert!(config.verify_id_token(&tampered, &jwks).is_err());
+}
@@ -0,0 +1,66 @@
+use anyhow::Result;
+use chrono::Utc;
+use shared::{ok, proxy::ProxySettings};
+use sqlx::{prelude::FromRow, query, query_as, Executor, SqlitePool};
+
+pub const PROXY_MIGRATION: &str = r#"
+    create table if not exists proxy_settings (
+        id integer primary key check (id = 1),
+        settings text not null,
+        updated_at text not null
+    );
+    "#;
+
+// the only row of proxy_settings, settings are stored as json
+#[derive(Debug, FromRow)]
+pub struct ProxyData {
+    pub id: i64,
+    pub settings: String,
+    pub updated_at: String,
+}
+
+impl ProxyData {
+    pub async fn migrate(conn: &SqlitePool) -> Result<()> {
+        conn.execute(PROXY_MIGRATION).await?;
+        Ok(())
+    }
+
+    pub async fn get_settings(conn: &SqlitePool) -> Result<Option<ProxySettings>> {
+        let row = query_as::<_, ProxyData>("select * from proxy_settings where id = 1")
+            .fetch_optional(conn)
+            .await?;
+        match row {
+            Some(row) => ok!(Some(serde_json::from_str(&row.settings)?)),
+            None => ok!(None),
+        }
+    }
+
+    pub async fn save_settings(settings: &ProxySettings, conn: &SqlitePool) -> Result<()> {
+        query(
+            "insert into proxy_settings values (1, ?, ?)
+            on conflict(id) do update set settings = excluded.settings, updated_at = excluded.updated_at",
+        )
+        .bind(serde_json::to_string(settings)?)
+        .bind(Utc::now().to_rfc3339())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_repo() {
+    let _caches = crate::repo::test_caches().await;
+    let repo = crate::repo::Repo::new("", true).await.unwrap();
+    assert!(ProxyData::get_settings(&repo.pool).await.unwrap().is_none());
+    let mut settings = ProxySettings::default();
+    ProxyData::save_settings(&settings, &repo.pool)
+        .await
+        .unwrap();
+    settings.access_logs = true;
+    ProxyData::save_settings(&settings, &repo.pool)
+        .await
+        .unwrap();
+    let saved = ProxyData::get_settings(&repo.pool).await.unwrap().unwrap();
+    assert_eq!(saved, settings);
+}
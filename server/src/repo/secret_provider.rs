@@ -0,0 +1,368 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use shared::{err, ok, SecretRef, SecretValue};
+use sqlx::SqlitePool;
+use tokio::process::Command;
+
+use crate::repo::{crypto::digest, secret_repo::SecretData};
+
+// where the values of ${secret.*} come from, keys are passed without the provider prefix
+#[async_trait]
+pub trait SecretProvider: Debug + Send + Sync {
+    // values the deploys of the project may use, keys that are not found are left out
+    async fn get_secrets(&self, project: &str, keys: &[String]) -> Result<Vec<SecretValue>>;
+}
+
+// secrets stored in the leverans database, used for every key without a provider prefix
+#[derive(Debug, Clone)]
+pub struct SqliteProvider {
+    pub pool: SqlitePool,
+}
+
+#[async_trait]
+impl SecretProvider for SqliteProvider {
+    async fn get_secrets(&self, project: &str, keys: &[String]) -> Result<Vec<SecretValue>> {
+        SecretData::list_for_project_db(project, &self.pool)
+            .await?
+            .into_iter()
+            .filter(|s| keys.contains(&s.key))
+            .map(|s| s.to_value())
+            .collect()
+    }
+}
+
+// a sops encrypted yaml or json file, decrypted by the sops binary, so age and kms keys are
+// configured the way sops expects them (eg SOPS_AGE_KEY_FILE)
+#[derive(Debug, Clone)]
+pub struct SopsProvider {
+    pub file: PathBuf,
+}
+
+#[async_trait]
+impl SecretProvider for SopsProvider {
+    async fn get_secrets(&self, _project: &str, keys: &[String]) -> Result<Vec<SecretValue>> {
+        let output = Command::new("sops")
+            .arg("--decrypt")
+            .arg("--output-type")
+            .arg("json")
+            .arg(&self.file)
+            .output()
+            .await
+            .map_err(|e| anyhow!("failed to run sops: {}", e))?;
+        if !output.status.success() {
+            err!(anyhow!(
+                "failed to decrypt {}: {}",
+                self.file.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+        let data: Value = serde_json::from_slice(&output.stdout)?;
+        let mut secrets = vec![];
+        for key in keys {
+            // db/pass reads the pass field of the db map
+            if let Some(value) = get_path(&data, key, '/') {
+                secrets.push(external_value(key, value)?);
+            }
+        }
+        ok!(secrets)
+    }
+}
+
+// a key value store over http, eg vault: GET <url>/<key>, the value is the body or the field
+// of a json response
+#[derive(Debug, Clone)]
+pub struct HttpProvider {
+    pub url: String,
+    pub header: String,
+    pub token: Option<String>,
+    pub field: Option<String>,
+    pub client: reqwest::Client,
+}
+
+#[async_trait]
+impl SecretProvider for HttpProvider {
+    async fn get_secrets(&self, _project: &str, keys: &[String]) -> Result<Vec<SecretValue>> {
+        let mut secrets = vec![];
+        for key in keys {
+            let mut req = self.client.get(key_url(&self.url, key)?);
+            if let Some(token) = &self.token {
+                req = req.header(&self.header, token);
+            }
+            let res = req.send().await?;
+            if res.status() == reqwest::StatusCode::NOT_FOUND {
+                continue;
+            }
+            if !res.status().is_success() {
+                err!(anyhow!(
+                    "failed to read {} from {}: {}",
+                    key,
+                    self.url,
+                    res.status()
+                ))
+            }
+            let body = res.text().await?;
+            let value = match &self.field {
+                Some(field) => {
+                    let data: Value = serde_json::from_str(&body)?;
+                    get_path(&data, field, '.').ok_or(anyhow!(
+                        "{} of {} has no field {}",
+                        self.url,
+                        key,
+                        field
+                    ))?
+                }
+                None => body,
+            };
+            secrets.push(external_value(key, value)?);
+        }
+        ok!(secrets)
+    }
+}
+
+// every part of the key is one encoded path segment, so a key can't leave the base url
+fn key_url(base: &str, key: &str) -> Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(base)?;
+    let segments = key.split('/').collect::<Vec<_>>();
+    if segments
+        .iter()
+        .any(|s| s.is_empty() || *s == "." || *s == "..")
+    {
+        err!(anyhow!("invalid secret key {}", key))
+    }
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid provider url {}", base))?
+        .pop_if_empty()
+        .extend(segments);
+    ok!(url)
+}
+
+fn get_path(data: &Value, path: &str, separator: char) -> Option<String> {
+    let value = path
+        .split(separator)
+        .try_fold(data, |data, part| data.get(part))?;
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+// external values have no versions, a deploy reads the value again
+fn external_value(key: &str, value: String) -> Result<SecretValue> {
+    ok!(SecretValue {
+        key: key.to_string(),
+        digest: digest(&value)?,
+        value,
+        project: None,
+        version: 0,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderEntry {
+    // projects whose deploys may read from the provider
+    projects: Vec<String>,
+    #[serde(flatten)]
+    config: ProviderConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ProviderConfig {
+    Sops {
+        file: PathBuf,
+    },
+    Http {
+        url: String,
+        // name of the env var holding the token, so it stays out of the file
+        #[serde(rename = "token-env")]
+        token_env: Option<String>,
+        header: Option<String>,
+        field: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct ProvidersFile {
+    providers: HashMap<String, ProviderEntry>,
+}
+
+#[derive(Debug)]
+pub struct ExternalProvider {
+    pub projects: Vec<String>,
+    pub provider: Box<dyn SecretProvider>,
+}
+
+#[derive(Debug)]
+pub struct SecretProviders {
+    pub default: SqliteProvider,
+    pub by_prefix: HashMap<String, ExternalProvider>,
+}
+
+impl SecretProviders {
+    // SECRET_PROVIDERS_FILE, otherwise secret-providers.yaml next to the database. Without
+    // the file every secret is read from the database
+    pub fn load(dbpath: &str, pool: SqlitePool) -> Result<Self> {
+        let file = match std::env::var("SECRET_PROVIDERS_FILE") {
+            Ok(file) => PathBuf::from(file),
+            Err(_) => Path::new(dbpath)
+                .parent()
+                .unwrap_or(Path::new("."))
+                .join("secret-providers.yaml"),
+        };
+        let mut providers = Self {
+            default: SqliteProvider { pool },
+            by_prefix: HashMap::new(),
+        };
+        if !file.exists() {
+            ok!(providers)
+        }
+        let config: ProvidersFile = serde_yaml::from_str(&std::fs::read_to_string(&file)?)
+            .map_err(|e| anyhow!("failed to read {}: {}", file.display(), e))?;
+        for (prefix, entry) in config.providers {
+            let provider: Box<dyn SecretProvider> = match entry.config {
+                ProviderConfig::Sops { file } => Box::new(SopsProvider { file }),
+                ProviderConfig::Http {
+                    url,
+                    token_env,
+                    header,
+                    field,
+                } => {
+                    let token = token_env
+                        .map(|name| {
+                            std::env::var(&name)
+                                .map_err(|_| anyhow!("{} of provider {} is not set", name, prefix))
+                        })
+                        .transpose()?;
+                    Box::new(HttpProvider {
+                        url,
+                        header: header.unwrap_or("X-Vault-Token".to_string()),
+                        token,
+                        field,
+                        client: reqwest::Client::builder()
+                            .timeout(Duration::from_secs(10))
+                            .build()?,
+                    })
+                }
+            };
+            println!("using secret provider {}", prefix);
+            providers.by_prefix.insert(
+                prefix,
+                ExternalProvider {
+                    projects: entry.projects,
+                    provider,
+                },
+            );
+        }
+        ok!(providers)
+    }
+
+    // vault/db-pass goes to the vault provider as db-pass
+    fn split_key<'a>(&self, key: &'a str) -> Option<(&'a str, &'a str)> {
+        key.split_once('/')
+            .filter(|(prefix, _)| self.by_prefix.contains_key(*prefix))
+    }
+
+    // values of the keys a config reads, external ones keep their prefix
+    pub async fn get_secrets(&self, project: &str, keys: &[String]) -> Result<Vec<SecretValue>> {
+        let mut local = vec![];
+        let mut external: HashMap<&str, Vec<String>> = HashMap::new();
+        for key in keys {
+            match self.split_key(key) {
+                Some((prefix, key)) => external.entry(prefix).or_default().push(key.to_string()),
+                None => local.push(key.clone()),
+            }
+        }
+        let mut secrets = self.default.get_secrets(project, &local).await?;
+        for (prefix, keys) in external {
+            let external = &self.by_prefix[prefix];
+            if !external.projects.iter().any(|p| p == project) {
+                err!(anyhow!(
+                    "secret provider {} can't be used by project {}",
+                    prefix,
+                    project
+                ))
+            }
+            for mut secret in external.provider.get_secrets(project, &keys).await? {
+                secret.key = format!("{}/{}", prefix, secret.key);
+                secrets.push(secret);
+            }
+        }
+        ok!(secrets)
+    }
+
+    // the exact versions of database secrets, external ones are read again
+    pub async fn get_versions(
+        &self,
+        project: &str,
+        refs: &[SecretRef],
+    ) -> Result<Vec<SecretValue>> {
+        let (external, local): (Vec<_>, Vec<_>) = refs
+            .iter()
+            .cloned()
+            .partition(|r| self.split_key(&r.key).is_some());
+        let mut values = SecretData::get_versions_db(project, &local, &self.default.pool).await?;
+        let keys: Vec<String> = external.into_iter().map(|r| r.key).collect();
+        let found = self.get_secrets(project, &keys).await?;
+        if let Some(key) = keys.iter().find(|k| !found.iter().any(|s| &s.key == *k)) {
+            err!(anyhow!("secret {} is not available anymore", key))
+        }
+        values.extend(found);
+        ok!(values)
+    }
+}
+
+#[test]
+fn secret_provider_test() {
+    let data = serde_json::json!({
+        "db": { "pass": "p4ss", "port": 5432 },
+        "data": { "data": { "value": "from-vault" } }
+    });
+    assert_eq!(get_path(&data, "db/pass", '/'), Some("p4ss".to_string()));
+    assert_eq!(get_path(&data, "db/port", '/'), Some("5432".to_string()));
+    assert_eq!(get_path(&data, "db", '/'), None);
+    assert_eq!(
+        get_path(&data, "data.data.value", '.'),
+        Some("from-vault".to_string())
+    );
+
+    let config: ProvidersFile = serde_yaml::from_str(
+        r#"
+        providers:
+            vault:
+                type: http
+                url: https://vault.internal:8200/v1/secret/data
+                field: data.data.value
+                projects: [shop]
+            prod:
+                type: sops
+                file: /etc/leverans/prod.enc.yaml
+                projects: [shop, blog]
+        "#,
+    )
+    .unwrap();
+    assert!(matches!(
+        config.providers["prod"].config,
+        ProviderConfig::Sops { .. }
+    ));
+    assert_eq!(config.providers["vault"].projects, vec!["shop".to_string()]);
+
+    let base = "https://vault.internal:8200/v1/secret/data/";
+    assert_eq!(
+        key_url(base, "shop/db pass").unwrap().as_str(),
+        "https://vault.internal:8200/v1/secret/data/shop/db%20pass"
+    );
+    assert!(key_url(base, "../../sys/seal").is_err());
+    assert!(key_url(base, "shop//db").is_err());
+    assert!(key_url(base, "./db").is_err());
+}
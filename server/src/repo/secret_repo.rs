@@ -1,9 +1,23 @@
-use anyhow::Result;
+use std::{collections::HashSet, fs};
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use chrono::{DateTime, Utc};
-use sqlx::{prelude::FromRow, query, query_as, Executor, SqlitePool};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use shared::{
+    deployable::generated::{GenerateKind, GeneratedSecret},
+    err, ok, SecretImportReport, SecretRef, SecretValue,
+};
+use sqlx::{prelude::FromRow, query, query_as, Executor, Sqlite, SqlitePool, Transaction};
 use uuid::Uuid;
 
-use crate::repo::Repo;
+use crate::repo::{
+    crypto::{
+        change_master_key, decrypt, derive_key, digest, encrypt, encrypt_with, generate_master_key,
+        get_master_key_file, is_encrypted, key_in_use, key_rotation, write_key_file,
+    },
+    Repo,
+};
 
 #[derive(Clone, Debug, FromRow)]
 pub struct SecretData {
@@ -11,70 +25,462 @@ pub struct SecretData {
     pub key: String,
     pub value: String,
     pub created_at: String,
+    // None for global secrets, visible to every project
+    pub project: Option<String>,
+    // latest version, its value is the one in this row
+    pub version: i64,
+}
+
+// every value a secret ever had, the first version is written with the secret
+#[derive(Clone, Debug, FromRow)]
+pub struct SecretVersionData {
+    pub id: String,
+    pub secret_id: String,
+    pub version: i64,
+    pub value: String,
+    pub author: String,
+    pub note: Option<String>,
+    pub created_at: String,
 }
 
 impl SecretData {
-    pub fn new(key: String, value: String) -> Self {
+    pub fn new(key: String, value: String, project: Option<String>) -> Self {
         Self {
             id: Uuid::new_v4().to_string(),
             key,
             value,
             created_at: Utc::now().to_rfc3339(),
+            project,
+            version: 1,
         }
     }
 
     pub async fn migrate(conn: &SqlitePool) -> Result<()> {
         conn.execute(SECRET_MIGRATION).await?;
+        // databases created before project scoped and versioned secrets
+        let columns: Vec<(String,)> = query_as("select name from pragma_table_info('secrets')")
+            .fetch_all(conn)
+            .await?;
+        for (column, definition) in [
+            ("project", "project text"),
+            ("version", "version integer not null default 1"),
+        ] {
+            if !columns.iter().any(|c| c.0 == column) {
+                conn.execute(format!("alter table secrets add column {}", definition).as_str())
+                    .await?;
+            }
+        }
+        mark_encrypted(conn, "secrets").await?;
+        conn.execute(SECRET_VERSION_MIGRATION).await?;
+        mark_encrypted(conn, "secret_versions").await?;
+        // secrets written before versioning get their current value as the first version
+        conn.execute(
+            "insert into secret_versions (id, secret_id, version, value, author, note, created_at,
+            encrypted)
+            select lower(hex(randomblob(16))), id, version, value, 'unknown', null, created_at,
+            encrypted from secrets where id not in (select secret_id from secret_versions)",
+        )
+        .await?;
         Ok(())
     }
 
-    pub async fn insert_db(self, conn: &SqlitePool) -> Result<()> {
-        query("insert into secrets values (?, ?, ?, ?)")
-            .bind(&self.id)
-            .bind(&self.key)
-            .bind(&self.value)
-            .bind(&self.created_at)
-            .execute(conn)
-            .await?;
+    pub async fn insert_db(
+        self,
+        author: &str,
+        note: Option<String>,
+        conn: &SqlitePool,
+    ) -> Result<()> {
+        let _key = key_in_use().await;
+        let mut tx = conn.begin().await?;
+        self.insert_tx(author, note, &mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_tx(
+        &self,
+        author: &str,
+        note: Option<String>,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<()> {
+        let value = encrypt(&self.value)?;
+        query(
+            "insert into secrets (id, key, value, created_at, project, version)
+            values (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&self.id)
+        .bind(&self.key)
+        .bind(&value)
+        .bind(&self.created_at)
+        .bind(&self.project)
+        .bind(self.version)
+        .execute(&mut **tx)
+        .await?;
+        insert_version(&self.id, self.version, &value, author, note, tx).await?;
         Ok(())
     }
 
+    // the whole set is applied in one transaction, existing keys of the scope are only
+    // changed with overwrite and when the value differs
+    pub async fn import_db(
+        secrets: Vec<(String, String)>,
+        project: Option<String>,
+        overwrite: bool,
+        author: &str,
+        note: Option<String>,
+        conn: &SqlitePool,
+    ) -> Result<SecretImportReport> {
+        let mut report = SecretImportReport::default();
+        let mut keys = HashSet::new();
+        if let Some((key, _)) = secrets.iter().find(|(key, _)| !keys.insert(key)) {
+            err!(anyhow!("secret {} is set more than once", key))
+        }
+        let _key = key_in_use().await;
+        let mut tx = conn.begin().await?;
+        let existing = query_as::<_, SecretData>("select * from secrets where project is ?")
+            .bind(&project)
+            .fetch_all(&mut *tx)
+            .await?;
+        for (key, value) in secrets {
+            let Some(row) = existing.iter().find(|r| r.key == key) else {
+                SecretData::new(key.clone(), value, project.clone())
+                    .insert_tx(author, note.clone(), &mut tx)
+                    .await?;
+                report.created.push(key);
+                continue;
+            };
+            if !overwrite || decrypt(&row.value)? == value {
+                report.skipped.push(key);
+                continue;
+            }
+            Self::add_version_tx(row, &value, author, note.clone(), &mut tx).await?;
+            report.updated.push(key);
+        }
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    // creates the generated secrets the project can't read yet, existing ones are reused even
+    // when the kind or length changed. Returns the names of the created secrets
+    pub async fn generate_db(
+        generated: &[GeneratedSecret],
+        project: &str,
+        author: &str,
+        conn: &SqlitePool,
+    ) -> Result<Vec<String>> {
+        let mut created = vec![];
+        let _key = key_in_use().await;
+        let mut tx = conn.begin().await?;
+        let existing: Vec<(String,)> =
+            query_as("select key from secrets where project is null or project = ?")
+                .bind(project)
+                .fetch_all(&mut *tx)
+                .await?;
+        for secret in generated {
+            if existing.iter().any(|e| e.0 == secret.name) {
+                continue;
+            }
+            SecretData::new(
+                secret.name.clone(),
+                generate_value(secret),
+                Some(project.to_string()),
+            )
+            .insert_tx(author, Some("generated".to_string()), &mut tx)
+            .await?;
+            created.push(secret.name.clone());
+        }
+        tx.commit().await?;
+        Ok(created)
+    }
+
+    async fn add_version_tx(
+        row: &SecretData,
+        value: &str,
+        author: &str,
+        note: Option<String>,
+        tx: &mut Transaction<'_, Sqlite>,
+    ) -> Result<i64> {
+        let value = encrypt(value)?;
+        let version = row.version + 1;
+        insert_version(&row.id, version, &value, author, note, tx).await?;
+        query("update secrets set value = ?, version = ? where id = ?")
+            .bind(&value)
+            .bind(version)
+            .bind(&row.id)
+            .execute(&mut **tx)
+            .await?;
+        Ok(version)
+    }
+
     pub async fn list_db(conn: &SqlitePool) -> Result<Vec<Self>> {
-        let rows = query_as::<_, SecretData>("select * from secrets")
+        let _key = key_in_use().await;
+        let mut rows = query_as::<_, SecretData>("select * from secrets")
             .fetch_all(conn)
             .await?;
+        for row in rows.iter_mut() {
+            row.value = decrypt(&row.value)?;
+        }
+        Ok(rows)
+    }
+
+    // global secrets and the ones of the project, what a deploy of the project may use
+    pub async fn list_for_project_db(project: &str, conn: &SqlitePool) -> Result<Vec<Self>> {
+        let _key = key_in_use().await;
+        let mut rows =
+            query_as::<_, SecretData>("select * from secrets where project is null or project = ?")
+                .bind(project)
+                .fetch_all(conn)
+                .await?;
+        for row in rows.iter_mut() {
+            row.value = decrypt(&row.value)?;
+        }
+        Ok(rows)
+    }
+
+    // secrets of exactly this scope, global ones when project is None
+    pub async fn list_scope_db(project: Option<String>, conn: &SqlitePool) -> Result<Vec<Self>> {
+        let _key = key_in_use().await;
+        let mut rows =
+            query_as::<_, SecretData>("select * from secrets where project is ? order by key")
+                .bind(project)
+                .fetch_all(conn)
+                .await?;
+        for row in rows.iter_mut() {
+            row.value = decrypt(&row.value)?;
+        }
         Ok(rows)
     }
 
+    pub fn to_value(self) -> Result<SecretValue> {
+        ok!(SecretValue {
+            digest: digest(&self.value)?,
+            key: self.key,
+            value: self.value,
+            project: self.project,
+            version: self.version,
+        })
+    }
+
     pub fn get_created_at(&self) -> Result<DateTime<Utc>> {
         let dt = DateTime::parse_from_rfc3339(&self.created_at)?;
         Ok(dt.with_timezone(&Utc))
     }
 
-    pub async fn delete_db(key: String, conn: &SqlitePool) -> Result<()> {
-        query("delete from secrets where key = ?")
+    pub async fn delete_db(key: String, project: Option<String>, conn: &SqlitePool) -> Result<()> {
+        let mut tx = conn.begin().await?;
+        query(
+            "delete from secret_versions where secret_id in
+            (select id from secrets where key = ? and project is ?)",
+        )
+        .bind(&key)
+        .bind(&project)
+        .execute(&mut *tx)
+        .await?;
+        query("delete from secrets where key = ? and project is ?")
             .bind(key)
-            .execute(conn)
+            .bind(project)
+            .execute(&mut *tx)
             .await?;
+        tx.commit().await?;
         Ok(())
     }
 
-    pub async fn update_db(key: String, value: String, conn: &SqlitePool) -> Result<()> {
-        query("update secrets set value = ? where key = ?")
-            .bind(value)
-            .bind(key)
-            .execute(conn)
-            .await?;
-        Ok(())
+    // writes the value as a new version, returns its number
+    pub async fn update_db(
+        key: String,
+        value: String,
+        project: Option<String>,
+        author: &str,
+        note: Option<String>,
+        conn: &SqlitePool,
+    ) -> Result<i64> {
+        let _key = key_in_use().await;
+        let mut tx = conn.begin().await?;
+        let row = query_as::<_, SecretData>("select * from secrets where key = ? and project is ?")
+            .bind(&key)
+            .bind(&project)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(anyhow!("secret {} not found", key))?;
+        let version = Self::add_version_tx(&row, &value, author, note, &mut tx).await?;
+        tx.commit().await?;
+        Ok(version)
     }
 
-    pub async fn show_db(key: String, conn: &SqlitePool) -> Result<SecretData> {
-        let row = query_as::<_, SecretData>("select * from secrets where key = ?")
-            .bind(key)
-            .fetch_one(conn)
-            .await?;
+    // refs come from the deploy body, a deploy may only read its own and global secrets
+    pub async fn get_versions_db(
+        project: &str,
+        refs: &[SecretRef],
+        conn: &SqlitePool,
+    ) -> Result<Vec<SecretValue>> {
+        let _key = key_in_use().await;
+        let mut values = vec![];
+        for secret in refs {
+            if secret.project.as_deref().is_some_and(|p| p != project) {
+                err!(anyhow!(
+                    "secret {} of project {} can't be used by project {}",
+                    secret.key,
+                    secret.project.as_deref().unwrap_or_default(),
+                    project
+                ))
+            }
+            let (value,): (String,) = query_as(
+                "select v.value from secret_versions v join secrets s on s.id = v.secret_id
+                where s.key = ? and s.project is ? and v.version = ?",
+            )
+            .bind(&secret.key)
+            .bind(&secret.project)
+            .bind(secret.version)
+            .fetch_optional(conn)
+            .await?
+            .ok_or(anyhow!(
+                "version {} of secret {} does not exist anymore",
+                secret.version,
+                secret.key
+            ))?;
+            let value = decrypt(&value)?;
+            values.push(SecretValue {
+                key: secret.key.clone(),
+                digest: digest(&value)?,
+                value,
+                project: secret.project.clone(),
+                version: secret.version,
+            });
+        }
+        Ok(values)
+    }
+
+    // newest first, values stay encrypted
+    pub async fn history_db(
+        key: String,
+        project: Option<String>,
+        conn: &SqlitePool,
+    ) -> Result<Vec<SecretVersionData>> {
+        let rows = query_as::<_, SecretVersionData>(
+            "select v.* from secret_versions v join secrets s on s.id = v.secret_id
+            where s.key = ? and s.project is ? order by v.version desc",
+        )
+        .bind(key)
+        .bind(project)
+        .fetch_all(conn)
+        .await?;
+        Ok(rows)
+    }
+
+    // the old value comes back as a new version, so the history is never rewritten
+    pub async fn revert_db(
+        key: String,
+        project: Option<String>,
+        version: i64,
+        author: &str,
+        conn: &SqlitePool,
+    ) -> Result<i64> {
+        // update_db takes the key itself, it can't be held twice while a rotation waits
+        let value = {
+            let _key = key_in_use().await;
+            let old = Self::history_db(key.clone(), project.clone(), conn)
+                .await?
+                .into_iter()
+                .find(|v| v.version == version)
+                .ok_or(anyhow!("version {} of secret {} not found", version, key))?;
+            decrypt(&old.value)?
+        };
+        Self::update_db(
+            key,
+            value,
+            project,
+            author,
+            Some(format!("revert to version {}", version)),
+            conn,
+        )
+        .await
+    }
+
+    pub async fn show_db(
+        key: String,
+        project: Option<String>,
+        conn: &SqlitePool,
+    ) -> Result<SecretData> {
+        let _key = key_in_use().await;
+        let mut row =
+            query_as::<_, SecretData>("select * from secrets where key = ? and project is ?")
+                .bind(key)
+                .bind(project)
+                .fetch_one(conn)
+                .await?;
+        row.value = decrypt(&row.value)?;
         Ok(row)
     }
+
+    // rows written before encryption at rest, runs on every start
+    pub async fn encrypt_plaintext_db(conn: &SqlitePool) -> Result<usize> {
+        let _key = key_in_use().await;
+        let mut count = 0;
+        for table in ["secrets", "secret_versions"] {
+            let rows: Vec<(String, String)> =
+                query_as(format!("select id, value from {} where encrypted = 0", table).as_str())
+                    .fetch_all(conn)
+                    .await?;
+            for (id, value) in rows {
+                query(
+                    format!("update {} set value = ?, encrypted = 1 where id = ?", table).as_str(),
+                )
+                .bind(encrypt(&value)?)
+                .bind(&id)
+                .execute(conn)
+                .await?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // re-encrypts every secret, jwt key and kept refresh token in one transaction. The new key
+    // is written next to the old one before the commit and replaces it after, so an
+    // interrupted rotation leaves both
+    pub async fn rotate_master_key_db(conn: &SqlitePool) -> Result<()> {
+        let file = get_master_key_file()?.ok_or(anyhow!(
+            "master key is set with LEV_MASTER_KEY, change it there and restart the manager"
+        ))?;
+        let _rotation = key_rotation().await;
+        let new_master = generate_master_key();
+        let new_key = derive_key(&new_master)?;
+        let new_file = file.with_extension("key.new");
+
+        let mut tx = conn.begin().await?;
+        for table in ["secrets", "secret_versions", "jwt_keys"] {
+            let rows: Vec<(String, String)> =
+                query_as(format!("select id, value from {}", table).as_str())
+                    .fetch_all(&mut *tx)
+                    .await?;
+            for (id, value) in rows {
+                query(format!("update {} set value = ? where id = ?", table).as_str())
+                    .bind(encrypt_with(&new_key, &decrypt(&value)?)?)
+                    .bind(&id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        // refresh tokens kept for the reuse grace period of sessions
+        let tokens: Vec<(String, String)> =
+            query_as("select id, next_token from sessions where next_token is not null")
+                .fetch_all(&mut *tx)
+                .await?;
+        for (id, token) in tokens {
+            query("update sessions set next_token = ? where id = ?")
+                .bind(encrypt_with(&new_key, &decrypt(&token)?)?)
+                .bind(&id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        write_key_file(&new_file, &new_master)?;
+        if let Err(e) = tx.commit().await {
+            let _ = fs::remove_file(&new_file);
+            return Err(e.into());
+        }
+        fs::rename(&new_file, &file)?;
+        change_master_key(&new_master, Some(file))
+    }
 }
 
 pub const SECRET_MIGRATION: &str = r#"
@@ -82,16 +488,344 @@ pub const SECRET_MIGRATION: &str = r#"
         id text primary key,
         key text not null,
         value text not null,
-        created_at text not null
+        created_at text not null,
+        project text,
+        version integer not null default 1,
+        encrypted integer not null default 1
     )
     "#;
+
+pub const SECRET_VERSION_MIGRATION: &str = r#"
+    create table if not exists secret_versions (
+        id text primary key,
+        secret_id text not null,
+        version integer not null,
+        value text not null,
+        author text not null,
+        note text,
+        created_at text not null,
+        encrypted integer not null default 1,
+        unique (secret_id, version)
+    )
+    "#;
+
+// value is already encrypted
+// every value is written encrypted, rows of databases from before encryption at rest are
+// marked 0 and encrypt_plaintext_db encrypts them. The prefix alone doesn't tell, a plaintext
+// value may start with it too
+async fn mark_encrypted(conn: &SqlitePool, table: &str) -> Result<()> {
+    let columns: Vec<(String,)> =
+        query_as(format!("select name from pragma_table_info('{}')", table).as_str())
+            .fetch_all(conn)
+            .await?;
+    if columns.iter().any(|c| c.0 == "encrypted") {
+        ok!(())
+    }
+    // jwt keys were always written encrypted. With a wrong master key one of them doesn't
+    // decrypt, and the encrypted secrets would be taken for plaintext
+    let (has_jwt_keys,): (bool,) = query_as(
+        "select count(*) > 0 from sqlite_master where type = 'table' and name = 'jwt_keys'",
+    )
+    .fetch_one(conn)
+    .await?;
+    if has_jwt_keys {
+        let jwt_key: Option<(String,)> = query_as("select value from jwt_keys limit 1")
+            .fetch_optional(conn)
+            .await?;
+        if let Some((value,)) = jwt_key {
+            if !is_encrypted(&value)? {
+                err!(anyhow!(
+                    "the jwt keys don't decrypt with the master key, is it the right one?"
+                ))
+            }
+        }
+    }
+    let rows: Vec<(String, String)> = query_as(format!("select id, value from {}", table).as_str())
+        .fetch_all(conn)
+        .await?;
+    let mut plaintext = vec![];
+    for (id, value) in &rows {
+        if !is_encrypted(value)? {
+            plaintext.push(id);
+        }
+    }
+    conn.execute(
+        format!(
+            "alter table {} add column encrypted integer not null default 1",
+            table
+        )
+        .as_str(),
+    )
+    .await?;
+    for id in plaintext {
+        query(format!("update {} set encrypted = 0 where id = ?", table).as_str())
+            .bind(id)
+            .execute(conn)
+            .await?;
+    }
+    ok!(())
+}
+
+async fn insert_version(
+    secret_id: &str,
+    version: i64,
+    value: &str,
+    author: &str,
+    note: Option<String>,
+    tx: &mut Transaction<'_, Sqlite>,
+) -> Result<()> {
+    query(
+        "insert into secret_versions (id, secret_id, version, value, author, note, created_at)
+        values (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(secret_id)
+    .bind(version)
+    .bind(value)
+    .bind(author)
+    .bind(note)
+    .bind(Utc::now().to_rfc3339())
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+fn generate_value(secret: &GeneratedSecret) -> String {
+    let mut rng = rand::thread_rng();
+    match secret.kind {
+        GenerateKind::Alnum => (&mut rng)
+            .sample_iter(Alphanumeric)
+            .take(secret.length)
+            .map(char::from)
+            .collect(),
+        GenerateKind::Uuid => Uuid::new_v4().to_string(),
+        GenerateKind::Hex => {
+            let mut bytes = vec![0u8; secret.length.div_ceil(2)];
+            rng.fill_bytes(&mut bytes);
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            hex[..secret.length].to_string()
+        }
+        GenerateKind::Base64 => {
+            let mut bytes = vec![0u8; secret.length];
+            rng.fill_bytes(&mut bytes);
+            STANDARD.encode(bytes)
+        }
+    }
+}
+
+#[tokio::test]
+async fn encrypted_mark() {
+    let _caches = crate::repo::test_caches().await;
+    let _key = crate::repo::crypto::test_master_key().await;
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    // a database from before the mark, with a plaintext value that looks encrypted
+    pool.execute(
+        "create table secrets (id text primary key, key text not null, value text not null,
+        created_at text not null, project text, version integer not null default 1)",
+    )
+    .await
+    .unwrap();
+    for (id, value) in [
+        ("1", "enc:v1:AAAA".to_string()),
+        ("2", "plain".to_string()),
+        ("3", encrypt("encrypted").unwrap()),
+    ] {
+        query("insert into secrets (id, key, value, created_at) values (?, ?, ?, ?)")
+            .bind(id)
+            .bind(format!("key{}", id))
+            .bind(value)
+            .bind(Utc::now().to_rfc3339())
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    SecretData::migrate(&pool).await.unwrap();
+    assert_eq!(SecretData::encrypt_plaintext_db(&pool).await.unwrap(), 4);
+    assert_eq!(SecretData::encrypt_plaintext_db(&pool).await.unwrap(), 0);
+    let values: Vec<_> = SecretData::list_scope_db(None, &pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|s| s.value)
+        .collect();
+    assert_eq!(values, vec!["enc:v1:AAAA", "plain", "encrypted"]);
+}
+
 #[tokio::test]
 async fn test_secret_repo() {
-    let secret = SecretData::new("key".to_string(), "value".to_string());
+    let _caches = crate::repo::test_caches().await;
+    let _key = crate::repo::crypto::test_master_key().await;
+    let secret = SecretData::new("key".to_string(), "value".to_string(), None);
     let pool = Repo::new("", true).await.unwrap();
-    secret.insert_db(&pool.pool).await.unwrap();
+    secret.insert_db("admin", None, &pool.pool).await.unwrap();
     let rows = SecretData::list_db(&pool.pool).await.unwrap();
     assert_eq!(rows.len(), 1);
     let sec = rows[0].clone();
     sec.get_created_at().unwrap();
+    assert_eq!(sec.value, "value");
+    let (raw,): (String,) = sqlx::query_as("select value from secrets")
+        .fetch_one(&pool.pool)
+        .await
+        .unwrap();
+    assert!(is_encrypted(&raw).unwrap());
+
+    SecretData::new(
+        "key".to_string(),
+        "staging".to_string(),
+        Some("staging".to_string()),
+    )
+    .insert_db("admin", None, &pool.pool)
+    .await
+    .unwrap();
+    SecretData::new(
+        "other".to_string(),
+        "prod".to_string(),
+        Some("prod".to_string()),
+    )
+    .insert_db("admin", None, &pool.pool)
+    .await
+    .unwrap();
+    let rows = SecretData::list_for_project_db("staging", &pool.pool)
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 2);
+    assert!(rows.iter().all(|r| r.project.as_deref() != Some("prod")));
+    let sec = SecretData::show_db("key".to_string(), Some("staging".to_string()), &pool.pool)
+        .await
+        .unwrap();
+    assert_eq!(sec.value, "staging");
+    SecretData::delete_db("key".to_string(), None, &pool.pool)
+        .await
+        .unwrap();
+    assert_eq!(SecretData::list_db(&pool.pool).await.unwrap().len(), 2);
+
+    let staging = Some("staging".to_string());
+    SecretData::new("db".to_string(), "one".to_string(), staging.clone())
+        .insert_db("admin", None, &pool.pool)
+        .await
+        .unwrap();
+    let version = SecretData::update_db(
+        "db".to_string(),
+        "two".to_string(),
+        staging.clone(),
+        "bob",
+        Some("bad value".to_string()),
+        &pool.pool,
+    )
+    .await
+    .unwrap();
+    assert_eq!(version, 2);
+    let version = SecretData::revert_db("db".to_string(), staging.clone(), 1, "admin", &pool.pool)
+        .await
+        .unwrap();
+    assert_eq!(version, 3);
+    let sec = SecretData::show_db("db".to_string(), staging.clone(), &pool.pool)
+        .await
+        .unwrap();
+    assert_eq!((sec.value.as_str(), sec.version), ("one", 3));
+    let history = SecretData::history_db("db".to_string(), staging, &pool.pool)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[1].author, "bob");
+
+    let report = SecretData::import_db(
+        vec![
+            ("db".to_string(), "one".to_string()),
+            ("api".to_string(), "key".to_string()),
+        ],
+        Some("staging".to_string()),
+        false,
+        "admin",
+        None,
+        &pool.pool,
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.created, vec!["api".to_string()]);
+    assert_eq!(report.skipped, vec!["db".to_string()]);
+    let report = SecretData::import_db(
+        vec![("db".to_string(), "four".to_string())],
+        Some("staging".to_string()),
+        true,
+        "admin",
+        None,
+        &pool.pool,
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.updated, vec!["db".to_string()]);
+
+    let prod_ref = SecretRef {
+        key: "other".to_string(),
+        project: Some("prod".to_string()),
+        version: 1,
+    };
+    let values = SecretData::get_versions_db("prod", std::slice::from_ref(&prod_ref), &pool.pool)
+        .await
+        .unwrap();
+    assert_eq!(values[0].value, "prod");
+    assert!(
+        SecretData::get_versions_db("staging", &[prod_ref], &pool.pool)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn master_key_rotation() {
+    let _caches = crate::repo::test_caches().await;
+    let _key = crate::repo::crypto::test_master_key().await;
+    let dir = std::env::temp_dir().join(format!("lev-rotate-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("master.key");
+    let master = generate_master_key();
+    write_key_file(&file, &master).unwrap();
+    change_master_key(&master, Some(file.clone())).unwrap();
+    let old_file = fs::read_to_string(&file).unwrap();
+    let pool = Repo::new("", true).await.unwrap().pool;
+
+    // secrets written while the key rotates still decrypt afterwards
+    let writes: Vec<_> = (0..100)
+        .map(|i| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                SecretData::new(format!("key{}", i), "value".to_string(), None)
+                    .insert_db("admin", None, &pool)
+                    .await
+            })
+        })
+        .collect();
+    SecretData::rotate_master_key_db(&pool).await.unwrap();
+    for write in writes {
+        write.await.unwrap().unwrap();
+    }
+    let rows = SecretData::list_db(&pool).await.unwrap();
+    assert_eq!(rows.len(), 100);
+    assert!(rows.iter().all(|r| r.value == "value"));
+    assert_ne!(fs::read_to_string(&file).unwrap(), old_file);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn generated_value() {
+    let generated = |kind, length| {
+        generate_value(&GeneratedSecret {
+            name: "key".to_string(),
+            kind,
+            length,
+        })
+    };
+    let alnum = generated(GenerateKind::Alnum, 24);
+    assert_eq!(alnum.len(), 24);
+    assert!(alnum.chars().all(|c| c.is_ascii_alphanumeric()));
+    assert_ne!(alnum, generated(GenerateKind::Alnum, 24));
+    assert_eq!(generated(GenerateKind::Hex, 7).len(), 7);
+    assert_eq!(
+        STANDARD
+            .decode(generated(GenerateKind::Base64, 32))
+            .unwrap()
+            .len(),
+        32
+    );
 }
@@ -0,0 +1,312 @@
+use std::{
+    collections::HashSet,
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use shared::{err, ok};
+use sqlx::{prelude::FromRow, query, query_as, Executor, SqlitePool};
+use uuid::Uuid;
+
+use super::{
+    crypto::{decrypt, encrypt, key_in_use},
+    token_repo::hash_token,
+};
+
+// lifetime of the jwt sent with every request
+pub const ACCESS_TOKEN_TTL: u64 = 15 * 60;
+// lifetime of a login, every refresh replaces the refresh token but keeps the expiry
+pub const REFRESH_TOKEN_TTL: i64 = 30 * 24 * 60 * 60;
+// two lev commands started together refresh with the same token, the later one gets the
+// tokens of the first refresh within this many seconds
+const REFRESH_REUSE_GRACE: i64 = 10;
+
+pub const SESSION_MIGRATION: &str = r#"
+    create table if not exists sessions (
+        id text primary key,
+        username text not null,
+        refresh_hash text not null unique,
+        created_at text not null,
+        expires_at text not null,
+        revoked integer not null default 0,
+        previous_hash text,
+        rotated_at text,
+        next_token text
+    );
+    "#;
+
+// a login of a user, access tokens carry its id so logout can revoke them before they expire
+#[derive(Debug, Clone, FromRow)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    pub refresh_hash: String,
+    pub created_at: String,
+    pub expires_at: String,
+    pub revoked: bool,
+    // the refresh token replaced by the last refresh, using it again means it was stolen
+    pub previous_hash: Option<String>,
+    pub rotated_at: Option<String>,
+    // the current refresh token, encrypted and only kept for the reuse grace period
+    pub next_token: Option<String>,
+}
+
+// ids of revoked sessions that are not expired yet, checked on each request
+pub static REVOKED_SESSIONS: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+pub fn is_revoked(id: &str) -> bool {
+    REVOKED_SESSIONS.lock().unwrap().contains(id)
+}
+
+fn new_refresh_token() -> String {
+    let token: String = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect();
+    token
+}
+
+impl Session {
+    pub async fn migrate(conn: &SqlitePool) -> Result<()> {
+        conn.execute(SESSION_MIGRATION).await?;
+        // databases created before refresh token reuse was detected
+        let columns: Vec<(String,)> = query_as("select name from pragma_table_info('sessions')")
+            .fetch_all(conn)
+            .await?;
+        for (column, definition) in [
+            ("previous_hash", "previous_hash text"),
+            ("rotated_at", "rotated_at text"),
+            ("next_token", "next_token text"),
+        ] {
+            if !columns.iter().any(|c| c.0 == column) {
+                conn.execute(format!("alter table sessions add column {}", definition).as_str())
+                    .await?;
+            }
+        }
+        Self::load_revoked_db(conn).await
+    }
+
+    // the refresh token itself is only returned here and by refresh_db, the session can be
+    // refreshed for ttl seconds
+    pub fn new(username: String, ttl: i64) -> (Self, String) {
+        let refresh_token = new_refresh_token();
+        let now = Utc::now();
+        (
+            Self {
+                id: Uuid::new_v4().to_string(),
+                username,
+                refresh_hash: hash_token(&refresh_token),
+                created_at: now.to_rfc3339(),
+                expires_at: (now + Duration::seconds(ttl)).to_rfc3339(),
+                revoked: false,
+                previous_hash: None,
+                rotated_at: None,
+                next_token: None,
+            },
+            refresh_token,
+        )
+    }
+
+    pub async fn insert_db(&self, conn: &SqlitePool) -> Result<()> {
+        // expired sessions can't be refreshed and their access tokens are expired too
+        query("delete from sessions where expires_at < ?")
+            .bind(Utc::now().to_rfc3339())
+            .execute(conn)
+            .await?;
+        query(
+            "insert into sessions (id, username, refresh_hash, created_at, expires_at, revoked)
+            values (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&self.id)
+        .bind(&self.username)
+        .bind(&self.refresh_hash)
+        .bind(&self.created_at)
+        .bind(&self.expires_at)
+        .bind(self.revoked)
+        .execute(conn)
+        .await?;
+        Self::load_revoked_db(conn).await
+    }
+
+    // a refresh token works once, the session gets a new one. A replaced token that comes
+    // back after the grace period was copied, so the whole session is revoked
+    pub async fn refresh_db(refresh_token: &str, conn: &SqlitePool) -> Result<(Self, String)> {
+        let hash = hash_token(refresh_token);
+        let now = Utc::now();
+        let _key = key_in_use().await;
+        query("update sessions set next_token = null where rotated_at < ?")
+            .bind((now - Duration::seconds(REFRESH_REUSE_GRACE)).to_rfc3339())
+            .execute(conn)
+            .await?;
+        let Some(mut session) =
+            query_as::<_, Self>("select * from sessions where refresh_hash = ?")
+                .bind(&hash)
+                .fetch_optional(conn)
+                .await?
+        else {
+            let reused = query_as::<_, Self>("select * from sessions where previous_hash = ?")
+                .bind(&hash)
+                .fetch_optional(conn)
+                .await?
+                .ok_or(anyhow!("unknown refresh token"))?;
+            return Self::reuse_db(reused, conn).await;
+        };
+        session.must_be_active()?;
+        let refresh_token = new_refresh_token();
+        session.refresh_hash = hash_token(&refresh_token);
+        session.previous_hash = Some(hash);
+        session.rotated_at = Some(now.to_rfc3339());
+        session.next_token = Some(encrypt(&refresh_token)?);
+        // a parallel refresh with the same token may have replaced it in the meantime
+        let result = query(
+            "update sessions set refresh_hash = ?, previous_hash = ?, rotated_at = ?,
+            next_token = ? where id = ? and refresh_hash = ?",
+        )
+        .bind(&session.refresh_hash)
+        .bind(&session.previous_hash)
+        .bind(&session.rotated_at)
+        .bind(&session.next_token)
+        .bind(&session.id)
+        .bind(&session.previous_hash)
+        .execute(conn)
+        .await?;
+        if result.rows_affected() == 0 {
+            let replaced =
+                query_as::<_, Self>("select * from sessions where id = ? and previous_hash = ?")
+                    .bind(&session.id)
+                    .bind(&session.previous_hash)
+                    .fetch_optional(conn)
+                    .await?;
+            let Some(replaced) = replaced else {
+                Self::revoke_db(&session.id, conn).await?;
+                err!(anyhow!("refresh token was already used, login again"))
+            };
+            return Self::reuse_db(replaced, conn).await;
+        }
+        ok!((session, refresh_token))
+    }
+
+    // the replaced token of the session came back. Shortly after the refresh it is another
+    // lev command of the same user and gets the current tokens, later it was copied
+    async fn reuse_db(session: Self, conn: &SqlitePool) -> Result<(Self, String)> {
+        let in_grace = session
+            .rotated_at
+            .as_deref()
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+            .is_some_and(|at| {
+                Utc::now() - at.with_timezone(&Utc) <= Duration::seconds(REFRESH_REUSE_GRACE)
+            });
+        if in_grace {
+            session.must_be_active()?;
+            if let Some(token) = session.next_token.as_deref().map(decrypt).transpose()? {
+                ok!((session, token))
+            }
+        }
+        Self::revoke_db(&session.id, conn).await?;
+        err!(anyhow!("refresh token was already used, login again"))
+    }
+
+    fn must_be_active(&self) -> Result<()> {
+        if self.revoked {
+            err!(anyhow!("session was revoked, login again"))
+        }
+        if DateTime::parse_from_rfc3339(&self.expires_at)? < Utc::now() {
+            err!(anyhow!("session has expired, login again"))
+        }
+        ok!(())
+    }
+
+    pub async fn revoke_db(id: &str, conn: &SqlitePool) -> Result<()> {
+        query("update sessions set revoked = 1 where id = ?")
+            .bind(id)
+            .execute(conn)
+            .await?;
+        Self::load_revoked_db(conn).await
+    }
+
+    // every session of the user, eg after a password or role change. The session making the
+    // change can be kept so the user isn't logged out by their own password change
+    pub async fn revoke_user_db(
+        username: &str,
+        except: Option<&str>,
+        conn: &SqlitePool,
+    ) -> Result<()> {
+        query("update sessions set revoked = 1 where username = ? and id is not ?")
+            .bind(username)
+            .bind(except)
+            .execute(conn)
+            .await?;
+        Self::load_revoked_db(conn).await
+    }
+
+    pub async fn load_revoked_db(conn: &SqlitePool) -> Result<()> {
+        let ids: Vec<(String,)> =
+            query_as("select id from sessions where revoked = 1 and expires_at >= ?")
+                .bind(Utc::now().to_rfc3339())
+                .fetch_all(conn)
+                .await?;
+        *REVOKED_SESSIONS.lock().unwrap() = ids.into_iter().map(|i| i.0).collect();
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn session_repo() {
+    let _caches = crate::repo::test_caches().await;
+    let _key = crate::repo::crypto::test_master_key().await;
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    Session::migrate(&pool).await.unwrap();
+    let (session, refresh_token) = Session::new("admin".to_string(), REFRESH_TOKEN_TTL);
+    session.insert_db(&pool).await.unwrap();
+
+    let (refreshed, new_token) = Session::refresh_db(&refresh_token, &pool).await.unwrap();
+    assert_eq!(refreshed.id, session.id);
+    assert!(!is_revoked(&session.id));
+    Session::revoke_db(&session.id, &pool).await.unwrap();
+    assert!(is_revoked(&session.id));
+    assert!(Session::refresh_db(&new_token, &pool).await.is_err());
+
+    // right after the refresh the replaced token gets the same tokens again
+    let (session, refresh_token) = Session::new("admin".to_string(), REFRESH_TOKEN_TTL);
+    session.insert_db(&pool).await.unwrap();
+    let (_, new_token) = Session::refresh_db(&refresh_token, &pool).await.unwrap();
+    let (_, again) = Session::refresh_db(&refresh_token, &pool).await.unwrap();
+    assert_eq!(again, new_token);
+    assert!(!is_revoked(&session.id));
+
+    // after the grace period a reused refresh token revokes the session it belonged to
+    query("update sessions set rotated_at = ? where id = ?")
+        .bind((Utc::now() - Duration::seconds(REFRESH_REUSE_GRACE + 1)).to_rfc3339())
+        .bind(&session.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert!(Session::refresh_db(&refresh_token, &pool).await.is_err());
+    assert!(is_revoked(&session.id));
+    assert!(Session::refresh_db(&new_token, &pool).await.is_err());
+
+    // two parallel refreshes with the same token end up with the same tokens
+    let (session, refresh_token) = Session::new("admin".to_string(), REFRESH_TOKEN_TTL);
+    session.insert_db(&pool).await.unwrap();
+    let (a, b) = tokio::join!(
+        Session::refresh_db(&refresh_token, &pool),
+        Session::refresh_db(&refresh_token, &pool)
+    );
+    assert_eq!(a.unwrap().1, b.unwrap().1);
+    assert!(!is_revoked(&session.id));
+
+    // a session past its ttl can't be refreshed
+    let (session, refresh_token) = Session::new("admin".to_string(), 1);
+    session.insert_db(&pool).await.unwrap();
+    query("update sessions set expires_at = ? where id = ?")
+        .bind((Utc::now() - Duration::seconds(1)).to_rfc3339())
+        .bind(&session.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert!(Session::refresh_db(&refresh_token, &pool).await.is_err());
+}
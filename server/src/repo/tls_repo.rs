@@ -0,0 +1,100 @@
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+use shared::{
+    ok,
+    tls::{fingerprint, DEFAULT_TLS_PORT},
+};
+
+use crate::repo::crypto::write_private_file;
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub port: u16,
+    pub config: ServerConfig,
+}
+
+// LEV_TLS=off serves plain http only. TLS_CERT_FILE and TLS_KEY_FILE, otherwise
+// tls.crt and tls.key next to the database, self-signed on the first start
+pub fn init_tls(dbpath: &str) -> Result<Option<TlsSettings>> {
+    if std::env::var("LEV_TLS").is_ok_and(|v| v == "off") {
+        println!("TLS is off, the api is only served over plain http");
+        return Ok(None);
+    }
+    let dir = Path::new(dbpath).parent().unwrap_or(Path::new("."));
+    let (cert_file, key_file) = match (
+        std::env::var("TLS_CERT_FILE"),
+        std::env::var("TLS_KEY_FILE"),
+    ) {
+        (Ok(cert), Ok(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+        _ => {
+            let (cert, key) = (dir.join("tls.crt"), dir.join("tls.key"));
+            if !cert.exists() || !key.exists() {
+                println!("generating self-signed certificate: {}", cert.display());
+                generate_self_signed(&cert, &key)?;
+            }
+            (cert, key)
+        }
+    };
+    let certs = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(&cert_file)?))
+        .collect::<Result<Vec<CertificateDer>, _>>()?;
+    let key: PrivateKeyDer =
+        rustls_pemfile::private_key(&mut BufReader::new(fs::File::open(&key_file)?))?
+            .ok_or(anyhow!("no private key in {}", key_file.display()))?;
+    let leaf = certs
+        .first()
+        .ok_or(anyhow!("no certificate in {}", cert_file.display()))?;
+    let fingerprint = fingerprint(leaf);
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    let port = std::env::var("LEV_TLS_PORT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(DEFAULT_TLS_PORT);
+    println!("serving TLS on port {}", port);
+    println!("certificate fingerprint (sha256): {}", fingerprint);
+    ok!(Some(TlsSettings { port, config }))
+}
+
+// LEV_TLS_NAMES, comma separated hostnames and addresses the certificate is for
+fn generate_self_signed(cert_file: &Path, key_file: &Path) -> Result<()> {
+    let mut names: Vec<String> = std::env::var("LEV_TLS_NAMES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .collect();
+    names.push("localhost".to_string());
+    let certified = rcgen::generate_simple_self_signed(names)?;
+    write_private_file(key_file, certified.key_pair.serialize_pem().as_bytes())?;
+    fs::write(cert_file, certified.cert.pem())?;
+    Ok(())
+}
+
+#[test]
+fn tls_repo() {
+    let dir = std::env::temp_dir().join(format!("lev-tls-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let dbpath = dir.join("db.sqlite").to_string_lossy().to_string();
+
+    let tls = init_tls(&dbpath).unwrap().unwrap();
+    assert_eq!(tls.port, DEFAULT_TLS_PORT);
+    assert!(dir.join("tls.key").exists());
+    // the certificate is kept across restarts
+    let cert = fs::read(dir.join("tls.crt")).unwrap();
+    init_tls(&dbpath).unwrap().unwrap();
+    assert_eq!(cert, fs::read(dir.join("tls.crt")).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,237 @@
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use shared::{err, ApiTokenInfo};
+use sqlx::{prelude::FromRow, query, query_as, Executor, SqlitePool};
+use uuid::Uuid;
+
+use super::expires_after;
+
+pub const TOKEN_PREFIX: &str = "lev_";
+
+pub const TOKEN_MIGRATION: &str = r#"
+    create table if not exists api_tokens (
+        id text primary key,
+        name text not null unique,
+        token_hash text not null unique,
+        role text not null,
+        project text,
+        created_by text not null,
+        created_at text not null,
+        expires_at text
+    );
+    "#;
+
+// named tokens for CI, only the sha256 of the token is stored
+#[derive(Debug, Clone, FromRow)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub token_hash: String,
+    pub role: String,
+    // a token with a project can only be used for that project
+    pub project: Option<String>,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+}
+
+// every token is checked on each request, so they are kept in memory and reloaded when
+// one is created or revoked
+pub static API_TOKENS: LazyLock<Mutex<Vec<ApiToken>>> = LazyLock::new(|| Mutex::new(vec![]));
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// the valid token with this value, expired ones are kept until they are revoked
+pub fn find_token(token: &str) -> Result<ApiToken> {
+    let hash = hash_token(token);
+    let api_token = API_TOKENS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|t| t.token_hash == hash)
+        .cloned()
+        .ok_or(anyhow!("unknown api token"))?;
+    if let Some(expires_at) = &api_token.expires_at {
+        if DateTime::parse_from_rfc3339(expires_at)? < Utc::now() {
+            err!(anyhow!("api token {} has expired", api_token.name))
+        }
+    }
+    Ok(api_token)
+}
+
+impl ApiToken {
+    pub async fn migrate(conn: &SqlitePool) -> Result<()> {
+        conn.execute(TOKEN_MIGRATION).await?;
+        Self::load_db(conn).await
+    }
+
+    // the token itself is returned only here, it can't be shown again
+    pub fn new(
+        name: String,
+        role: String,
+        project: Option<String>,
+        created_by: String,
+        expires_in: Option<u64>,
+    ) -> Result<(Self, String)> {
+        let secret: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(40)
+            .map(char::from)
+            .collect();
+        let token = format!("{}{}", TOKEN_PREFIX, secret);
+        let expires_at = match expires_in {
+            Some(secs) => Some(expires_after(secs)?.to_rfc3339()),
+            None => None,
+        };
+        Ok((
+            Self {
+                id: Uuid::new_v4().to_string(),
+                name,
+                token_hash: hash_token(&token),
+                role,
+                project,
+                created_by,
+                created_at: Utc::now().to_rfc3339(),
+                expires_at,
+            },
+            token,
+        ))
+    }
+
+    pub async fn insert_db(&self, conn: &SqlitePool) -> Result<()> {
+        query(
+            "insert into api_tokens (id, name, token_hash, role, project, created_by, created_at,
+            expires_at) values (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&self.id)
+        .bind(&self.name)
+        .bind(&self.token_hash)
+        .bind(&self.role)
+        .bind(&self.project)
+        .bind(&self.created_by)
+        .bind(&self.created_at)
+        .bind(&self.expires_at)
+        .execute(conn)
+        .await?;
+        Self::load_db(conn).await
+    }
+
+    pub async fn list_db(conn: &SqlitePool) -> Result<Vec<Self>> {
+        let rows = query_as::<_, Self>("select * from api_tokens order by created_at")
+            .fetch_all(conn)
+            .await?;
+        Ok(rows)
+    }
+
+    pub async fn revoke_db(name: &str, conn: &SqlitePool) -> Result<()> {
+        let result = query("delete from api_tokens where name = ?")
+            .bind(name)
+            .execute(conn)
+            .await?;
+        if result.rows_affected() == 0 {
+            err!(anyhow!("there is no token named {}", name))
+        }
+        Self::load_db(conn).await
+    }
+
+    // tokens of a deleted or disabled user stop working with the user
+    pub async fn revoke_created_by_db(username: &str, conn: &SqlitePool) -> Result<()> {
+        query("delete from api_tokens where created_by = ?")
+            .bind(username)
+            .execute(conn)
+            .await?;
+        Self::load_db(conn).await
+    }
+
+    pub async fn load_db(conn: &SqlitePool) -> Result<()> {
+        *API_TOKENS.lock().unwrap() = Self::list_db(conn).await?;
+        Ok(())
+    }
+
+    pub fn to_info(&self) -> ApiTokenInfo {
+        ApiTokenInfo {
+            name: self.name.clone(),
+            role: self.role.clone(),
+            project: self.project.clone(),
+            created_by: self.created_by.clone(),
+            created_at: self.created_at.clone(),
+            expires_at: self.expires_at.clone(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn token_repo() {
+    let _caches = crate::repo::test_caches().await;
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    ApiToken::migrate(&pool).await.unwrap();
+    let (token, secret) = ApiToken::new(
+        "ci".to_string(),
+        "update_only".to_string(),
+        Some("web".to_string()),
+        "admin".to_string(),
+        Some(60),
+    )
+    .unwrap();
+    assert!(secret.starts_with(TOKEN_PREFIX));
+    assert_ne!(token.token_hash, secret);
+    token.insert_db(&pool).await.unwrap();
+    assert_eq!(
+        find_token(&secret).unwrap().project,
+        Some("web".to_string())
+    );
+    assert!(find_token("lev_wrong").is_err());
+    for too_long in [99_999_999_999 * 24 * 60 * 60, u64::MAX] {
+        assert!(ApiToken::new(
+            "forever".to_string(),
+            "read_only".to_string(),
+            None,
+            "admin".to_string(),
+            Some(too_long),
+        )
+        .is_err());
+    }
+
+    let (expired, expired_secret) = ApiToken::new(
+        "old".to_string(),
+        "read_only".to_string(),
+        None,
+        "admin".to_string(),
+        None,
+    )
+    .unwrap();
+    ApiToken {
+        expires_at: Some((Utc::now() - chrono::Duration::seconds(1)).to_rfc3339()),
+        ..expired
+    }
+    .insert_db(&pool)
+    .await
+    .unwrap();
+    assert!(find_token(&expired_secret).is_err());
+
+    ApiToken::revoke_db("ci", &pool).await.unwrap();
+    assert!(find_token(&secret).is_err());
+    assert!(ApiToken::revoke_db("ci", &pool).await.is_err());
+
+    let (token, secret) = ApiToken::new(
+        "deploy".to_string(),
+        "full_access".to_string(),
+        None,
+        "dev".to_string(),
+        None,
+    )
+    .unwrap();
+    token.insert_db(&pool).await.unwrap();
+    ApiToken::revoke_created_by_db("admin", &pool)
+        .await
+        .unwrap();
+    assert!(find_token(&secret).is_ok());
+    ApiToken::revoke_created_by_db("dev", &pool).await.unwrap();
+    assert!(find_token(&secret).is_err());
+}
@@ -1,8 +1,8 @@
 use std::time::Instant;
 
-use anyhow::Result;
-use bcrypt::{hash, verify, DEFAULT_COST};
-use shared::ok;
+use anyhow::{anyhow, Result};
+use bcrypt::{hash, verify, HashParts, DEFAULT_COST};
+use shared::{err, ok};
 use sqlx::{prelude::FromRow, query, query_as, Executor, SqlitePool};
 use uuid::Uuid;
 
@@ -14,8 +14,59 @@ pub struct User {
     pub username: String,
     pub password_hash: String,
     pub role: RoleType,
+    // a disabled user can't login, the account and its history are kept
+    pub disabled: bool,
+    // the subject of the sso account the user logs in with, if any
+    pub oidc_subject: Option<String>,
 }
 
+// the password hash of sso users, no password matches it
+pub const NO_PASSWORD: &str = "!";
+
+// PASSWORD_MIN_LENGTH
+pub const DEFAULT_PASSWORD_MIN_LENGTH: usize = 10;
+
+// BCRYPT_COST, from 4 to 31
+pub fn bcrypt_cost() -> u32 {
+    std::env::var("BCRYPT_COST")
+        .ok()
+        .and_then(|c| c.parse::<u32>().ok())
+        .map(|c| c.clamp(4, 31))
+        .unwrap_or(DEFAULT_COST)
+}
+
+// hashes made with another cost are replaced on the next login
+pub fn needs_rehash(password_hash: &str) -> bool {
+    password_hash
+        .parse::<HashParts>()
+        .map(|parts| parts.get_cost() != bcrypt_cost())
+        .unwrap_or(false)
+}
+
+// the policy for passwords set through the api
+pub fn validate_password(username: &str, password: &str) -> Result<()> {
+    let min_length = std::env::var("PASSWORD_MIN_LENGTH")
+        .ok()
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_PASSWORD_MIN_LENGTH);
+    if password.chars().count() < min_length {
+        err!(anyhow!(
+            "Password must be at least {} characters long",
+            min_length
+        ))
+    }
+    if !username.is_empty() && password.to_lowercase().contains(&username.to_lowercase()) {
+        err!(anyhow!("Password can't contain the username"))
+    }
+    let first = password.chars().next();
+    if password.chars().all(|c| Some(c) == first) {
+        err!(anyhow!("Password can't be a single repeated character"))
+    }
+    ok!(())
+}
+
+pub const ROLE_NAMES: [&str; 4] = ["super_user", "full_access", "update_only", "read_only"];
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum RoleType {
     SuperUser,
@@ -51,30 +102,68 @@ pub struct UserRawData {
     pub username: String,
     pub password_hash: String,
     pub role: String,
+    pub disabled: bool,
+    pub oidc_subject: Option<String>,
+}
+
+impl From<UserRawData> for User {
+    fn from(row: UserRawData) -> Self {
+        User {
+            id: row.id,
+            username: row.username,
+            password_hash: row.password_hash,
+            role: RoleType::from_string(&row.role),
+            disabled: row.disabled,
+            oidc_subject: row.oidc_subject,
+        }
+    }
 }
 
 impl User {
     pub async fn migrate(conn: &SqlitePool) -> Result<()> {
         conn.execute(USER_MIGRATION).await?;
+        // databases created before users could be disabled
+        let columns: Vec<(String,)> = query_as("select name from pragma_table_info('users')")
+            .fetch_all(conn)
+            .await?;
+        if !columns.iter().any(|c| c.0 == "disabled") {
+            conn.execute("alter table users add column disabled integer not null default 0")
+                .await?;
+        }
+        if !columns.iter().any(|c| c.0 == "oidc_subject") {
+            conn.execute("alter table users add column oidc_subject text")
+                .await?;
+        }
+        conn.execute(
+            "create unique index if not exists users_oidc_subject on users (oidc_subject)",
+        )
+        .await?;
         Ok(())
     }
     pub fn new(username: String, password: String, role: &str) -> Result<Self> {
         ok!(Self {
             id: Uuid::new_v4().to_string(),
             username,
-            password_hash: hash(password, 4)?,
+            password_hash: hash(password, bcrypt_cost())?,
             role: RoleType::from_string(role),
+            disabled: false,
+            oidc_subject: None,
         })
     }
 
     pub async fn insert_db(self, conn: &SqlitePool) -> Result<Self> {
-        query("insert into users values (?,  ?, ?, ?)")
-            .bind(&self.id)
-            .bind(&self.username)
-            .bind(&self.password_hash)
-            .bind(&self.role.to_string())
-            .execute(conn)
-            .await?;
+        query(
+            "insert into users (id, username, password_hash, role, disabled, oidc_subject)
+            values (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&self.id)
+        .bind(&self.username)
+        .bind(&self.password_hash)
+        .bind(self.role.to_string())
+        .bind(self.disabled)
+        .bind(&self.oidc_subject)
+        .execute(conn)
+        .await?;
         Ok(self)
     }
 
@@ -82,15 +171,7 @@ impl User {
         let rows = query_as::<_, UserRawData>("select * from users")
             .fetch_all(conn)
             .await?;
-        let users: Vec<User> = rows
-            .into_iter()
-            .map(|u| User {
-                id: u.id,
-                username: u.username,
-                password_hash: u.password_hash,
-                role: RoleType::from_string(&u.role),
-            })
-            .collect();
+        let users: Vec<User> = rows.into_iter().map(User::from).collect();
         Ok(users)
     }
 
@@ -99,13 +180,7 @@ impl User {
             .bind(id)
             .fetch_one(conn)
             .await?;
-        let user = User {
-            id: row.id,
-            username: row.username,
-            password_hash: row.password_hash,
-            role: RoleType::from_string(&row.role),
-        };
-        Ok(user)
+        Ok(User::from(row))
     }
 
     pub async fn get_by_username(username: &str, conn: &SqlitePool) -> Result<Self> {
@@ -113,13 +188,49 @@ impl User {
             .bind(username)
             .fetch_one(conn)
             .await?;
-        let user = User {
-            id: row.id,
-            username: row.username,
-            password_hash: row.password_hash,
-            role: RoleType::from_string(&row.role),
+        Ok(User::from(row))
+    }
+
+    pub async fn get_by_subject(subject: &str, conn: &SqlitePool) -> Result<Option<Self>> {
+        let row = query_as::<_, UserRawData>("select * from users where oidc_subject = ?")
+            .bind(subject)
+            .fetch_optional(conn)
+            .await?;
+        ok!(row.map(User::from))
+    }
+
+    // the local user of an sso account, created on the first login. The role follows
+    // the groups of the account on every login
+    pub async fn sso_login_db(
+        subject: &str,
+        username: &str,
+        role: RoleType,
+        conn: &SqlitePool,
+    ) -> Result<Self> {
+        let Some(mut user) = Self::get_by_subject(subject, conn).await? else {
+            if Self::get_by_username(username, conn).await.is_ok() {
+                err!(anyhow!(
+                    "a local user named {} already exists, rename or delete it first",
+                    username
+                ))
+            }
+            let user = Self {
+                id: Uuid::new_v4().to_string(),
+                username: username.to_string(),
+                password_hash: NO_PASSWORD.to_string(),
+                role,
+                disabled: false,
+                oidc_subject: Some(subject.to_string()),
+            };
+            return user.insert_db(conn).await;
         };
-        Ok(user)
+        if user.role != role {
+            match Self::set_role_db(&user.username, role.clone(), conn).await {
+                Ok(()) => user.role = role,
+                Err(e) => println!("kept the role of {}: {}", user.username, e),
+            }
+        }
+        ok!(user)
     }
 
     pub async fn super_user_exists(conn: &SqlitePool) -> Result<bool> {
@@ -131,6 +242,86 @@ impl User {
             .into_iter()
             .any(|u| u.role == RoleType::SuperUser.to_string()))
     }
+
+    // the last enabled super user can't be deleted, demoted or disabled, nobody could
+    // manage the server after that. Writes add this condition, so the count and the change
+    // happen in one statement and parallel changes can't both pass it
+    const NOT_LAST_SUPER_USER: &str = "not (role = 'super_user' and disabled = 0
+        and (select count(*) from users where role = 'super_user' and disabled = 0) <= 1)";
+
+    // a guarded write changed nothing, either the user doesn't exist or is the last super user
+    async fn guarded_write_error(username: &str, conn: &SqlitePool) -> anyhow::Error {
+        match Self::get_by_username(username, conn).await {
+            Ok(_) => anyhow!("{} is the last super user", username),
+            Err(_) => anyhow!("there is no user named {}", username),
+        }
+    }
+
+    pub async fn delete_db(username: &str, conn: &SqlitePool) -> Result<()> {
+        let result = query(&format!(
+            "delete from users where username = ? and {}",
+            Self::NOT_LAST_SUPER_USER
+        ))
+        .bind(username)
+        .execute(conn)
+        .await?;
+        if result.rows_affected() == 0 {
+            err!(Self::guarded_write_error(username, conn).await)
+        }
+        ok!(())
+    }
+
+    pub async fn set_role_db(username: &str, role: RoleType, conn: &SqlitePool) -> Result<()> {
+        let guard = if role != RoleType::SuperUser {
+            Self::NOT_LAST_SUPER_USER
+        } else {
+            "1"
+        };
+        let result = query(&format!(
+            "update users set role = ? where username = ? and {}",
+            guard
+        ))
+        .bind(role.to_string())
+        .bind(username)
+        .execute(conn)
+        .await?;
+        if result.rows_affected() == 0 {
+            err!(Self::guarded_write_error(username, conn).await)
+        }
+        ok!(())
+    }
+
+    pub async fn set_password_db(username: &str, password: &str, conn: &SqlitePool) -> Result<()> {
+        let result = query("update users set password_hash = ? where username = ?")
+            .bind(hash(password, bcrypt_cost())?)
+            .bind(username)
+            .execute(conn)
+            .await?;
+        if result.rows_affected() == 0 {
+            err!(anyhow!("there is no user named {}", username))
+        }
+        ok!(())
+    }
+
+    pub async fn set_disabled_db(username: &str, disabled: bool, conn: &SqlitePool) -> Result<()> {
+        let guard = if disabled {
+            Self::NOT_LAST_SUPER_USER
+        } else {
+            "1"
+        };
+        let result = query(&format!(
+            "update users set disabled = ? where username = ? and {}",
+            guard
+        ))
+        .bind(disabled)
+        .bind(username)
+        .execute(conn)
+        .await?;
+        if result.rows_affected() == 0 {
+            err!(Self::guarded_write_error(username, conn).await)
+        }
+        ok!(())
+    }
 }
 
 pub const USER_MIGRATION: &str = r#"
@@ -138,12 +329,14 @@ pub const USER_MIGRATION: &str = r#"
         id text primary key,
         username text not null unique,
         password_hash text not null,
-        role text not null
+        role text not null,
+        disabled integer not null default 0
     );
     "#;
 
 #[tokio::test]
 async fn user_repo() {
+    let _caches = crate::repo::test_caches().await;
     let pool = Repo::new(":memory:", true).await.unwrap().pool;
     User::migrate(&pool).await.unwrap();
 
@@ -170,3 +363,84 @@ async fn user_repo() {
     let just_user = User::get_by_username("just", &pool).await.unwrap();
     assert!(!(just_user.role == RoleType::ReadOnly));
 }
+
+#[tokio::test]
+async fn user_management() {
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    User::migrate(&pool).await.unwrap();
+    for (username, role) in [("root", "super_user"), ("dev", "full_access")] {
+        User::new(username.to_string(), "pass".to_string(), role)
+            .unwrap()
+            .insert_db(&pool)
+            .await
+            .unwrap();
+    }
+    assert!(User::delete_db("root", &pool).await.is_err());
+    assert!(User::set_role_db("root", RoleType::ReadOnly, &pool)
+        .await
+        .is_err());
+    assert!(User::set_disabled_db("root", true, &pool).await.is_err());
+
+    User::set_role_db("dev", RoleType::SuperUser, &pool)
+        .await
+        .unwrap();
+    User::set_disabled_db("root", true, &pool).await.unwrap();
+    assert!(User::get_by_username("root", &pool).await.unwrap().disabled);
+    assert!(User::set_disabled_db("dev", true, &pool).await.is_err());
+
+    User::set_password_db("dev", "new", &pool).await.unwrap();
+    let dev = User::get_by_username("dev", &pool).await.unwrap();
+    assert!(verify("new", &dev.password_hash).unwrap());
+    User::delete_db("root", &pool).await.unwrap();
+    assert!(User::delete_db("nobody", &pool).await.is_err());
+
+    // of two super users disabled at the same time, one stays
+    User::new("ops".to_string(), "pass".to_string(), "super_user")
+        .unwrap()
+        .insert_db(&pool)
+        .await
+        .unwrap();
+    let (a, b) = tokio::join!(
+        User::set_disabled_db("dev", true, &pool),
+        User::set_disabled_db("ops", true, &pool)
+    );
+    assert!(a.is_ok() != b.is_ok());
+}
+
+#[test]
+fn password_policy() {
+    assert!(validate_password("admin", "short").is_err());
+    assert!(validate_password("admin", "my-Admin-password").is_err());
+    assert!(validate_password("admin", "aaaaaaaaaaaa").is_err());
+    assert!(validate_password("admin", "correct horse battery").is_ok());
+    assert!(needs_rehash(&hash("pass", 4).unwrap()) == (bcrypt_cost() != 4));
+}
+
+#[tokio::test]
+async fn sso_users() {
+    let pool = SqlitePool::connect(":memory:").await.unwrap();
+    User::migrate(&pool).await.unwrap();
+    User::new("root".to_string(), "pass".to_string(), "super_user")
+        .unwrap()
+        .insert_db(&pool)
+        .await
+        .unwrap();
+
+    let alice = User::sso_login_db("sub-1", "alice", RoleType::FullAccess, &pool)
+        .await
+        .unwrap();
+    assert_eq!(alice.oidc_subject.as_deref(), Some("sub-1"));
+    assert!(!verify("", &alice.password_hash).unwrap_or(false));
+    // the same account maps to the same user, with the role of its current groups
+    let again = User::sso_login_db("sub-1", "alice", RoleType::ReadOnly, &pool)
+        .await
+        .unwrap();
+    assert_eq!(again.id, alice.id);
+    assert_eq!(again.role, RoleType::ReadOnly);
+    // a local user isn't taken over by an sso account with the same name
+    assert!(
+        User::sso_login_db("sub-2", "root", RoleType::SuperUser, &pool)
+            .await
+            .is_err()
+    );
+}
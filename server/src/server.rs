@@ -1,44 +1,79 @@
 use std::{sync::Arc, time::Instant};
 
-use actix_web::{dev::Service, web, App, HttpServer};
+use actix_web::{
+    dev::Service,
+    http::header::{HeaderName, HeaderValue},
+    web, App, HttpResponse, HttpServer,
+};
+use audit_handler::{audit_record, handle_audit, is_pending_sso_poll, should_audit};
 use auth_handler::{
-    create_new_user, handle_is_super_user_exists, login_user, register_super_user, user_list,
+    client_allowed, create_new_user, delete_user, disable_user, enable_user, grant_project,
+    handle_is_super_user_exists, handle_rotate_jwt_key, init_allowed_cidrs, login_user,
+    logout_user, refresh_session, register_super_user, revoke_project_grant, set_user_password,
+    set_user_role, user_list,
 };
 use deploy_handler::handle_deploy;
 use docker_handler::upload;
-use futures::FutureExt;
 use healthz_handler::handle_healthz;
-use plan_handler::{handle_plan, handle_rollback};
+use maintenance_handler::{handle_maintenance_off, handle_maintenance_on};
+use plan_handler::{handle_plan, handle_preflight, handle_rollback};
+use proxy_handler::{handle_get_proxy, handle_update_proxy, manager_domain};
 use secret_handler::{
-    handle_add_secret, handle_delete_secret, handle_list_secrets, handle_show_secret,
-    handle_update_secret,
+    handle_add_secret, handle_delete_secret, handle_export_secrets, handle_import_secrets,
+    handle_list_secrets, handle_revert_secret, handle_rotate_master_key, handle_secret_history,
+    handle_show_secret, handle_update_secret,
 };
 use shared::docker::DockerService;
+use sso_handler::{handle_sso_device, handle_sso_token};
+use token_handler::{handle_create_token, handle_list_tokens, handle_revoke_token};
+use uuid::Uuid;
 
-use crate::repo::Repo;
+use crate::repo::{
+    bootstrap_repo::init_bootstrap_token,
+    jwt_key_repo::init_jwt_keys,
+    oidc_repo::{init_oidc, OidcConfig},
+    secret_provider::SecretProviders,
+    tls_repo::{init_tls, TlsSettings},
+    Repo,
+};
 
+pub mod audit_handler;
 pub mod auth_handler;
 pub mod deploy_handler;
 pub mod docker_handler;
 pub mod healthz_handler;
+pub mod maintenance_handler;
 pub mod plan_handler;
+pub mod proxy_handler;
 pub mod secret_handler;
+pub mod sso_handler;
+pub mod token_handler;
 
 #[derive(Debug, Clone)]
 pub struct ServerData {
     port: u16,
     docker_service: DockerService,
     pub repo: Repo,
+    pub providers: Arc<SecretProviders>,
+    tls: Option<TlsSettings>,
+    pub oidc: Option<OidcConfig>,
 }
 
 impl ServerData {
     pub async fn new(port: u16) -> ServerData {
         dbg!("Starting server on port: {}", port);
         let dbpath = std::env::var("DBPATH").unwrap();
+        let repo = Repo::new(&dbpath, false).await.unwrap();
+        init_jwt_keys(&repo.pool).await.unwrap();
+        init_bootstrap_token(&dbpath, &repo.pool).await.unwrap();
+        init_allowed_cidrs().unwrap();
         ServerData {
             port,
             docker_service: DockerService::new().unwrap(),
-            repo: Repo::new(&dbpath, false).await.unwrap(),
+            providers: Arc::new(SecretProviders::load(&dbpath, repo.pool.clone()).unwrap()),
+            repo,
+            tls: init_tls(&dbpath).unwrap(),
+            oidc: init_oidc().unwrap(),
         }
     }
 }
@@ -46,39 +81,110 @@ impl ServerData {
 pub async fn start_server(server: ServerData) -> std::io::Result<()> {
     let sv = Arc::new(server);
     let port = sv.port;
-    HttpServer::new(move || {
+    let tls = sv.tls.clone();
+    let server = HttpServer::new(move || {
         let server = Arc::clone(&sv);
+        let pool = server.repo.pool.clone();
         App::new()
-            .wrap_fn(|req, srv| {
+            .wrap_fn(move |req, srv| {
                 let now = Instant::now();
                 let method = req.method().clone();
                 let path = req.path().to_string();
-                srv.call(req).map(move |res| {
+                let pool = pool.clone();
+                let request_id = Uuid::new_v4().to_string();
+                // the health check stays open for monitoring
+                let allowed = path == "/healthz" || client_allowed(req.peer_addr().map(|a| a.ip()));
+                let fut = if allowed { Ok(srv.call(req)) } else { Err(req) };
+                async move {
+                    let mut res = match fut {
+                        Ok(fut) => fut.await,
+                        Err(req) => Ok(req.into_response(
+                            HttpResponse::Forbidden().body("Your address is not allowed"),
+                        )),
+                    };
                     let duration = now.elapsed();
-                    if let Ok(ref res) = res {
+                    if let Ok(ref mut res) = res {
                         println!("{} {} {} {:?}", method, path, res.status(), duration);
+                        if should_audit(method.as_str(), &path)
+                            && !is_pending_sso_poll(&path, res.status())
+                        {
+                            let record = audit_record(res, &request_id, duration);
+                            if let Err(e) = record.insert_db(&pool).await {
+                                println!("failed to write audit log: {}", e);
+                            }
+                        }
+                        res.headers_mut().insert(
+                            HeaderName::from_static("x-request-id"),
+                            HeaderValue::from_str(&request_id).unwrap(),
+                        );
                     }
                     res
-                })
+                }
             })
             .app_data(web::Data::new(server))
             .route("/upload_image", web::post().to(upload))
             .route("/new-deploy", web::post().to(handle_deploy))
             .route("/plan", web::get().to(handle_plan))
+            .route("/preflight", web::post().to(handle_preflight))
             .route("/rollback", web::get().to(handle_rollback))
             .route("/healthz", web::get().to(handle_healthz))
             .route("/auth/super", web::get().to(handle_is_super_user_exists))
             .route("/register/super", web::post().to(register_super_user))
             .route("/login/super", web::post().to(login_user))
+            .route("/sso/device", web::post().to(handle_sso_device))
+            .route("/sso/token", web::post().to(handle_sso_token))
+            .route("/auth/refresh", web::post().to(refresh_session))
+            .route("/logout", web::post().to(logout_user))
             .route("/secret", web::post().to(handle_add_secret))
             .route("/secret", web::get().to(handle_list_secrets))
             .route("/secret", web::delete().to(handle_delete_secret))
             .route("/secret", web::put().to(handle_update_secret))
             .route("/secret/show", web::get().to(handle_show_secret))
+            .route("/secret/history", web::get().to(handle_secret_history))
+            .route("/secret/revert", web::post().to(handle_revert_secret))
+            .route("/secret/import", web::post().to(handle_import_secrets))
+            .route("/secret/export", web::get().to(handle_export_secrets))
+            .route(
+                "/secret/rotate-master-key",
+                web::post().to(handle_rotate_master_key),
+            )
+            .route("/audit", web::get().to(handle_audit))
+            .route("/jwt-key/rotate", web::post().to(handle_rotate_jwt_key))
             .route("/users", web::post().to(create_new_user))
             .route("/users", web::get().to(user_list))
+            .route("/users", web::delete().to(delete_user))
+            .route("/users/role", web::put().to(set_user_role))
+            .route("/users/password", web::put().to(set_user_password))
+            .route("/users/disable", web::post().to(disable_user))
+            .route("/users/enable", web::post().to(enable_user))
+            .route("/users/grant", web::put().to(grant_project))
+            .route("/users/grant", web::delete().to(revoke_project_grant))
+            .route("/proxy", web::get().to(handle_get_proxy))
+            .route("/proxy", web::put().to(handle_update_proxy))
+            .route("/maintenance", web::post().to(handle_maintenance_on))
+            .route("/maintenance", web::delete().to(handle_maintenance_off))
+            .route("/tokens", web::post().to(handle_create_token))
+            .route("/tokens", web::get().to(handle_list_tokens))
+            .route("/tokens", web::delete().to(handle_revoke_token))
     })
-    .bind(("0.0.0.0", port))?
-    .run()
-    .await
+    .bind((plain_http_addr(tls.is_some()), port))?;
+    match tls {
+        Some(tls) => {
+            server
+                .bind_rustls_0_23(("0.0.0.0", tls.port), tls.config)?
+                .run()
+                .await
+        }
+        None => server.run().await,
+    }
+}
+
+// LEV_HTTP_ADDR, where plain http is served. With TLS it stays on localhost, so passwords
+// can't reach it unencrypted from the network, unless traefik routes LEV_DOMAIN to it
+fn plain_http_addr(tls: bool) -> String {
+    match std::env::var("LEV_HTTP_ADDR") {
+        Ok(addr) => addr,
+        Err(_) if tls && manager_domain().is_none() => "127.0.0.1".to_string(),
+        Err(_) => "0.0.0.0".to_string(),
+    }
 }
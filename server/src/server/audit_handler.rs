@@ -0,0 +1,161 @@
+use std::{sync::Arc, time::Duration as StdDuration};
+
+use actix_web::{
+    dev::ServiceResponse, error::InternalError, http::StatusCode, web, HttpMessage, HttpRequest,
+    Responder, Result,
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Deserialize;
+use shared::{ok, AuditEntry};
+use sqlx::SqlitePool;
+
+use crate::{
+    repo::{
+        audit_repo::{outcome, retention_days, AuditFilter, AuditLog},
+        user_repo::RoleType,
+    },
+    server::auth_handler::must_auth,
+};
+
+use super::ServerData;
+
+// reads that are audited too, they show secret values or may generate secrets
+const AUDITED_READS: [&str; 4] = ["/secret/show", "/secret/history", "/secret/export", "/plan"];
+
+// who made the request, set by the auth checks for the audit middleware
+#[derive(Clone)]
+pub struct AuditUser {
+    pub username: String,
+    pub role: String,
+}
+
+// project of the request, set by must_auth_project
+#[derive(Clone)]
+pub struct AuditProject(pub String);
+
+pub fn should_audit(method: &str, path: &str) -> bool {
+    method != "GET" || AUDITED_READS.contains(&path)
+}
+
+// the cli polls every few seconds while an sso login waits for the user, only the
+// outcome is kept
+pub fn is_pending_sso_poll(path: &str, status: StatusCode) -> bool {
+    path == "/sso/token" && status == StatusCode::ACCEPTED
+}
+
+pub fn audit_record(res: &ServiceResponse, request_id: &str, duration: StdDuration) -> AuditLog {
+    let req = res.request();
+    // the address of the connection, forwarded headers are set by the client or the proxy
+    // and kept apart since anyone can write them
+    let client_ip = req.peer_addr().map(|a| a.ip().to_string());
+    let forwarded_for = req
+        .headers()
+        .get("x-forwarded-for")
+        .or(req.headers().get("forwarded"))
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let extensions = req.extensions();
+    let user = extensions.get::<AuditUser>();
+    let status = res.status().as_u16();
+    AuditLog {
+        id: request_id.to_string(),
+        created_at: Utc::now().to_rfc3339(),
+        username: user.map(|u| u.username.clone()),
+        role: user.map(|u| u.role.clone()),
+        method: req.method().to_string(),
+        path: req.path().to_string(),
+        project: extensions.get::<AuditProject>().map(|p| p.0.clone()),
+        status: status as i64,
+        outcome: outcome(status).to_string(),
+        duration_ms: duration.as_millis() as i64,
+        client_ip,
+        forwarded_for,
+    }
+}
+
+pub fn set_audit_user(req: &HttpRequest, username: &str, role: &str) {
+    req.extensions_mut().insert(AuditUser {
+        username: username.to_string(),
+        role: role.to_string(),
+    });
+}
+
+// removes entries older than AUDIT_RETENTION_DAYS, once an hour
+pub async fn prune_audit_log(pool: SqlitePool) {
+    loop {
+        match AuditLog::prune_db(retention_days(), &pool).await {
+            Ok(0) => {}
+            Ok(count) => println!("removed {} old audit log entries", count),
+            Err(e) => println!("failed to prune audit log: {}", e),
+        }
+        tokio::time::sleep(StdDuration::from_secs(60 * 60)).await;
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditQuery {
+    pub project: Option<String>,
+    pub user: Option<String>,
+    // rfc3339 or a date like 2024-05-01
+    pub since: Option<String>,
+    // seconds back from now, used instead of since
+    pub within: Option<u64>,
+    pub limit: Option<i64>,
+}
+
+fn parse_since(since: &str) -> Option<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since) {
+        return Some(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|time| time.and_utc())
+}
+
+pub async fn handle_audit(
+    sd: web::Data<Arc<ServerData>>,
+    query: web::Query<AuditQuery>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    let since = match (&query.since, query.within) {
+        (_, Some(within)) => Some(Utc::now() - Duration::seconds(within as i64)),
+        (Some(since), None) => Some(parse_since(since).ok_or(InternalError::new(
+            format!("Invalid date {}, use eg 2024-05-01", since),
+            StatusCode::from_u16(400).unwrap(),
+        ))?),
+        (None, None) => None,
+    };
+    let filter = AuditFilter {
+        project: query.project.clone(),
+        username: query.user.clone(),
+        since,
+        limit: query.limit.unwrap_or(100),
+    };
+    let entries: Vec<AuditEntry> = AuditLog::list_db(&filter, &sd.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to read audit log",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?
+        .iter()
+        .map(|a| a.to_entry())
+        .collect();
+    ok!(web::Json(entries))
+}
+
+#[test]
+fn audit_handler_test() {
+    assert!(should_audit("POST", "/new-deploy"));
+    assert!(should_audit("GET", "/secret/show"));
+    assert!(!should_audit("GET", "/secret"));
+    assert_eq!(
+        parse_since("2024-05-01").unwrap().to_rfc3339(),
+        "2024-05-01T00:00:00+00:00"
+    );
+    assert!(parse_since("2024-05-01T10:00:00+02:00").is_some());
+    assert!(parse_since("yesterday").is_none());
+}
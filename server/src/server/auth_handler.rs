@@ -1,21 +1,40 @@
 use std::{
     borrow::Borrow,
+    net::IpAddr,
     sync::{Arc, LazyLock, Mutex},
     time::{SystemTime, UNIX_EPOCH},
 };
 
 use actix_web::{
-    error::InternalError, http::StatusCode, web, HttpRequest, HttpResponse, Responder, Result,
+    error::InternalError, http::StatusCode, web, HttpMessage, HttpRequest, HttpResponse, Responder,
+    Result,
 };
 use anyhow::{anyhow, Result as AnyResult};
 use bcrypt::verify;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use ipnet::IpNet;
+use jsonwebtoken::{decode, decode_header, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
-use shared::{ok, UserAuthBody, UserSafe};
+use shared::{ok, AuthTokens, UserAuthBody, UserSafe};
+use sqlx::SqlitePool;
 
-use crate::repo::user_repo::{RoleType, User};
+use crate::repo::{
+    bootstrap_repo::{consume_bootstrap_token, return_bootstrap_token, take_bootstrap_token},
+    expires_after,
+    grant_repo::{grant_role, Grant},
+    jwt_key_repo::{signing_key, verifying_key, JwtKey, DEFAULT_GRACE},
+    lockout_repo::{lockout_seconds, login_attempted, login_succeeded, LoginLockout},
+    session_repo::{is_revoked, Session, ACCESS_TOKEN_TTL, REFRESH_TOKEN_TTL},
+    token_repo::{find_token, ApiToken, TOKEN_PREFIX},
+    user_repo::{needs_rehash, validate_password, RoleType, User, ROLE_NAMES},
+};
+
+use super::{
+    audit_handler::{set_audit_user, AuditProject},
+    ServerData,
+};
 
-use super::ServerData;
+static ALLOWED_CIDRS: LazyLock<Mutex<Vec<IpNet>>> = LazyLock::new(|| Mutex::new(vec![]));
+static TRUSTED_PROXIES: LazyLock<Mutex<Vec<IpNet>>> = LazyLock::new(|| Mutex::new(vec![]));
 
 pub async fn handle_is_super_user_exists(
     sd: web::Data<Arc<ServerData>>,
@@ -42,26 +61,162 @@ pub async fn login_user(
 ) -> Result<impl Responder> {
     must_have_levpass(&req)?;
     let pool = sd.repo.pool.borrow();
-    let user = User::get_by_username(&body.username, pool)
-        .await
-        .map_err(|_| {
-            InternalError::new(
-                "Failed to get user, maybe there is no user with that name",
-                StatusCode::from_u16(500).unwrap(),
-            )
-        })?;
-    if !verify(body.password.clone(), &user.password_hash)
-        .map_err(|_| InternalError::new("Wrong password", StatusCode::from_u16(401).unwrap()))?
-    {
+    let user = User::get_by_username(&body.username, pool).await.ok();
+    // only names of existing users are tracked, so made up ones can't fill the attempts
+    let targets = login_targets(&req, user.as_ref().map(|u| u.username.as_str()));
+    let locked = must_not_be_blocked(&targets)?;
+    let Some(user) = user else {
+        record_login_failure(locked, pool).await;
+        return Err(InternalError::new(
+            "Failed to get user, maybe there is no user with that name",
+            StatusCode::from_u16(500).unwrap(),
+        )
+        .into());
+    };
+    if !verify(body.password.clone(), &user.password_hash).unwrap_or(false) {
+        record_login_failure(locked, pool).await;
         return Err(
             InternalError::new("Wrong password", StatusCode::from_u16(401).unwrap()).into(),
         );
     }
-    ok!(
-        HttpResponse::Ok().body(create_jwt(body.username.borrow(), user.role).map_err(|_| {
+    login_succeeded(&targets);
+    set_audit_user(&req, &user.username, &user.role.to_string());
+    if needs_rehash(&user.password_hash) {
+        if let Err(e) = User::set_password_db(&user.username, &body.password, pool).await {
+            println!("failed to rehash password of {}: {}", user.username, e);
+        }
+    }
+    if user.disabled {
+        return Err(
+            InternalError::new("User is disabled", StatusCode::from_u16(403).unwrap()).into(),
+        );
+    }
+    let tokens = create_session(&body.username, user.role, REFRESH_TOKEN_TTL, pool)
+        .await
+        .map_err(|_| {
             InternalError::new("Failed to login user", StatusCode::from_u16(500).unwrap())
-        })?)
+        })?;
+    ok!(HttpResponse::Ok().json(tokens))
+}
+
+// rate limited by client ip, and by username when there is one
+fn login_targets(req: &HttpRequest, username: Option<&str>) -> Vec<String> {
+    let ip = client_ip(
+        &TRUSTED_PROXIES.lock().unwrap(),
+        req.peer_addr().map(|a| a.ip()),
+        req.headers()
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok()),
     )
+    .map_or("unknown".to_string(), |ip| ip.to_string());
+    let mut targets = vec![format!("ip:{}", ip)];
+    if let Some(username) = username {
+        targets.push(format!("user:{}", username));
+    }
+    targets
+}
+
+// the address of the connection, or the one a trusted proxy added last to X-Forwarded-For.
+// Entries before it come from the client and can be anything
+fn client_ip(proxies: &[IpNet], peer: Option<IpAddr>, forwarded: Option<&str>) -> Option<IpAddr> {
+    let peer = peer?.to_canonical();
+    if !proxies.iter().any(|p| p.contains(&peer)) {
+        return Some(peer);
+    }
+    forwarded
+        .and_then(|f| f.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .or(Some(peer))
+}
+
+// counts the attempt right away, returns the targets it locks out if it fails
+fn must_not_be_blocked(targets: &[String]) -> Result<Vec<(String, u32)>> {
+    login_attempted(targets).map_err(|seconds| {
+        InternalError::new(
+            format!("Too many failed attempts, try again in {} seconds", seconds),
+            StatusCode::from_u16(429).unwrap(),
+        )
+        .into()
+    })
+}
+
+async fn record_login_failure(locked: Vec<(String, u32)>, pool: &SqlitePool) {
+    let seconds = lockout_seconds();
+    for (target, failures) in locked {
+        println!(
+            "locked out {} for {} seconds after {} failed attempts",
+            target, seconds, failures
+        );
+        if let Err(e) = LoginLockout::new(&target, failures, seconds)
+            .insert_db(pool)
+            .await
+        {
+            println!("failed to save lockout of {}: {}", target, e);
+        }
+    }
+}
+
+fn must_pass_policy(username: &str, password: &str) -> Result<()> {
+    validate_password(username, password)
+        .map_err(|e| InternalError::new(e.to_string(), StatusCode::from_u16(400).unwrap()).into())
+}
+
+#[derive(Deserialize)]
+pub struct RefreshBody {
+    refresh_token: String,
+}
+
+// new access and refresh tokens for a session, the role is read again so changes apply
+pub async fn refresh_session(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<RefreshBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_have_levpass(&req)?;
+    let pool = sd.repo.pool.borrow();
+    let (session, refresh_token) = Session::refresh_db(&body.refresh_token, pool)
+        .await
+        .map_err(|e| InternalError::new(format!("{}", e), StatusCode::from_u16(401).unwrap()))?;
+    let user = User::get_by_username(&session.username, pool)
+        .await
+        .map_err(|_| InternalError::new("User not found", StatusCode::from_u16(401).unwrap()))?;
+    if user.disabled {
+        return Err(
+            InternalError::new("User is disabled", StatusCode::from_u16(401).unwrap()).into(),
+        );
+    }
+    let access_token = create_jwt(&session.username, user.role, &session.id).map_err(|_| {
+        InternalError::new(
+            "Failed to refresh session",
+            StatusCode::from_u16(500).unwrap(),
+        )
+    })?;
+    ok!(HttpResponse::Ok().json(AuthTokens {
+        access_token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_TTL,
+    }))
+}
+
+// revokes the session of the token, its access tokens stop working right away
+pub async fn logout_user(
+    sd: web::Data<Arc<ServerData>>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    let claims = check_auth(&req)
+        .map_err(|_| InternalError::new("Unauthorized", StatusCode::from_u16(401).unwrap()))?;
+    let Some(sid) = claims.sid else {
+        return Err(InternalError::new(
+            "API tokens can't log out, revoke them with `lev token revoke`",
+            StatusCode::from_u16(400).unwrap(),
+        )
+        .into());
+    };
+    Session::revoke_db(&sid, &sd.repo.pool)
+        .await
+        .map_err(|_| InternalError::new("Failed to log out", StatusCode::from_u16(500).unwrap()))?;
+    ok!(HttpResponse::Ok().body("OK"))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -85,6 +240,12 @@ impl CreateUserBody {
 pub async fn user_list(sd: web::Data<Arc<ServerData>>, req: HttpRequest) -> Result<impl Responder> {
     must_auth(&req, vec![RoleType::SuperUser])?;
     let pool = sd.repo.pool.borrow();
+    let grants = Grant::list_db(pool).await.map_err(|_| {
+        InternalError::new(
+            "Failed to get user list",
+            StatusCode::from_u16(500).unwrap(),
+        )
+    })?;
     let user_list: Vec<UserSafe> = User::get_all(pool)
         .await
         .map_err(|_| {
@@ -95,8 +256,14 @@ pub async fn user_list(sd: web::Data<Arc<ServerData>>, req: HttpRequest) -> Resu
         })?
         .into_iter()
         .map(|u| UserSafe {
+            grants: grants
+                .iter()
+                .filter(|g| g.username == u.username)
+                .map(|g| (g.project.clone(), g.role.clone()))
+                .collect(),
             username: u.username,
             role: u.role.to_string(),
+            disabled: u.disabled,
         })
         .collect();
     ok!(web::Json(user_list))
@@ -108,6 +275,7 @@ pub async fn create_new_user(
     req: HttpRequest,
 ) -> Result<impl Responder> {
     must_auth(&req, vec![RoleType::SuperUser])?;
+    must_pass_policy(&body.username, &body.password)?;
     let pool = sd.repo.pool.borrow();
     User::new(
         body.username.clone(),
@@ -141,34 +309,272 @@ pub async fn register_super_user(
     req: HttpRequest,
 ) -> Result<impl Responder> {
     must_have_levpass(&req)?;
+    must_pass_policy(&body.username, &body.password)?;
+    let targets = login_targets(&req, None);
+    let locked = must_not_be_blocked(&targets)?;
     let pool = sd.repo.pool.borrow();
-    User::new(
+    let token = req
+        .headers()
+        .get("X-Bootstrap-Token")
+        .and_then(|t| t.to_str().ok())
+        .unwrap_or_default();
+    let Some(bootstrap_token) = take_bootstrap_token(token) else {
+        record_login_failure(locked, pool).await;
+        return Err(InternalError::new(
+            "Invalid bootstrap token, it is printed in the server log and saved in \
+            bootstrap.token next to the database until the first super user is registered",
+            StatusCode::from_u16(403).unwrap(),
+        )
+        .into());
+    };
+    let created = match User::new(
         body.username.clone(),
         body.password.clone(),
         RoleType::SuperUser.to_string().as_str(),
-    )
-    .map_err(|_| {
-        InternalError::new(
+    ) {
+        Ok(user) => user.insert_db(pool).await,
+        Err(e) => Err(e),
+    };
+    if created.is_err() {
+        return_bootstrap_token(bootstrap_token);
+        record_login_failure(locked, pool).await;
+        return Err(InternalError::new(
             "Failed to create super user",
             StatusCode::from_u16(500).unwrap(),
         )
-    })?
-    .insert_db(pool)
+        .into());
+    }
+    consume_bootstrap_token(bootstrap_token);
+    login_succeeded(&targets);
+    set_audit_user(&req, &body.username, &RoleType::SuperUser.to_string());
+    let tokens = create_session(&body.username, RoleType::SuperUser, REFRESH_TOKEN_TTL, pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to create super user",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?;
+    ok!(HttpResponse::Ok().json(tokens))
+}
+
+#[derive(Deserialize)]
+pub struct UsernameBody {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleBody {
+    username: String,
+    role: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetPasswordBody {
+    username: String,
+    password: String,
+}
+
+// the user can't use the tokens it has anymore, api tokens it created are kept
+async fn logout_everywhere(username: &str, except: Option<&str>, pool: &SqlitePool) -> Result<()> {
+    Session::revoke_user_db(username, except, pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to revoke sessions",
+                StatusCode::from_u16(500).unwrap(),
+            )
+            .into()
+        })
+}
+
+// a deleted or disabled user is gone, so are the api tokens it created for CI
+async fn revoke_everything(username: &str, pool: &SqlitePool) -> Result<()> {
+    logout_everywhere(username, None, pool).await?;
+    ApiToken::revoke_created_by_db(username, pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to revoke api tokens",
+                StatusCode::from_u16(500).unwrap(),
+            )
+            .into()
+        })
+}
+
+fn user_error(e: anyhow::Error) -> actix_web::Error {
+    InternalError::new(format!("{}", e), StatusCode::from_u16(400).unwrap()).into()
+}
+
+pub async fn delete_user(
+    sd: web::Data<Arc<ServerData>>,
+    query: web::Query<UsernameBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    User::delete_db(&query.username, &sd.repo.pool)
+        .await
+        .map_err(user_error)?;
+    Grant::delete_user_db(&query.username, &sd.repo.pool)
+        .await
+        .map_err(user_error)?;
+    revoke_everything(&query.username, &sd.repo.pool).await?;
+    ok!(HttpResponse::Ok().body(format!("User {} deleted", query.username)))
+}
+
+pub async fn set_user_role(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<SetRoleBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    if !ROLE_NAMES.contains(&body.role.as_str()) {
+        return Err(InternalError::new(
+            format!("Unknown role {}, use {}", body.role, ROLE_NAMES.join(", ")),
+            StatusCode::from_u16(400).unwrap(),
+        )
+        .into());
+    }
+    User::set_role_db(
+        &body.username,
+        RoleType::from_string(&body.role),
+        &sd.repo.pool,
+    )
     .await
-    .map_err(|_| {
+    .map_err(user_error)?;
+    logout_everywhere(&body.username, None, &sd.repo.pool).await?;
+    ok!(HttpResponse::Ok().body(format!("User {} is {} now", body.username, body.role)))
+}
+
+// the super user can set any password, others only their own
+pub async fn set_user_password(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<SetPasswordBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    let author = must_auth(&req, vec![])?;
+    let is_self = author == body.username;
+    if !is_self {
+        must_auth(&req, vec![RoleType::SuperUser])?;
+    }
+    must_pass_policy(&body.username, &body.password)?;
+    User::set_password_db(&body.username, &body.password, &sd.repo.pool)
+        .await
+        .map_err(user_error)?;
+    let current_session = check_auth(&req).ok().and_then(|c| c.sid);
+    let except = if is_self {
+        current_session.as_deref()
+    } else {
+        None
+    };
+    logout_everywhere(&body.username, except, &sd.repo.pool).await?;
+    ok!(HttpResponse::Ok().body(format!("Password of {} changed", body.username)))
+}
+
+#[derive(Deserialize)]
+pub struct GrantBody {
+    username: String,
+    project: String,
+    role: String,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeGrantQuery {
+    username: String,
+    project: String,
+}
+
+pub async fn grant_project(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<GrantBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    let author = must_auth(&req, vec![RoleType::SuperUser])?;
+    User::get_by_username(&body.username, &sd.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                format!("There is no user named {}", body.username),
+                StatusCode::from_u16(404).unwrap(),
+            )
+        })?;
+    Grant::new(
+        body.username.clone(),
+        body.project.clone(),
+        body.role.clone(),
+        author,
+    )
+    .upsert_db(&sd.repo.pool)
+    .await
+    .map_err(user_error)?;
+    ok!(HttpResponse::Ok().body(format!(
+        "User {} is {} on {} now",
+        body.username, body.role, body.project
+    )))
+}
+
+pub async fn revoke_project_grant(
+    sd: web::Data<Arc<ServerData>>,
+    query: web::Query<RevokeGrantQuery>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    Grant::revoke_db(&query.username, &query.project, &sd.repo.pool)
+        .await
+        .map_err(user_error)?;
+    ok!(HttpResponse::Ok().body(format!(
+        "User {} has the global role on {} now",
+        query.username, query.project
+    )))
+}
+
+pub async fn disable_user(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<UsernameBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    User::set_disabled_db(&body.username, true, &sd.repo.pool)
+        .await
+        .map_err(user_error)?;
+    revoke_everything(&body.username, &sd.repo.pool).await?;
+    ok!(HttpResponse::Ok().body(format!("User {} disabled", body.username)))
+}
+
+pub async fn enable_user(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<UsernameBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    User::set_disabled_db(&body.username, false, &sd.repo.pool)
+        .await
+        .map_err(user_error)?;
+    ok!(HttpResponse::Ok().body(format!("User {} enabled", body.username)))
+}
+
+#[derive(Deserialize)]
+pub struct RotateJwtKeyQuery {
+    // seconds the tokens of the current key stay valid
+    grace: Option<u64>,
+}
+
+pub async fn handle_rotate_jwt_key(
+    sd: web::Data<Arc<ServerData>>,
+    query: web::Query<RotateJwtKeyQuery>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    let grace = query.grace.unwrap_or(DEFAULT_GRACE);
+    expires_after(grace)
+        .map_err(|e| InternalError::new(e.to_string(), StatusCode::from_u16(400).unwrap()))?;
+    let kid = JwtKey::rotate_db(grace, &sd.repo.pool).await.map_err(|e| {
         InternalError::new(
-            "Failed to create super user",
+            format!("Failed to rotate jwt key: {}", e),
             StatusCode::from_u16(500).unwrap(),
         )
     })?;
-    ok!(HttpResponse::Ok().body(
-        create_jwt(body.username.borrow(), RoleType::SuperUser).map_err(|_| {
-            InternalError::new(
-                "Failed to create super user",
-                StatusCode::from_u16(500).unwrap(),
-            )
-        })?
-    ))
+    ok!(HttpResponse::Ok().body(kid))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -176,37 +582,55 @@ pub struct Claims {
     sub: String,
     exp: usize,
     role: String,
+    // set for api tokens limited to one project
+    #[serde(default)]
+    project: Option<String>,
+    // session of a user login, api tokens have none
+    #[serde(default)]
+    sid: Option<String>,
 }
 
-pub static JWT_KEY: LazyLock<Mutex<String>> =
-    LazyLock::new(|| Mutex::new("dont_even_try_to_hack_me!".to_string()));
-
-pub fn change_jwt_secret(secret_key: &str) {
-    *JWT_KEY.lock().unwrap() = secret_key.to_string();
+pub async fn create_session(
+    username: &str,
+    role: RoleType,
+    ttl: i64,
+    conn: &SqlitePool,
+) -> AnyResult<AuthTokens> {
+    let (session, refresh_token) = Session::new(username.to_string(), ttl);
+    session.insert_db(conn).await?;
+    ok!(AuthTokens {
+        access_token: create_jwt(username, role, &session.id)?,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_TTL,
+    })
 }
 
-pub fn create_jwt(username: &str, role: RoleType) -> AnyResult<String> {
+pub fn create_jwt(username: &str, role: RoleType, sid: &str) -> AnyResult<String> {
     let claims = Claims {
         sub: username.to_string(),
-        exp: (SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + 60 * 60 * 1000) as usize,
+        exp: (SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + ACCESS_TOKEN_TTL) as usize,
         role: role.to_string(),
+        project: None,
+        sid: Some(sid.to_string()),
     };
 
-    let header = Header::default();
-    let secret_key = JWT_KEY.lock().unwrap().clone();
-    let key = EncodingKey::from_secret(secret_key.as_ref());
+    let signing_key = signing_key()?;
+    let header = Header {
+        kid: Some(signing_key.id),
+        ..Header::default()
+    };
+    let key = EncodingKey::from_secret(signing_key.value.as_ref());
 
     let token = encode(&header, &claims, &key)?;
     Ok(token)
 }
 
 pub fn verify_jwt(token: &str) -> AnyResult<Claims> {
-    let secret_key = JWT_KEY.lock().unwrap().clone();
-    let key = DecodingKey::from_secret(secret_key.as_ref());
-    let mut validation = Validation::default();
-    validation.validate_exp = false;
-
-    let token_data = decode::<Claims>(token, &key, &validation)?;
+    let kid = decode_header(token)?
+        .kid
+        .ok_or(anyhow!("token has no key id, login again"))?;
+    let key = DecodingKey::from_secret(verifying_key(&kid)?.value.as_ref());
+    let token_data = decode::<Claims>(token, &key, &Validation::default())?;
     Ok(token_data.claims)
 }
 
@@ -223,6 +647,47 @@ pub fn check_header(req: &HttpRequest) -> AnyResult<()> {
     Ok(())
 }
 
+// LEV_ALLOWED_CIDRS, comma separated networks or addresses, empty allows every client.
+// LEV_TRUSTED_PROXIES, the same for proxies whose X-Forwarded-For is used by login limits
+pub fn init_allowed_cidrs() -> AnyResult<()> {
+    let value = std::env::var("LEV_ALLOWED_CIDRS").unwrap_or_default();
+    let cidrs = parse_cidrs(&value).map_err(|e| anyhow!("LEV_ALLOWED_CIDRS: {}", e))?;
+    if !cidrs.is_empty() {
+        println!("only accepting clients from {}", value);
+    }
+    *ALLOWED_CIDRS.lock().unwrap() = cidrs;
+    let value = std::env::var("LEV_TRUSTED_PROXIES").unwrap_or_default();
+    *TRUSTED_PROXIES.lock().unwrap() =
+        parse_cidrs(&value).map_err(|e| anyhow!("LEV_TRUSTED_PROXIES: {}", e))?;
+    Ok(())
+}
+
+fn parse_cidrs(value: &str) -> AnyResult<Vec<IpNet>> {
+    value
+        .split(',')
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .map(|c| {
+            c.parse::<IpNet>()
+                .or_else(|_| c.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow!("invalid network {}", c))
+        })
+        .collect()
+}
+
+fn ip_allowed(cidrs: &[IpNet], ip: Option<IpAddr>) -> bool {
+    if cidrs.is_empty() {
+        return true;
+    }
+    ip.map(|ip| cidrs.iter().any(|c| c.contains(&ip.to_canonical())))
+        .unwrap_or(false)
+}
+
+// checks the address of the connection, forwarded headers can be set by anyone
+pub fn client_allowed(ip: Option<IpAddr>) -> bool {
+    ip_allowed(&ALLOWED_CIDRS.lock().unwrap(), ip)
+}
+
 pub fn must_have_levpass(req: &HttpRequest) -> Result<()> {
     match check_header(req)
         .map_err(|_| InternalError::new("Forbidden", StatusCode::from_u16(403).unwrap()))
@@ -232,16 +697,43 @@ pub fn must_have_levpass(req: &HttpRequest) -> Result<()> {
     }
 }
 
+// api tokens limited to a project can't be used here, see must_auth_project
 pub fn must_auth(req: &HttpRequest, should_be: Vec<RoleType>) -> Result<String> {
+    must_auth_project(req, should_be, None)
+}
+
+// for requests on one project, global ones pass None
+pub fn must_auth_project(
+    req: &HttpRequest,
+    should_be: Vec<RoleType>,
+    project: Option<&str>,
+) -> Result<String> {
     match check_auth(req).map_err(|e| {
         println!("error: {:?}", e);
         InternalError::new("Unauthorized", StatusCode::from_u16(401).unwrap())
     }) {
         Ok(e) => {
+            set_audit_user(req, &e.sub, &e.role);
+            if let Some(project) = project {
+                req.extensions_mut()
+                    .insert(AuditProject(project.to_string()));
+            }
+            if let Some(scope) = &e.project {
+                if project != Some(scope.as_str()) {
+                    return Err(InternalError::new(
+                        format!(
+                            "Forbidden: the token can only be used for project {}",
+                            scope
+                        ),
+                        StatusCode::from_u16(403).unwrap(),
+                    )
+                    .into());
+                }
+            }
             if should_be.is_empty() {
                 return Ok(e.sub);
             }
-            if !should_be.contains(&RoleType::from_string(&e.role)) {
+            if !should_be.contains(&effective_role(&e, project)) {
                 return Err(InternalError::new(
                     format!(
                         "Forbidden: role should be: {} ",
@@ -264,6 +756,68 @@ pub fn check_auth(req: &HttpRequest) -> AnyResult<Claims> {
         .get("Authorization")
         .ok_or(anyhow!("could not find Authorization"))?
         .to_str()?;
+    if token.starts_with(TOKEN_PREFIX) {
+        let api_token = find_token(token)?;
+        ok!(Claims {
+            sub: format!("token:{}", api_token.name),
+            exp: 0,
+            role: api_token.role,
+            project: api_token.project,
+            sid: None,
+        })
+    }
     let claims = verify_jwt(token)?;
-    ok!(claims)
+    // tokens issued before sessions never expire, they have to login again
+    match &claims.sid {
+        Some(sid) if !is_revoked(sid) => ok!(claims),
+        Some(_) => Err(anyhow!("session was revoked")),
+        None => Err(anyhow!("token has no session, login again")),
+    }
+}
+
+// the role on the project, a grant replaces the global role. Super users keep theirs
+fn effective_role(claims: &Claims, project: Option<&str>) -> RoleType {
+    let role = RoleType::from_string(&claims.role);
+    match project {
+        Some(project) if role != RoleType::SuperUser && claims.sid.is_some() => {
+            grant_role(&claims.sub, project).unwrap_or(role)
+        }
+        _ => role,
+    }
+}
+
+pub fn get_project_role(req: &HttpRequest, project: &str) -> AnyResult<RoleType> {
+    let claims = check_auth(req)?;
+    ok!(effective_role(&claims, Some(project)))
+}
+
+// the project an api token is limited to, None for users and global tokens
+pub fn get_project_scope(req: &HttpRequest) -> Option<String> {
+    check_auth(req).ok().and_then(|claims| claims.project)
+}
+
+#[test]
+fn allowed_cidrs() {
+    let cidrs = parse_cidrs("10.0.0.0/8, 192.168.1.5,fd00::/8").unwrap();
+    assert_eq!(cidrs.len(), 3);
+    assert!(parse_cidrs("10.0.0.0/33").is_err());
+    assert!(ip_allowed(&[], None));
+    assert!(ip_allowed(&cidrs, "10.1.2.3".parse().ok()));
+    assert!(ip_allowed(&cidrs, "::ffff:10.1.2.3".parse().ok()));
+    assert!(ip_allowed(&cidrs, "192.168.1.5".parse().ok()));
+    assert!(!ip_allowed(&cidrs, "192.168.1.6".parse().ok()));
+    assert!(!ip_allowed(&cidrs, None));
+
+    let proxies = parse_cidrs("10.0.0.0/24").unwrap();
+    let ip = |s: &str| s.parse::<IpAddr>().ok();
+    assert_eq!(
+        client_ip(&proxies, ip("203.0.113.7"), Some("1.2.3.4")),
+        ip("203.0.113.7")
+    );
+    assert_eq!(
+        client_ip(&proxies, ip("10.0.0.3"), Some("1.2.3.4, 198.51.100.9")),
+        ip("198.51.100.9")
+    );
+    assert_eq!(client_ip(&proxies, ip("10.0.0.3"), None), ip("10.0.0.3"));
+    assert_eq!(client_ip(&[], None, Some("1.2.3.4")), None);
 }
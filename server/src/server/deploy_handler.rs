@@ -4,11 +4,17 @@ use actix_web::{
     error::InternalError, http::StatusCode, web, HttpRequest, HttpResponse, Responder, Result,
 };
 use serde::Deserialize;
-use shared::{deployable::deploy::Deploy, err};
+use shared::{
+    deployable::{
+        blue_green::reconcile_colors,
+        deploy::{Deploy, DeployAction},
+    },
+    err, ok,
+};
 
 use crate::{
     repo::{deploy_repo::DeployData, user_repo::RoleType},
-    server::auth_handler::must_auth,
+    server::{auth_handler::must_auth_project, maintenance_handler::sync_maintenance},
 };
 
 use super::ServerData;
@@ -19,13 +25,44 @@ pub struct ConfigBody {
     pub filter: Option<String>,
 }
 
+// removes blue-green colors left behind when the manager stopped during a grace period
+pub async fn reconcile_deploys(sv: &ServerData) -> anyhow::Result<()> {
+    reconcile_colors(sv.docker_service.clone()).await
+}
+
 pub async fn handle_deploy(
     sd: web::Data<Arc<ServerData>>,
     body: web::Json<Vec<Deploy>>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
-    must_auth(&req, vec![RoleType::FullAccess, RoleType::SuperUser])?;
-    let mut project_name: String = String::new();
+    let project_name = body
+        .first()
+        .map(|d| d.deployable.project_name.clone())
+        .unwrap_or_default();
+    must_auth_project(
+        &req,
+        vec![RoleType::FullAccess, RoleType::SuperUser],
+        body.first().map(|_| project_name.as_str()),
+    )?;
+    if body
+        .iter()
+        .any(|d| d.deployable.project_name != project_name)
+    {
+        err!(InternalError::new(
+            "All deploys must be from the same project",
+            StatusCode::from_u16(400).unwrap(),
+        )
+        .into());
+    }
+    // new routes of a project in maintenance must be covered before they go live
+    sync_maintenance(&sd, &project_name, &body)
+        .await
+        .map_err(|e| {
+            InternalError::new(
+                format!("Failed to keep maintenance mode: {}", e),
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?;
     let service_names: Vec<_> = sd
         .docker_service
         .list_services()
@@ -45,18 +82,21 @@ pub async fn handle_deploy(
             }
         })
         .collect();
-    for deploy in body.iter() {
+    let mut deploys = body.into_inner();
+    for deploy in deploys.iter_mut() {
         println!("deploying {}", deploy.deployable.short_name);
-        if !project_name.is_empty() && project_name != deploy.deployable.project_name.clone() {
-            err!(InternalError::new(
-                "All deploys must be from the same project",
-                StatusCode::from_u16(400).unwrap(),
-            )
-            .into());
+        let is_live =
+            deploy.action == DeployAction::Create || deploy.action == DeployAction::Update;
+        let mut live = deploy.clone();
+        if is_live {
+            live = render_deploy(&sd, deploy).await.map_err(|e| {
+                InternalError::new(
+                    format!("Failed to mount secrets: {}", e),
+                    StatusCode::from_u16(500).unwrap(),
+                )
+            })?;
         }
-        project_name = deploy.deployable.project_name.clone();
-        deploy
-            .deploy(sd.docker_service.clone(), service_names.clone())
+        live.deploy(sd.docker_service.clone(), service_names.clone())
             .await
             .map_err(|e| {
                 dbg!(&e);
@@ -65,10 +105,15 @@ pub async fn handle_deploy(
                     StatusCode::from_u16(500).unwrap(),
                 )
             })?;
+        if is_live || deploy.action == DeployAction::Delete {
+            live.deployable
+                .remove_unused_secrets(&sd.docker_service)
+                .await;
+        }
     }
     DeployData::new(
-        project_name,
-        serde_json::to_string(&body)
+        project_name.clone(),
+        serde_json::to_string(&deploys)
             .map_err(|e| InternalError::new(e, StatusCode::from_u16(500).unwrap()))?,
     )
     .map_err(|e| InternalError::new(e, StatusCode::from_u16(500).unwrap()))?
@@ -78,3 +123,19 @@ pub async fn handle_deploy(
     println!("Deployed successfully");
     Ok(HttpResponse::Ok().body("Deployed successfully"))
 }
+
+// the stored deploy keeps masked values, the returned one has the exact secret versions the
+// deploy was planned with and is only used to update the service
+async fn render_deploy(sd: &ServerData, deploy: &mut Deploy) -> anyhow::Result<Deploy> {
+    let values = sd
+        .providers
+        .get_versions(&deploy.deployable.project_name, &deploy.secrets)
+        .await?;
+    deploy.mask_build_args(&values);
+    let mut live = deploy.clone();
+    live.deployable = deploy.deployable.render_secrets(&values)?;
+    live.deployable
+        .mount_secrets(&sd.docker_service, &values)
+        .await?;
+    ok!(live)
+}
@@ -1,27 +1,47 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, LazyLock, Mutex},
+};
 
 use actix_multipart::Multipart;
 use actix_web::{web, HttpRequest, HttpResponse, Responder, Result};
 use bytes::Bytes;
 use futures::{channel::mpsc, SinkExt, StreamExt};
+use serde::Deserialize;
+use shared::{deployable::deploy::Deploy, docker::image::get_tarball_tags};
 
-use crate::{repo::user_repo::RoleType, server::auth_handler::must_auth};
+use crate::{
+    repo::{deploy_repo::DeployData, user_repo::RoleType},
+    server::auth_handler::{get_project_scope, must_auth_project},
+};
 
 use super::ServerData;
 
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    pub project: Option<String>,
+}
+
 pub async fn upload(
     sv: web::Data<Arc<ServerData>>,
     mut payload: Multipart,
+    query: web::Query<UploadQuery>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
     println!("upload");
-    must_auth(
+    // the project comes from lev, the tags of the tarball are checked against its apps
+    let Some(project) = query.project.clone().or(get_project_scope(&req)) else {
+        return Ok(HttpResponse::BadRequest().body("The project of the image is required"));
+    };
+    must_auth_project(
         &req,
         vec![
             RoleType::FullAccess,
             RoleType::SuperUser,
             RoleType::UpdateOnly,
         ],
+        Some(&project),
     )?;
     let images_dir = std::env::var("IMAGES_DIR").unwrap_or("/images".to_string());
 
@@ -32,7 +52,11 @@ pub async fn upload(
             Ok(mut field) => {
                 if let Some(content_disp) = field.content_disposition() {
                     if let Some(filename) = content_disp.get_filename() {
-                        let file_path = format!("{}/{}", &images_dir, filename);
+                        // only the name, so the file can't be written outside of images_dir
+                        let Some(filename) = Path::new(filename).file_name() else {
+                            return Ok(HttpResponse::BadRequest().body("Invalid file name"));
+                        };
+                        let file_path = Path::new(&images_dir).join(filename);
 
                         let mut file = match tokio::fs::File::create(&file_path).await {
                             Ok(f) => f,
@@ -66,9 +90,51 @@ pub async fn upload(
 
                         drop(file);
 
-                        println!("File saved successfully: {}", file_path);
-                        println!("Loading image from file: {}", file_path);
+                        println!("File saved successfully: {}", file_path.display());
+                        let owners = match image_owners(&sv).await {
+                            Ok(owners) => owners,
+                            Err(e) => {
+                                println!("Failed to get the apps of the projects: {:?}", e);
+                                return Ok(HttpResponse::InternalServerError()
+                                    .body("Failed to get the apps of the projects"));
+                            }
+                        };
+                        // the tags are checked before the load, since docker load moves a tag
+                        // that already exists to the loaded image
+                        let path = file_path.clone();
+                        let tags = match web::block(move || get_tarball_tags(&path)).await {
+                            Ok(Ok(tags)) => tags,
+                            Ok(Err(e)) => {
+                                let _ = tokio::fs::remove_file(&file_path).await;
+                                return Ok(HttpResponse::BadRequest()
+                                    .body(format!("Failed to read the image tags: {}", e)));
+                            }
+                            Err(e) => {
+                                let _ = tokio::fs::remove_file(&file_path).await;
+                                println!("Failed to read the image tags: {:?}", e);
+                                return Ok(HttpResponse::InternalServerError()
+                                    .body("Failed to read the image tags"));
+                            }
+                        };
+                        if let Some(tag) = tags
+                            .iter()
+                            .find(|t| !is_project_image(t, &project, &owners))
+                        {
+                            let _ = tokio::fs::remove_file(&file_path).await;
+                            return Ok(HttpResponse::Forbidden()
+                                .body(format!("Image {} doesn't belong to the project", tag)));
+                        }
+                        let existing: HashSet<_> = match sv.docker_service.list_images().await {
+                            Ok(images) => images.into_iter().map(|i| i.tag).collect(),
+                            Err(e) => {
+                                let _ = tokio::fs::remove_file(&file_path).await;
+                                println!("Failed to list images: {:?}", e);
+                                return Ok(HttpResponse::InternalServerError()
+                                    .body("Failed to list images"));
+                            }
+                        };
 
+                        println!("Loading image from file: {}", file_path.display());
                         let file_stream = match tokio::fs::File::open(&file_path).await {
                             Ok(f) => tokio_util::io::ReaderStream::new(f)
                                 .map(|result| result.map(Bytes::from).unwrap()),
@@ -79,15 +145,32 @@ pub async fn upload(
                             }
                         };
 
-                        if let Err(e) = sv.docker_service.load_image(file_stream).await {
-                            println!("Docker load error: {:?}", e);
-                            return Ok(HttpResponse::InternalServerError()
-                                .body(format!("Failed to load image: {:?}", e)));
-                        }
+                        let loaded = match sv.docker_service.load_image(file_stream).await {
+                            Ok(tags) => tags,
+                            Err(e) => {
+                                println!("Docker load error: {:?}", e);
+                                return Ok(HttpResponse::InternalServerError()
+                                    .body(format!("Failed to load image: {:?}", e)));
+                            }
+                        };
+                        tokio::fs::remove_file(&file_path).await.unwrap();
 
-                        println!("Image loaded successfully from file: {}", file_path);
+                        // docker reported a tag the tarball didn't show, only the tags this
+                        // upload created are removed again
+                        if let Some(tag) = loaded.iter().find(|t| !tags.contains(t)) {
+                            for tag in loaded.iter().filter(|t| !existing.contains(*t)) {
+                                if let Err(e) = sv.docker_service.remove_image(tag).await {
+                                    println!("Failed to remove image {}: {:?}", tag, e);
+                                }
+                            }
+                            return Ok(HttpResponse::Forbidden()
+                                .body(format!("Image {} doesn't belong to the project", tag)));
+                        }
 
-                        tokio::fs::remove_file(&file_path).await.unwrap();
+                        println!(
+                            "Image loaded successfully from file: {}",
+                            file_path.display()
+                        );
                     }
                 }
             }
@@ -102,3 +185,88 @@ pub async fn upload(
 
     Ok(HttpResponse::Ok().body("Image uploaded and loaded successfully"))
 }
+
+// apps of the latest plan of every project that could upload, a new app has no deploy
+// yet when its image is uploaded
+static PLANNED_APPS: LazyLock<Mutex<HashMap<String, HashSet<String>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn set_planned_apps(project: &str, deploys: &[Deploy]) {
+    let apps = deploys
+        .iter()
+        .filter(|d| d.deployable.config_type == "app")
+        .map(|d| d.deployable.short_name.clone())
+        .collect();
+    PLANNED_APPS
+        .lock()
+        .unwrap()
+        .insert(project.to_string(), apps);
+}
+
+// image names lev builds for the apps of every project, <project>-<app>-image, with the
+// projects they belong to
+async fn image_owners(sv: &ServerData) -> anyhow::Result<HashMap<String, HashSet<String>>> {
+    let mut apps: Vec<(String, String)> = vec![];
+    for data in DeployData::get_last_deploys(&sv.repo.pool, 1).await? {
+        let deploys: Vec<Deploy> = serde_json::from_str(&data.deploys)?;
+        deploys
+            .iter()
+            .filter(|d| d.deployable.config_type == "app")
+            .for_each(|d| apps.push((data.project_name.clone(), d.deployable.short_name.clone())));
+    }
+    for (project, planned) in PLANNED_APPS.lock().unwrap().iter() {
+        planned
+            .iter()
+            .for_each(|app| apps.push((project.clone(), app.clone())));
+    }
+    let mut owners: HashMap<String, HashSet<String>> = HashMap::new();
+    for (project, app) in apps {
+        owners
+            .entry(format!("{}-{}-image", project, app))
+            .or_default()
+            .insert(project);
+    }
+    Ok(owners)
+}
+
+// a name like shop-admin-web-image can be app admin-web of shop or app web of shop-admin,
+// so it has to be the image of an app of the project and of no other project
+fn is_project_image(tag: &str, project: &str, owners: &HashMap<String, HashSet<String>>) -> bool {
+    let name = tag.rsplit_once(':').map_or(tag, |(name, _)| name);
+    owners
+        .get(name)
+        .is_some_and(|projects| projects.len() == 1 && projects.contains(project))
+}
+
+#[test]
+fn docker_handler_test() {
+    let mut owners: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut add = |project: &str, app: &str| {
+        owners
+            .entry(format!("{}-{}-image", project, app))
+            .or_default()
+            .insert(project.to_string());
+    };
+    add("shop", "web");
+    add("blog", "web");
+    add("shop-admin", "web");
+    add("shop", "admin-web");
+    add("shop-admin", "api");
+    assert!(is_project_image("shop-web-image:3", "shop", &owners));
+    assert!(!is_project_image("blog-web-image:3", "shop", &owners));
+    assert!(!is_project_image("traefik:v3.1", "shop", &owners));
+    assert!(!is_project_image("shop-db-image:1", "shop", &owners));
+    assert!(!is_project_image("shop-admin-api-image:1", "shop", &owners));
+    assert!(is_project_image(
+        "shop-admin-api-image:1",
+        "shop-admin",
+        &owners
+    ));
+    // claimed by both projects, so neither can upload it
+    assert!(!is_project_image("shop-admin-web-image:1", "shop", &owners));
+    assert!(!is_project_image(
+        "shop-admin-web-image:1",
+        "shop-admin",
+        &owners
+    ));
+}
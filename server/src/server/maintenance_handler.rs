@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use actix_web::{
+    error::InternalError, http::StatusCode, web, HttpRequest, HttpResponse, Responder, Result,
+};
+use serde::Deserialize;
+use shared::{
+    deployable::{
+        deploy::Deploy,
+        maintenance::{
+            disable_maintenance, enable_maintenance, get_live_deployables, DEFAULT_MAINTENANCE_PAGE,
+        },
+    },
+    ok,
+};
+
+use crate::{
+    repo::{deploy_repo::DeployData, maintenance_repo::MaintenanceData, user_repo::RoleType},
+    server::auth_handler::must_auth_project,
+};
+
+use super::ServerData;
+
+const MAINTENANCE_NETWORK: &str = "lev";
+
+#[derive(Deserialize, Debug)]
+pub struct MaintenanceBody {
+    pub project: String,
+    pub page: Option<String>,
+}
+
+pub async fn handle_maintenance_on(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<MaintenanceBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth_project(
+        &req,
+        vec![RoleType::FullAccess, RoleType::SuperUser],
+        Some(&body.project),
+    )?;
+    let last_deploys = DeployData::get_last_deploys(&sd.repo.pool, 1)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to get last deploys",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?
+        .into_iter()
+        .find(|d| d.project_name == body.project)
+        .ok_or(InternalError::new(
+            format!("Project {} was never deployed", body.project),
+            StatusCode::from_u16(404).unwrap(),
+        ))?;
+    let deploys: Vec<Deploy> = serde_json::from_str(&last_deploys.deploys).map_err(|_| {
+        InternalError::new(
+            "Failed to read last deploys",
+            StatusCode::from_u16(500).unwrap(),
+        )
+    })?;
+    let page = body
+        .page
+        .clone()
+        .unwrap_or(DEFAULT_MAINTENANCE_PAGE.to_string());
+    enable_maintenance(
+        &sd.docker_service,
+        &body.project,
+        &page,
+        &get_live_deployables(&deploys),
+        MAINTENANCE_NETWORK,
+    )
+    .await
+    .map_err(|e| {
+        InternalError::new(
+            format!("Failed to enable maintenance: {}", e),
+            StatusCode::from_u16(500).unwrap(),
+        )
+    })?;
+    MaintenanceData::new(body.project.clone(), page)
+        .insert_db(&sd.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to save maintenance state",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?;
+    ok!(HttpResponse::Ok().body("OK"))
+}
+
+pub async fn handle_maintenance_off(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<MaintenanceBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth_project(
+        &req,
+        vec![RoleType::FullAccess, RoleType::SuperUser],
+        Some(&body.project),
+    )?;
+    disable_maintenance(&sd.docker_service, &body.project)
+        .await
+        .map_err(|e| {
+            InternalError::new(
+                format!("Failed to disable maintenance: {}", e),
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?;
+    MaintenanceData::delete_db(&body.project, &sd.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to save maintenance state",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?;
+    ok!(HttpResponse::Ok().body("OK"))
+}
+
+// routers of a project in maintenance follow its deploys, so new or moved routes stay
+// behind the maintenance page until it is turned off
+pub async fn sync_maintenance(
+    sd: &ServerData,
+    project_name: &str,
+    deploys: &[Deploy],
+) -> anyhow::Result<()> {
+    let Some(maintenance) = MaintenanceData::get_db(project_name, &sd.repo.pool).await? else {
+        return Ok(());
+    };
+    enable_maintenance(
+        &sd.docker_service,
+        project_name,
+        &maintenance.page,
+        &get_live_deployables(deploys),
+        MAINTENANCE_NETWORK,
+    )
+    .await
+}
@@ -1,26 +1,39 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use actix_web::{
     error::InternalError, http::StatusCode, web, HttpRequest, HttpResponse, Responder, Result,
 };
 use serde::Deserialize;
 use shared::{
+    config::MainConfig,
     deployable::{
-        deploy::{plan, PlanParamaters},
+        deploy::{plan, Deploy, PlanParamaters},
+        generated::get_generated_secrets,
+        get_secret_keys,
+        preflight::{
+            check_dns, find_duplicate_routes, get_dns_domains, is_public_ip, parse_public_ips,
+            resolve_domain,
+        },
         rollback::{rollback, RollBackParams},
     },
-    ok, SecretValue,
+    ok,
 };
 
 use crate::repo::{deploy_repo::DeployData, secret_repo::SecretData, user_repo::RoleType};
 
-use super::{auth_handler::must_auth, ServerData};
+use super::{
+    auth_handler::{get_project_role, must_auth_project},
+    docker_handler::set_planned_apps,
+    ServerData,
+};
 
 #[derive(Deserialize, Debug)]
 pub struct PlanBody {
     pub config: String,
     pub filter: Option<Vec<String>>,
     pub to_build: Option<Vec<String>>,
+    #[serde(default)]
+    pub envs: HashMap<String, String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -33,7 +46,9 @@ pub async fn handle_rollback(
     body: web::Json<RollBackBody>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
-    must_auth(
+    let project = MainConfig::get_project_name(&body.config)
+        .map_err(|e| InternalError::new(format!("{}", e), StatusCode::from_u16(400).unwrap()))?;
+    must_auth_project(
         &req,
         vec![
             RoleType::FullAccess,
@@ -41,6 +56,7 @@ pub async fn handle_rollback(
             RoleType::UpdateOnly,
             RoleType::ReadOnly,
         ],
+        Some(&project),
     )?;
     let last_deploys: Vec<_> = DeployData::get_last_deploys(&sd.repo.pool, 1)
         .await
@@ -87,7 +103,9 @@ pub async fn handle_plan(
     body: web::Json<PlanBody>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
-    must_auth(
+    let project = MainConfig::get_project_name(&body.config)
+        .map_err(|e| InternalError::new(format!("{}", e), StatusCode::from_u16(400).unwrap()))?;
+    let author = must_auth_project(
         &req,
         vec![
             RoleType::FullAccess,
@@ -95,22 +113,38 @@ pub async fn handle_plan(
             RoleType::UpdateOnly,
             RoleType::ReadOnly,
         ],
+        Some(&project),
     )?;
-    dbg!(&body);
-    let secrets: Vec<_> = SecretData::list_db(&sd.repo.pool)
+    // only roles that can deploy create secrets and build images with the real build args
+    let can_deploy = matches!(
+        get_project_role(&req, &project),
+        Ok(RoleType::FullAccess) | Ok(RoleType::SuperUser)
+    );
+    if can_deploy {
+        let generated = get_generated_secrets(&body.config).map_err(|e| {
+            InternalError::new(format!("{}", e), StatusCode::from_u16(400).unwrap())
+        })?;
+        SecretData::generate_db(&generated, &project, &author, &sd.repo.pool)
+            .await
+            .map_err(|_| {
+                InternalError::new(
+                    "Failed to generate secrets",
+                    StatusCode::from_u16(500).unwrap(),
+                )
+            })?;
+    }
+    let keys = get_secret_keys(&body.config)
+        .map_err(|e| InternalError::new(format!("{}", e), StatusCode::from_u16(400).unwrap()))?;
+    let secrets = sd
+        .providers
+        .get_secrets(&project, &keys)
         .await
-        .map_err(|_| {
+        .map_err(|e| {
             InternalError::new(
-                "Failed to get secret list",
+                format!("Failed to read secrets: {}", e),
                 StatusCode::from_u16(500).unwrap(),
             )
-        })?
-        .into_iter()
-        .map(|s| SecretValue {
-            key: s.key,
-            value: s.value,
-        })
-        .collect();
+        })?;
     let deploys: Vec<_> = DeployData::get_last_deploys(&sd.repo.pool, 1)
         .await
         .map_err(|e| {
@@ -133,16 +167,100 @@ pub async fn handle_plan(
         .into_iter()
         .map(|i| i.tag)
         .collect();
+    let revision = DeployData::count_db(&project, &sd.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to count deploys",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?
+        + 1;
     let params = PlanParamaters {
         main_config: body.config.clone(),
         last_deploys: deploys,
-        secrets,
+        secrets: secrets.clone(),
         network_name: "lev".to_string(),
         filter: body.filter.clone(),
         to_build: body.to_build.clone().unwrap_or(vec![]),
         images,
+        envs: body.envs.clone(),
+        revision: revision as u64,
     };
-    let this_deploys = plan(params)
+    let mut this_deploys = plan(params)
         .map_err(|e| InternalError::new(format!("{}", e), StatusCode::from_u16(400).unwrap()))?;
+    if !can_deploy {
+        this_deploys
+            .iter_mut()
+            .for_each(|d| d.mask_build_args(&secrets));
+    }
+    // images of the apps are uploaded after the plan, by roles that can upload
+    if can_deploy || matches!(get_project_role(&req, &project), Ok(RoleType::UpdateOnly)) {
+        set_planned_apps(&project, &this_deploys);
+    }
     ok!(HttpResponse::Ok().json(this_deploys))
 }
+
+// domains of the plan that don't point to this server yet, and routes that another
+// project or service already serves
+pub async fn handle_preflight(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<Vec<Deploy>>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    let project_name = body
+        .first()
+        .map(|d| d.deployable.project_name.clone())
+        .unwrap_or_default();
+    must_auth_project(
+        &req,
+        vec![
+            RoleType::FullAccess,
+            RoleType::SuperUser,
+            RoleType::UpdateOnly,
+            RoleType::ReadOnly,
+        ],
+        Some(&project_name),
+    )?;
+    let other_projects: Vec<(String, Vec<Deploy>)> = DeployData::get_last_deploys(&sd.repo.pool, 1)
+        .await
+        .map_err(|e| {
+            println!("failed to get last deploys: {}", e);
+            InternalError::new(
+                "Failed to get last deploys",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?
+        .into_iter()
+        .filter(|d| d.project_name != project_name)
+        .filter_map(|d| {
+            serde_json::from_str::<Vec<Deploy>>(&d.deploys)
+                .ok()
+                .map(|deploys| (d.project_name, deploys))
+        })
+        .collect();
+    let mut issues = find_duplicate_routes(&body, &other_projects);
+
+    let manager_ips = get_manager_ips(&sd).await;
+    for domain in get_dns_domains(&body) {
+        let resolved = resolve_domain(&domain).await;
+        if let Some(issue) = check_dns(&domain, &resolved, &manager_ips) {
+            issues.push(issue);
+        }
+    }
+    ok!(web::Json(issues))
+}
+
+// LEV_PUBLIC_IPS wins, the swarm address is only useful when it is public
+async fn get_manager_ips(sd: &ServerData) -> Vec<std::net::IpAddr> {
+    if let Ok(raw) = std::env::var("LEV_PUBLIC_IPS") {
+        return parse_public_ips(&raw);
+    }
+    match sd.docker_service.get_node_addr().await {
+        Ok(Some(addr)) => parse_public_ips(&addr)
+            .into_iter()
+            .filter(is_public_ip)
+            .collect(),
+        _ => vec![],
+    }
+}
@@ -0,0 +1,317 @@
+use std::{collections::HashMap, sync::Arc};
+
+use actix_web::{error::InternalError, http::StatusCode, web, HttpRequest, Responder, Result};
+use anyhow::{anyhow, Result as AnyResult};
+use bcrypt::{hash_with_result, Version, DEFAULT_COST};
+use bollard::secret::{EndpointPortConfig, EndpointPortConfigPublishModeEnum};
+use shared::{
+    deployable::{
+        deploy::{DeployTask, HealthCheckable},
+        task::run_deploy_task,
+    },
+    docker::DockerService,
+    err, ok,
+    proxy::{ProxySettings, ProxySettingsUpdate, TRAEFIK_SERVICE_NAME},
+};
+
+use crate::{
+    repo::{proxy_repo::ProxyData, user_repo::RoleType},
+    server::auth_handler::must_auth,
+};
+
+use super::ServerData;
+
+const PROXY_NETWORK: &str = "lev";
+pub const MANAGER_SERVICE_NAME: &str = "lev-service";
+const MANAGER_HTTP_PORT: u16 = 8081;
+
+// LEV_DOMAIN, traefik routes it to the plain http of the manager
+pub fn manager_domain() -> Option<String> {
+    std::env::var("LEV_DOMAIN").ok().filter(|d| !d.is_empty())
+}
+
+// creates traefik on the first start or brings the running one to the stored settings
+pub async fn reconcile_proxy(sv: &ServerData) -> AnyResult<()> {
+    let settings = match ProxyData::get_settings(&sv.repo.pool).await? {
+        Some(settings) => settings,
+        None => {
+            let mut settings = running_settings(&sv.docker_service)
+                .await
+                .unwrap_or_default();
+            if let Ok(email) = std::env::var("ACME_EMAIL") {
+                settings.acme_email = Some(email);
+            }
+            if let Ok(version) = std::env::var("TRAEFIK_VERSION") {
+                settings.version = version;
+            }
+            ProxyData::save_settings(&settings, &sv.repo.pool).await?;
+            settings
+        }
+    };
+    apply_proxy(&sv.docker_service, &settings).await?;
+    println!("proxy is up to date: traefik:{}", settings.version);
+    ok!(())
+}
+
+// services created by an older manager.sh don't publish the TLS port and route through
+// traefik to plain http, which only listens on localhost with TLS. The manager brings its
+// own service up to date, swarm then replaces it with a task of the new spec
+pub async fn reconcile_manager(sv: &ServerData) -> AnyResult<()> {
+    let Ok(service) = sv
+        .docker_service
+        .inspect_service(MANAGER_SERVICE_NAME.to_string())
+        .await
+    else {
+        println!("no {} service to update", MANAGER_SERVICE_NAME);
+        ok!(())
+    };
+    let (Some(mut spec), Some(version)) = (service.spec, service.version.and_then(|v| v.index))
+    else {
+        ok!(())
+    };
+    let mut changed = false;
+    if let Some(tls) = &sv.tls {
+        let ports = spec
+            .endpoint_spec
+            .get_or_insert_with(Default::default)
+            .ports
+            .get_or_insert_with(Vec::new);
+        if !ports.iter().any(|p| p.target_port == Some(tls.port as i64)) {
+            ports.push(EndpointPortConfig {
+                target_port: Some(tls.port as i64),
+                published_port: Some(tls.port as i64),
+                publish_mode: Some(EndpointPortConfigPublishModeEnum::HOST),
+                ..Default::default()
+            });
+            changed = true;
+        }
+    }
+    let labels = spec.labels.get_or_insert_with(HashMap::new);
+    if let Some(new_labels) = manager_labels(labels, manager_domain().as_deref(), sv.tls.is_some())
+    {
+        *labels = new_labels;
+        changed = true;
+    }
+    if !changed {
+        ok!(())
+    }
+    println!(
+        "updating {} to the current api addresses",
+        MANAGER_SERVICE_NAME
+    );
+    sv.docker_service
+        .update_service_spec(MANAGER_SERVICE_NAME, spec, version)
+        .await?;
+    ok!(())
+}
+
+// with LEV_DOMAIN traefik routes the domain to plain http, otherwise there is no route once
+// plain http stays on localhost. None when the labels are right already
+fn manager_labels(
+    current: &HashMap<String, String>,
+    domain: Option<&str>,
+    tls: bool,
+) -> Option<HashMap<String, String>> {
+    let mut labels: HashMap<String, String> = current
+        .iter()
+        .filter(|(key, _)| !key.starts_with("traefik."))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    match domain {
+        Some(domain) => {
+            let router = format!("traefik.http.routers.{}", MANAGER_SERVICE_NAME);
+            labels.insert("traefik.enable".to_string(), "true".to_string());
+            labels.insert(format!("{}.rule", router), format!("Host(`{}`)", domain));
+            labels.insert(
+                format!("{}.service", router),
+                MANAGER_SERVICE_NAME.to_string(),
+            );
+            labels.insert(format!("{}.entrypoints", router), "websecure".to_string());
+            labels.insert(format!("{}.tls", router), "true".to_string());
+            labels.insert(
+                format!("{}.tls.certresolver", router),
+                "myresolver".to_string(),
+            );
+            labels.insert(
+                format!(
+                    "traefik.http.services.{}.loadbalancer.server.port",
+                    MANAGER_SERVICE_NAME
+                ),
+                MANAGER_HTTP_PORT.to_string(),
+            );
+        }
+        None if tls => {
+            labels.insert("traefik.enable".to_string(), "false".to_string());
+        }
+        // without TLS plain http still listens for the route of older installs
+        None => return None,
+    }
+    (labels != *current).then_some(labels)
+}
+
+// settings of the traefik an older manager.sh created, so the first reconcile keeps its
+// acme email and entrypoints
+async fn running_settings(docker: &DockerService) -> Option<ProxySettings> {
+    let spec = docker
+        .inspect_service(TRAEFIK_SERVICE_NAME.to_string())
+        .await
+        .ok()?
+        .spec?
+        .task_template?
+        .container_spec?;
+    let settings = ProxySettings::from_service(&spec.image?, &spec.args.unwrap_or_default());
+    println!("taking over the settings of the running proxy");
+    Some(settings)
+}
+
+// the update rolls back by itself when new traefik tasks don't become healthy
+async fn apply_proxy(docker: &DockerService, settings: &ProxySettings) -> AnyResult<()> {
+    let params = settings.to_service_param(PROXY_NETWORK);
+    if !docker
+        .is_service_exists(TRAEFIK_SERVICE_NAME.to_string())
+        .await
+    {
+        docker.create_service(params).await?;
+        ok!(())
+    }
+    docker.update_service(params).await?;
+    run_deploy_task(
+        DeployTask::HealthCheck(HealthCheckable {
+            service_name: TRAEFIK_SERVICE_NAME.to_string(),
+            wait_sec: 5,
+        }),
+        docker.clone(),
+    )
+    .await?;
+    ok!(())
+}
+
+pub async fn handle_get_proxy(
+    sv: web::Data<Arc<ServerData>>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    let settings = ProxyData::get_settings(&sv.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to get proxy settings",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?
+        .unwrap_or_default();
+    ok!(web::Json(without_password_hash(settings)))
+}
+
+// only the user is shown, the password hash stays on the server
+fn without_password_hash(mut settings: ProxySettings) -> ProxySettings {
+    settings.dashboard_auth = settings
+        .dashboard_auth
+        .map(|auth| auth.split(':').next().unwrap_or_default().to_string());
+    settings
+}
+
+pub async fn handle_update_proxy(
+    sv: web::Data<Arc<ServerData>>,
+    body: web::Json<ProxySettingsUpdate>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    let current = ProxyData::get_settings(&sv.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to get proxy settings",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?
+        .unwrap_or_default();
+    let settings = merge_settings(current, body.into_inner())
+        .map_err(|e| InternalError::new(e.to_string(), StatusCode::from_u16(400).unwrap()))?;
+    settings
+        .validate()
+        .map_err(|e| InternalError::new(e.to_string(), StatusCode::from_u16(400).unwrap()))?;
+    apply_proxy(&sv.docker_service, &settings)
+        .await
+        .map_err(|e| {
+            InternalError::new(
+                format!("Failed to update proxy: {}", e),
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?;
+    // saved only after traefik is healthy, so a restart doesn't bring back a broken setup
+    ProxyData::save_settings(&settings, &sv.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new(
+                "Failed to save proxy settings",
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?;
+    ok!(web::Json(without_password_hash(settings)))
+}
+
+fn merge_settings(
+    mut settings: ProxySettings,
+    update: ProxySettingsUpdate,
+) -> AnyResult<ProxySettings> {
+    if let Some(email) = update.acme_email {
+        settings.acme_email = Some(email);
+    }
+    if let Some(entrypoints) = update.entrypoints {
+        settings.entrypoints.extend(entrypoints);
+    }
+    if let Some(access_logs) = update.access_logs {
+        settings.access_logs = access_logs;
+    }
+    if let Some(version) = update.version {
+        settings.version = version;
+    }
+    if update.disable_dashboard {
+        settings.dashboard_domain = None;
+        settings.dashboard_auth = None;
+    }
+    if let Some(domain) = update.dashboard_domain {
+        settings.dashboard_domain = Some(domain);
+    }
+    match (update.dashboard_user, update.dashboard_password) {
+        (Some(user), Some(password)) => {
+            let hash = hash_with_result(password, DEFAULT_COST)?.format_for_version(Version::TwoY);
+            settings.dashboard_auth = Some(format!("{}:{}", user, hash));
+        }
+        (None, None) => {}
+        _ => err!(anyhow!(
+            "dashboard user and password should be set together"
+        )),
+    }
+    ok!(settings)
+}
+
+#[test]
+fn manager_labels_test() {
+    let old = HashMap::from([
+        ("traefik.enable".to_string(), "true".to_string()),
+        (
+            "traefik.http.routers.lev-service.rule".to_string(),
+            "Headers(`X-LEVERANS-PASS`, `true`)".to_string(),
+        ),
+        ("com.example.team".to_string(), "ops".to_string()),
+    ]);
+    let labels = manager_labels(&old, None, true).unwrap();
+    assert_eq!(labels.len(), 2);
+    assert_eq!(labels["traefik.enable"], "false");
+    assert_eq!(labels["com.example.team"], "ops");
+    assert!(manager_labels(&labels, None, true).is_none());
+    assert!(manager_labels(&old, None, false).is_none());
+
+    let labels = manager_labels(&old, Some("lev.example.com"), true).unwrap();
+    assert_eq!(
+        labels["traefik.http.routers.lev-service.rule"],
+        "Host(`lev.example.com`)"
+    );
+    assert_eq!(
+        labels["traefik.http.services.lev-service.loadbalancer.server.port"],
+        "8081"
+    );
+    assert!(manager_labels(&labels, Some("lev.example.com"), true).is_none());
+}
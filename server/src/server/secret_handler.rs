@@ -4,11 +4,11 @@ use actix_web::{
     error::InternalError, http::StatusCode, web, HttpRequest, HttpResponse, Responder, Result,
 };
 use serde::Deserialize;
-use shared::ok;
+use shared::{ok, SecretValue, SecretVersion};
 
 use crate::{
     repo::{secret_repo::SecretData, user_repo::RoleType},
-    server::auth_handler::must_auth,
+    server::auth_handler::{get_project_scope, must_auth, must_auth_project},
 };
 
 use super::ServerData;
@@ -18,35 +18,47 @@ pub async fn handle_add_secret(
     body: web::Json<AddSecretBody>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
-    must_auth(&req, vec![RoleType::FullAccess, RoleType::SuperUser])?;
+    let author = must_auth_project(
+        &req,
+        vec![RoleType::FullAccess, RoleType::SuperUser],
+        body.project.as_deref(),
+    )?;
     let secret_list = SecretData::list_db(&sv.repo.pool).await.map_err(|_| {
         InternalError::new(
             "Failed to get secret list",
             StatusCode::from_u16(500).unwrap(),
         )
     })?;
-    if secret_list.iter().any(|s| s.key == body.key) {
+    if secret_list
+        .iter()
+        .any(|s| s.key == body.key && s.project == body.project)
+    {
         return Err(InternalError::new(
             "Secret already exists, delete it first or use another key",
             StatusCode::from_u16(409).unwrap(),
         )
         .into());
     }
-    SecretData::new(body.key.to_owned(), body.value.to_owned())
-        .insert_db(&sv.repo.pool)
-        .await
-        .map_err(|_| {
-            InternalError::new(
-                "Failed to insert secret",
-                StatusCode::from_u16(500).unwrap(),
-            )
-        })?;
+    SecretData::new(
+        body.key.to_owned(),
+        body.value.to_owned(),
+        body.project.to_owned(),
+    )
+    .insert_db(&author, body.note.to_owned(), &sv.repo.pool)
+    .await
+    .map_err(|_| {
+        InternalError::new(
+            "Failed to insert secret",
+            StatusCode::from_u16(500).unwrap(),
+        )
+    })?;
     ok!(HttpResponse::Ok().body("OK"))
 }
 
 #[derive(Deserialize)]
 pub struct DeleteSecretBody {
     key: String,
+    project: Option<String>,
 }
 
 pub async fn handle_delete_secret(
@@ -54,8 +66,12 @@ pub async fn handle_delete_secret(
     body: web::Json<DeleteSecretBody>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
-    must_auth(&req, vec![RoleType::SuperUser, RoleType::FullAccess])?;
-    SecretData::delete_db(body.key.to_owned(), &sv.repo.pool)
+    must_auth_project(
+        &req,
+        vec![RoleType::SuperUser, RoleType::FullAccess],
+        body.project.as_deref(),
+    )?;
+    SecretData::delete_db(body.key.to_owned(), body.project.to_owned(), &sv.repo.pool)
         .await
         .map_err(|_| {
             InternalError::new(
@@ -71,8 +87,12 @@ pub async fn handle_show_secret(
     body: web::Json<DeleteSecretBody>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
-    must_auth(&req, vec![RoleType::SuperUser, RoleType::FullAccess])?;
-    let secret = SecretData::show_db(body.key.to_owned(), &sv.repo.pool)
+    must_auth_project(
+        &req,
+        vec![RoleType::SuperUser, RoleType::FullAccess],
+        body.project.as_deref(),
+    )?;
+    let secret = SecretData::show_db(body.key.to_owned(), body.project.to_owned(), &sv.repo.pool)
         .await
         .map_err(|_| {
             InternalError::new(
@@ -88,52 +108,261 @@ pub async fn handle_update_secret(
     body: web::Json<AddSecretBody>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
-    must_auth(&req, vec![RoleType::SuperUser, RoleType::FullAccess])?;
-    SecretData::update_db(body.key.to_owned(), body.value.to_owned(), &sv.repo.pool)
-        .await
+    let author = must_auth_project(
+        &req,
+        vec![RoleType::SuperUser, RoleType::FullAccess],
+        body.project.as_deref(),
+    )?;
+    let version = SecretData::update_db(
+        body.key.to_owned(),
+        body.value.to_owned(),
+        body.project.to_owned(),
+        &author,
+        body.note.to_owned(),
+        &sv.repo.pool,
+    )
+    .await
+    .map_err(|e| {
+        InternalError::new(
+            format!("Failed to update secret: {}", e),
+            StatusCode::from_u16(500).unwrap(),
+        )
+    })?;
+    ok!(HttpResponse::Ok().body(version.to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct ListSecretQuery {
+    project: Option<String>,
+}
+
+// keys are only listed for the scopes the caller could read the values of
+fn can_read_scope(req: &HttpRequest, project: Option<&str>) -> bool {
+    must_auth_project(
+        req,
+        vec![RoleType::SuperUser, RoleType::FullAccess],
+        project,
+    )
+    .is_ok()
+}
+
+pub async fn handle_list_secrets(
+    sv: web::Data<Arc<ServerData>>,
+    query: web::Query<ListSecretQuery>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    // a token limited to a project lists that project
+    let project = query.project.clone().or(get_project_scope(&req));
+    let secrets = match &project {
+        Some(project) => {
+            must_auth_project(
+                &req,
+                vec![RoleType::SuperUser, RoleType::FullAccess],
+                Some(project),
+            )?;
+            SecretData::list_for_project_db(project, &sv.repo.pool).await
+        }
+        None => {
+            must_auth(
+                &req,
+                vec![
+                    RoleType::SuperUser,
+                    RoleType::FullAccess,
+                    RoleType::ReadOnly,
+                    RoleType::UpdateOnly,
+                ],
+            )?;
+            SecretData::list_db(&sv.repo.pool).await
+        }
+    };
+    let secret_list: Vec<HashMap<String, String>> = secrets
         .map_err(|_| {
             InternalError::new(
-                "Failed to update secret",
+                "Failed to get secret list",
                 StatusCode::from_u16(500).unwrap(),
             )
-        })?;
-    ok!(HttpResponse::Ok().body("OK"))
+        })?
+        .into_iter()
+        .filter(|s| project.is_some() || can_read_scope(&req, s.project.as_deref()))
+        .map(|s| {
+            let mut secret = HashMap::from([
+                ("key".to_string(), s.key),
+                ("created_at".to_string(), s.created_at),
+            ]);
+            if let Some(project) = s.project {
+                secret.insert("project".to_string(), project);
+            }
+            secret
+        })
+        .collect();
+    ok!(web::Json(secret_list))
 }
 
-pub async fn handle_list_secrets(
+pub async fn handle_secret_history(
+    sv: web::Data<Arc<ServerData>>,
+    body: web::Json<DeleteSecretBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth_project(
+        &req,
+        vec![RoleType::SuperUser, RoleType::FullAccess],
+        body.project.as_deref(),
+    )?;
+    let history: Vec<SecretVersion> =
+        SecretData::history_db(body.key.to_owned(), body.project.to_owned(), &sv.repo.pool)
+            .await
+            .map_err(|_| {
+                InternalError::new(
+                    "Failed to get secret history",
+                    StatusCode::from_u16(500).unwrap(),
+                )
+            })?
+            .into_iter()
+            .map(|v| SecretVersion {
+                version: v.version,
+                author: v.author,
+                note: v.note,
+                created_at: v.created_at,
+            })
+            .collect();
+    if history.is_empty() {
+        return Err(
+            InternalError::new("Secret not found", StatusCode::from_u16(404).unwrap()).into(),
+        );
+    }
+    ok!(web::Json(history))
+}
+
+pub async fn handle_revert_secret(
+    sv: web::Data<Arc<ServerData>>,
+    body: web::Json<RevertSecretBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    let author = must_auth_project(
+        &req,
+        vec![RoleType::SuperUser, RoleType::FullAccess],
+        body.project.as_deref(),
+    )?;
+    let version = SecretData::revert_db(
+        body.key.to_owned(),
+        body.project.to_owned(),
+        body.version,
+        &author,
+        &sv.repo.pool,
+    )
+    .await
+    .map_err(|e| {
+        InternalError::new(
+            format!("Failed to revert secret: {}", e),
+            StatusCode::from_u16(400).unwrap(),
+        )
+    })?;
+    ok!(HttpResponse::Ok().body(version.to_string()))
+}
+
+pub async fn handle_import_secrets(
     sv: web::Data<Arc<ServerData>>,
+    body: web::Json<ImportSecretsBody>,
     req: HttpRequest,
 ) -> Result<impl Responder> {
-    must_auth(
+    let author = must_auth_project(
         &req,
-        vec![
-            RoleType::SuperUser,
-            RoleType::FullAccess,
-            RoleType::ReadOnly,
-            RoleType::UpdateOnly,
-        ],
+        vec![RoleType::SuperUser, RoleType::FullAccess],
+        body.project.as_deref(),
     )?;
-    let secret_list: Vec<HashMap<String, String>> = SecretData::list_db(&sv.repo.pool)
+    let body = body.into_inner();
+    let report = SecretData::import_db(
+        body.secrets.into_iter().map(|s| (s.key, s.value)).collect(),
+        body.project,
+        body.overwrite,
+        &author,
+        body.note,
+        &sv.repo.pool,
+    )
+    .await
+    .map_err(|e| {
+        InternalError::new(
+            format!("Failed to import secrets: {}", e),
+            StatusCode::from_u16(400).unwrap(),
+        )
+    })?;
+    ok!(web::Json(report))
+}
+
+// values leave the server in plain text, only for the super user
+pub async fn handle_export_secrets(
+    sv: web::Data<Arc<ServerData>>,
+    body: web::Json<ExportSecretsBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth_project(&req, vec![RoleType::SuperUser], body.project.as_deref())?;
+    let secrets: Vec<SecretValue> = SecretData::list_scope_db(body.project.clone(), &sv.repo.pool)
         .await
         .map_err(|_| {
             InternalError::new(
-                "Failed to get secret list",
+                "Failed to export secrets",
                 StatusCode::from_u16(500).unwrap(),
             )
         })?
         .into_iter()
-        .map(|s| {
-            HashMap::from([
-                ("key".to_string(), s.key),
-                ("created_at".to_string(), s.created_at),
-            ])
+        .map(|s| SecretValue {
+            key: s.key,
+            value: s.value,
+            project: s.project,
+            version: s.version,
+            digest: String::new(),
         })
         .collect();
-    ok!(web::Json(secret_list))
+    ok!(web::Json(secrets))
+}
+
+pub async fn handle_rotate_master_key(
+    sv: web::Data<Arc<ServerData>>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    SecretData::rotate_master_key_db(&sv.repo.pool)
+        .await
+        .map_err(|e| {
+            InternalError::new(
+                format!("Failed to rotate master key: {}", e),
+                StatusCode::from_u16(500).unwrap(),
+            )
+        })?;
+    ok!(HttpResponse::Ok().body("OK"))
 }
 
 #[derive(Deserialize, Debug)]
 pub struct AddSecretBody {
     pub key: String,
     pub value: String,
+    pub project: Option<String>,
+    pub note: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportSecret {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+pub struct ImportSecretsBody {
+    pub secrets: Vec<ImportSecret>,
+    pub project: Option<String>,
+    #[serde(default)]
+    pub overwrite: bool,
+    pub note: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportSecretsBody {
+    pub project: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RevertSecretBody {
+    pub key: String,
+    pub project: Option<String>,
+    pub version: i64,
 }
@@ -0,0 +1,101 @@
+use std::{borrow::Borrow, sync::Arc};
+
+use actix_web::{
+    error::InternalError, http::StatusCode, web, HttpRequest, HttpResponse, Responder, Result,
+};
+use shared::{ok, SsoLogin, SsoTokenBody};
+
+use crate::repo::{
+    oidc_repo::{role_for_groups, DevicePoll, OidcConfig},
+    user_repo::User,
+};
+
+use super::{
+    audit_handler::set_audit_user,
+    auth_handler::{create_session, must_have_levpass},
+    ServerData,
+};
+
+fn must_have_sso(sd: &ServerData) -> Result<&OidcConfig> {
+    sd.oidc.as_ref().ok_or(
+        InternalError::new(
+            "SSO is not configured on this server",
+            StatusCode::from_u16(404).unwrap(),
+        )
+        .into(),
+    )
+}
+
+// starts a device login at the sso provider, the cli shows the code to the user
+pub async fn handle_sso_device(
+    sd: web::Data<Arc<ServerData>>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_have_levpass(&req)?;
+    let oidc = must_have_sso(&sd)?;
+    let device = oidc.start_device_flow().await.map_err(|e| {
+        InternalError::new(
+            format!("Failed to start sso login: {}", e),
+            StatusCode::from_u16(502).unwrap(),
+        )
+    })?;
+    ok!(HttpResponse::Ok().json(device))
+}
+
+// polled by the cli, 202 while the user hasn't approved the login yet
+pub async fn handle_sso_token(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<SsoTokenBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_have_levpass(&req)?;
+    let oidc = must_have_sso(&sd)?;
+    let poll = oidc
+        .poll_device_flow(&body.device_code)
+        .await
+        .map_err(|e| {
+            InternalError::new(
+                format!("Failed to login with sso: {}", e),
+                StatusCode::from_u16(401).unwrap(),
+            )
+        })?;
+    let identity = match poll {
+        DevicePoll::Pending => ok!(HttpResponse::Accepted().body("authorization_pending")),
+        DevicePoll::SlowDown => ok!(HttpResponse::Accepted().body("slow_down")),
+        DevicePoll::Done(identity) => identity,
+    };
+    let Some(role) = role_for_groups(&oidc.group_roles, &identity.groups) else {
+        return Err(InternalError::new(
+            format!(
+                "None of the groups of {} has a role here",
+                identity.username
+            ),
+            StatusCode::from_u16(403).unwrap(),
+        )
+        .into());
+    };
+    let pool = sd.repo.pool.borrow();
+    let user = User::sso_login_db(&identity.subject, &identity.username, role, pool)
+        .await
+        .map_err(|e| {
+            InternalError::new(
+                format!("Failed to login with sso: {}", e),
+                StatusCode::from_u16(409).unwrap(),
+            )
+        })?;
+    set_audit_user(&req, &user.username, &user.role.to_string());
+    if user.disabled {
+        return Err(
+            InternalError::new("User is disabled", StatusCode::from_u16(403).unwrap()).into(),
+        );
+    }
+    let tokens = create_session(&user.username, user.role, oidc.session_ttl, pool)
+        .await
+        .map_err(|_| {
+            InternalError::new("Failed to login user", StatusCode::from_u16(500).unwrap())
+        })?;
+    ok!(HttpResponse::Ok().json(SsoLogin {
+        username: user.username,
+        tokens,
+    }))
+}
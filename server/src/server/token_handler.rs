@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use actix_web::{
+    error::InternalError, http::StatusCode, web, HttpRequest, HttpResponse, Responder, Result,
+};
+use serde::Deserialize;
+use shared::{ok, ApiTokenInfo};
+
+use crate::{
+    repo::{
+        token_repo::ApiToken,
+        user_repo::{RoleType, ROLE_NAMES},
+    },
+    server::auth_handler::must_auth,
+};
+
+use super::ServerData;
+
+#[derive(Deserialize, Debug)]
+pub struct CreateTokenBody {
+    pub name: String,
+    pub role: String,
+    pub project: Option<String>,
+    // seconds, no expiry when missing
+    pub expires_in: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RevokeTokenQuery {
+    pub name: String,
+}
+
+// the token is only in this response, the server keeps its hash
+pub async fn handle_create_token(
+    sd: web::Data<Arc<ServerData>>,
+    body: web::Json<CreateTokenBody>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    let author = must_auth(&req, vec![RoleType::SuperUser])?;
+    if !ROLE_NAMES.contains(&body.role.as_str()) {
+        return Err(InternalError::new(
+            format!("Unknown role {}, use {}", body.role, ROLE_NAMES.join(", ")),
+            StatusCode::from_u16(400).unwrap(),
+        )
+        .into());
+    }
+    if body.name.trim().is_empty() {
+        return Err(InternalError::new(
+            "Token name can't be empty",
+            StatusCode::from_u16(400).unwrap(),
+        )
+        .into());
+    }
+    let exists = ApiToken::list_db(&sd.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new("Failed to get tokens", StatusCode::from_u16(500).unwrap())
+        })?
+        .iter()
+        .any(|t| t.name == body.name);
+    if exists {
+        return Err(InternalError::new(
+            "Token already exists, revoke it first or use another name",
+            StatusCode::from_u16(409).unwrap(),
+        )
+        .into());
+    }
+    let (api_token, token) = ApiToken::new(
+        body.name.clone(),
+        body.role.clone(),
+        body.project.clone(),
+        author,
+        body.expires_in,
+    )
+    .map_err(|e| InternalError::new(format!("{}", e), StatusCode::from_u16(400).unwrap()))?;
+    api_token.insert_db(&sd.repo.pool).await.map_err(|_| {
+        InternalError::new("Failed to save token", StatusCode::from_u16(500).unwrap())
+    })?;
+    ok!(HttpResponse::Ok().body(token))
+}
+
+pub async fn handle_list_tokens(
+    sd: web::Data<Arc<ServerData>>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    let tokens: Vec<ApiTokenInfo> = ApiToken::list_db(&sd.repo.pool)
+        .await
+        .map_err(|_| {
+            InternalError::new("Failed to get tokens", StatusCode::from_u16(500).unwrap())
+        })?
+        .iter()
+        .map(|t| t.to_info())
+        .collect();
+    ok!(web::Json(tokens))
+}
+
+pub async fn handle_revoke_token(
+    sd: web::Data<Arc<ServerData>>,
+    query: web::Query<RevokeTokenQuery>,
+    req: HttpRequest,
+) -> Result<impl Responder> {
+    must_auth(&req, vec![RoleType::SuperUser])?;
+    ApiToken::revoke_db(&query.name, &sd.repo.pool)
+        .await
+        .map_err(|e| InternalError::new(format!("{}", e), StatusCode::from_u16(404).unwrap()))?;
+    ok!(HttpResponse::Ok().body("OK"))
+}
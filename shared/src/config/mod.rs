@@ -10,6 +10,11 @@ pub struct MainConfig {
     pub project: String,
     pub apps: Option<HashMap<String, AppConfig>>,
     pub services: Option<HashMap<String, ServiceConfig>>,
+    // html file shown by `lev maintenance on`, relative to the config file
+    #[serde(rename = "maintenance-page")]
+    pub maintenance_page: Option<String>,
+    // the cli context the config is meant for, `lev` warns when another one is used
+    pub server: Option<String>,
 }
 
 #[skip_serializing_none]
@@ -43,6 +48,11 @@ pub struct AppConfig {
     #[serde(rename = "health-check")]
     pub health_check: Option<HealthCheck>,
     pub restart: Option<String>,
+    pub strategy: Option<String>,
+    #[serde(rename = "grace-period")]
+    pub grace_period: Option<u32>,
+    pub secrets: Option<Vec<ConfigSecret>>,
+    pub files: Option<Vec<ConfigFile>>,
 }
 
 #[skip_serializing_none]
@@ -70,6 +80,11 @@ pub struct ServiceConfig {
     #[serde(rename = "health-check")]
     pub health_check: Option<HealthCheck>,
     pub restart: Option<String>,
+    pub strategy: Option<String>,
+    #[serde(rename = "grace-period")]
+    pub grace_period: Option<u32>,
+    pub secrets: Option<Vec<ConfigSecret>>,
+    pub files: Option<Vec<ConfigFile>>,
 }
 
 #[skip_serializing_none]
@@ -79,6 +94,42 @@ pub struct ConfigProxy {
     pub domain: String,
     pub port: u16,
     pub path_prefix: Option<String>,
+    pub sticky: Option<StickyConfig>,
+    #[serde(rename = "health-path")]
+    pub health_path: Option<String>,
+    #[serde(rename = "health-interval")]
+    pub health_interval: Option<u32>,
+    #[serde(rename = "pass-host-header")]
+    pub pass_host_header: Option<bool>,
+    pub scheme: Option<String>,
+}
+
+// stored secret mounted as a file, /run/secrets/<key> by default
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigSecret {
+    pub key: String,
+    pub target: Option<String>,
+}
+
+// inline template mounted as a file, ${secret.*} in it is filled in on the server at deploy
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub target: String,
+    pub content: String,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StickyConfig {
+    pub cookie: Option<String>,
+    pub secure: Option<bool>,
+    #[serde(rename = "http-only")]
+    pub http_only: Option<bool>,
 }
 
 #[skip_serializing_none]
@@ -124,6 +175,16 @@ impl MainConfig {
     pub fn to_string(&self) -> String {
         serde_yaml::to_string(self).unwrap()
     }
+
+    // the project of a config whose smart strings are not resolved yet
+    pub fn get_project_name(s: &str) -> Result<String, Box<dyn Error>> {
+        let value: serde_yaml::Value = serde_yaml::from_str(s)?;
+        let project = value
+            .get("project")
+            .and_then(|p| p.as_str())
+            .ok_or("project is missing in the config")?;
+        Ok(project.to_string())
+    }
 }
 
 #[test]
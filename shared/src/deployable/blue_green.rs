@@ -0,0 +1,298 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use tokio::time::sleep;
+
+use crate::{docker::service::ServiceParam, docker::DockerService, get_unix_millis, ok};
+
+use super::{
+    deploy::{Deploy, DeployAction, DeployColor, DeployLifecycle, DeployTask, HealthCheckable},
+    task::{run_deploy_task, wait_for_running},
+};
+
+const START_TIMEOUT_SEC: u64 = 300;
+const DEFAULT_GRACE_PERIOD_SEC: u32 = 60;
+const BASE_ROUTER_PRIORITY: i64 = 1000;
+// unix seconds on the live color after which the other color is removed, kept on the
+// service so a restarted manager can still remove it
+const RETIRE_AFTER_LABEL: &str = "lev.retire-after";
+
+pub fn get_color_service_name(service_name: &str, color: DeployColor) -> String {
+    format!("{}-{}", service_name, color.as_str())
+}
+
+impl Deploy {
+    // brings up the whole new color next to the old one, waits until all of its tasks are
+    // running, then moves the routes to it with a single service update. The old color
+    // stays around for the grace period, so rollback can switch back without a restart.
+    pub async fn deploy_blue_green(
+        &self,
+        docker: DockerService,
+        service_names: Vec<String>,
+    ) -> Result<()> {
+        if self.lifecycle != DeployLifecycle::Always {
+            ok!(())
+        }
+        match self.action {
+            DeployAction::Create | DeployAction::Update => {}
+            DeployAction::Delete => {
+                if service_names.contains(&self.deployable.service_name) {
+                    docker
+                        .delete_service(self.deployable.service_name.clone())
+                        .await?;
+                }
+                return self.delete_colors(docker, &service_names).await;
+            }
+            DeployAction::Nothing => ok!(()),
+        }
+
+        let color = self.color.unwrap_or(DeployColor::Blue);
+        let name = get_color_service_name(&self.deployable.service_name, color);
+        let old_names: Vec<String> = [
+            get_color_service_name(&self.deployable.service_name, color.opposite()),
+            self.deployable.service_name.clone(),
+        ]
+        .into_iter()
+        .filter(|n| service_names.contains(n))
+        .collect();
+
+        let mut params = self.color_params(&name)?;
+        params
+            .labels
+            .insert("traefik.enable".into(), "false".into());
+        if service_names.contains(&name) {
+            println!("updating {} before switching to it", name);
+            docker.update_service(params).await?;
+            run_deploy_task(
+                DeployTask::HealthCheck(HealthCheckable {
+                    service_name: name.clone(),
+                    wait_sec: 5,
+                }),
+                docker.clone(),
+            )
+            .await?;
+        } else {
+            println!("creating {}", name);
+            docker.create_service(params).await?;
+        }
+        wait_for_running(
+            name.clone(),
+            docker.clone(),
+            Duration::from_secs(START_TIMEOUT_SEC),
+        )
+        .await?;
+
+        let grace = self
+            .deployable
+            .grace_period
+            .unwrap_or(DEFAULT_GRACE_PERIOD_SEC);
+        let priority = next_router_priority(&docker, &old_names).await;
+        let mut params = self.color_params(&name)?;
+        params.labels = self
+            .deployable
+            .get_proxy_labels(&name, true, Some(priority));
+        params.labels.extend(self.deployable.user_labels.clone());
+        params.labels.insert(
+            RETIRE_AFTER_LABEL.into(),
+            (unix_seconds() + grace as u64).to_string(),
+        );
+        docker.update_service(params).await?;
+        println!(
+            "switched {} to {}",
+            self.deployable.short_name,
+            color.as_str()
+        );
+
+        // the plain service name doesn't exist in blue-green, checks go to the live color
+        for mut task in self.after_tasks.clone() {
+            if let DeployTask::HealthCheck(check) = &mut task {
+                if check.service_name == self.deployable.service_name {
+                    check.service_name = name.clone();
+                }
+            }
+            run_deploy_task(task, docker.clone()).await?;
+        }
+
+        for old_name in old_names {
+            let version = docker
+                .inspect_service(old_name.clone())
+                .await?
+                .version
+                .and_then(|v| v.index);
+            retire_later(docker.clone(), old_name, version, grace as u64);
+        }
+        ok!(())
+    }
+
+    pub async fn delete_colors(
+        &self,
+        docker: DockerService,
+        service_names: &[String],
+    ) -> Result<()> {
+        for color in [DeployColor::Blue, DeployColor::Green] {
+            let name = get_color_service_name(&self.deployable.service_name, color);
+            if service_names.contains(&name) {
+                docker.delete_service(name).await?;
+            }
+        }
+        ok!(())
+    }
+
+    fn color_params(&self, name: &str) -> Result<ServiceParam> {
+        let mut params = self
+            .deployable
+            .to_docker_params(self.network_name.clone(), true)?;
+        params.name = name.to_string();
+        params.network_aliases = vec![self.deployable.service_name.clone()];
+        params.labels = self.deployable.user_labels.clone();
+        params.container_labels = Some(self.deployable.user_labels.clone());
+        ok!(params)
+    }
+}
+
+fn unix_seconds() -> u64 {
+    (get_unix_millis() / 1000) as u64
+}
+
+fn retire_later(docker: DockerService, name: String, version: Option<u64>, after_sec: u64) {
+    tokio::spawn(async move {
+        sleep(Duration::from_secs(after_sec)).await;
+        if let Err(e) = retire_service(docker, name.clone(), version).await {
+            println!("failed to remove {}: {}", name, e);
+        }
+    });
+}
+
+struct ColorService {
+    name: String,
+    // of its routes, -1 when it has none
+    priority: i64,
+    retire_after: u64,
+    version: Option<u64>,
+}
+
+// both colors of a service still run when the manager stopped during the grace period,
+// on startup the one that isn't live is removed once its grace period is over
+pub async fn reconcile_colors(docker: DockerService) -> Result<()> {
+    let mut colors: HashMap<String, Vec<ColorService>> = HashMap::new();
+    for service in docker.list_services().await? {
+        let Some(spec) = service.spec else {
+            continue;
+        };
+        let Some(name) = spec.name else {
+            continue;
+        };
+        let version = service.version.and_then(|v| v.index);
+        let labels = spec.labels.unwrap_or_default();
+        if let Some((base, color)) = color_service(name, &labels, version) {
+            colors.entry(base).or_default().push(color);
+        }
+    }
+    for (_, mut pair) in colors.into_iter().filter(|(_, pair)| pair.len() > 1) {
+        // the live color has the routes with the highest priority
+        pair.sort_by_key(|color| color.priority);
+        let Some(live) = pair.pop() else {
+            continue;
+        };
+        // without a clear winner nothing is removed
+        if live.priority < 0 || pair.iter().any(|c| c.priority == live.priority) {
+            continue;
+        }
+        for color in pair {
+            let wait = live.retire_after.saturating_sub(unix_seconds());
+            println!(
+                "{} is live, removing {} in {}s",
+                live.name, color.name, wait
+            );
+            retire_later(docker.clone(), color.name, color.version, wait);
+        }
+    }
+    ok!(())
+}
+
+// only services a switch made live carry the retire label, others that just end with a
+// color are left alone
+fn color_service(
+    name: String,
+    labels: &HashMap<String, String>,
+    version: Option<u64>,
+) -> Option<(String, ColorService)> {
+    let base = [DeployColor::Blue, DeployColor::Green]
+        .iter()
+        .find_map(|c| name.strip_suffix(&format!("-{}", c.as_str())))?
+        .to_string();
+    let retire_after = labels.get(RETIRE_AFTER_LABEL)?.parse().ok()?;
+    let routed = labels.get("traefik.enable").is_some_and(|v| v == "true");
+    let priority = if routed {
+        router_priority(labels).unwrap_or(0)
+    } else {
+        -1
+    };
+    Some((
+        base,
+        ColorService {
+            name,
+            priority,
+            retire_after,
+            version,
+        },
+    ))
+}
+
+fn router_priority(labels: &HashMap<String, String>) -> Option<i64> {
+    labels
+        .iter()
+        .filter(|(key, _)| key.starts_with("traefik.http.routers.") && key.ends_with(".priority"))
+        .filter_map(|(_, value)| value.parse::<i64>().ok())
+        .max()
+}
+
+// the new color has to win over every route of the old one
+async fn next_router_priority(docker: &DockerService, old_names: &[String]) -> i64 {
+    let mut priority = BASE_ROUTER_PRIORITY;
+    for name in old_names {
+        let Ok(service) = docker.inspect_service(name.clone()).await else {
+            continue;
+        };
+        let labels = service.spec.and_then(|s| s.labels).unwrap_or_default();
+        if let Some(p) = router_priority(&labels) {
+            priority = priority.max(p + 1);
+        }
+    }
+    priority
+}
+
+// deletes the old color unless it was updated after the switch, which means a later
+// deploy or rollback made it active again
+async fn retire_service(docker: DockerService, name: String, version: Option<u64>) -> Result<()> {
+    let current = docker
+        .inspect_service(name.clone())
+        .await?
+        .version
+        .and_then(|v| v.index);
+    if current != version {
+        println!("{} was changed after the switch, keeping it", name);
+        ok!(())
+    }
+    docker.delete_service(name.clone()).await?;
+    println!("removed old color {}", name);
+    ok!(())
+}
+
+#[test]
+fn color_service_test() {
+    let mut labels = HashMap::from([
+        ("traefik.enable".to_string(), "true".to_string()),
+        (
+            "traefik.http.routers.api.priority".to_string(),
+            "1001".to_string(),
+        ),
+    ]);
+    assert!(color_service("api-blue".to_string(), &labels, Some(1)).is_none());
+    labels.insert(RETIRE_AFTER_LABEL.to_string(), "100".to_string());
+    let (base, color) = color_service("api-blue".to_string(), &labels, Some(1)).unwrap();
+    assert_eq!(base, "api");
+    assert_eq!(color.priority, 1001);
+    assert_eq!(color.retire_after, 100);
+    assert!(color_service("api".to_string(), &labels, Some(1)).is_none());
+}
@@ -2,12 +2,18 @@ use std::{collections::HashMap, str::FromStr, sync::Arc};
 
 use crate::{
     config::MainConfig,
-    deployable::{get_parsed_config, get_regex_parsed_config},
+    deployable::{get_parsed_config, get_regex_parsed_config, get_secret_refs},
     docker::DockerService,
-    err, ok, SecretValue,
+    err, ok, SecretRef, SecretValue,
 };
 
-use super::{task::run_deploy_task, Buildable, Connectable, Deployable};
+use super::{
+    masking::mask_secrets,
+    mounted::{check_targets, get_mounted_files, get_mounted_secrets, take_mounts},
+    smart::SmartContext,
+    task::run_deploy_task,
+    Buildable, Connectable, Deployable,
+};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
@@ -129,6 +135,12 @@ pub struct Deploy {
     pub client_tasks: Vec<DeployTask>,
     pub action: DeployAction,
     pub network_name: String,
+    // active color of blue-green deployables after this deploy
+    #[serde(default)]
+    pub color: Option<DeployColor>,
+    // secret versions the deployable was rendered with
+    #[serde(default)]
+    pub secrets: Vec<SecretRef>,
 }
 
 impl PartialEq for Deploy {
@@ -167,6 +179,48 @@ pub enum DeployAction {
     Nothing,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+pub enum DeployStrategy {
+    #[default]
+    Rolling,
+    BlueGreen,
+}
+
+impl DeployStrategy {
+    pub fn from_config(strategy: Option<String>) -> Result<Self> {
+        match strategy.as_deref() {
+            None | Some("rolling") => ok!(DeployStrategy::Rolling),
+            Some("blue-green") => ok!(DeployStrategy::BlueGreen),
+            Some(s) => err!(anyhow!(
+                "unknown strategy: {}, use \"rolling\" or \"blue-green\"",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DeployColor {
+    Blue,
+    Green,
+}
+
+impl DeployColor {
+    pub fn opposite(&self) -> Self {
+        match self {
+            DeployColor::Blue => DeployColor::Green,
+            DeployColor::Green => DeployColor::Blue,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            DeployColor::Blue => "blue",
+            DeployColor::Green => "green",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PlanParamaters {
     pub main_config: String,
@@ -176,10 +230,17 @@ pub struct PlanParamaters {
     pub filter: Option<Vec<String>>,
     pub to_build: Vec<String>,
     pub images: Vec<String>,
+    // values of ${env.*} from the machine running lev
+    pub envs: HashMap<String, String>,
+    // number of this deploy of the project, for ${deploy.revision}
+    pub revision: u64,
 }
 
 impl Deploy {
     pub async fn deploy(&self, docker: DockerService, service_names: Vec<String>) -> Result<()> {
+        if self.deployable.strategy == DeployStrategy::BlueGreen {
+            return self.deploy_blue_green(docker, service_names).await;
+        }
         match self.lifecycle {
             DeployLifecycle::Always => match self.action {
                 DeployAction::Update => {
@@ -191,7 +252,7 @@ impl Deploy {
                     for task in self.after_tasks.clone() {
                         run_deploy_task(task, docker.clone()).await?;
                     }
-                    Ok(())
+                    self.delete_colors(docker, &service_names).await
                 }
                 DeployAction::Create => {
                     if service_names.contains(&self.deployable.service_name) {
@@ -202,7 +263,7 @@ impl Deploy {
                     for task in self.after_tasks.clone() {
                         run_deploy_task(task, docker.clone()).await?;
                     }
-                    Ok(())
+                    self.delete_colors(docker, &service_names).await
                 }
                 DeployAction::Delete => {
                     if service_names.contains(&self.deployable.service_name) {
@@ -241,6 +302,21 @@ impl Deploy {
     }
 }
 
+// config of an app or service as written, before smart strings are resolved
+fn get_raw_config(config: &MainConfig, name: &str) -> String {
+    let app = config
+        .apps
+        .as_ref()
+        .and_then(|a| a.get(name))
+        .and_then(|a| serde_yaml::to_string(a).ok());
+    let service = config
+        .services
+        .as_ref()
+        .and_then(|s| s.get(name))
+        .and_then(|s| serde_yaml::to_string(s).ok());
+    app.or(service).unwrap_or_default()
+}
+
 pub fn plan(mut params: PlanParamaters) -> Result<Vec<Deploy>> {
     let main_config = MainConfig::from_str(&params.main_config)
         .map_err(|e| anyhow!("cannot parse last config: {}", e.to_string()))?;
@@ -268,24 +344,52 @@ pub fn plan(mut params: PlanParamaters) -> Result<Vec<Deploy>> {
 
     // get all configuration // all logic is here
     let connectables = config_to_connectable(main_config.clone())?;
-    let mconfig = get_regex_parsed_config(
-        main_config.to_string().as_str(),
-        &connectables,
-        &params.secrets,
-    )?;
+    // mounted files keep their secrets until the deploy, so they skip the regex parsing
+    let mut unmounted_config = main_config.clone();
+    let mut mounts = take_mounts(&mut unmounted_config);
+    // deployables get masked secrets and are rendered on the server at deploy, build args
+    // need the real values since images are built before that
+    let ctx = SmartContext {
+        connectables: connectables.clone(),
+        secrets: params.secrets.clone(),
+        envs: params.envs.clone(),
+        project: main_config.project.clone(),
+        revision: params.revision,
+    };
+    let masked_ctx = SmartContext {
+        secrets: mask_secrets(&params.secrets),
+        ..ctx.clone()
+    };
+    let mconfig = get_regex_parsed_config(unmounted_config.to_string().as_str(), &masked_ctx)?;
+    let build_config = get_regex_parsed_config(unmounted_config.to_string().as_str(), &ctx)?;
     let buildables = config_to_buildables(
-        mconfig.clone(),
+        build_config,
         Some(params.to_build.clone()),
         params.images.clone(),
         params.filter.clone(),
     )?;
     dbg!("parsed config: {}", &mconfig);
-    let deployables = config_to_deployable(mconfig, buildables.clone(), params.images.clone())?;
+    let mut deployables = config_to_deployable(mconfig, buildables.clone(), params.images.clone())?;
+    for d in deployables.iter_mut() {
+        let (secrets, files) = mounts.remove(&d.short_name).unwrap_or_default();
+        d.secrets = get_mounted_secrets(secrets, &params.secrets)?;
+        d.files = get_mounted_files(files, &ctx)?;
+        check_targets(&d.short_name, &d.secrets, &d.files)?;
+    }
 
     // get this time deploys // without comparing with last one
     let main_deploys: Vec<_> = deployables
         .into_iter()
         .map::<Result<Deploy>, _>(|d| {
+            let mut secrets = get_secret_refs(
+                &get_raw_config(&main_config, &d.short_name),
+                &params.secrets,
+            )?;
+            for secret_ref in d.get_mounted_refs() {
+                if !secrets.contains(&secret_ref) {
+                    secrets.push(secret_ref);
+                }
+            }
             Ok(Deploy {
                 deployable: d.clone(),
                 lifecycle: DeployLifecycle::Always,
@@ -309,6 +413,8 @@ pub fn plan(mut params: PlanParamaters) -> Result<Vec<Deploy>> {
                 },
                 action: DeployAction::Nothing,
                 network_name: params.network_name.clone(),
+                color: None,
+                secrets,
             })
         })
         .collect();
@@ -380,7 +486,7 @@ pub fn plan(mut params: PlanParamaters) -> Result<Vec<Deploy>> {
     }
 
     // find deploys to delete
-    if let Some(last_deploy) = last_deploys {
+    if let Some(last_deploy) = last_deploys.clone() {
         let deploys_to_delete = last_deploy
             .into_iter()
             .filter(|d| {
@@ -396,5 +502,69 @@ pub fn plan(mut params: PlanParamaters) -> Result<Vec<Deploy>> {
             final_deploys.push(deploy);
         }
     }
+
+    // blue-green deploys switch to the other color on every change
+    for deploy in final_deploys.iter_mut() {
+        if deploy.deployable.strategy != DeployStrategy::BlueGreen {
+            deploy.color = None;
+            continue;
+        }
+        let last_color = last_deploys.as_ref().and_then(|ds| {
+            ds.iter()
+                .find(|d| d.deployable.short_name == deploy.deployable.short_name)
+                .and_then(|d| d.color)
+        });
+        deploy.color = match deploy.action {
+            DeployAction::Create | DeployAction::Update => Some(
+                last_color
+                    .map(|c| c.opposite())
+                    .unwrap_or(DeployColor::Blue),
+            ),
+            DeployAction::Nothing | DeployAction::Delete => {
+                Some(last_color.unwrap_or(DeployColor::Blue))
+            }
+        };
+    }
     ok!(final_deploys)
 }
+
+#[test]
+fn blue_green_plan_switches_color() {
+    let config = |image: &str| {
+        format!(
+            "
+    project: bg
+    services:
+        web:
+            image: {}
+            domain: bg.example.com
+            port: 80
+            strategy: blue-green
+    ",
+            image
+        )
+    };
+    let params = |main_config: String, last_deploys: Vec<(String, String)>| PlanParamaters {
+        main_config,
+        last_deploys,
+        secrets: vec![],
+        network_name: "lev".to_string(),
+        filter: None,
+        to_build: vec![],
+        images: vec![],
+        envs: HashMap::new(),
+        revision: 1,
+    };
+    let first = plan(params(config("nginx:1"), vec![])).unwrap();
+    assert_eq!(first[0].action, DeployAction::Create);
+    assert_eq!(first[0].color, Some(DeployColor::Blue));
+
+    let last = vec![("bg".to_string(), serde_json::to_string(&first).unwrap())];
+    let same = plan(params(config("nginx:1"), last.clone())).unwrap();
+    assert_eq!(same[0].action, DeployAction::Nothing);
+    assert_eq!(same[0].color, Some(DeployColor::Blue));
+
+    let changed = plan(params(config("nginx:2"), last)).unwrap();
+    assert_eq!(changed[0].action, DeployAction::Update);
+    assert_eq!(changed[0].color, Some(DeployColor::Green));
+}
@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{err, ok};
+
+use super::smart::{parse_smart_expr, SMART_STRING_PATTERN};
+
+const MAX_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum GenerateKind {
+    Alnum,
+    Uuid,
+    Hex,
+    Base64,
+}
+
+// ${generate.<name>:<kind>[:<length>]}, the value is created on the server by the first plan
+// and stored as the project secret <name>
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GeneratedSecret {
+    pub name: String,
+    pub kind: GenerateKind,
+    // characters for alnum and hex, bytes for base64
+    pub length: usize,
+}
+
+pub fn parse_generated(key: &str) -> Result<GeneratedSecret> {
+    let spec = key
+        .strip_prefix("generate.")
+        .ok_or(anyhow!("Invalid generated secret: {}", key))?;
+    let parts = spec.split(':').collect::<Vec<_>>();
+    if parts[0].is_empty() || parts.len() > 3 {
+        err!(anyhow!(
+            "Invalid generated secret, expected generate.<name>:<kind>[:<length>]: {}",
+            key
+        ))
+    }
+    let kind = match parts.get(1).copied().unwrap_or("alnum") {
+        "alnum" => GenerateKind::Alnum,
+        "uuid" => GenerateKind::Uuid,
+        "hex" => GenerateKind::Hex,
+        "base64" => GenerateKind::Base64,
+        kind => err!(anyhow!(
+            "Unknown kind of generated secret {}: {}, use alnum, uuid, hex or base64",
+            parts[0],
+            kind
+        )),
+    };
+    let length = match parts.get(2) {
+        Some(length) => length.parse::<usize>().map_err(|_| {
+            anyhow!(
+                "Invalid length of generated secret {}: {}",
+                parts[0],
+                length
+            )
+        })?,
+        None => 32,
+    };
+    if length == 0 || length > MAX_LENGTH {
+        err!(anyhow!(
+            "Length of generated secret {} must be between 1 and {}",
+            parts[0],
+            MAX_LENGTH
+        ))
+    }
+    ok!(GeneratedSecret {
+        name: parts[0].to_string(),
+        kind,
+        length,
+    })
+}
+
+// every generated secret of a raw config, a name used twice must have the same spec
+pub fn get_generated_secrets(raw: &str) -> Result<Vec<GeneratedSecret>> {
+    let re = Regex::new(SMART_STRING_PATTERN)?;
+    let mut generated: Vec<GeneratedSecret> = vec![];
+    for caps in re.captures_iter(raw) {
+        let Ok(expr) = parse_smart_expr(caps.get(1).unwrap().as_str()) else {
+            continue;
+        };
+        if !expr.key.starts_with("generate.") {
+            continue;
+        }
+        let secret = parse_generated(&expr.key)?;
+        match generated.iter().find(|g| g.name == secret.name) {
+            Some(g) if *g != secret => err!(anyhow!(
+                "Generated secret {} is used with different kinds or lengths",
+                secret.name
+            )),
+            Some(_) => {}
+            None => generated.push(secret),
+        }
+    }
+    ok!(generated)
+}
+
+#[test]
+fn generated_test() {
+    let secret = parse_generated("generate.db-pass:alnum:24").unwrap();
+    assert_eq!(secret.name, "db-pass");
+    assert_eq!(secret.kind, GenerateKind::Alnum);
+    assert_eq!(secret.length, 24);
+    assert_eq!(parse_generated("generate.key:base64").unwrap().length, 32);
+    assert!(parse_generated("generate.key:rsa").is_err());
+    assert!(parse_generated("generate.key:hex:0").is_err());
+
+    let raw = r#"
+    services:
+        db:
+            envs:
+                POSTGRES_PASSWORD: ${generate.db-pass:alnum:24}
+        api:
+            envs:
+                DATABASE_URL: postgres://app:${generate.db-pass:alnum:24}@db/app
+                SESSION_KEY: ${generate.session-key:hex}
+                COOKIE_KEY: ${generate.cookie-key:hex | base64}
+    "#;
+    let generated = get_generated_secrets(raw).unwrap();
+    assert_eq!(generated.len(), 3);
+    assert_eq!(generated[1].kind, GenerateKind::Hex);
+    assert_eq!(generated[2].name, "cookie-key");
+    assert!(get_generated_secrets("${generate.a:hex} ${generate.a:uuid}").is_err());
+}
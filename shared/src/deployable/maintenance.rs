@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    docker::{
+        service::{ServiceParam, ServiceSecret},
+        DockerService,
+    },
+    ok,
+};
+
+use super::{
+    deploy::{Deploy, DeployAction},
+    Deployable,
+};
+
+// above every router of deployed services, including blue-green switches
+const MAINTENANCE_ROUTER_PRIORITY: i64 = 1_000_000_000;
+const MAINTENANCE_IMAGE: &str = "nginx:alpine";
+const PAGE_TARGET: &str = "/usr/share/nginx/html/maintenance.html";
+const NGINX_CONF_TARGET: &str = "/etc/nginx/conf.d/default.conf";
+// longest secret name swarm accepts
+const MAX_NAME_LEN: usize = 64;
+
+pub const DEFAULT_MAINTENANCE_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>Under maintenance</title>
+    <style>
+        body { font-family: sans-serif; text-align: center; padding-top: 15vh; color: #333; }
+    </style>
+</head>
+<body>
+    <h1>We'll be back soon</h1>
+    <p>This site is under maintenance right now. Please check back in a few minutes.</p>
+</body>
+</html>
+"#;
+
+// every request gets the page with 503, so clients and crawlers don't cache it
+const NGINX_CONF: &str = r#"server {
+    listen 80;
+    root /usr/share/nginx/html;
+    error_page 503 /maintenance.html;
+    location = /maintenance.html {
+        internal;
+    }
+    location / {
+        return 503;
+    }
+}
+"#;
+
+pub fn get_maintenance_service_name(project_name: &str) -> String {
+    format!("{}-maintenance", project_name)
+}
+
+fn get_secret_label(project_name: &str) -> String {
+    format!("lev.maintenance={}", project_name)
+}
+
+// deployables that are live after the given deploys, the ones being deleted have no routes
+pub fn get_live_deployables(deploys: &[Deploy]) -> Vec<Deployable> {
+    deploys
+        .iter()
+        .filter(|d| d.action != DeployAction::Delete)
+        .map(|d| d.deployable.clone())
+        .collect()
+}
+
+// copies of the project routers that point to the maintenance service
+pub fn get_maintenance_labels(
+    project_name: &str,
+    deployables: &[Deployable],
+) -> HashMap<String, String> {
+    let service_name = get_maintenance_service_name(project_name);
+    let mut labels = HashMap::new();
+    for deployable in deployables {
+        let name = format!("{}-maintenance", deployable.service_name);
+        let proxy_labels =
+            deployable.get_proxy_labels(&name, true, Some(MAINTENANCE_ROUTER_PRIORITY));
+        for (key, value) in proxy_labels {
+            if key.starts_with("traefik.http.services.") {
+                continue;
+            }
+            if key.starts_with("traefik.http.routers.") && key.ends_with(".service") {
+                labels.insert(key, service_name.clone());
+                continue;
+            }
+            labels.insert(key, value);
+        }
+    }
+    labels.insert("traefik.enable".to_string(), "true".to_string());
+    labels.insert(
+        format!(
+            "traefik.http.services.{}.loadbalancer.server.port",
+            service_name
+        ),
+        "80".to_string(),
+    );
+    labels
+}
+
+pub async fn enable_maintenance(
+    docker: &DockerService,
+    project_name: &str,
+    page: &str,
+    deployables: &[Deployable],
+    network_name: &str,
+) -> Result<()> {
+    let service_name = get_maintenance_service_name(project_name);
+    let page_secret = ensure_secret(docker, project_name, "page", page).await?;
+    let conf_secret = ensure_secret(docker, project_name, "conf", NGINX_CONF).await?;
+
+    let mut params = ServiceParam::new(
+        service_name.clone(),
+        MAINTENANCE_IMAGE.to_string(),
+        network_name.to_string(),
+    );
+    params.change_limits(0.1, 64);
+    params.labels = get_maintenance_labels(project_name, deployables);
+    params.secrets = vec![
+        ServiceSecret {
+            id: page_secret.0,
+            name: page_secret.1.clone(),
+            target: PAGE_TARGET.to_string(),
+        },
+        ServiceSecret {
+            id: conf_secret.0,
+            name: conf_secret.1.clone(),
+            target: NGINX_CONF_TARGET.to_string(),
+        },
+    ];
+    if docker.is_service_exists(service_name.clone()).await {
+        docker.update_service(params).await?;
+    } else {
+        docker.create_service(params).await?;
+    }
+    remove_unused_secrets(docker, project_name, &[page_secret.1, conf_secret.1]).await;
+    ok!(())
+}
+
+pub async fn disable_maintenance(docker: &DockerService, project_name: &str) -> Result<()> {
+    let service_name = get_maintenance_service_name(project_name);
+    if docker.is_service_exists(service_name.clone()).await {
+        docker.delete_service(service_name).await?;
+    }
+    remove_unused_secrets(docker, project_name, &[]).await;
+    ok!(())
+}
+
+// swarm names have at most 64 characters, a long project name is cut and gets a hash of
+// the whole name so two projects starting the same don't share secrets
+fn get_secret_name(project_name: &str, kind: &str, hash: &str) -> String {
+    let suffix = format!("-maintenance-{}-{}", kind, hash);
+    if project_name.len() + suffix.len() <= MAX_NAME_LEN {
+        return format!("{}{}", project_name, suffix);
+    }
+    let project_hash = format!("{:x}", Sha256::digest(project_name.as_bytes()));
+    let keep = MAX_NAME_LEN.saturating_sub(suffix.len() + 9);
+    let mut cut = String::new();
+    for c in project_name.chars() {
+        if cut.len() + c.len_utf8() > keep {
+            break;
+        }
+        cut.push(c);
+    }
+    format!("{}-{}{}", cut, &project_hash[..8], suffix)
+}
+
+// returns id and name of the secret, the name carries the content hash
+async fn ensure_secret(
+    docker: &DockerService,
+    project_name: &str,
+    kind: &str,
+    content: &str,
+) -> Result<(String, String)> {
+    let hash = format!("{:x}", Sha256::digest(content.as_bytes()));
+    let name = get_secret_name(project_name, kind, &hash[..12]);
+    let existing = docker
+        .list_secrets_by_label(&get_secret_label(project_name))
+        .await?
+        .into_iter()
+        .find(|s| s.spec.as_ref().and_then(|s| s.name.as_ref()) == Some(&name));
+    if let Some(id) = existing.and_then(|s| s.id) {
+        ok!((id, name))
+    }
+    let labels = HashMap::from([("lev.maintenance".to_string(), project_name.to_string())]);
+    let id = docker
+        .create_secret(&name, content.as_bytes(), labels)
+        .await?;
+    ok!((id, name))
+}
+
+// secrets of the old page stay until the service stops using them
+async fn remove_unused_secrets(docker: &DockerService, project_name: &str, keep: &[String]) {
+    let Ok(secrets) = docker
+        .list_secrets_by_label(&get_secret_label(project_name))
+        .await
+    else {
+        return;
+    };
+    for secret in secrets {
+        let name = secret.spec.and_then(|s| s.name).unwrap_or_default();
+        if keep.contains(&name) {
+            continue;
+        }
+        if let Some(id) = secret.id {
+            if let Err(e) = docker.delete_secret(&id).await {
+                println!("failed to remove secret {}: {}", name, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn maintenance_labels_test() {
+    use crate::config::MainConfig;
+    use std::str::FromStr;
+
+    let raw_config = r#"
+    project: my-pro
+    services:
+        web:
+            image: web:1
+            domain: example.com
+            port: 3000
+        api:
+            image: api:1
+            proxy:
+                - domain: example.com
+                  port: 8080
+                  path_prefix: /api
+        db:
+            image: postgres
+    "#;
+    let config = MainConfig::from_str(raw_config).unwrap();
+    let deployables: Vec<_> = config
+        .services
+        .unwrap()
+        .into_iter()
+        .map(|(name, service)| {
+            Deployable::from_service_config(name, service, "my-pro".to_string()).unwrap()
+        })
+        .collect();
+    let labels = get_maintenance_labels("my-pro", &deployables);
+    let api_router = "traefik.http.routers.my-pro-api-service-maintenance-1";
+    assert_eq!(
+        labels.get(&format!("{}.rule", api_router)),
+        Some(&"Host(`example.com`) && PathPrefix(`/api`)".to_string())
+    );
+    assert_eq!(
+        labels.get(&format!("{}.service", api_router)),
+        Some(&"my-pro-maintenance".to_string())
+    );
+    assert_eq!(
+        labels.get(&format!("{}.priority", api_router)),
+        Some(&MAINTENANCE_ROUTER_PRIORITY.to_string())
+    );
+    let services: Vec<_> = labels
+        .keys()
+        .filter(|k| k.starts_with("traefik.http.services."))
+        .collect();
+    assert_eq!(
+        services,
+        vec!["traefik.http.services.my-pro-maintenance.loadbalancer.server.port"]
+    );
+}
+
+#[test]
+fn secret_name_test() {
+    assert_eq!(
+        get_secret_name("my-pro", "page", "0123456789ab"),
+        "my-pro-maintenance-page-0123456789ab"
+    );
+    let long = "a".repeat(60);
+    let name = get_secret_name(&long, "page", "0123456789ab");
+    assert_eq!(name.len(), MAX_NAME_LEN);
+    assert!(name.ends_with("-maintenance-page-0123456789ab"));
+    assert_ne!(
+        name,
+        get_secret_name(&format!("{}b", long), "page", "0123456789ab")
+    );
+}
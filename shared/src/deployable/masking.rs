@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{ok, SecretValue};
+
+use super::{
+    deploy::{Deploy, DeployTask},
+    find_secret,
+    smart::{transform, TRANSFORMS},
+    Deployable,
+};
+
+const TOKEN_PATTERN: &str = r"<secret:([A-Za-z0-9_\-\/\.]+):[0-9a-f]*((?:\|[a-z0-9]+)*)>";
+
+// stands for the value in plans and stored deploys, the digest changes with the value, so
+// comparing deploys still finds changed secrets
+pub fn mask_token(secret: &SecretValue) -> String {
+    format!("<secret:{}:{}>", secret.key, secret.digest)
+}
+
+pub fn is_masked(value: &str) -> bool {
+    value.contains("<secret:")
+}
+
+// <secret:key:digest|base64>, the transform is applied when the token is rendered
+pub fn transform_token(value: &str, name: &str) -> Option<String> {
+    let re = Regex::new(&format!("^{}$", TOKEN_PATTERN)).ok()?;
+    if !re.is_match(value) {
+        return None;
+    }
+    Some(format!("{}|{}>", value.strip_suffix('>')?, name))
+}
+
+pub fn mask_secrets(secrets: &[SecretValue]) -> Vec<SecretValue> {
+    secrets
+        .iter()
+        .map(|s| SecretValue {
+            value: mask_token(s),
+            ..s.clone()
+        })
+        .collect()
+}
+
+// tokens are matched by key, a deploy stored before a master key rotation still renders
+pub fn render_str(value: &str, secrets: &[SecretValue]) -> Result<String> {
+    let re = Regex::new(TOKEN_PATTERN)?;
+    let mut rendered = String::new();
+    let mut last_match_end = 0;
+    for caps in re.captures_iter(value) {
+        let mat = caps.get(0).unwrap();
+        let key = caps.get(1).unwrap().as_str();
+        let secret = find_secret(secrets, key).ok_or(anyhow!("Secret not found : {}", key))?;
+        let mut secret_value = secret.value.clone();
+        for name in caps.get(2).unwrap().as_str().split('|').skip(1) {
+            secret_value = transform(&secret_value, name)?;
+        }
+        rendered.push_str(&value[last_match_end..mat.start()]);
+        rendered.push_str(&secret_value);
+        last_match_end = mat.end();
+    }
+    rendered.push_str(&value[last_match_end..]);
+    ok!(rendered)
+}
+
+// transformed values are masked as well, so a base64 build arg doesn't show the secret
+fn mask_str(value: &str, secrets: &[SecretValue]) -> String {
+    let mut masked = value.to_string();
+    for s in secrets.iter().filter(|s| !s.value.is_empty()) {
+        let token = mask_token(s);
+        masked = masked.replace(&s.value, &token);
+        for name in TRANSFORMS {
+            if let Ok(transformed) = transform(&s.value, name) {
+                if transformed != s.value {
+                    masked = masked.replace(&transformed, &transform_token(&token, name).unwrap());
+                }
+            }
+        }
+    }
+    masked
+}
+
+fn map_strings(value: &mut Value, f: &dyn Fn(&str) -> Result<String>) -> Result<()> {
+    match value {
+        Value::String(s) => *s = f(s)?,
+        Value::Array(values) => {
+            for v in values.iter_mut() {
+                map_strings(v, f)?;
+            }
+        }
+        Value::Object(map) => {
+            for v in map.values_mut() {
+                map_strings(v, f)?;
+            }
+        }
+        _ => {}
+    }
+    ok!(())
+}
+
+impl Deployable {
+    // the deployable with real values, only to create the service, never stored
+    pub fn render_secrets(&self, secrets: &[SecretValue]) -> Result<Deployable> {
+        let mut value = serde_json::to_value(self)?;
+        map_strings(&mut value, &|s| render_str(s, secrets))?;
+        let mut rendered: Deployable = serde_json::from_value(value)?;
+        rendered.mounted = self.mounted.clone();
+        ok!(rendered)
+    }
+}
+
+impl Deploy {
+    // images are built by the client, so build args keep real values in plans for roles
+    // that can deploy
+    pub fn mask_build_args(&mut self, secrets: &[SecretValue]) {
+        for task in self.client_tasks.iter_mut() {
+            if let DeployTask::Build(buildable) = task {
+                for value in buildable.build_args.iter_mut().flat_map(|a| a.values_mut()) {
+                    *value = mask_str(value, secrets);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn masking_test() {
+    let secret = SecretValue {
+        key: "db-pass".to_string(),
+        value: "p4ss".to_string(),
+        project: None,
+        version: 1,
+        digest: "0a1b2c3d4e5f".to_string(),
+    };
+    let masked = mask_secrets(std::slice::from_ref(&secret));
+    assert_eq!(masked[0].value, "<secret:db-pass:0a1b2c3d4e5f>");
+    let url = format!("postgres://app:{}@db:5432", masked[0].value);
+    assert!(is_masked(&url));
+    assert_eq!(
+        render_str(&url, std::slice::from_ref(&secret)).unwrap(),
+        "postgres://app:p4ss@db:5432"
+    );
+    // digest from before a master key rotation
+    assert_eq!(
+        render_str(
+            "<secret:db-pass:ffffffffffff>",
+            std::slice::from_ref(&secret)
+        )
+        .unwrap(),
+        "p4ss"
+    );
+    assert!(render_str(&url, &[]).is_err());
+    let encoded = transform_token(&masked[0].value, "base64").unwrap();
+    assert_eq!(encoded, "<secret:db-pass:0a1b2c3d4e5f|base64>");
+    assert_eq!(
+        render_str(&encoded, std::slice::from_ref(&secret)).unwrap(),
+        "cDRzcw=="
+    );
+    assert!(transform_token("x<secret:db-pass:0a1b2c3d4e5f>", "base64").is_none());
+    assert_eq!(
+        mask_str("x=p4ss", &[secret]),
+        "x=<secret:db-pass:0a1b2c3d4e5f>"
+    );
+}
@@ -1,23 +1,35 @@
+pub mod blue_green;
 pub mod deploy;
+pub mod generated;
+pub mod maintenance;
+pub mod masking;
+pub mod mounted;
+pub mod preflight;
 pub mod rollback;
+pub mod smart;
 pub mod task;
 
 use std::{collections::HashMap, fmt::format, path::PathBuf, str::FromStr, u128};
 
 use anyhow::{anyhow, Result};
 use bollard::secret::TaskSpecRestartPolicyConditionEnum;
-use deploy::config_to_connectable;
+use deploy::{config_to_connectable, DeployStrategy};
+use generated::parse_generated;
+use mounted::{MountedFile, MountedSecret};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use smart::{
+    apply_transform, describe_position, parse_smart_expr, SmartContext, SMART_STRING_PATTERN,
+};
 
 use crate::{
-    config::{AppConfig, ConfigProxy, HealthCheck, MainConfig, ServiceConfig},
+    config::{AppConfig, ConfigProxy, HealthCheck, MainConfig, ServiceConfig, StickyConfig},
     docker::{
-        service::{ServiceMount, ServiceParam},
+        service::{ServiceMount, ServiceParam, ServiceSecret},
         DockerService,
     },
     docker_platform::get_docker_platform,
-    err, get_unix_millis, ok, SecretValue, SmartString,
+    err, get_unix_millis, ok, SecretRef, SecretValue, SmartString,
 };
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -49,13 +61,111 @@ pub struct Deployable {
 
     pub https_enabled: bool,
     pub healthcheck: Option<HealthCheck>,
+
+    #[serde(default)]
+    pub strategy: DeployStrategy,
+    #[serde(default)]
+    pub grace_period: Option<u32>,
+
+    #[serde(default)]
+    pub secrets: Vec<MountedSecret>,
+    #[serde(default)]
+    pub files: Vec<MountedFile>,
+    // swarm secrets created for this deploy by mount_secrets, never stored
+    #[serde(skip)]
+    pub mounted: Vec<ServiceSecret>,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Default)]
 pub struct ProxyParams {
     pub port: u16,
     pub path_prefix: String,
     pub domain: String,
+
+    // load balancer options of the traefik service
+    #[serde(default)]
+    pub sticky: Option<StickyConfig>,
+    #[serde(default)]
+    pub health_path: Option<String>,
+    #[serde(default)]
+    pub health_interval: Option<u32>,
+    #[serde(default)]
+    pub pass_host_header: Option<bool>,
+    #[serde(default)]
+    pub scheme: Option<String>,
+}
+
+impl ProxyParams {
+    pub fn from_config(p: ConfigProxy) -> Result<Self> {
+        if let Some(scheme) = &p.scheme {
+            if !["http", "https", "h2c"].contains(&scheme.as_str()) {
+                err!(anyhow!(
+                    "unknown scheme for {}: {}, use http, https or h2c",
+                    p.domain,
+                    scheme
+                ))
+            }
+        }
+        if p.health_interval.is_some() && p.health_path.is_none() {
+            err!(anyhow!(
+                "health-interval for {} requires health-path",
+                p.domain
+            ))
+        }
+        ok!(Self {
+            port: p.port,
+            path_prefix: p.path_prefix.unwrap_or("/".to_string()),
+            domain: p.domain,
+            sticky: p.sticky,
+            health_path: p.health_path,
+            health_interval: p.health_interval,
+            pass_host_header: p.pass_host_header,
+            scheme: p.scheme,
+        })
+    }
+
+    // traefik.http.services.<name>.loadbalancer.* labels besides the server port
+    pub fn get_loadbalancer_labels(&self, name: &str) -> HashMap<String, String> {
+        let prefix = format!("traefik.http.services.{}.loadbalancer", name);
+        let mut labels = HashMap::new();
+        if let Some(sticky) = &self.sticky {
+            labels.insert(format!("{}.sticky.cookie", prefix), "true".into());
+            if let Some(cookie) = &sticky.cookie {
+                labels.insert(format!("{}.sticky.cookie.name", prefix), cookie.clone());
+            }
+            if let Some(secure) = sticky.secure {
+                labels.insert(
+                    format!("{}.sticky.cookie.secure", prefix),
+                    secure.to_string(),
+                );
+            }
+            if let Some(http_only) = sticky.http_only {
+                labels.insert(
+                    format!("{}.sticky.cookie.httponly", prefix),
+                    http_only.to_string(),
+                );
+            }
+        }
+        if let Some(path) = &self.health_path {
+            labels.insert(format!("{}.healthcheck.path", prefix), path.clone());
+        }
+        if let Some(interval) = self.health_interval {
+            labels.insert(
+                format!("{}.healthcheck.interval", prefix),
+                format!("{}s", interval),
+            );
+        }
+        if let Some(pass_host_header) = self.pass_host_header {
+            labels.insert(
+                format!("{}.passhostheader", prefix),
+                pass_host_header.to_string(),
+            );
+        }
+        if let Some(scheme) = &self.scheme {
+            labels.insert(format!("{}.server.scheme", prefix), scheme.clone());
+        }
+        labels
+    }
 }
 
 pub fn get_last_image_tag(
@@ -115,17 +225,14 @@ impl Deployable {
                 port: config.port.unwrap(),
                 path_prefix: config.path_prefix.unwrap_or("/".to_string()),
                 domain: config.domain.unwrap(),
+                ..Default::default()
             }]
         } else {
             vec![]
         };
         if let Some(proxies) = config.proxy {
             for p in proxies {
-                proxy.push(ProxyParams {
-                    port: p.port,
-                    path_prefix: p.path_prefix.unwrap_or("/".to_string()),
-                    domain: p.domain,
-                });
+                proxy.push(ProxyParams::from_config(p)?);
             }
         }
 
@@ -150,6 +257,11 @@ impl Deployable {
             memory: config.memory.unwrap_or(1024) as u64,
             https_enabled: config.https.unwrap_or(true),
             healthcheck: config.health_check,
+            strategy: DeployStrategy::from_config(config.strategy)?,
+            grace_period: config.grace_period,
+            secrets: vec![],
+            files: vec![],
+            mounted: vec![],
         })
     }
 
@@ -163,17 +275,14 @@ impl Deployable {
                 port: config.port.unwrap(),
                 path_prefix: config.path_prefix.unwrap_or("/".to_string()),
                 domain: config.domain.unwrap(),
+                ..Default::default()
             }]
         } else {
             vec![]
         };
         if let Some(proxies) = config.proxy {
             for p in proxies {
-                proxy.push(ProxyParams {
-                    port: p.port,
-                    path_prefix: p.path_prefix.unwrap_or("/".to_string()),
-                    domain: p.domain,
-                });
+                proxy.push(ProxyParams::from_config(p)?);
             }
         }
 
@@ -198,6 +307,11 @@ impl Deployable {
             memory: config.memory.unwrap_or(1024) as u64,
             https_enabled: config.https.unwrap_or(true),
             healthcheck: config.health_check,
+            strategy: DeployStrategy::from_config(config.strategy)?,
+            grace_period: config.grace_period,
+            secrets: vec![],
+            files: vec![],
+            mounted: vec![],
         })
     }
 
@@ -250,6 +364,7 @@ impl Deployable {
             image: self.docker_image.clone(),
             network_name: network_name,
             labels: self.get_labels(is_https),
+            container_labels: None,
             exposed_ports: self.expose.clone().into_iter().map(|p| (p, p)).collect(),
             envs: self.envs.clone(),
             mounts: service_mounts,
@@ -261,10 +376,24 @@ impl Deployable {
             healthcheck: self.healthcheck.clone(),
             constraints: self.constraints.clone().unwrap_or(vec![]),
             restart: restart,
+            network_aliases: vec![],
+            rollback_on_failure: false,
+            secrets: self.mounted.clone(),
         })
     }
 
-    pub fn get_labels(&self, mut is_https: bool) -> HashMap<String, String> {
+    pub fn get_labels(&self, is_https: bool) -> HashMap<String, String> {
+        self.get_proxy_labels(&self.service_name, is_https, None)
+    }
+
+    // labels for routers and services named after `name`, blue-green deploys use the
+    // color service name here and a priority to take over the routes of the other color
+    pub fn get_proxy_labels(
+        &self,
+        name: &str,
+        mut is_https: bool,
+        priority: Option<i64>,
+    ) -> HashMap<String, String> {
         if !self.https_enabled {
             is_https = self.https_enabled;
         }
@@ -278,7 +407,7 @@ impl Deployable {
             proxy_counter += 1;
             let domain = &p.domain;
             let path_prefix = &p.path_prefix;
-            let host = &format!("{}-{}", self.service_name, proxy_counter);
+            let host = &format!("{}-{}", name, proxy_counter);
             let port = &p.port;
             let mut host_params = format!("Host(`{}`)", domain.clone());
             if path_prefix != "/" {
@@ -299,6 +428,13 @@ impl Deployable {
                 ),
                 port.to_string(),
             );
+            labels.extend(p.get_loadbalancer_labels(host));
+            if let Some(priority) = priority {
+                labels.insert(
+                    format!("traefik.http.routers.{}.priority", host.clone()),
+                    priority.to_string(),
+                );
+            }
             if is_https {
                 labels.insert(
                     format!("traefik.http.routers.{}.tls", host.clone()),
@@ -323,12 +459,8 @@ impl Deployable {
     }
 }
 
-pub fn get_regex_parsed_config(
-    config: &str,
-    connectables: &[Connectable],
-    secrets: &[SecretValue],
-) -> Result<MainConfig> {
-    let re = Regex::new(r"\$\{([A-Za-z0-9_\-\/\.]+)\}")?;
+pub fn get_regex_parsed_config(config: &str, ctx: &SmartContext) -> Result<MainConfig> {
+    let re = Regex::new(SMART_STRING_PATTERN)?;
 
     let mut replaced_config = String::new();
     let mut last_match_end = 0;
@@ -341,8 +473,14 @@ pub fn get_regex_parsed_config(
 
         let key = caps.get(1).unwrap().as_str();
         dbg!(&key);
-        let value = smarter_string(key, connectables, secrets)
-            .map_err(|_| anyhow!("Failed to resolve key: {}", key))?;
+        let value = smarter_string(key, ctx).map_err(|e| {
+            anyhow!(
+                "Failed to resolve ${{{}}} at {}: {}",
+                key,
+                describe_position(config, mat.start()),
+                e
+            )
+        })?;
         replaced_config.push_str(&value);
 
         last_match_end = mat.end();
@@ -355,52 +493,143 @@ pub fn get_regex_parsed_config(
     Ok(rconfig)
 }
 
-pub fn smarter_string(
-    s: &str,
-    connectables: &[Connectable],
-    secrets: &[SecretValue],
-) -> Result<String> {
-    let value = s.trim();
-    dbg!(&value);
-    if value.starts_with("secret.") {
-        let key = value
-            .strip_prefix("secret.")
-            .ok_or(anyhow!("Invalid secret key"))?;
-        let secret = secrets
-            .iter()
-            .find(|s| s.key == key)
-            .ok_or(anyhow!("Secret not found : {}", key))?;
-        ok!(secret.value.clone())
-    } else if value.starts_with("this.") {
-        let key = value
-            .strip_prefix("this.")
-            .ok_or(anyhow!("Invalid connectable key"))?;
-        dbg!(&key);
-        let parts = key.splitn(2, '.').collect::<Vec<_>>();
-        let connectable = connectables
+// secrets are already limited to the project and global ones, the project one wins
+pub fn find_secret<'a>(secrets: &'a [SecretValue], key: &str) -> Option<&'a SecretValue> {
+    secrets
+        .iter()
+        .find(|s| s.key == key && s.project.is_some())
+        .or(secrets.iter().find(|s| s.key == key))
+}
+
+// key of the stored secret a smart string reads, generated secrets are stored under their name
+pub fn get_secret_key(key: &str) -> Result<Option<String>> {
+    if let Some(key) = key.strip_prefix("secret.") {
+        ok!(Some(key.to_string()))
+    }
+    if key.starts_with("generate.") {
+        ok!(Some(parse_generated(key)?.name))
+    }
+    ok!(None)
+}
+
+// secrets used by a piece of unparsed config and the versions they resolve to
+pub fn get_secret_refs(raw: &str, secrets: &[SecretValue]) -> Result<Vec<SecretRef>> {
+    let re = Regex::new(SMART_STRING_PATTERN)?;
+    let mut refs: Vec<SecretRef> = vec![];
+    for caps in re.captures_iter(raw) {
+        let Ok(expr) = parse_smart_expr(caps.get(1).unwrap().as_str()) else {
+            continue;
+        };
+        let Some(key) = get_secret_key(&expr.key)? else {
+            continue;
+        };
+        let Some(secret) = find_secret(secrets, &key) else {
+            continue;
+        };
+        let secret_ref = SecretRef {
+            key: secret.key.clone(),
+            project: secret.project.clone(),
+            version: secret.version,
+        };
+        if !refs.contains(&secret_ref) {
+            refs.push(secret_ref);
+        }
+    }
+    ok!(refs)
+}
+
+// stored secrets a raw config reads, in smart strings and mounts
+pub fn get_secret_keys(raw: &str) -> Result<Vec<String>> {
+    let re = Regex::new(SMART_STRING_PATTERN)?;
+    let mut keys: Vec<String> = vec![];
+    for caps in re.captures_iter(raw) {
+        let Ok(expr) = parse_smart_expr(caps.get(1).unwrap().as_str()) else {
+            continue;
+        };
+        keys.extend(get_secret_key(&expr.key)?);
+    }
+    if let Ok(config) = MainConfig::from_str(raw) {
+        let apps = config.apps.iter().flatten().map(|(_, a)| &a.secrets);
+        let services = config.services.iter().flatten().map(|(_, s)| &s.secrets);
+        for secrets in apps.chain(services) {
+            keys.extend(secrets.iter().flatten().map(|s| s.key.clone()));
+        }
+    }
+    keys.sort();
+    keys.dedup();
+    ok!(keys)
+}
+
+// a missing value takes the default of ${key:-default}, then the transforms are applied
+pub fn smarter_string(s: &str, ctx: &SmartContext) -> Result<String> {
+    let expr = parse_smart_expr(s)?;
+    let value = match (resolve_smart_key(&expr.key, ctx)?, expr.default) {
+        (Some(value), _) => value,
+        (None, Some(default)) => default,
+        (None, None) => err!(missing_error(&expr.key)),
+    };
+    expr.transforms
+        .iter()
+        .try_fold(value, |value, name| apply_transform(&value, name))
+}
+
+// None when the value is not set, errors are for keys that can never resolve
+fn resolve_smart_key(key: &str, ctx: &SmartContext) -> Result<Option<String>> {
+    if let Some(key) = key.strip_prefix("secret.") {
+        ok!(find_secret(&ctx.secrets, key).map(|s| s.value.clone()))
+    } else if key.starts_with("generate.") {
+        let name = parse_generated(key)?.name;
+        ok!(find_secret(&ctx.secrets, &name).map(|s| s.value.clone()))
+    } else if let Some(name) = key.strip_prefix("env.") {
+        ok!(ctx.envs.get(name).cloned())
+    } else if key == "project.name" {
+        ok!(Some(ctx.project.clone()))
+    } else if key == "deploy.revision" {
+        ok!(Some(ctx.revision.to_string()))
+    } else if let Some(key) = key.strip_prefix("this.") {
+        let (service, method) = key
+            .split_once('.')
+            .ok_or(anyhow!("Invalid connectable key: {}", key))?;
+        let connectable = ctx
+            .connectables
             .iter()
-            .find(|c| c.short_name == parts[0])
-            .ok_or(anyhow!("Connect not found : {}", parts[0]))?;
-        let final_str = match parts[1] {
-            "internal" => connectable
-                .internal_link
-                .clone()
-                .ok_or(anyhow!("internal not found: {}", parts[0]))?,
-            "external" => connectable
+            .find(|c| c.short_name == service)
+            .ok_or(anyhow!("Connect not found : {}", service))?;
+        ok!(match method {
+            "internal" => connectable.internal_link.clone(),
+            "external" => connectable.external_link.clone(),
+            "host" => connectable.host.clone(),
+            "port" => connectable.port.map(|p| p.to_string()),
+            "domain" => connectable
                 .external_link
-                .clone()
-                .ok_or(anyhow!("external not found: {}", parts[0]))?,
-            "host" => connectable.host.clone().ok_or(anyhow!("host not found"))?,
-            "port" => connectable
-                .port
-                .clone()
-                .map(|p| p.to_string())
-                .ok_or(anyhow!("port not found"))?,
-            _ => err!(anyhow!("unknown method: {}", parts[1])),
-        };
-        ok!(final_str)
+                .as_ref()
+                .and_then(|link| link.split_once("://"))
+                .map(|(_, domain)| domain.to_string()),
+            // the public address when there is a domain, the internal one otherwise
+            "url" => connectable.external_link.clone().or(connectable
+                .internal_link
+                .as_ref()
+                .map(|link| format!("http://{}", link))),
+            _ => err!(anyhow!("unknown method: {}", method)),
+        })
     } else {
-        err!(anyhow!("Invalid value: {}", value));
+        err!(anyhow!("Invalid value: {}", key));
+    }
+}
+
+fn missing_error(key: &str) -> anyhow::Error {
+    if let Some(key) = key.strip_prefix("secret.") {
+        anyhow!("Secret not found : {}", key)
+    } else if let Some(spec) = key.strip_prefix("generate.") {
+        anyhow!(
+            "Generated secret {} does not exist yet, it is created by the first plan of a user \
+            that can deploy",
+            spec.split(':').next().unwrap_or_default()
+        )
+    } else if let Some(name) = key.strip_prefix("env.") {
+        anyhow!("env {} is not set on the machine running lev", name)
+    } else {
+        anyhow!("{} is not set, add a default with ${{{}:-value}}", key, key)
     }
 }
 
@@ -890,17 +1119,166 @@ fn parser_test() {
         SecretValue {
             key: "app1-domain".to_string(),
             value: "my-domain.com".to_string(),
+            project: None,
+            version: 1,
+            digest: String::new(),
         },
         SecretValue {
             key: "s1-image".to_string(),
             value: "postgres:16".to_string(),
+            project: None,
+            version: 1,
+            digest: String::new(),
         },
         SecretValue {
             key: "s1-label".to_string(),
             value: "app-host".to_string(),
+            project: None,
+            version: 1,
+            digest: String::new(),
         },
     ];
     let connectables = config_to_connectable(MainConfig::from_str(raw_config).unwrap()).unwrap();
-    let parsed_config = get_regex_parsed_config(&raw_config, &connectables, &secrets).unwrap();
+    let ctx = SmartContext {
+        connectables,
+        secrets,
+        ..Default::default()
+    };
+    let parsed_config = get_regex_parsed_config(raw_config, &ctx).unwrap();
     dbg!(parsed_config);
 }
+
+#[test]
+fn project_secret_test() {
+    let secrets = vec![
+        SecretValue {
+            key: "db-pass".to_string(),
+            value: "global".to_string(),
+            project: None,
+            version: 1,
+            digest: String::new(),
+        },
+        SecretValue {
+            key: "db-pass".to_string(),
+            value: "staging".to_string(),
+            project: Some("staging".to_string()),
+            version: 2,
+            digest: String::new(),
+        },
+        SecretValue {
+            key: "api-key".to_string(),
+            value: "shared".to_string(),
+            project: None,
+            version: 1,
+            digest: String::new(),
+        },
+    ];
+    let ctx = SmartContext {
+        secrets: secrets.clone(),
+        ..Default::default()
+    };
+    assert_eq!(smarter_string("secret.db-pass", &ctx).unwrap(), "staging");
+    assert_eq!(smarter_string("secret.api-key", &ctx).unwrap(), "shared");
+    assert_eq!(
+        get_secret_keys("a: ${secret.db-pass}\nb: ${generate.key:hex | base64}").unwrap(),
+        vec!["db-pass".to_string(), "key".to_string()]
+    );
+    let refs =
+        get_secret_refs("image: ${secret.db-pass}\nkey: ${secret.api-key}", &secrets).unwrap();
+    assert_eq!(refs.len(), 2);
+    assert_eq!(refs[0].project, Some("staging".to_string()));
+    assert_eq!(
+        MainConfig::get_project_name(
+            "project: staging\nservices:\n  db:\n    image: ${secret.img}"
+        )
+        .unwrap(),
+        "staging"
+    );
+}
+
+#[test]
+fn smarter_string_test() {
+    let raw_config = r#"
+    project: shop
+    services:
+        api:
+            image: api:1
+            domain: Shop.example.com
+            port: 8080
+        db:
+            image: postgres:16
+            port: 5432
+    "#;
+    let ctx = SmartContext {
+        connectables: config_to_connectable(MainConfig::from_str(raw_config).unwrap()).unwrap(),
+        envs: HashMap::from([("CI_TAG".to_string(), "v1.2".to_string())]),
+        project: "shop".to_string(),
+        revision: 7,
+        ..Default::default()
+    };
+    let resolve = |s: &str| smarter_string(s, &ctx);
+    assert_eq!(resolve("env.CI_TAG").unwrap(), "v1.2");
+    assert_eq!(resolve("env.MISSING:-latest").unwrap(), "latest");
+    assert_eq!(
+        resolve("secret.db-pass:-dev pass | urlencode").unwrap(),
+        "dev%20pass"
+    );
+    assert_eq!(
+        resolve("this.api.domain | lowercase").unwrap(),
+        "shop.example.com"
+    );
+    assert_eq!(resolve("this.api.url").unwrap(), "https://Shop.example.com");
+    assert_eq!(
+        resolve("this.db.url").unwrap(),
+        "http://shop-db-service:5432"
+    );
+    assert_eq!(resolve("project.name").unwrap(), "shop");
+    assert_eq!(resolve("deploy.revision").unwrap(), "7");
+    assert!(resolve("this.db.domain").is_err());
+
+    let error = get_regex_parsed_config(
+        "project: shop\nservices:\n  api:\n    envs:\n      DB_PASS: ${secret.db-pass}\n",
+        &ctx,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(error.contains("line 5 (DB_PASS)"), "{}", error);
+}
+
+#[test]
+fn loadbalancer_labels_test() {
+    let raw_config = r#"
+    project: my-pro
+    services:
+        legacy:
+            image: legacy:1
+            proxy:
+                - domain: legacy.example.com
+                  port: 8080
+                  sticky:
+                      cookie: legacy_lb
+                      secure: true
+                      http-only: true
+                  health-path: /healthz
+                  health-interval: 10
+                  pass-host-header: false
+                  scheme: https
+    "#;
+    let config = MainConfig::from_str(raw_config).unwrap();
+    let service = config.services.unwrap().remove("legacy").unwrap();
+    let deployable =
+        Deployable::from_service_config("legacy".to_string(), service, "my-pro".to_string())
+            .unwrap();
+    let labels = deployable.get_labels(true);
+    let lb = "traefik.http.services.my-pro-legacy-service-1.loadbalancer";
+    let label = |key: &str| labels.get(&format!("{}.{}", lb, key)).cloned();
+    assert_eq!(label("sticky.cookie"), Some("true".to_string()));
+    assert_eq!(label("sticky.cookie.name"), Some("legacy_lb".to_string()));
+    assert_eq!(label("sticky.cookie.secure"), Some("true".to_string()));
+    assert_eq!(label("sticky.cookie.httponly"), Some("true".to_string()));
+    assert_eq!(label("healthcheck.path"), Some("/healthz".to_string()));
+    assert_eq!(label("healthcheck.interval"), Some("10s".to_string()));
+    assert_eq!(label("passhostheader"), Some("false".to_string()));
+    assert_eq!(label("server.scheme"), Some("https".to_string()));
+    assert_eq!(label("server.port"), Some("8080".to_string()));
+}
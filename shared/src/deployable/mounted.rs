@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{ConfigFile, ConfigSecret, MainConfig},
+    docker::{service::ServiceSecret, DockerService},
+    err, ok, SecretRef, SecretValue,
+};
+
+use super::{
+    find_secret, get_secret_key,
+    smart::{parse_smart_expr, transform, SmartContext, SMART_STRING_PATTERN},
+    smarter_string, Deployable,
+};
+
+// stored secret mounted into the containers, the value is read on the server at deploy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MountedSecret {
+    pub secret: SecretRef,
+    pub target: String,
+}
+
+// template with ${secret.*} left in place, so the stored deploy never has the values
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MountedFile {
+    pub target: String,
+    pub template: String,
+    pub secrets: Vec<SecretRef>,
+}
+
+type Mounts = (Option<Vec<ConfigSecret>>, Option<Vec<ConfigFile>>);
+
+// secrets and files of every app and service, taken out of the config before its smart
+// strings are resolved
+pub fn take_mounts(config: &mut MainConfig) -> HashMap<String, Mounts> {
+    let mut mounts = HashMap::new();
+    for (name, app) in config.apps.iter_mut().flatten() {
+        mounts.insert(name.clone(), (app.secrets.take(), app.files.take()));
+    }
+    for (name, service) in config.services.iter_mut().flatten() {
+        mounts.insert(name.clone(), (service.secrets.take(), service.files.take()));
+    }
+    mounts
+}
+
+pub fn get_mounted_secrets(
+    config: Option<Vec<ConfigSecret>>,
+    secrets: &[SecretValue],
+) -> Result<Vec<MountedSecret>> {
+    let mut mounted = vec![];
+    for c in config.unwrap_or_default() {
+        let secret = find_secret(secrets, &c.key).ok_or(anyhow!("Secret not found : {}", c.key))?;
+        mounted.push(MountedSecret {
+            secret: SecretRef {
+                key: secret.key.clone(),
+                project: secret.project.clone(),
+                version: secret.version,
+            },
+            target: c.target.unwrap_or(format!("/run/secrets/{}", c.key)),
+        });
+    }
+    ok!(mounted)
+}
+
+pub fn get_mounted_files(
+    config: Option<Vec<ConfigFile>>,
+    ctx: &SmartContext,
+) -> Result<Vec<MountedFile>> {
+    let mut mounted = vec![];
+    for c in config.unwrap_or_default() {
+        let (template, refs) =
+            resolve_template(&c.content, ctx).map_err(|e| anyhow!("file {}: {}", c.target, e))?;
+        mounted.push(MountedFile {
+            target: c.target,
+            template,
+            secrets: refs,
+        });
+    }
+    ok!(mounted)
+}
+
+pub fn check_targets(name: &str, secrets: &[MountedSecret], files: &[MountedFile]) -> Result<()> {
+    let mut targets: Vec<&String> = vec![];
+    for target in secrets
+        .iter()
+        .map(|s| &s.target)
+        .chain(files.iter().map(|f| &f.target))
+    {
+        if !target.starts_with('/') {
+            err!(anyhow!(
+                "target of {} must be an absolute path: {}",
+                name,
+                target
+            ))
+        }
+        if targets.contains(&target) {
+            err!(anyhow!("{} mounts two files at {}", name, target))
+        }
+        targets.push(target);
+    }
+    ok!(())
+}
+
+// resolves every smart string but ${secret.*}, those are kept with the version they point to
+pub fn resolve_template(content: &str, ctx: &SmartContext) -> Result<(String, Vec<SecretRef>)> {
+    let re = Regex::new(SMART_STRING_PATTERN)?;
+    let mut template = String::new();
+    let mut refs: Vec<SecretRef> = vec![];
+    let mut last_match_end = 0;
+    for caps in re.captures_iter(content) {
+        let mat = caps.get(0).unwrap();
+        template.push_str(&content[last_match_end..mat.start()]);
+        let key = caps.get(1).unwrap().as_str();
+        let expr = parse_smart_expr(key)?;
+        let secret = match get_secret_key(&expr.key)? {
+            Some(secret_key) => find_secret(&ctx.secrets, &secret_key),
+            None => None,
+        };
+        if let Some(secret) = secret {
+            let secret_ref = SecretRef {
+                key: secret.key.clone(),
+                project: secret.project.clone(),
+                version: secret.version,
+            };
+            if !refs.contains(&secret_ref) {
+                refs.push(secret_ref);
+            }
+            // generated secrets are read like any other stored secret
+            let transforms: String = expr.transforms.iter().map(|t| format!("|{}", t)).collect();
+            template.push_str(&format!("${{secret.{}{}}}", secret.key, transforms));
+        } else {
+            // a missing secret takes its default or fails here
+            template.push_str(&smarter_string(key, ctx)?);
+        }
+        last_match_end = mat.end();
+    }
+    template.push_str(&content[last_match_end..]);
+    ok!((template, refs))
+}
+
+fn find_value<'a>(values: &'a [SecretValue], secret: &SecretRef) -> Result<&'a SecretValue> {
+    values
+        .iter()
+        .find(|v| v.key == secret.key && v.project == secret.project && v.version == secret.version)
+        .ok_or(anyhow!(
+            "version {} of secret {} is not available",
+            secret.version,
+            secret.key
+        ))
+}
+
+pub fn render_template(file: &MountedFile, values: &[SecretValue]) -> Result<String> {
+    let re = Regex::new(SMART_STRING_PATTERN)?;
+    let mut content = String::new();
+    let mut last_match_end = 0;
+    for caps in re.captures_iter(&file.template) {
+        let mat = caps.get(0).unwrap();
+        let expr = parse_smart_expr(caps.get(1).unwrap().as_str())?;
+        let Some(secret) = expr
+            .key
+            .strip_prefix("secret.")
+            .and_then(|key| file.secrets.iter().find(|s| s.key == key))
+        else {
+            continue;
+        };
+        let mut value = find_value(values, secret)?.value.clone();
+        for name in &expr.transforms {
+            value = transform(&value, name)?;
+        }
+        content.push_str(&file.template[last_match_end..mat.start()]);
+        content.push_str(&value);
+        last_match_end = mat.end();
+    }
+    content.push_str(&file.template[last_match_end..]);
+    ok!(content)
+}
+
+fn get_secret_label(service_name: &str) -> String {
+    format!("lev.service={}", service_name)
+}
+
+impl Deployable {
+    // secret versions the server has to read before deploying
+    pub fn get_mounted_refs(&self) -> Vec<SecretRef> {
+        let mut refs: Vec<SecretRef> = vec![];
+        for secret_ref in self
+            .secrets
+            .iter()
+            .map(|s| &s.secret)
+            .chain(self.files.iter().flat_map(|f| &f.secrets))
+        {
+            if !refs.contains(secret_ref) {
+                refs.push(secret_ref.clone());
+            }
+        }
+        refs
+    }
+
+    // creates the swarm secrets of this deploy, a changed value gets a new name, so the
+    // service update swaps them
+    pub async fn mount_secrets(
+        &mut self,
+        docker: &DockerService,
+        values: &[SecretValue],
+    ) -> Result<()> {
+        let mut mounted = vec![];
+        for secret in &self.secrets {
+            let value = &find_value(values, &secret.secret)?.value;
+            mounted.push(self.ensure_secret(docker, &secret.target, value).await?);
+        }
+        for file in &self.files {
+            let content = render_template(file, values)?;
+            mounted.push(self.ensure_secret(docker, &file.target, &content).await?);
+        }
+        self.mounted = mounted;
+        ok!(())
+    }
+
+    async fn ensure_secret(
+        &self,
+        docker: &DockerService,
+        target: &str,
+        content: &str,
+    ) -> Result<ServiceSecret> {
+        let hash = format!(
+            "{:x}",
+            Sha256::digest(format!("{}\0{}", target, content).as_bytes())
+        );
+        let name = format!("{}-{}", self.service_name, &hash[..12]);
+        let existing = docker
+            .list_secrets_by_label(&get_secret_label(&self.service_name))
+            .await?
+            .into_iter()
+            .find(|s| s.spec.as_ref().and_then(|s| s.name.as_ref()) == Some(&name));
+        let id = match existing.and_then(|s| s.id) {
+            Some(id) => id,
+            None => {
+                let labels =
+                    HashMap::from([("lev.service".to_string(), self.service_name.clone())]);
+                docker
+                    .create_secret(&name, content.as_bytes(), labels)
+                    .await?
+            }
+        };
+        ok!(ServiceSecret {
+            id,
+            name,
+            target: target.to_string(),
+        })
+    }
+
+    // secrets still used by running tasks can't be removed, they go on the next deploy
+    pub async fn remove_unused_secrets(&self, docker: &DockerService) {
+        let Ok(secrets) = docker
+            .list_secrets_by_label(&get_secret_label(&self.service_name))
+            .await
+        else {
+            return;
+        };
+        for secret in secrets {
+            let name = secret.spec.and_then(|s| s.name).unwrap_or_default();
+            if self.mounted.iter().any(|m| m.name == name) {
+                continue;
+            }
+            if let Some(id) = secret.id {
+                if let Err(e) = docker.delete_secret(&id).await {
+                    println!("failed to remove secret {}: {}", name, e);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn mounted_files_test() {
+    let secrets = vec![SecretValue {
+        key: "db-pass".to_string(),
+        value: "v2-pass".to_string(),
+        project: Some("shop".to_string()),
+        version: 2,
+        digest: String::new(),
+    }];
+    let ctx = SmartContext {
+        secrets: secrets.clone(),
+        ..Default::default()
+    };
+    let (template, refs) = resolve_template("user=admin\npass=${secret.db-pass}\n", &ctx).unwrap();
+    assert_eq!(template, "user=admin\npass=${secret.db-pass}\n");
+    assert_eq!(refs[0].version, 2);
+    let file = MountedFile {
+        target: "/etc/app.conf".to_string(),
+        template,
+        secrets: refs,
+    };
+    assert_eq!(
+        render_template(&file, &secrets).unwrap(),
+        "user=admin\npass=v2-pass\n"
+    );
+    let mut old = secrets.clone();
+    old[0].version = 1;
+    assert!(render_template(&file, &old).is_err());
+
+    let mounted = get_mounted_secrets(
+        Some(vec![ConfigSecret {
+            key: "db-pass".to_string(),
+            target: None,
+        }]),
+        &secrets,
+    )
+    .unwrap();
+    assert_eq!(mounted[0].target, "/run/secrets/db-pass");
+    assert!(check_targets("web", &mounted, std::slice::from_ref(&file)).is_ok());
+    let mut twice = file.clone();
+    twice.target = "/run/secrets/db-pass".to_string();
+    assert!(check_targets("web", &mounted, &[twice]).is_err());
+
+    let raw_config = r#"
+    project: shop
+    services:
+        web:
+            image: web:1
+            secrets:
+                - key: db-pass
+            files:
+                - target: /etc/app.conf
+                  content: "pass=${secret.db-pass}"
+    "#;
+    let deploys = super::deploy::plan(super::deploy::PlanParamaters {
+        main_config: raw_config.to_string(),
+        last_deploys: vec![],
+        secrets: secrets.clone(),
+        network_name: "lev".to_string(),
+        filter: None,
+        to_build: vec![],
+        images: vec![],
+        envs: HashMap::new(),
+        revision: 1,
+    })
+    .unwrap();
+    assert_eq!(
+        deploys[0].deployable.files[0].template,
+        "pass=${secret.db-pass}"
+    );
+    assert_eq!(deploys[0].secrets.len(), 1);
+    assert!(!serde_json::to_string(&deploys).unwrap().contains("v2-pass"));
+}
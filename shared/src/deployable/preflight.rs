@@ -0,0 +1,228 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
+
+use super::{
+    deploy::{Deploy, DeployAction},
+    masking::is_masked,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PreflightIssueKind {
+    DnsMissing,
+    DnsMismatch,
+    DuplicateRoute,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PreflightIssue {
+    pub kind: PreflightIssueKind,
+    pub domain: String,
+    pub message: String,
+}
+
+impl PreflightIssue {
+    pub fn is_dns(&self) -> bool {
+        self.kind != PreflightIssueKind::DuplicateRoute
+    }
+}
+
+// domains that will get a new certificate or router, local ones never need dns
+pub fn get_dns_domains(deploys: &[Deploy]) -> Vec<String> {
+    let mut domains = vec![];
+    for deploy in deploys {
+        if deploy.action != DeployAction::Create && deploy.action != DeployAction::Update {
+            continue;
+        }
+        for proxy in &deploy.deployable.proxies {
+            let domain = proxy.domain.to_lowercase();
+            // a domain from a secret can't be resolved before the deploy
+            if domain == "localhost" || domain.ends_with(".localhost") || is_masked(&domain) {
+                continue;
+            }
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+    domains
+}
+
+pub async fn resolve_domain(domain: &str) -> Vec<IpAddr> {
+    match lookup_host((domain, 443)).await {
+        Ok(addrs) => addrs
+            .map(|a| a.ip())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+// without known manager ips only missing records can be found
+pub fn check_dns(
+    domain: &str,
+    resolved: &[IpAddr],
+    manager_ips: &[IpAddr],
+) -> Option<PreflightIssue> {
+    if resolved.is_empty() {
+        return Some(PreflightIssue {
+            kind: PreflightIssueKind::DnsMissing,
+            domain: domain.to_string(),
+            message: format!(
+                "{} has no A/AAAA records, point it to the server before deploying",
+                domain
+            ),
+        });
+    }
+    if manager_ips.is_empty() || resolved.iter().any(|ip| manager_ips.contains(ip)) {
+        return None;
+    }
+    let resolved: Vec<String> = resolved.iter().map(|ip| ip.to_string()).collect();
+    let managers: Vec<String> = manager_ips.iter().map(|ip| ip.to_string()).collect();
+    Some(PreflightIssue {
+        kind: PreflightIssueKind::DnsMismatch,
+        domain: domain.to_string(),
+        message: format!(
+            "{} points to {}, but the server is {} (ignore it if a CDN proxies the domain)",
+            domain,
+            resolved.join(", "),
+            managers.join(", ")
+        ),
+    })
+}
+
+// comma separated list, eg from LEV_PUBLIC_IPS
+pub fn parse_public_ips(raw: &str) -> Vec<IpAddr> {
+    raw.split(',')
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect()
+}
+
+// private addresses of the swarm can't be what public records point to
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified())
+        }
+        IpAddr::V6(ip) => !(ip.is_loopback() || ip.is_unspecified()),
+    }
+}
+
+fn route_key(domain: &str, path_prefix: &str) -> (String, String) {
+    let path = path_prefix.trim_end_matches('/');
+    let path = if path.is_empty() { "/" } else { path };
+    (domain.to_lowercase(), path.to_string())
+}
+
+// the same domain and path prefix on two services makes traefik pick one of them at random
+pub fn find_duplicate_routes(
+    deploys: &[Deploy],
+    other_projects: &[(String, Vec<Deploy>)],
+) -> Vec<PreflightIssue> {
+    let mut owners: HashMap<(String, String), String> = HashMap::new();
+    for (project, project_deploys) in other_projects {
+        for deploy in project_deploys {
+            if deploy.action == DeployAction::Delete {
+                continue;
+            }
+            for proxy in &deploy.deployable.proxies {
+                owners.insert(
+                    route_key(&proxy.domain, &proxy.path_prefix),
+                    format!("{}/{}", project, deploy.deployable.short_name),
+                );
+            }
+        }
+    }
+
+    let mut issues = vec![];
+    let mut this_owners: HashMap<(String, String), String> = HashMap::new();
+    for deploy in deploys {
+        if deploy.action == DeployAction::Delete {
+            continue;
+        }
+        let name = &deploy.deployable.short_name;
+        for proxy in &deploy.deployable.proxies {
+            let key = route_key(&proxy.domain, &proxy.path_prefix);
+            let owner = owners
+                .get(&key)
+                .or(this_owners.get(&key).filter(|owner| *owner != name));
+            if let Some(owner) = owner {
+                issues.push(PreflightIssue {
+                    kind: PreflightIssueKind::DuplicateRoute,
+                    domain: key.0.clone(),
+                    message: format!(
+                        "{}{} of {} is already routed to {}",
+                        key.0, key.1, name, owner
+                    ),
+                });
+            }
+            this_owners.insert(key, name.clone());
+        }
+    }
+    issues
+}
+
+#[test]
+fn preflight_test() {
+    use super::deploy::{plan, PlanParamaters};
+
+    let get_deploys = |project: &str, domain: &str, prefix: &str| {
+        let raw_config = format!(
+            r#"
+            project: {}
+            services:
+                web:
+                    image: web:1
+                    domain: {}
+                    port: 80
+                    path-prefix: {}
+            "#,
+            project, domain, prefix
+        );
+        plan(PlanParamaters {
+            main_config: raw_config,
+            last_deploys: vec![],
+            secrets: vec![],
+            network_name: "lev".to_string(),
+            filter: None,
+            to_build: vec![],
+            images: vec![],
+            envs: HashMap::new(),
+            revision: 1,
+        })
+        .unwrap()
+    };
+
+    let deploys = get_deploys("shop", "Example.com", "/api/");
+    assert_eq!(get_dns_domains(&deploys), vec!["example.com".to_string()]);
+
+    let others = vec![(
+        "blog".to_string(),
+        get_deploys("blog", "example.com", "/api"),
+    )];
+    let issues = find_duplicate_routes(&deploys, &others);
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].kind, PreflightIssueKind::DuplicateRoute);
+    let others = vec![("blog".to_string(), get_deploys("blog", "example.com", "/"))];
+    assert!(find_duplicate_routes(&deploys, &others).is_empty());
+
+    let server: IpAddr = "203.0.113.10".parse().unwrap();
+    let other: IpAddr = "198.51.100.7".parse().unwrap();
+    assert_eq!(
+        check_dns("example.com", &[], &[server]).unwrap().kind,
+        PreflightIssueKind::DnsMissing
+    );
+    assert_eq!(
+        check_dns("example.com", &[other], &[server]).unwrap().kind,
+        PreflightIssueKind::DnsMismatch
+    );
+    assert!(check_dns("example.com", &[server], &[server]).is_none());
+    assert!(check_dns("example.com", &[other], &[]).is_none());
+    assert_eq!(parse_public_ips("203.0.113.10, bad"), vec![server]);
+    assert!(!is_public_ip(&"10.0.0.2".parse().unwrap()));
+}
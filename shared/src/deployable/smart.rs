@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use regex::Regex;
+
+use crate::{err, ok, SecretValue};
+
+use super::{masking::transform_token, Connectable};
+
+// ${key}, ${key:-default} and ${key | transform | ...}
+pub const SMART_STRING_PATTERN: &str =
+    r"\$\{([A-Za-z0-9_\-\/\.:]+(?::-[^{}|]*)?\s*(?:\|[^{}]*)?)\}";
+
+pub const TRANSFORMS: [&str; 3] = ["base64", "urlencode", "lowercase"];
+
+// what smart strings can read besides the config itself, envs come from the machine
+// running lev
+#[derive(Debug, Clone, Default)]
+pub struct SmartContext {
+    pub connectables: Vec<Connectable>,
+    pub secrets: Vec<SecretValue>,
+    pub envs: HashMap<String, String>,
+    pub project: String,
+    pub revision: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmartExpr {
+    pub key: String,
+    pub default: Option<String>,
+    pub transforms: Vec<String>,
+}
+
+pub fn parse_smart_expr(expr: &str) -> Result<SmartExpr> {
+    let mut parts = expr.split('|');
+    let value = parts.next().unwrap_or_default().trim();
+    let mut transforms = vec![];
+    for transform in parts.map(|t| t.trim()) {
+        if !TRANSFORMS.contains(&transform) {
+            err!(anyhow!(
+                "unknown transform {}, use {}",
+                transform,
+                TRANSFORMS.join(", ")
+            ))
+        }
+        transforms.push(transform.to_string());
+    }
+    let (key, default) = match value.split_once(":-") {
+        Some((key, default)) => (key.trim(), Some(default.trim().to_string())),
+        None => (value, None),
+    };
+    ok!(SmartExpr {
+        key: key.to_string(),
+        default,
+        transforms,
+    })
+}
+
+pub fn transform(value: &str, transform: &str) -> Result<String> {
+    ok!(match transform {
+        "base64" => STANDARD.encode(value),
+        "urlencode" => urlencode(value),
+        "lowercase" => value.to_lowercase(),
+        _ => err!(anyhow!("unknown transform {}", transform)),
+    })
+}
+
+// a masked secret only gets its value on the server, so the transform goes into the token
+pub fn apply_transform(value: &str, name: &str) -> Result<String> {
+    match transform_token(value, name) {
+        Some(token) => ok!(token),
+        None => transform(value, name),
+    }
+}
+
+fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// line and yaml key of a smart string in the raw config, so errors point at the field
+pub fn describe_position(config: &str, offset: usize) -> String {
+    let number = config[..offset].matches('\n').count() + 1;
+    let line_start = config[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line = config[line_start..].lines().next().unwrap_or_default();
+    let field = line
+        .split_once(':')
+        .map(|(field, _)| field.trim().trim_start_matches("- ").trim_matches('"'))
+        .filter(|field| {
+            !field.is_empty()
+                && field
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-. ".contains(c))
+        });
+    match field {
+        Some(field) => format!("line {} ({})", number, field),
+        None => format!("line {}", number),
+    }
+}
+
+// names of the ${env.*} a config reads, lev sends only these to the server
+pub fn get_env_refs(raw: &str) -> Result<Vec<String>> {
+    let re = Regex::new(SMART_STRING_PATTERN)?;
+    let mut names: Vec<String> = vec![];
+    for caps in re.captures_iter(raw) {
+        let Ok(expr) = parse_smart_expr(caps.get(1).unwrap().as_str()) else {
+            continue;
+        };
+        if let Some(name) = expr.key.strip_prefix("env.") {
+            if !names.iter().any(|n| n == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    ok!(names)
+}
+
+#[test]
+fn smart_expr_test() {
+    let expr = parse_smart_expr("secret.db-host:-db.internal | lowercase").unwrap();
+    assert_eq!(expr.key, "secret.db-host");
+    assert_eq!(expr.default, Some("db.internal".to_string()));
+    assert_eq!(expr.transforms, vec!["lowercase".to_string()]);
+    assert!(parse_smart_expr("env.USER | upper").is_err());
+    assert_eq!(
+        apply_transform("p@ss word/1", "urlencode").unwrap(),
+        "p%40ss%20word%2F1"
+    );
+    assert_eq!(apply_transform("Key", "base64").unwrap(), "S2V5");
+
+    let raw = "services:\n  api:\n    envs:\n      TOKEN: ${env.CI_TOKEN}\n";
+    let offset = raw.find("${").unwrap();
+    assert_eq!(describe_position(raw, offset), "line 4 (TOKEN)");
+    assert_eq!(get_env_refs(raw).unwrap(), vec!["CI_TOKEN".to_string()]);
+}
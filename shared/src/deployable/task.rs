@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::docker::DockerService;
 use anyhow::{anyhow, Result};
@@ -51,6 +51,18 @@ pub async fn run_deploy_task(task: DeployTask, docker: DockerService) -> Result<
                 ));
             }
 
+            if let Some(
+                ServiceUpdateStatusStateEnum::ROLLBACK_STARTED
+                | ServiceUpdateStatusStateEnum::ROLLBACK_PAUSED
+                | ServiceUpdateStatusStateEnum::ROLLBACK_COMPLETED,
+            ) = update_status.state
+            {
+                return Err(anyhow!(
+                    "health check failed, update was rolled back: {}",
+                    health_check_task.service_name
+                ));
+            }
+
             if update_status.state.is_none() {
                 break;
             }
@@ -59,3 +71,28 @@ pub async fn run_deploy_task(task: DeployTask, docker: DockerService) -> Result<
     }
     Ok(())
 }
+
+pub async fn wait_for_running(
+    service_name: String,
+    docker: DockerService,
+    timeout: Duration,
+) -> Result<()> {
+    println!("waiting for running tasks: {}", service_name);
+    let started = Instant::now();
+    loop {
+        let status = docker.get_service_status(service_name.clone()).await?;
+        let desired = status.desired_tasks.unwrap_or(0);
+        if desired > 0 && status.running_tasks.unwrap_or(0) >= desired {
+            println!("all tasks are running: {}", service_name);
+            return Ok(());
+        }
+        if started.elapsed() > timeout {
+            return Err(anyhow!(
+                "tasks of {} are not running after {} seconds",
+                service_name,
+                timeout.as_secs()
+            ));
+        }
+        sleep(Duration::from_millis(1000)).await;
+    }
+}
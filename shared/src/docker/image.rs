@@ -1,7 +1,7 @@
 use std::{
     collections::HashMap,
     fs::File,
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, Read},
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
@@ -11,7 +11,7 @@ use anyhow::{anyhow, Result};
 use bollard::{
     image::{
         BuildImageOptions, BuilderVersion, CreateImageOptions, ImportImageOptions,
-        ListImagesOptions,
+        ListImagesOptions, RemoveImageOptions,
     },
     secret::BuildInfo,
 };
@@ -19,10 +19,11 @@ use bytes::Bytes;
 use futures_util::{stream, Stream, StreamExt};
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use serde::Serialize;
-use tar::Builder;
+use serde_json::Value;
+use tar::{Archive, Builder};
 use walkdir::WalkDir;
 
-use crate::{docker_platform::get_docker_platform, ok};
+use crate::{docker_platform::get_docker_platform, err, ok};
 
 use super::DockerService;
 
@@ -75,19 +76,37 @@ impl DockerService {
         Ok(images)
     }
 
-    pub async fn load_image<T>(&self, stream: T) -> Result<()>
+    // returns the tags the tarball set, untagged images are left out
+    pub async fn load_image<T>(&self, stream: T) -> Result<Vec<String>>
     where
         T: Stream<Item = Bytes> + Send + 'static,
     {
         let load_options = ImportImageOptions { quiet: false };
         let mut result = self.conn.import_image_stream(load_options, stream, None);
 
+        let mut tags = vec![];
         while let Some(s) = result.next().await {
-            if let Err(e) = s {
-                return Err(anyhow!(e));
+            let info = s.map_err(|e| anyhow!(e))?;
+            if let Some(tag) = info
+                .stream
+                .as_deref()
+                .and_then(|line| line.trim().strip_prefix("Loaded image: "))
+            {
+                tags.push(tag.to_string());
             }
         }
 
+        Ok(tags)
+    }
+
+    pub async fn remove_image(&self, image_name: &str) -> Result<()> {
+        let options = RemoveImageOptions {
+            force: true,
+            ..Default::default()
+        };
+        self.conn
+            .remove_image(image_name, Some(options), None)
+            .await?;
         Ok(())
     }
 
@@ -192,3 +211,105 @@ pub struct DockerImage {
     pub image_id: String,
     pub tag: String,
 }
+
+// every tag `docker load` would set from the tarball, read before it is loaded. Docker
+// takes them from manifest.json, the index.json of oci tarballs and the legacy
+// repositories file
+pub fn get_tarball_tags(path: &Path) -> Result<Vec<String>> {
+    let mut tags = vec![];
+    let mut found = false;
+    for entry in Archive::new(File::open(path)?).entries()? {
+        let mut entry = entry?;
+        let name = entry
+            .path()?
+            .to_string_lossy()
+            .trim_start_matches("./")
+            .to_string();
+        if !matches!(
+            name.as_str(),
+            "manifest.json" | "index.json" | "repositories"
+        ) {
+            continue;
+        }
+        found = true;
+        let mut text = String::new();
+        entry.read_to_string(&mut text)?;
+        let json: Value = serde_json::from_str(&text)?;
+        match name.as_str() {
+            "manifest.json" => json
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|m| m["RepoTags"].as_array())
+                .flatten()
+                .filter_map(|t| t.as_str())
+                .for_each(|t| tags.push(t.to_string())),
+            "index.json" => json["manifests"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|m| m["annotations"].as_object())
+                .flat_map(|a| {
+                    [
+                        a.get("io.containerd.image.name"),
+                        // a bare tag here is only the tag of the name above
+                        a.get("org.opencontainers.image.ref.name")
+                            .filter(|r| r.as_str().is_some_and(|r| r.contains([':', '/']))),
+                    ]
+                })
+                .flatten()
+                .filter_map(|t| t.as_str())
+                .for_each(|t| tags.push(t.to_string())),
+            _ => json
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(repo, t)| t.as_object().map(|t| (repo, t)))
+                .for_each(|(repo, t)| {
+                    t.keys()
+                        .for_each(|tag| tags.push(format!("{}:{}", repo, tag)))
+                }),
+        }
+    }
+    if !found {
+        err!(anyhow!("the file is not an image tarball"));
+    }
+    tags.iter_mut().for_each(|t| {
+        if let Some(short) = t.strip_prefix("docker.io/library/") {
+            *t = short.to_string();
+        }
+    });
+    tags.sort();
+    tags.dedup();
+    ok!(tags)
+}
+
+#[test]
+fn tarball_tags() {
+    let path = std::env::temp_dir().join(format!("lev-tags-{}.tar", std::process::id()));
+    let mut tar = Builder::new(File::create(&path).unwrap());
+    let mut add = |name: &str, data: &str| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, name, data.as_bytes()).unwrap();
+    };
+    add("blobs/sha256/abc", "{}");
+    add(
+        "manifest.json",
+        r#"[{"Config":"c","RepoTags":["shop-web-image:3"],"Layers":[]}]"#,
+    );
+    add(
+        "index.json",
+        r#"{"manifests":[{"annotations":{"io.containerd.image.name":"docker.io/library/shop-web-image:3","org.opencontainers.image.ref.name":"3"}},{"annotations":{"org.opencontainers.image.ref.name":"traefik:v3.1"}}]}"#,
+    );
+    add("repositories", r#"{"blog-web-image":{"1":"abc"}}"#);
+    drop(add);
+    tar.finish().unwrap();
+    let tags = get_tarball_tags(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        tags,
+        vec!["blog-web-image:1", "shop-web-image:3", "traefik:v3.1"]
+    );
+}
@@ -4,6 +4,7 @@ use anyhow::Result;
 use bollard::Docker;
 pub mod custom;
 pub mod image;
+pub mod secret;
 pub mod service;
 pub mod volume;
 
@@ -18,6 +19,12 @@ impl DockerService {
             conn: Arc::new(Docker::connect_with_socket_defaults()?),
         })
     }
+
+    // address other swarm nodes use to reach this one
+    pub async fn get_node_addr(&self) -> Result<Option<String>> {
+        let info = self.conn.info().await?;
+        Ok(info.swarm.and_then(|s| s.node_addr))
+    }
 }
 
 #[tokio::test]
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bollard::secret::{ListSecretsOptions, Secret, SecretSpec};
+
+use crate::ok;
+
+use super::DockerService;
+
+impl DockerService {
+    pub async fn list_secrets_by_label(&self, label: &str) -> Result<Vec<Secret>> {
+        let mut filters: HashMap<String, Vec<String>> = HashMap::new();
+        filters.insert("label".to_string(), vec![label.to_string()]);
+        let secrets = self
+            .conn
+            .list_secrets(Some(ListSecretsOptions { filters }))
+            .await?;
+        ok!(secrets)
+    }
+
+    // swarm secrets are immutable, so a changed content needs a new name
+    pub async fn create_secret(
+        &self,
+        name: &str,
+        data: &[u8],
+        labels: HashMap<String, String>,
+    ) -> Result<String> {
+        let res = self
+            .conn
+            .create_secret(SecretSpec {
+                name: Some(name.to_string()),
+                labels: Some(labels),
+                data: Some(STANDARD.encode(data)),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| anyhow!("error create secret {}: {}", name, e))?;
+        ok!(res.id)
+    }
+
+    pub async fn delete_secret(&self, id: &str) -> Result<()> {
+        ok!(self.conn.delete_secret(id).await?)
+    }
+}
@@ -4,11 +4,13 @@ use std::{collections::HashMap, time::Duration};
 use bollard::{
     secret::{
         EndpointPortConfig, EndpointPortConfigPublishModeEnum, EndpointSpec, HealthConfig, Limit,
-        Mount, MountTypeEnum, NetworkAttachmentConfig, Service, ServiceCreateResponse, ServiceSpec,
-        ServiceSpecMode, ServiceSpecModeReplicated, ServiceSpecUpdateConfig,
+        Mount, MountTypeEnum, NetworkAttachmentConfig, Service, ServiceCreateResponse,
+        ServiceServiceStatus, ServiceSpec, ServiceSpecMode, ServiceSpecModeReplicated,
+        ServiceSpecRollbackConfig, ServiceSpecRollbackConfigOrderEnum, ServiceSpecUpdateConfig,
         ServiceSpecUpdateConfigFailureActionEnum, ServiceSpecUpdateConfigOrderEnum,
-        ServiceUpdateResponse, TaskSpec, TaskSpecContainerSpec, TaskSpecPlacement,
-        TaskSpecResources, TaskSpecRestartPolicy, TaskSpecRestartPolicyConditionEnum,
+        ServiceUpdateResponse, TaskSpec, TaskSpecContainerSpec, TaskSpecContainerSpecFile,
+        TaskSpecContainerSpecSecrets, TaskSpecPlacement, TaskSpecResources, TaskSpecRestartPolicy,
+        TaskSpecRestartPolicyConditionEnum,
     },
     service::{InspectServiceOptions, ListServicesOptions, UpdateServiceOptions},
 };
@@ -65,6 +67,26 @@ impl DockerService {
         ok!(res)
     }
 
+    // for services that weren't created from a ServiceParam, like the manager itself, the
+    // spec from inspect_service is changed and written back as a whole
+    pub async fn update_service_spec(
+        &self,
+        name: &str,
+        spec: ServiceSpec,
+        version: u64,
+    ) -> Result<ServiceUpdateResponse> {
+        let opts = UpdateServiceOptions {
+            version,
+            ..Default::default()
+        };
+        let res = self
+            .conn
+            .update_service(name, spec, opts, None)
+            .await
+            .map_err(|e| anyhow!(format!("error update services {}", e)))?;
+        ok!(res)
+    }
+
     pub async fn delete_service(&self, name: String) -> Result<()> {
         ok!(self.conn.delete_service(&name).await?)
     }
@@ -78,6 +100,25 @@ impl DockerService {
         false
     }
 
+    pub async fn get_service_status(&self, name: String) -> Result<ServiceServiceStatus> {
+        let mut filters: HashMap<String, Vec<String>> = HashMap::new();
+        filters.insert("name".to_string(), vec![name.clone()]);
+        let opt = Some(ListServicesOptions {
+            filters,
+            status: true,
+        });
+        let service = self
+            .conn
+            .list_services(opt)
+            .await?
+            .into_iter()
+            .find(|s| s.spec.as_ref().and_then(|s| s.name.as_ref()) == Some(&name))
+            .ok_or(anyhow!("service not found: {}", name))?;
+        service
+            .service_status
+            .ok_or(anyhow!("no status for service: {}", name))
+    }
+
     pub async fn get_service(&self, name: String) -> Result<Service> {
         let services = self.list_services().await?;
         let service = services
@@ -106,6 +147,9 @@ pub struct ServiceParam {
 
     // container params
     pub labels: HashMap<String, String>,
+    // labels of the containers if they should differ from the service labels, changing
+    // only service labels does not restart the tasks
+    pub container_labels: Option<HashMap<String, String>>,
     pub exposed_ports: HashMap<u16, u16>,
     pub envs: HashMap<String, String>,
     pub mounts: Vec<ServiceMount>,
@@ -120,6 +164,10 @@ pub struct ServiceParam {
     pub restart: TaskSpecRestartPolicyConditionEnum,
 
     pub healthcheck: Option<HealthCheck>,
+    pub network_aliases: Vec<String>,
+    // roll the update back instead of continuing when new tasks fail
+    pub rollback_on_failure: bool,
+    pub secrets: Vec<ServiceSecret>,
 }
 
 #[derive(Clone, Debug)]
@@ -128,6 +176,14 @@ pub enum ServiceMount {
     Bind(String, String),
 }
 
+// swarm secret mounted as a read only file at target
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceSecret {
+    pub id: String,
+    pub name: String,
+    pub target: String,
+}
+
 impl ServiceParam {
     pub fn new(name: String, image: String, network: String) -> ServiceParam {
         ServiceParam {
@@ -135,6 +191,7 @@ impl ServiceParam {
             image,
             network_name: network,
             labels: HashMap::new(),
+            container_labels: None,
             exposed_ports: HashMap::new(),
             envs: HashMap::new(),
             mounts: vec![],
@@ -146,6 +203,9 @@ impl ServiceParam {
             constraints: vec![],
             restart: TaskSpecRestartPolicyConditionEnum::ANY,
             healthcheck: None,
+            network_aliases: vec![],
+            rollback_on_failure: false,
+            secrets: vec![],
         }
     }
 
@@ -193,7 +253,7 @@ impl ServiceParam {
             task_template: Some(TaskSpec {
                 container_spec: Some(TaskSpecContainerSpec {
                     image: Some(self.image.clone()),
-                    labels: Some(self.labels.clone()),
+                    labels: Some(self.container_labels.clone().unwrap_or(self.labels.clone())),
                     args: Some(self.args.clone()),
                     env: Some(
                         self.envs
@@ -236,6 +296,21 @@ impl ServiceParam {
                             .collect(),
                     ),
                     command: self.cmd.clone(),
+                    secrets: Some(
+                        self.secrets
+                            .iter()
+                            .map(|s| TaskSpecContainerSpecSecrets {
+                                file: Some(TaskSpecContainerSpecFile {
+                                    name: Some(s.target.clone()),
+                                    uid: Some("0".to_string()),
+                                    gid: Some("0".to_string()),
+                                    mode: Some(0o444),
+                                }),
+                                secret_id: Some(s.id.clone()),
+                                secret_name: Some(s.name.clone()),
+                            })
+                            .collect(),
+                    ),
                     ..Default::default()
                 }),
                 resources: Some(TaskSpecResources {
@@ -265,13 +340,27 @@ impl ServiceParam {
             update_config: Some(ServiceSpecUpdateConfig {
                 parallelism: Some(1),
                 order: Some(ServiceSpecUpdateConfigOrderEnum::START_FIRST),
-                failure_action: Some(ServiceSpecUpdateConfigFailureActionEnum::CONTINUE),
+                failure_action: Some(if self.rollback_on_failure {
+                    ServiceSpecUpdateConfigFailureActionEnum::ROLLBACK
+                } else {
+                    ServiceSpecUpdateConfigFailureActionEnum::CONTINUE
+                }),
                 delay: Some(5 * 1000 * 1000 * 1000),
+                monitor: self.rollback_on_failure.then_some(15 * 1000 * 1000 * 1000),
+                ..Default::default()
+            }),
+            rollback_config: self.rollback_on_failure.then(|| ServiceSpecRollbackConfig {
+                parallelism: Some(1),
+                order: Some(ServiceSpecRollbackConfigOrderEnum::START_FIRST),
                 ..Default::default()
             }),
-            rollback_config: None,
             networks: Some(vec![NetworkAttachmentConfig {
                 target: Some(self.network_name.clone()),
+                aliases: if self.network_aliases.is_empty() {
+                    None
+                } else {
+                    Some(self.network_aliases.clone())
+                },
                 ..Default::default()
             }]),
             endpoint_spec: Some(EndpointSpec {
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     fs,
     path::{Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
@@ -11,6 +12,8 @@ pub mod console;
 pub mod deployable;
 pub mod docker;
 pub mod docker_platform;
+pub mod proxy;
+pub mod tls;
 
 #[macro_export]
 macro_rules! err {
@@ -100,12 +103,45 @@ pub fn create_file_if_not_exist(path: &str) -> Result<()> {
 pub struct Secret {
     pub key: String,
     pub created_at: String,
+    #[serde(default)]
+    pub project: Option<String>,
 }
 
+// project is None for global secrets, the ones scoped to a project win over them
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SecretValue {
     pub key: String,
     pub value: String,
+    #[serde(default)]
+    pub project: Option<String>,
+    #[serde(default)]
+    pub version: i64,
+    // keyed hash of the value from the server, masked values carry it
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub digest: String,
+}
+
+// the secret version a deploy was rendered with
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SecretRef {
+    pub key: String,
+    pub project: Option<String>,
+    pub version: i64,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SecretImportReport {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SecretVersion {
+    pub version: i64,
+    pub author: String,
+    pub note: Option<String>,
+    pub created_at: String,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -167,8 +203,76 @@ pub fn get_unix_millis() -> u128 {
         .as_millis()
 }
 
+// returned by login, register and refresh
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    // seconds until the access token expires
+    pub expires_in: u64,
+}
+
+// the code the user enters at the sso provider, see lev login --sso
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SsoDevice {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    // seconds until the code expires
+    pub expires_in: u64,
+    // seconds to wait between polls
+    pub interval: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SsoTokenBody {
+    pub device_code: String,
+}
+
+// returned once the user approved the login at the sso provider
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SsoLogin {
+    pub username: String,
+    pub tokens: AuthTokens,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UserSafe {
     pub username: String,
     pub role: String,
+    #[serde(default)]
+    pub disabled: bool,
+    // project and the role the user has there instead of the global one
+    #[serde(default)]
+    pub grants: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiTokenInfo {
+    pub name: String,
+    pub role: String,
+    pub project: Option<String>,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+}
+
+// one audited request, see lev audit
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub request_id: String,
+    pub created_at: String,
+    pub username: Option<String>,
+    pub role: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub project: Option<String>,
+    pub status: u16,
+    pub outcome: String,
+    pub duration_ms: u64,
+    pub client_ip: Option<String>,
+    // X-Forwarded-For or Forwarded as sent, anyone can set them
+    #[serde(default)]
+    pub forwarded_for: Option<String>,
 }
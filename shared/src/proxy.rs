@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::HealthCheck,
+    docker::service::{ServiceMount, ServiceParam},
+    err, ok,
+};
+
+pub const TRAEFIK_SERVICE_NAME: &str = "traefik-service";
+pub const DEFAULT_TRAEFIK_VERSION: &str = "v2.10";
+
+// routers of deployed services and of the manager itself point to these entrypoints
+const REQUIRED_ENTRYPOINTS: [&str; 2] = ["web", "websecure"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProxySettings {
+    pub version: String,
+    pub acme_email: Option<String>,
+    pub entrypoints: BTreeMap<String, u16>,
+    pub dashboard_domain: Option<String>,
+    // htpasswd formatted "user:bcrypt-hash"
+    pub dashboard_auth: Option<String>,
+    pub access_logs: bool,
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        Self {
+            version: DEFAULT_TRAEFIK_VERSION.to_string(),
+            acme_email: None,
+            entrypoints: BTreeMap::from([("web".to_string(), 80), ("websecure".to_string(), 443)]),
+            dashboard_domain: None,
+            dashboard_auth: None,
+            access_logs: false,
+        }
+    }
+}
+
+// body of PUT /proxy, only given fields are changed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProxySettingsUpdate {
+    pub acme_email: Option<String>,
+    pub entrypoints: Option<BTreeMap<String, u16>>,
+    pub dashboard_domain: Option<String>,
+    pub dashboard_user: Option<String>,
+    pub dashboard_password: Option<String>,
+    #[serde(default)]
+    pub disable_dashboard: bool,
+    pub access_logs: Option<bool>,
+    pub version: Option<String>,
+}
+
+impl ProxySettings {
+    pub fn validate(&self) -> Result<()> {
+        for name in REQUIRED_ENTRYPOINTS {
+            if !self.entrypoints.contains_key(name) {
+                err!(anyhow!("entrypoint {} is required", name))
+            }
+        }
+        if self.dashboard_domain.is_some() && self.dashboard_auth.is_none() {
+            err!(anyhow!(
+                "dashboard needs a user and a password to be exposed on a domain"
+            ))
+        }
+        if self.version.is_empty() {
+            err!(anyhow!("traefik version is empty"))
+        }
+        ok!(())
+    }
+
+    pub fn to_service_param(&self, network_name: &str) -> ServiceParam {
+        let mut params = ServiceParam::new(
+            TRAEFIK_SERVICE_NAME.to_string(),
+            format!("traefik:{}", self.version),
+            network_name.to_string(),
+        );
+        for port in self.entrypoints.values() {
+            params.add_port(*port, *port);
+        }
+        params.add_mount(ServiceMount::Bind(
+            "/var/run/docker.sock".to_string(),
+            "/var/run/docker.sock".to_string(),
+        ));
+        params.add_mount(ServiceMount::Volume(
+            "letsencrypt".to_string(),
+            "/letsencrypt".to_string(),
+        ));
+        params.set_constraints(vec!["node.role == manager".to_string()]);
+        params.add_args(self.get_args());
+        params.labels = self.get_labels();
+        params.healthcheck = Some(HealthCheck {
+            cmd: Some(vec![
+                "CMD".to_string(),
+                "traefik".to_string(),
+                "healthcheck".to_string(),
+                "--ping".to_string(),
+            ]),
+            interval: Some(5),
+            timeout: Some(3),
+            retries: Some(3),
+            start_period: Some(5),
+        });
+        params.rollback_on_failure = true;
+        params
+    }
+
+    // settings of a traefik created before they were stored, like the one of older
+    // manager.sh installs. The insecure dashboard on port 8080 isn't taken over
+    pub fn from_service(image: &str, args: &[String]) -> Self {
+        let mut settings = Self::default();
+        let image = image.split('@').next().unwrap_or(image);
+        if let Some((_, version)) = image.rsplit_once(':') {
+            settings.version = version.to_string();
+        }
+        let mut entrypoints = BTreeMap::new();
+        for arg in args {
+            let Some((key, value)) = arg.trim_start_matches("--").split_once('=') else {
+                continue;
+            };
+            let key = key.to_lowercase();
+            if key == "certificatesresolvers.myresolver.acme.email" && !value.is_empty() {
+                settings.acme_email = Some(value.to_string());
+            } else if key == "accesslog" {
+                settings.access_logs = value == "true";
+            } else if let Some(name) = key
+                .strip_prefix("entrypoints.")
+                .and_then(|k| k.strip_suffix(".address"))
+            {
+                if let Some(port) = value.rsplit(':').next().and_then(|p| p.parse().ok()) {
+                    entrypoints.insert(name.to_string(), port);
+                }
+            }
+        }
+        settings.entrypoints.extend(entrypoints);
+        settings
+    }
+
+    fn get_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--providers.docker.swarmMode=true".to_string(),
+            "--ping=true".to_string(),
+        ];
+        for (name, port) in &self.entrypoints {
+            args.push(format!("--entryPoints.{}.address=:{}", name, port));
+        }
+        args.push("--certificatesresolvers.myresolver.acme.tlschallenge=true".to_string());
+        args.push(
+            "--certificatesresolvers.myresolver.acme.storage=/letsencrypt/acme.json".to_string(),
+        );
+        if let Some(email) = &self.acme_email {
+            args.push(format!(
+                "--certificatesresolvers.myresolver.acme.email={}",
+                email
+            ));
+        }
+        if self.dashboard_domain.is_some() {
+            args.push("--api.dashboard=true".to_string());
+        }
+        if self.access_logs {
+            args.push("--accesslog=true".to_string());
+        }
+        args
+    }
+
+    fn get_labels(&self) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        let (Some(domain), Some(auth)) = (&self.dashboard_domain, &self.dashboard_auth) else {
+            labels.insert("traefik.enable".to_string(), "false".to_string());
+            return labels;
+        };
+        let router = "traefik.http.routers.lev-dashboard";
+        labels.insert("traefik.enable".to_string(), "true".to_string());
+        labels.insert(format!("{}.rule", router), format!("Host(`{}`)", domain));
+        labels.insert(format!("{}.service", router), "api@internal".to_string());
+        labels.insert(format!("{}.entrypoints", router), "websecure".to_string());
+        labels.insert(format!("{}.tls", router), "true".to_string());
+        labels.insert(
+            format!("{}.tls.certresolver", router),
+            "myresolver".to_string(),
+        );
+        labels.insert(
+            format!("{}.middlewares", router),
+            "lev-dashboard-auth".to_string(),
+        );
+        labels.insert(
+            "traefik.http.middlewares.lev-dashboard-auth.basicauth.users".to_string(),
+            auth.clone(),
+        );
+        // swarm mode needs a port even for internal services
+        labels.insert(
+            "traefik.http.services.lev-dashboard.loadbalancer.server.port".to_string(),
+            "8080".to_string(),
+        );
+        labels
+    }
+}
+
+#[test]
+fn proxy_settings_test() {
+    let mut settings = ProxySettings::default();
+    settings.validate().unwrap();
+    let params = settings.to_service_param("lev");
+    assert_eq!(params.image, "traefik:v2.10");
+    assert_eq!(params.exposed_ports.get(&443), Some(&443));
+    assert_eq!(
+        params.labels.get("traefik.enable"),
+        Some(&"false".to_string())
+    );
+    assert!(!params.args.contains(&"--api.dashboard=true".to_string()));
+
+    settings.dashboard_domain = Some("traefik.example.com".to_string());
+    assert!(settings.validate().is_err());
+    settings.dashboard_auth = Some("admin:$2y$05$hash".to_string());
+    settings.validate().unwrap();
+    let params = settings.to_service_param("lev");
+    assert!(params.args.contains(&"--api.dashboard=true".to_string()));
+    assert_eq!(
+        params.labels.get("traefik.http.routers.lev-dashboard.rule"),
+        Some(&"Host(`traefik.example.com`)".to_string())
+    );
+
+    settings.entrypoints.remove("web");
+    assert!(settings.validate().is_err());
+}
+
+#[test]
+fn proxy_from_service_test() {
+    let args: Vec<String> = [
+        "--api.insecure=true",
+        "--api.dashboard=true",
+        "--entryPoints.web.address=:80",
+        "--entryPoints.websecure.address=:443",
+        "--entryPoints.mqtt.address=:1883",
+        "--providers.docker.swarmMode=true",
+        "--certificatesresolvers.myresolver.acme.tlschallenge=true",
+        "--certificatesresolvers.myresolver.acme.email=ops@example.com",
+        "--certificatesresolvers.myresolver.acme.storage=/letsencrypt/acme.json",
+    ]
+    .iter()
+    .map(|a| a.to_string())
+    .collect();
+    let settings = ProxySettings::from_service("traefik:v2.11@sha256:abc", &args);
+    settings.validate().unwrap();
+    assert_eq!(settings.version, "v2.11");
+    assert_eq!(settings.acme_email, Some("ops@example.com".to_string()));
+    assert_eq!(settings.entrypoints.get("mqtt"), Some(&1883));
+    assert_eq!(settings.entrypoints.len(), 3);
+    assert!(settings.dashboard_domain.is_none());
+    assert_eq!(
+        ProxySettings::from_service("traefik", &[]),
+        ProxySettings::default()
+    );
+}
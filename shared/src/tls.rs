@@ -0,0 +1,31 @@
+use sha2::{Digest, Sha256};
+
+// the manager serves the api over TLS on this port, LEV_TLS_PORT changes it
+pub const DEFAULT_TLS_PORT: u16 = 8443;
+
+// sha256 of a certificate as colon separated hex, like openssl prints it. The server prints
+// it on start and the cli pins it when the certificate isn't signed by a known ca
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// fingerprints typed by hand may be lowercase or without colons
+pub fn same_fingerprint(a: &str, b: &str) -> bool {
+    let normalize = |f: &str| f.replace(':', "").to_uppercase();
+    normalize(a) == normalize(b)
+}
+
+#[test]
+fn tls_test() {
+    assert_eq!(fingerprint(b"lev").len(), 32 * 3 - 1);
+    assert!(same_fingerprint(
+        &fingerprint(b"lev"),
+        &fingerprint(b"lev").to_lowercase()
+    ));
+    assert!(same_fingerprint("ab:cd:01", "ABCD01"));
+    assert!(!same_fingerprint("ab:cd:01", "ABCD02"));
+}